@@ -14,8 +14,10 @@ use common::all_packets::subscribe::Subscribe;
 use common::packet::WritePacket;
 use common::packet::SOCKET_CLOSED_ERROR_MSG;
 use common::packet::{Packet, Qos, Subscription};
+use common::stream::MqttStream;
+use common::tls::TlsClientConfig;
 use std::collections::HashMap;
-use std::net::{Shutdown, TcpStream};
+use std::net::Shutdown;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
@@ -30,7 +32,7 @@ pub type PacketResult = Result<Packet, Box<dyn std::error::Error + Send>>;
 
 #[derive(Debug)]
 pub struct Client {
-    server_stream: Option<TcpStream>,
+    server_stream: Option<MqttStream>,
     packets_id: HashMap<u16, bool>,
     tx_to_puback_processor: Sender<PacketResult>,
     rx_from_packet_processor: Option<Receiver<PacketResult>>,
@@ -52,7 +54,7 @@ impl Client {
         }
     }
 
-    pub fn set_server_stream(&mut self, stream: TcpStream) {
+    pub fn set_server_stream(&mut self, stream: MqttStream) {
         self.server_stream = Some(stream);
     }
 
@@ -160,7 +162,7 @@ impl Client {
     }
 
     pub fn handle_response(
-        mut s: TcpStream,
+        mut s: MqttStream,
         sender: Sender<ResponseHandlers>,
         sender_intern: Sender<EventHandlers>,
     ) {
@@ -409,7 +411,7 @@ impl Client {
         sender_intern: Sender<EventHandlers>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let address = conec.get_address();
-        let mut socket = TcpStream::connect(address.clone())?;
+        let mut socket = MqttStream::connect(&address, TlsClientConfig::from_env().as_ref())?;
         let keep_alive_time = conec.keep_alive_second.parse()?;
         println!("Connecting to: {:?}", address);
         println!("Client id: {:?}", conec.client_id);
@@ -1,10 +1,12 @@
+use crate::logging::logger::{log_global, LogMessage};
 use crate::packet::{Packet, ReadPacket, WritePacket};
 use crate::parser::{decode_remaining_length, encode_remaining_length};
 use std::io::{Cursor, Read, Write};
 
 pub const CONNACK_PACKET_TYPE: u8 = 0x20;
-const CONNACK_REMAINING_LENGTH: u32 = 2;
+pub(crate) const CONNACK_REMAINING_LENGTH: u32 = 2;
 pub const CONNACK_BAD_USERNAME_OR_PASSWORD: u8 = 0x04;
+pub const CONNACK_NOT_AUTHORIZED: u8 = 0x05;
 pub const CONNACK_CONNECTION_ACCEPTED: u8 = 0x00;
 
 #[derive(Debug, Clone)]
@@ -42,7 +44,10 @@ impl WritePacket for Connack {
         // Escribimos el connect return code
         stream.write_all(&[self.connect_return_code])?;
 
-        println!("Connack packet escrito correctamente");
+        log_global(LogMessage::trace(
+            "Connack packet written".to_string(),
+            "".to_string(),
+        ));
 
         Ok(())
     }
@@ -73,7 +78,10 @@ impl ReadPacket for Connack {
 
         verify_packet(session_present, connect_return_code)?;
 
-        println!("Connack packet leido correctamente");
+        log_global(LogMessage::trace(
+            "Connack packet read".to_string(),
+            "".to_string(),
+        ));
 
         Ok(Packet::Connack(Connack {
             session_present,
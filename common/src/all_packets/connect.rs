@@ -1,3 +1,4 @@
+use crate::logging::logger::{log_global, LogMessage};
 use crate::packet::{Packet, ReadPacket, WritePacket};
 use crate::parser::decode_mqtt_string;
 use crate::parser::decode_remaining_length;
@@ -63,7 +64,7 @@ impl Connect {
         }
     }
 
-    fn get_remaining_length(&self) -> Result<u32, String> {
+    pub(crate) fn get_remaining_length(&self) -> Result<u32, String> {
         //Variable header bytes + Payload bytes
         Ok(CONNECT_VARIABLE_HEADER_BYTES + self.connect_payload.length()?)
     }
@@ -160,7 +161,10 @@ impl ReadPacket for Connect {
         let payload = ConnectPayload::read_from(&mut remaining_bytes, &connect_flags)?;
         verify_payload(&connect_flags, &payload)?;
 
-        println!("Connect packet leido correctamente");
+        log_global(LogMessage::trace(
+            "Connect packet read".to_string(),
+            "".to_string(),
+        ));
 
         Ok(Packet::Connect(Connect::new(
             payload,
@@ -227,7 +231,6 @@ fn verify_client_id(client_id: &str) -> Result<(), Box<dyn std::error::Error>> {
         .all(|c| ALLOWED_CHARS_CLIENT_ID.contains(c))
         || !ALLOWED_RANGE_CLIENT_ID.contains(&client_id.chars().count())
     {
-        println!("El client id ingresado es inválido");
         return Err(INVALID_CLIENT_ID_ERROR_MSG.into());
     }
     Ok(())
@@ -361,6 +364,9 @@ impl ConnectPayload {
         let mut password = None;
         if flags.username {
             username = Some(decode_mqtt_string(stream)?);
+        }
+        // Un usuario puede venir sin password, pero no al revés (ver verify_connect_flags)
+        if flags.password {
             password = Some(decode_mqtt_string(stream)?);
         }
 
@@ -488,6 +494,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_comparison)]
     fn correct_packet() {
         let connect_packet = Connect::new(
             ConnectPayload::new(
@@ -518,6 +525,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn username_without_password_is_read() {
+        let connect_packet = Connect::new(
+            ConnectPayload::new("u".to_owned(), None, None, Some("sensor".to_owned()), None),
+            60,
+            true,
+            false,
+            false,
+        );
+
+        let mut buff = Cursor::new(Vec::new());
+        connect_packet.write_to(&mut buff).unwrap();
+        buff.set_position(1);
+        match Connect::read_from(&mut buff, 0x10).unwrap() {
+            Packet::Connect(to_test) => {
+                assert_eq!(to_test.connect_payload, connect_packet.connect_payload)
+            }
+            _ => panic!("Se esperaba un Connect"),
+        }
+    }
+
     #[test]
     fn error_packet() {
         let connect_packet = Connect::new(
@@ -623,6 +651,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn valid_client_id() {
         let connect_packet = Connect::new(
             ConnectPayload::new(
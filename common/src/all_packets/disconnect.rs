@@ -2,7 +2,7 @@ use crate::packet::{Packet, ReadPacket, WritePacket};
 use crate::parser::{decode_remaining_length, encode_remaining_length};
 use std::io::{Read, Write};
 
-const DISCONNECT_REMAINING_LENGTH: u32 = 0;
+pub(crate) const DISCONNECT_REMAINING_LENGTH: u32 = 0;
 pub const DISCONNECT_PACKET_TYPE: u8 = 0xe0;
 
 #[derive(Debug)]
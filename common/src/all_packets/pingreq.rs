@@ -2,7 +2,7 @@ use crate::packet::{Packet, ReadPacket, WritePacket};
 use crate::parser::{decode_remaining_length, encode_remaining_length};
 use std::io::{Read, Write};
 
-const PINGREQ_REMAINING_LENGTH: u32 = 0;
+pub(crate) const PINGREQ_REMAINING_LENGTH: u32 = 0;
 pub const PINGREQ_PACKET_TYPE: u8 = 0xc0;
 
 #[derive(Debug)]
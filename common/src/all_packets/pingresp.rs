@@ -2,7 +2,7 @@ use crate::packet::{Packet, ReadPacket, WritePacket};
 use crate::parser::{decode_remaining_length, encode_remaining_length};
 use std::io::{Read, Write};
 
-const PINGRESP_REMAINING_LENGTH: u32 = 0;
+pub(crate) const PINGRESP_REMAINING_LENGTH: u32 = 0;
 pub const PINGRESP_PACKET_TYPE: u8 = 0xd0;
 
 #[derive(Debug)]
@@ -1,10 +1,11 @@
+use crate::logging::logger::{log_global, LogMessage};
 use crate::packet::{Packet, ReadPacket, WritePacket};
 use crate::parser::{decode_remaining_length, encode_remaining_length};
 use std::error::Error;
 use std::io::{Cursor, Read, Write};
 
 pub const PUBACK_PACKET_TYPE: u8 = 0x40;
-const PUBACK_REMAINING_LENGTH: u32 = 2;
+pub(crate) const PUBACK_REMAINING_LENGTH: u32 = 2;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Puback {
@@ -33,7 +34,10 @@ impl WritePacket for Puback {
         let packet_id_from_publish = self.packet_id.to_be_bytes();
         stream.write_all(&packet_id_from_publish)?;
 
-        println!("Puback packet escrito correctamente");
+        log_global(LogMessage::trace(
+            "Puback packet written".to_string(),
+            "".to_string(),
+        ));
 
         Ok(())
     }
@@ -53,7 +57,10 @@ impl ReadPacket for Puback {
         remaining_bytes.read_exact(&mut packet_id)?;
         let packet_id = u16::from_be_bytes(packet_id);
 
-        println!("Puback packet leido correctamente");
+        log_global(LogMessage::trace(
+            "Puback packet read".to_string(),
+            "".to_string(),
+        ));
 
         Ok(Packet::Puback(Puback { packet_id }))
     }
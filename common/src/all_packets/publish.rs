@@ -29,7 +29,8 @@ impl Publish {
         }
     }
 
-    fn get_remaining_length(&self) -> Result<u32, String> {
+    #[allow(clippy::needless_as_bytes)]
+    pub(crate) fn get_remaining_length(&self) -> Result<u32, String> {
         //VARIABLE HEADER
         let mut length = encode_mqtt_string(&self.topic_name)?.len();
         if let Some(packet_identifier) = self.packet_id {
@@ -212,12 +213,12 @@ mod tests {
             Some(15),
             "Message".to_string(),
         );
-        println!("{:?}", publish);
         let to_test = publish.get_remaining_length().unwrap();
         assert_eq!(to_test, 16);
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn correct_new_publishflag_all_true() {
         let to_test = PublishFlags::new(0b0100_1011);
 
@@ -227,6 +228,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn correct_new_publishflag_all_false() {
         let to_test = PublishFlags::new(0b0100_0000);
 
@@ -43,7 +43,7 @@ impl Suback {
         }
     }
 
-    fn get_remaining_length(&self) -> Result<u32, String> {
+    pub(crate) fn get_remaining_length(&self) -> Result<u32, String> {
         //VARIABLE HEADER
         let mut length = VARIABLE_HEADER_REMAINING_LENGTH;
         length += self.return_codes.len() as u8;
@@ -24,7 +24,7 @@ impl Subscribe {
         self.subscriptions.push(subscription);
     }
 
-    fn get_remaining_length(&self) -> Result<u32, String> {
+    pub(crate) fn get_remaining_length(&self) -> Result<u32, String> {
         //VARIABLE HEADER
         let mut length = VARIABLE_HEADER_REMAINING_LENGTH;
 
@@ -3,11 +3,11 @@ use crate::parser::{decode_remaining_length, encode_remaining_length};
 use std::io::{Cursor, Read, Write};
 
 pub const UNSUBACK_PACKET_TYPE: u8 = 0xb0;
-const UNSUBACK_REMAINING_LENGTH: u32 = 2;
+pub(crate) const UNSUBACK_REMAINING_LENGTH: u32 = 2;
 
 #[derive(Debug)]
 pub struct Unsuback {
-    packet_id: u16,
+    pub packet_id: u16,
 }
 
 impl Unsuback {
@@ -26,7 +26,7 @@ impl Unsubscribe {
         self.topics.push(topic);
     }
 
-    fn get_remaining_length(&self) -> Result<u32, String> {
+    pub(crate) fn get_remaining_length(&self) -> Result<u32, String> {
         //VARIABLE HEADER
         let mut length = VARIABLE_HEADER_REMAINING_LENGTH;
 
@@ -0,0 +1,154 @@
+/*
+Formato de los archivos de captura de tráfico: una línea por paquete, con los bytes tal como
+pasaron por el socket.
+
+    2021-11-05T18:30:00.250Z 1636137000250123 17 sensor-1 in 100c00044d515454...
+
+Los campos son la hora legible, la hora en microsegundos desde epoch (la que se usa para
+reproducir la captura), el id de la conexión, el client id, la dirección (in = del cliente al
+server, out = del server al cliente) y el paquete en hexadecimal.
+*/
+
+use crate::logging::timestamp;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureRecord {
+    pub time: SystemTime,
+    pub connection: u32,
+    pub client_id: String,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+impl CaptureRecord {
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {} {} {}\n",
+            timestamp::rfc3339(self.time),
+            self.micros(),
+            self.connection,
+            self.client_id,
+            self.direction,
+            to_hex(&self.bytes)
+        )
+    }
+
+    pub fn parse_line(line: &str) -> Result<CaptureRecord, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(format!("expected 6 fields, found {}", fields.len()));
+        }
+        let micros: u64 = fields[1]
+            .parse()
+            .map_err(|_| format!("invalid time '{}'", fields[1]))?;
+        let connection = fields[2]
+            .parse()
+            .map_err(|_| format!("invalid connection id '{}'", fields[2]))?;
+        let direction = match fields[4] {
+            "in" => Direction::Inbound,
+            "out" => Direction::Outbound,
+            other => return Err(format!("invalid direction '{}'", other)),
+        };
+        Ok(CaptureRecord {
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            connection,
+            client_id: fields[3].to_string(),
+            direction,
+            bytes: from_hex(fields[5])?,
+        })
+    }
+
+    pub fn micros(&self) -> u64 {
+        self.time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64
+    }
+}
+
+// Lee un archivo de captura entero. Las líneas vacías y las que empiezan con # se ignoran
+pub fn parse(contents: &str) -> Result<Vec<CaptureRecord>, String> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            CaptureRecord::parse_line(line).map_err(|e| format!("line {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Acepta mayúsculas y minúsculas, con o sin espacios entre los bytes
+pub fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16).map_err(|_| format!("invalid hex byte '{}'", byte))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_survive_a_round_trip() {
+        let record = CaptureRecord {
+            time: UNIX_EPOCH + Duration::from_micros(1_636_137_000_250_123),
+            connection: 17,
+            client_id: "sensor-1".to_string(),
+            direction: Direction::Inbound,
+            bytes: vec![0xc0, 0x00],
+        };
+        let line = record.to_line();
+        assert_eq!(
+            line,
+            "2021-11-05T18:30:00.250Z 1636137000250123 17 sensor-1 in c000\n"
+        );
+        assert_eq!(CaptureRecord::parse_line(&line).unwrap(), record);
+    }
+
+    #[test]
+    fn invalid_lines_report_their_number() {
+        let contents = "# captura\n\n2021-11-05T18:30:00.250Z 1 2 a out d000\nroto\n";
+        assert_eq!(
+            parse(contents).unwrap_err(),
+            "line 4: expected 6 fields, found 1"
+        );
+        assert!(CaptureRecord::parse_line("t 1 2 a sideways d000").is_err());
+        assert!(CaptureRecord::parse_line("t 1 2 a in d00").is_err());
+    }
+
+    #[test]
+    fn hex_accepts_spaces_and_uppercase() {
+        assert_eq!(from_hex("C0 00").unwrap(), vec![0xc0, 0x00]);
+        assert!(from_hex("zz").is_err());
+    }
+}
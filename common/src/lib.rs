@@ -1,4 +1,7 @@
 pub mod all_packets; // Archivo que contiene todos los packets (structs)
+pub mod capture;
 pub mod logging;
 pub mod packet; // Archivo que contiene el enum packets
 pub mod parser;
+pub mod stream;
+pub mod tls;
@@ -0,0 +1,135 @@
+use std::fmt;
+use std::str::FromStr;
+
+// De más grave a más detallado: con un nivel configurado se escriben ese y los anteriores
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Level, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("nivel de log invalido '{}'", value)),
+        }
+    }
+}
+
+// Nivel general y niveles propios de algunos módulos. Un módulo configurado como
+// "packet_processor" vale para server::packet_processor; "server" vale para todo server::*
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelFilter {
+    pub default: Level,
+    pub modules: Vec<(String, Level)>,
+}
+
+impl Default for LevelFilter {
+    fn default() -> Self {
+        LevelFilter {
+            default: Level::Info,
+            modules: vec![],
+        }
+    }
+}
+
+impl LevelFilter {
+    // Si varios módulos configurados coinciden, gana el más largo
+    pub fn enabled(&self, level: Level, module: &str) -> bool {
+        let threshold = self
+            .modules
+            .iter()
+            .filter(|(name, _)| module_matches(name, module))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default);
+        level <= threshold
+    }
+
+    // Lista con formato "modulo=nivel, otro=nivel"
+    pub fn parse_modules(value: &str) -> Result<Vec<(String, Level)>, String> {
+        value
+            .split(',')
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once('=') {
+                Some((module, level)) if !module.trim().is_empty() => {
+                    Ok((module.trim().to_string(), level.parse()?))
+                }
+                _ => Err(format!("se esperaba modulo=nivel: '{}'", entry)),
+            })
+            .collect()
+    }
+
+    pub fn modules_to_string(&self) -> String {
+        self.modules
+            .iter()
+            .map(|(module, level)| format!("{}={}", module, level))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+fn module_matches(configured: &str, module: &str) -> bool {
+    module == configured
+        || module.starts_with(&format!("{}::", configured))
+        || module.ends_with(&format!("::{}", configured))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_most_specific_module_wins() {
+        let filter = LevelFilter {
+            default: Level::Info,
+            modules: LevelFilter::parse_modules("server=warn, packet_processor=trace").unwrap(),
+        };
+        assert!(filter.enabled(Level::Trace, "server::packet_processor"));
+        assert!(!filter.enabled(Level::Info, "server::client_handler"));
+        assert!(filter.enabled(Level::Warn, "server::client_handler"));
+        assert!(filter.enabled(Level::Info, "common::packet"));
+        assert!(!filter.enabled(Level::Debug, "common::packet"));
+        assert_eq!(
+            filter.modules_to_string(),
+            "server=warn, packet_processor=trace"
+        );
+    }
+
+    #[test]
+    fn invalid_module_levels_are_errors() {
+        assert!(LevelFilter::parse_modules("server").is_err());
+        assert!(LevelFilter::parse_modules("server=verbose").is_err());
+        assert!(LevelFilter::parse_modules("=debug").is_err());
+        assert_eq!(LevelFilter::parse_modules(" ").unwrap(), vec![]);
+    }
+}
@@ -1,35 +1,140 @@
-use std::fmt::Debug;
-use std::fs::File;
-use std::io::prelude::*;
+use crate::logging::level::{Level, LevelFilter};
+use crate::logging::rotating_file::{RotatingFile, RotationConfig};
+use crate::logging::timestamp;
+use std::fmt::{self, Debug};
+use std::panic::Location;
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Mutex};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
+use std::time::SystemTime;
+
+// Logger al que mandan los módulos que no reciben uno (por ejemplo common::packet)
+static GLOBAL_LOGGER: RwLock<Option<Arc<Logger>>> = RwLock::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // timestamp nivel módulo mensaje client_id
+    Text,
+    // Un objeto JSON por línea
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<LogFormat, String> {
+        match value.trim() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("formato de log invalido '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggerConfig {
+    pub file: String,
+    pub levels: LevelFilter,
+    pub format: LogFormat,
+    // Además del archivo, cada línea se muestra por stdout
+    pub stdout: bool,
+    pub rotation: RotationConfig,
+}
+
+impl LoggerConfig {
+    pub fn new(file: &str) -> LoggerConfig {
+        LoggerConfig {
+            file: file.to_string(),
+            levels: LevelFilter::default(),
+            format: LogFormat::Text,
+            stdout: false,
+            rotation: RotationConfig::default(),
+        }
+    }
+}
 
 pub struct Logger {
     logger_send: Mutex<Sender<LogMessage>>,
+    // Se pueden cambiar mientras corre el server (al recargar la configuración)
+    levels: RwLock<LevelFilter>,
 }
 
 impl Logger {
     pub fn new(file_path: &str) -> Result<Logger, Box<dyn std::error::Error>> {
-        if let Ok(mut file) = File::create(file_path) {
-            let (sender, receiver) = mpsc::channel::<LogMessage>();
-            thread::spawn(move || loop {
-                let msg = receiver.recv();
-                if let Ok(m) = msg {
-                    file.write_all(m.msg_to_string().as_bytes()).unwrap();
+        Logger::from_config(&LoggerConfig::new(file_path))
+    }
+
+    pub fn from_config(config: &LoggerConfig) -> Result<Logger, Box<dyn std::error::Error>> {
+        let file = RotatingFile::open(&config.file, config.rotation.clone())
+            .map_err(|e| format!("No se pudo crear el logger en {}: {}", config.file, e))?;
+        Ok(Logger::spawn(config, Some(file)))
+    }
+
+    // Ignora config.file: las líneas sólo se muestran por stdout si config.stdout lo indica
+    pub fn without_file(config: &LoggerConfig) -> Logger {
+        Logger::spawn(config, None)
+    }
+
+    fn spawn(config: &LoggerConfig, mut file: Option<RotatingFile>) -> Logger {
+        let format = config.format;
+        let stdout = config.stdout;
+        let (sender, receiver) = mpsc::channel::<LogMessage>();
+        thread::spawn(move || {
+            while let Ok(msg) = receiver.recv() {
+                let line = match format {
+                    LogFormat::Text => msg.to_text_line(),
+                    LogFormat::Json => msg.to_json_line(),
+                };
+                if stdout {
+                    print!("{}", line);
                 }
-            });
+                if let Some(file) = file.as_mut() {
+                    if let Err(e) = file.write_line(&line, msg.timestamp) {
+                        eprintln!("Could not write to the log file: {}", e);
+                    }
+                }
+            }
+        });
+
+        Logger {
+            logger_send: Mutex::new(sender),
+            levels: RwLock::new(config.levels.clone()),
+        }
+    }
 
-            let sender_mtx = Mutex::new(sender);
-            return Ok(Logger {
-                logger_send: sender_mtx,
-            });
+    pub fn levels(&self) -> LevelFilter {
+        self.levels
+            .read()
+            .map(|levels| levels.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set_levels(&self, levels: LevelFilter) {
+        if let Ok(mut current) = self.levels.write() {
+            *current = levels;
         }
-        Err("No se pudo crear el logger".into())
     }
 
-    pub fn log_msg(&self, msg: LogMessage) -> Result<(), Box<dyn std::error::Error>> {
-        println!("{:?}", msg);
+    // El módulo se toma del archivo desde el que se llama
+    #[track_caller]
+    pub fn log_msg(&self, mut msg: LogMessage) -> Result<(), Box<dyn std::error::Error>> {
+        msg.module = module_from_path(Location::caller().file());
+        match self.levels.read() {
+            Ok(levels) if !levels.enabled(msg.level, &msg.module) => return Ok(()),
+            Ok(_) => {}
+            Err(_) => return Err("Error al loggear el mensaje".into()),
+        }
+        msg.timestamp = SystemTime::now();
         if let Ok(sender) = self.logger_send.lock() {
             sender.send(msg)?;
         } else {
@@ -40,28 +145,133 @@ impl Logger {
     }
 }
 
+pub fn set_global_logger(logger: Arc<Logger>) {
+    if let Ok(mut global) = GLOBAL_LOGGER.write() {
+        *global = Some(logger);
+    }
+}
+
+// Si todavía no hay un logger global el mensaje se descarta
+#[track_caller]
+pub fn log_global(msg: LogMessage) {
+    if let Ok(global) = GLOBAL_LOGGER.read() {
+        if let Some(logger) = global.as_ref() {
+            let _ = logger.log_msg(msg);
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LogMessage {
+    level: Level,
     message: String,
     client_id: String,
+    module: String,
+    timestamp: SystemTime,
 }
 
 impl LogMessage {
     pub fn new(msg: String, client: String) -> LogMessage {
+        LogMessage::with_level(Level::Info, msg, client)
+    }
+
+    pub fn error(msg: String, client: String) -> LogMessage {
+        LogMessage::with_level(Level::Error, msg, client)
+    }
+
+    pub fn warn(msg: String, client: String) -> LogMessage {
+        LogMessage::with_level(Level::Warn, msg, client)
+    }
+
+    pub fn debug(msg: String, client: String) -> LogMessage {
+        LogMessage::with_level(Level::Debug, msg, client)
+    }
+
+    pub fn trace(msg: String, client: String) -> LogMessage {
+        LogMessage::with_level(Level::Trace, msg, client)
+    }
+
+    pub fn with_level(level: Level, msg: String, client: String) -> LogMessage {
         LogMessage {
+            level,
             message: msg,
             client_id: client,
+            module: String::new(),
+            timestamp: SystemTime::UNIX_EPOCH,
         }
     }
 
     pub fn msg_to_string(self) -> String {
         self.message + " " + &*self.client_id + "\n"
     }
+
+    fn to_text_line(&self) -> String {
+        let line = format!(
+            "{} {:<5} {} {} {}",
+            timestamp::rfc3339(self.timestamp),
+            self.level.name().to_uppercase(),
+            self.module,
+            self.message,
+            self.client_id
+        );
+        line.trim_end().to_string() + "\n"
+    }
+
+    fn to_json_line(&self) -> String {
+        format!(
+            "{{\"timestamp\":\"{}\",\"level\":\"{}\",\"module\":{},\"message\":{},\"client_id\":{}}}\n",
+            timestamp::rfc3339(self.timestamp),
+            self.level,
+            json_string(&self.module),
+            json_string(&self.message),
+            json_string(&self.client_id)
+        )
+    }
+}
+
+// "server/src/packet_processor.rs" -> "server::packet_processor"
+fn module_from_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let parts: Vec<&str> = path.split('/').collect();
+    let src = match parts.iter().rposition(|part| *part == "src") {
+        Some(src) if src > 0 => src,
+        _ => {
+            let file = parts.last().unwrap_or(&"");
+            return file.trim_end_matches(".rs").replace('-', "_");
+        }
+    };
+    let mut module = vec![parts[src - 1].replace('-', "_")];
+    for part in &parts[src + 1..] {
+        let part = part.trim_end_matches(".rs");
+        if !matches!(part, "mod" | "lib" | "main") {
+            module.push(part.replace('-', "_"));
+        }
+    }
+    module.join("::")
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
 }
 
 #[cfg(test)]
 pub mod test_logger {
-    use crate::logging::logger::LogMessage;
+    use crate::logging::logger::*;
+    use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
     fn test_log_message_01_() {
@@ -72,4 +282,61 @@ pub mod test_logger {
         let msg_from_log_message = "Servidor inicializado correctamente en: 8080\n".to_string();
         assert_eq!(message.msg_to_string(), msg_from_log_message);
     }
+
+    fn stamped(msg: LogMessage) -> LogMessage {
+        LogMessage {
+            module: "server::packet_processor".to_string(),
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_636_137_000_250),
+            ..msg
+        }
+    }
+
+    #[test]
+    fn text_lines_have_timestamp_level_and_module() {
+        let msg = stamped(LogMessage::warn(
+            "Publish denied".to_string(),
+            "sensor-1".to_string(),
+        ));
+        assert_eq!(
+            msg.to_text_line(),
+            "2021-11-05T18:30:00.250Z WARN  server::packet_processor Publish denied sensor-1\n"
+        );
+        let msg = stamped(LogMessage::new(
+            "Server started".to_string(),
+            "".to_string(),
+        ));
+        assert!(msg.to_text_line().ends_with("Server started\n"));
+    }
+
+    #[test]
+    fn json_lines_escape_the_message() {
+        let msg = stamped(LogMessage::debug(
+            "topic \"a/b\"\n".to_string(),
+            "c".to_string(),
+        ));
+        assert_eq!(
+            msg.to_json_line(),
+            "{\"timestamp\":\"2021-11-05T18:30:00.250Z\",\"level\":\"debug\",\"module\":\"server::packet_processor\",\"message\":\"topic \\\"a/b\\\"\\n\",\"client_id\":\"c\"}\n"
+        );
+    }
+
+    #[test]
+    fn module_names_come_from_the_source_path() {
+        assert_eq!(
+            module_from_path("server/src/packet_processor.rs"),
+            "server::packet_processor"
+        );
+        assert_eq!(
+            module_from_path("common/src/all_packets/connect.rs"),
+            "common::all_packets::connect"
+        );
+        assert_eq!(
+            module_from_path("random-client/src/main.rs"),
+            "random_client"
+        );
+        assert_eq!(
+            module_from_path("common/src/logging/mod.rs"),
+            "common::logging"
+        );
+    }
 }
@@ -1 +1,4 @@
+pub mod level;
 pub mod logger;
+pub mod rotating_file;
+pub mod timestamp;
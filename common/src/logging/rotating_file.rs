@@ -0,0 +1,182 @@
+use crate::logging::timestamp;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Never,
+    // Al cambiar el día (UTC)
+    Daily,
+    // Antes de que el archivo pase del tamaño máximo
+    Size,
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Rotation::Never => "never",
+            Rotation::Daily => "daily",
+            Rotation::Size => "size",
+        })
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Rotation, String> {
+        match value.trim() {
+            "never" => Ok(Rotation::Never),
+            "daily" => Ok(Rotation::Daily),
+            "size" => Ok(Rotation::Size),
+            _ => Err(format!("rotacion invalida '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RotationConfig {
+    pub rotation: Rotation,
+    pub max_size_bytes: u64,
+    // Cuántos archivos rotados se guardan (archivo.1 es el más nuevo)
+    pub retention: usize,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        RotationConfig {
+            rotation: Rotation::Never,
+            max_size_bytes: 10 * 1024 * 1024,
+            retention: 5,
+        }
+    }
+}
+
+// Archivo que solo crece al final. Al rotar, archivo pasa a archivo.1, archivo.1 a
+// archivo.2, y así hasta `retention`; el que sobra se borra
+pub struct RotatingFile {
+    path: String,
+    file: File,
+    config: RotationConfig,
+    size: u64,
+    day: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, config: RotationConfig) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // Un archivo que viene de otro día se rota con la primera línea nueva
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file,
+            config,
+            size: metadata.len(),
+            day: timestamp::days_since_epoch(modified),
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str, now: SystemTime) -> io::Result<()> {
+        let today = timestamp::days_since_epoch(now);
+        let rotate = self.size > 0
+            && match self.config.rotation {
+                Rotation::Never => false,
+                Rotation::Daily => today != self.day,
+                Rotation::Size => self.size + line.len() as u64 > self.config.max_size_bytes,
+            };
+        if rotate {
+            self.rotate()?;
+        }
+        self.day = today;
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |index: usize| format!("{}.{}", self.path, index);
+        if self.config.retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.config.retention));
+            for index in (1..self.config.retention).rev() {
+                let _ = fs::rename(rotated(index), rotated(index + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn temp_path(name: &str) -> String {
+        let path = env::temp_dir()
+            .join(format!("rotating_file_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string();
+        for suffix in ["", ".1", ".2", ".3"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+        path
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_only_the_retained_files() {
+        let path = temp_path("size");
+        let config = RotationConfig {
+            rotation: Rotation::Size,
+            max_size_bytes: 10,
+            retention: 2,
+        };
+        let mut file = RotatingFile::open(&path, config).unwrap();
+        let now = SystemTime::now();
+        for line in ["uno 1\n", "dos 2\n", "tres\n", "cuatro\n"].iter() {
+            file.write_line(line, now).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cuatro\n");
+        assert_eq!(fs::read_to_string(format!("{}.1", path)).unwrap(), "tres\n");
+        assert_eq!(
+            fs::read_to_string(format!("{}.2", path)).unwrap(),
+            "dos 2\n"
+        );
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+    }
+
+    #[test]
+    fn rotates_when_the_day_changes_and_appends_on_reopen() {
+        let path = temp_path("daily");
+        let config = RotationConfig {
+            rotation: Rotation::Daily,
+            ..RotationConfig::default()
+        };
+        let day = UNIX_EPOCH + Duration::from_secs(1_636_137_000);
+        let mut file = RotatingFile::open(&path, config.clone()).unwrap();
+        file.write_line("a\n", day).unwrap();
+        drop(file);
+        let mut file = RotatingFile::open(&path, config).unwrap();
+        file.day = timestamp::days_since_epoch(day);
+        file.write_line("b\n", day + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\nb\n");
+
+        file.write_line("c\n", day + Duration::from_secs(86_400))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "c\n");
+        assert_eq!(fs::read_to_string(format!("{}.1", path)).unwrap(), "a\nb\n");
+    }
+}
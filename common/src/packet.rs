@@ -1,15 +1,18 @@
-use crate::all_packets::connack::{Connack, CONNACK_PACKET_TYPE};
+use crate::all_packets::connack::{Connack, CONNACK_PACKET_TYPE, CONNACK_REMAINING_LENGTH};
 use crate::all_packets::connect::{Connect, CONNECT_PACKET_TYPE};
-use crate::all_packets::disconnect::{Disconnect, DISCONNECT_PACKET_TYPE};
-use crate::all_packets::pingreq::{Pingreq, PINGREQ_PACKET_TYPE};
-use crate::all_packets::pingresp::{Pingresp, PINGRESP_PACKET_TYPE};
-use crate::all_packets::puback::{Puback, PUBACK_PACKET_TYPE};
+use crate::all_packets::disconnect::{
+    Disconnect, DISCONNECT_PACKET_TYPE, DISCONNECT_REMAINING_LENGTH,
+};
+use crate::all_packets::pingreq::{Pingreq, PINGREQ_PACKET_TYPE, PINGREQ_REMAINING_LENGTH};
+use crate::all_packets::pingresp::{Pingresp, PINGRESP_PACKET_TYPE, PINGRESP_REMAINING_LENGTH};
+use crate::all_packets::puback::{Puback, PUBACK_PACKET_TYPE, PUBACK_REMAINING_LENGTH};
 use crate::all_packets::publish::{Publish, PUBLISH_PACKET_TYPE};
 use crate::all_packets::suback::{Suback, SUBACK_PACKET_TYPE};
 use crate::all_packets::subscribe::{Subscribe, SUBSCRIBE_PACKET_TYPE};
-use crate::all_packets::unsuback::{Unsuback, UNSUBACK_PACKET_TYPE};
+use crate::all_packets::unsuback::{Unsuback, UNSUBACK_PACKET_TYPE, UNSUBACK_REMAINING_LENGTH};
 use crate::all_packets::unsubscribe::{Unsubscribe, UNSUBSCRIBE_PACKET_TYPE};
-use crate::parser::decode_mqtt_string;
+use crate::logging::logger::{log_global, LogMessage};
+use crate::parser::{decode_mqtt_string, encoded_remaining_length_len};
 use std::io::{Error, ErrorKind::Other, Read, Write};
 
 const PACKET_TYPE_BYTE: u8 = 0xF0;
@@ -107,61 +110,104 @@ impl Packet {
     }
 
     pub fn write_to(&self, stream: &mut dyn Write) -> Result<(), Box<dyn std::error::Error>> {
+        log_global(LogMessage::trace(
+            format!("Sending {} packet", self.type_name()),
+            "".to_string(),
+        ));
         match self {
-            Packet::Connect(connect) => {
-                println!("Se manda el connect...");
-                connect.write_to(stream)
-            }
-
-            Packet::Connack(connack) => {
-                println!("Se manda el connack...");
-                connack.write_to(stream)
-            }
-
-            Packet::Publish(publish) => {
-                println!("Se manda el publish...");
-                publish.write_to(stream)
-            }
-
-            Packet::Puback(puback) => {
-                println!("Se manda el puback...");
-                puback.write_to(stream)
-            }
-
-            Packet::Subscribe(subscribe) => {
-                println!("Se manda el subscribe...");
-                subscribe.write_to(stream)
-            }
-
-            Packet::Suback(suback) => {
-                println!("Se manda el suback...");
-                suback.write_to(stream)
-            }
-
-            Packet::Unsubscribe(unsubscribe) => {
-                println!("Se manda el unsubscribe...");
-                unsubscribe.write_to(stream)
-            }
-
-            Packet::Unsuback(unsuback) => {
-                println!("Se manda el unsuback...");
-                unsuback.write_to(stream)
-            }
-
-            Packet::Pingreq(pingreq) => {
-                println!("Se manda el pingreq...");
-                pingreq.write_to(stream)
-            }
-
-            Packet::Pingresp(pingresp) => {
-                println!("Se manda el pingresp...");
-                pingresp.write_to(stream)
-            }
-
-            Packet::Disconnect(disconnect) => {
-                println!("Se manda el disconnect...");
-                disconnect.write_to(stream)
-            }
+            Packet::Connect(connect) => connect.write_to(stream),
+            Packet::Connack(connack) => connack.write_to(stream),
+            Packet::Publish(publish) => publish.write_to(stream),
+            Packet::Puback(puback) => puback.write_to(stream),
+            Packet::Subscribe(subscribe) => subscribe.write_to(stream),
+            Packet::Suback(suback) => suback.write_to(stream),
+            Packet::Unsubscribe(unsubscribe) => unsubscribe.write_to(stream),
+            Packet::Unsuback(unsuback) => unsuback.write_to(stream),
+            Packet::Pingreq(pingreq) => pingreq.write_to(stream),
+            Packet::Pingresp(pingresp) => pingresp.write_to(stream),
+            Packet::Disconnect(disconnect) => disconnect.write_to(stream),
         }
     }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Packet::Connect(_) => "Connect",
+            Packet::Connack(_) => "Connack",
+            Packet::Publish(_) => "Publish",
+            Packet::Puback(_) => "Puback",
+            Packet::Subscribe(_) => "Subscribe",
+            Packet::Suback(_) => "Suback",
+            Packet::Unsubscribe(_) => "Unsubscribe",
+            Packet::Unsuback(_) => "Unsuback",
+            Packet::Pingreq(_) => "Pingreq",
+            Packet::Pingresp(_) => "Pingresp",
+            Packet::Disconnect(_) => "Disconnect",
+        }
+    }
+
+    // Cantidad de bytes que ocupa el paquete en el socket, header incluido.
+    // Se calcula a partir del remaining length, sin volver a serializar el paquete
+    pub fn encoded_len(&self) -> usize {
+        let remaining_length = match self {
+            Packet::Connect(connect) => connect.get_remaining_length(),
+            Packet::Connack(_) => Ok(CONNACK_REMAINING_LENGTH),
+            Packet::Publish(publish) => publish.get_remaining_length(),
+            Packet::Puback(_) => Ok(PUBACK_REMAINING_LENGTH),
+            Packet::Subscribe(subscribe) => subscribe.get_remaining_length(),
+            Packet::Suback(suback) => suback.get_remaining_length(),
+            Packet::Unsubscribe(unsubscribe) => unsubscribe.get_remaining_length(),
+            Packet::Unsuback(_) => Ok(UNSUBACK_REMAINING_LENGTH),
+            Packet::Pingreq(_) => Ok(PINGREQ_REMAINING_LENGTH),
+            Packet::Pingresp(_) => Ok(PINGRESP_REMAINING_LENGTH),
+            Packet::Disconnect(_) => Ok(DISCONNECT_REMAINING_LENGTH),
+        };
+        match remaining_length {
+            // Byte de tipo + bytes del remaining length + remaining length
+            Ok(length) => 1 + encoded_remaining_length_len(length) + length as usize,
+            Err(_) => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::all_packets::connect::ConnectPayload;
+    use crate::all_packets::publish::PublishFlags;
+
+    fn written_len(packet: &dyn WritePacket) -> usize {
+        let mut buffer = vec![];
+        packet.write_to(&mut buffer).unwrap();
+        buffer.len()
+    }
+
+    #[test]
+    fn encoded_len_matches_written_bytes() {
+        let connect = Connect::new(
+            ConnectPayload::new(
+                "cliente".to_string(),
+                None,
+                None,
+                Some("usuario".to_string()),
+                Some("contraseña".to_string()),
+            ),
+            60,
+            true,
+            false,
+            false,
+        );
+        let expected = written_len(&connect);
+        assert_eq!(Packet::Connect(connect).encoded_len(), expected);
+
+        let publish = Publish::new(
+            PublishFlags::new(0b0011_0010),
+            "casa/temperatura".to_string(),
+            Some(10),
+            "x".repeat(200),
+        );
+        let expected = written_len(&publish);
+        assert_eq!(Packet::Publish(publish).encoded_len(), expected);
+
+        assert_eq!(Packet::Pingreq(Pingreq::new()).encoded_len(), 2);
+    }
 }
@@ -4,6 +4,7 @@ const MAX_MQTT_STRING_BYTES: usize = 65535;
 
 // Algoritmo para decodificar el número que representa el Remaining Length
 // en el fixed header de cualquier packet
+#[allow(clippy::unbuffered_bytes)]
 pub fn decode_remaining_length(stream: &mut dyn Read) -> Result<u32, Box<dyn std::error::Error>> {
     let mut multiplier: u32 = 1;
     let mut value: u32 = 0;
@@ -41,6 +42,17 @@ pub fn encode_remaining_length(packet_length: u32) -> Vec<u8> {
     vec
 }
 
+// Cantidad de bytes que ocupa el Remaining Length codificado, sin armar el vector
+pub fn encoded_remaining_length_len(packet_length: u32) -> usize {
+    let mut len = 1;
+    let mut x = packet_length / 0x80;
+    while x > 0 {
+        len += 1;
+        x /= 0x80;
+    }
+    len
+}
+
 pub fn encode_mqtt_string(string: &str) -> Result<Vec<u8>, String> {
     let string_bytes = string.as_bytes();
     let len_string_bytes = string_bytes.len();
@@ -58,6 +70,7 @@ pub fn encode_mqtt_string(string: &str) -> Result<Vec<u8>, String> {
     Ok(vec)
 }
 
+#[allow(clippy::io_other_error)]
 pub fn decode_mqtt_string(stream: &mut dyn Read) -> Result<String, std::io::Error> {
     let mut bytes_length = [0u8; 2];
     stream.read_exact(&mut bytes_length)?;
@@ -83,6 +96,16 @@ mod tests {
     use super::*;
     use std::io::Cursor;
 
+    #[test]
+    fn encoded_remaining_length_len_matches_encoding() {
+        for length in [0, 127, 128, 16383, 16384, 2097151, 2097152, 268435455] {
+            assert_eq!(
+                encoded_remaining_length_len(length),
+                encode_remaining_length(length).len()
+            );
+        }
+    }
+
     #[test]
     fn encode_length_1_byte_min() {
         let to_test = encode_remaining_length(1);
@@ -188,6 +211,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn error_decode_length() {
         let mut buff = Cursor::new(vec![255, 255, 255, 255, 127]);
         let to_test = decode_remaining_length(&mut buff);
@@ -0,0 +1,127 @@
+use crate::tls::{TlsClientConfig, TlsStream};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+// Conexión de un cliente con el server, con o sin TLS
+#[derive(Debug)]
+pub enum MqttStream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl MqttStream {
+    // Si hay configuración de TLS hace el handshake antes de devolver la conexión
+    pub fn connect(
+        address: &str,
+        tls: Option<&TlsClientConfig>,
+    ) -> Result<MqttStream, Box<dyn std::error::Error>> {
+        let socket = TcpStream::connect(address)?;
+        match tls {
+            Some(config) => {
+                let stream = TlsStream::connect(socket, host(address), config)?;
+                Ok(MqttStream::Tls(stream))
+            }
+            None => Ok(MqttStream::Tcp(socket)),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<MqttStream> {
+        match self {
+            MqttStream::Tcp(socket) => Ok(MqttStream::Tcp(socket.try_clone()?)),
+            MqttStream::Tls(stream) => Ok(MqttStream::Tls(stream.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            MqttStream::Tcp(socket) => socket.shutdown(how),
+            MqttStream::Tls(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            MqttStream::Tcp(socket) => socket.set_read_timeout(timeout),
+            MqttStream::Tls(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+// Saca el puerto de ip:puerto, y los corchetes si es IPv6
+fn host(address: &str) -> &str {
+    let host = match address.rfind(':') {
+        Some(index) if !address[index..].contains(']') => &address[..index],
+        _ => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+impl Read for MqttStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MqttStream::Tcp(socket) => socket.read(buf),
+            MqttStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MqttStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MqttStream::Tcp(socket) => socket.write(buf),
+            MqttStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MqttStream::Tcp(socket) => socket.flush(),
+            MqttStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// Lo que queda hasta `deadline`, o TimedOut si ya pasó
+pub fn time_left(deadline: Instant) -> io::Result<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining == Duration::from_secs(0) {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    Ok(remaining)
+}
+
+// Lectura directa del socket con un plazo total: antes de cada lectura el timeout se ajusta a
+// lo que queda. Las capas de arriba (TLS, WebSocket) pueden leer varias veces del socket para
+// devolver un solo read, así que el plazo se controla acá abajo. Sin plazo lee normalmente
+pub struct DeadlineSocket<'a> {
+    socket: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl<'a> DeadlineSocket<'a> {
+    pub fn new(socket: &'a TcpStream, deadline: Option<Instant>) -> DeadlineSocket<'a> {
+        DeadlineSocket { socket, deadline }
+    }
+}
+
+impl Read for DeadlineSocket<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            self.socket.set_read_timeout(Some(time_left(deadline)?))?;
+        }
+        self.socket.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_drops_the_port_and_brackets() {
+        assert_eq!(host("localhost:1883"), "localhost");
+        assert_eq!(host("[::1]:8883"), "::1");
+        assert_eq!(host("broker"), "broker");
+    }
+}
@@ -0,0 +1,405 @@
+use crate::stream::DeadlineSocket;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::ServerConnection;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const CA_FILE_ENV: &str = "MQTT_TLS_CA_FILE";
+const CERT_FILE_ENV: &str = "MQTT_TLS_CERT_FILE";
+const KEY_FILE_ENV: &str = "MQTT_TLS_KEY_FILE";
+const SERVER_NAME_ENV: &str = "MQTT_TLS_SERVER_NAME";
+
+const NO_CERTIFICATES_ERROR_MSG: &str = "No certificates found in";
+const POISONED_CONNECTION_ERROR_MSG: &str = "TLS connection poisoned";
+const HANDSHAKE_TIMEOUT_ERROR_MSG: &str = "TLS handshake not completed in time";
+const INCOMPLETE_CLIENT_CERT_ERROR_MSG: &str = "TLS client certificate and key go together";
+// Un registro TLS ocupa como mucho 16 KiB más el overhead
+const READ_BUFFER_SIZE: usize = 18 * 1024;
+// OID 2.5.4.3 (commonName)
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+// Configuración ya cargada de un listener con TLS, compartida por todas sus conexiones
+pub type TlsServerConfig = Arc<ServerConfig>;
+
+// Con qué se conecta un cliente a un server con TLS. El certificado propio solo hace falta si el
+// server pide certificados de cliente
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsClientConfig {
+    // CA con la que se verifica el certificado del server
+    pub ca_file: String,
+    // Nombre contra el que se valida el certificado (None = el host de la dirección)
+    pub server_name: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+}
+
+impl TlsClientConfig {
+    pub fn new(ca_file: &str) -> TlsClientConfig {
+        TlsClientConfig {
+            ca_file: ca_file.to_string(),
+            server_name: None,
+            cert_file: None,
+            key_file: None,
+        }
+    }
+
+    pub fn with_client_cert(mut self, cert_file: &str, key_file: &str) -> TlsClientConfig {
+        self.cert_file = Some(cert_file.to_string());
+        self.key_file = Some(key_file.to_string());
+        self
+    }
+
+    // Lee MQTT_TLS_CA_FILE, MQTT_TLS_CERT_FILE, MQTT_TLS_KEY_FILE y MQTT_TLS_SERVER_NAME.
+    // Devuelve None si no hay CA, es decir si el cliente se conecta sin TLS
+    pub fn from_env() -> Option<TlsClientConfig> {
+        let ca_file = env::var(CA_FILE_ENV).ok()?;
+        Some(TlsClientConfig {
+            ca_file,
+            server_name: env::var(SERVER_NAME_ENV).ok(),
+            cert_file: env::var(CERT_FILE_ENV).ok(),
+            key_file: env::var(KEY_FILE_ENV).ok(),
+        })
+    }
+
+    fn rustls_config(&self) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error>> {
+        let mut roots = RootCertStore::empty();
+        for certificate in load_certificates(&self.ca_file)? {
+            roots.add(certificate)?;
+        }
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => builder.with_client_auth_cert(
+                load_certificates(cert_file)?,
+                load_private_key(key_file)?,
+            )?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(INCOMPLETE_CLIENT_CERT_ERROR_MSG.into()),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+// Arma la configuración de un listener con TLS. Si se pasa una CA de clientes, solo se aceptan
+// clientes con un certificado firmado por ella
+pub fn server_config(
+    cert_file: &str,
+    key_file: &str,
+    client_ca_file: Option<&str>,
+) -> Result<TlsServerConfig, Box<dyn std::error::Error>> {
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca_file)? {
+                roots.add(certificate)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider()).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config =
+        builder.with_single_cert(load_certificates(cert_file)?, load_private_key(key_file)?)?;
+    Ok(Arc::new(config))
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certificates(
+    path: &str,
+) -> Result<Vec<CertificateDer<'static>>, Box<dyn std::error::Error>> {
+    let certificates = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(format!("{} {}", NO_CERTIFICATES_ERROR_MSG, path).into());
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, Box<dyn std::error::Error>> {
+    Ok(PrivateKeyDer::from_pem_file(path)?)
+}
+
+// Conexión TLS arriba de un TcpStream. Los clones comparten la sesión, así un thread puede leer
+// mientras otro escribe: la lectura del socket se hace sin tomar el lock, que solo se toma para
+// descifrar lo que llegó o cifrar lo que se manda
+#[derive(Debug)]
+pub struct TlsStream {
+    connection: Arc<Mutex<Connection>>,
+    socket: TcpStream,
+    // Lo que se lee del socket antes de descifrarlo. Se reserva en la primera lectura, así los
+    // clones que solo escriben no lo tienen
+    read_buffer: Vec<u8>,
+}
+
+impl TlsStream {
+    // Hace el handshake como cliente
+    pub fn connect(
+        socket: TcpStream,
+        host: &str,
+        config: &TlsClientConfig,
+    ) -> Result<TlsStream, Box<dyn std::error::Error>> {
+        let name = config.server_name.as_deref().unwrap_or(host).to_string();
+        let server_name = ServerName::try_from(name)?;
+        let connection = ClientConnection::new(config.rustls_config()?, server_name)?;
+        TlsStream::handshake(Connection::Client(connection), socket, None)
+    }
+
+    // Hace el handshake como server. El timeout evita que un cliente que no termina el handshake
+    // deje el thread colgado
+    pub fn accept(
+        socket: TcpStream,
+        config: TlsServerConfig,
+        timeout: Option<Duration>,
+    ) -> Result<TlsStream, Box<dyn std::error::Error>> {
+        let connection = ServerConnection::new(config)?;
+        TlsStream::handshake(Connection::Server(connection), socket, timeout)
+    }
+
+    fn handshake(
+        mut connection: Connection,
+        mut socket: TcpStream,
+        timeout: Option<Duration>,
+    ) -> Result<TlsStream, Box<dyn std::error::Error>> {
+        // El timeout es para todo el handshake, así un cliente que manda de a un byte no lo estira
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while connection.is_handshaking() {
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    return Err(HANDSHAKE_TIMEOUT_ERROR_MSG.into());
+                }
+                socket.set_read_timeout(Some(remaining))?;
+            }
+            connection.complete_io(&mut socket)?;
+        }
+        socket.set_read_timeout(None)?;
+        Ok(TlsStream {
+            connection: Arc::new(Mutex::new(connection)),
+            socket,
+            read_buffer: vec![],
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            connection: self.connection.clone(),
+            socket: self.socket.try_clone()?,
+            read_buffer: vec![],
+        })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    // Avisa al otro lado con un close_notify antes de cerrar el socket
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if let Ok(mut connection) = self.lock() {
+            connection.send_close_notify();
+            let _ = Self::flush_tls(&mut connection, &self.socket);
+        }
+        self.socket.shutdown(how)
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    // Certificado (DER) con el que se presentó el otro lado, si mandó uno
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        let connection = self.lock().ok()?;
+        let certificates = connection.peer_certificates()?;
+        certificates.first().map(|certificate| certificate.to_vec())
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| io::Error::other(POISONED_CONNECTION_ERROR_MSG))
+    }
+
+    fn flush_tls(connection: &mut Connection, mut socket: &TcpStream) -> io::Result<()> {
+        while connection.wants_write() {
+            connection.write_tls(&mut socket)?;
+        }
+        Ok(())
+    }
+
+    // Como read, pero todas las lecturas del socket que haga falta tienen que terminar antes
+    // de `deadline`
+    pub fn read_before(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        self.read_until(buf, Some(deadline))
+    }
+
+    fn read_until(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize> {
+        if self.read_buffer.is_empty() {
+            self.read_buffer = vec![0u8; READ_BUFFER_SIZE];
+        }
+        loop {
+            {
+                let mut connection = self.lock()?;
+                match connection.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+            }
+
+            let read = DeadlineSocket::new(&self.socket, deadline).read(&mut self.read_buffer)?;
+            let mut connection = self.lock()?;
+            let mut received = &self.read_buffer[..read];
+            loop {
+                // Con 0 bytes rustls se entera de que se cerró el socket
+                connection.read_tls(&mut received)?;
+                connection
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if received.is_empty() {
+                    break;
+                }
+            }
+            // Alertas o actualizaciones de claves que haya que contestar
+            Self::flush_tls(&mut connection, &self.socket)?;
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_until(buf, None)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.lock()?;
+        connection.writer().write_all(buf)?;
+        Self::flush_tls(&mut connection, &self.socket)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.lock()?;
+        Self::flush_tls(&mut connection, &self.socket)
+    }
+}
+
+// Devuelve el CN del subject de un certificado X.509 en DER
+pub fn certificate_common_name(certificate: &[u8]) -> Option<String> {
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+    // version es opcional ([0])
+    if tbs.first() == Some(&0xA0) {
+        tbs = der_element(tbs)?.2;
+    }
+    // serialNumber, signature, issuer y validity
+    for _ in 0..4 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, mut subject, _) = der_element(tbs)?;
+    while !subject.is_empty() {
+        let (_, set, rest) = der_element(subject)?;
+        subject = rest;
+        let (_, attribute, _) = der_element(set)?;
+        let (_, oid, value) = der_element(attribute)?;
+        if oid == COMMON_NAME_OID {
+            let (_, value, _) = der_element(value)?;
+            return String::from_utf8(value.to_vec()).ok();
+        }
+    }
+    None
+}
+
+// Separa un elemento DER en (tag, contenido, lo que sigue)
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first_length = *data.get(1)? as usize;
+    let (length, header) = if first_length < 0x80 {
+        (first_length, 2)
+    } else {
+        let bytes = first_length & 0x7F;
+        if bytes == 0 || bytes > 4 {
+            return None;
+        }
+        let length = data
+            .get(2..2 + bytes)?
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, 2 + bytes)
+    };
+    let content = data.get(header..header.checked_add(length)?)?;
+    Some((tag, content, &data[header + length..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        if content.len() < 0x80 {
+            element.push(content.len() as u8);
+        } else {
+            element.push(0x82);
+            element.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        }
+        element.extend_from_slice(content);
+        element
+    }
+
+    fn name(common_name: &str) -> Vec<u8> {
+        let organization = element(
+            0x30,
+            &[element(0x06, &[0x55, 0x04, 0x0A]), element(0x0C, b"Fiuba")].concat(),
+        );
+        let common_name = element(
+            0x30,
+            &[
+                element(0x06, COMMON_NAME_OID),
+                element(0x0C, common_name.as_bytes()),
+            ]
+            .concat(),
+        );
+        element(
+            0x30,
+            &[element(0x31, &organization), element(0x31, &common_name)].concat(),
+        )
+    }
+
+    #[test]
+    fn common_name_comes_from_the_subject_and_not_the_issuer() {
+        let tbs = [
+            element(0xA0, &element(0x02, &[2])),
+            element(0x02, &[1, 2, 3]),
+            element(0x30, &element(0x06, &[0x2A, 0x86, 0x48])),
+            name("ca"),
+            element(0x30, &[element(0x17, b"260101000000Z")].concat()),
+            name("sensor-1"),
+            element(0x30, &[0u8; 200]),
+        ]
+        .concat();
+        let certificate = element(0x30, &[element(0x30, &tbs), element(0x03, &[0])].concat());
+        assert_eq!(
+            certificate_common_name(&certificate),
+            Some("sensor-1".to_string())
+        );
+    }
+
+    #[test]
+    fn truncated_certificates_have_no_common_name() {
+        assert_eq!(certificate_common_name(&[0x30, 0x82, 0x10]), None);
+        assert_eq!(certificate_common_name(&[]), None);
+    }
+}
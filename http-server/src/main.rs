@@ -45,6 +45,7 @@ const OK_RETURN_CODE: &str = "200 OK";
 const NOT_FOUND_RETURN_CODE: &str = "404 Not Found";
 const SERVER_ERROR_RETURN_CODE: &str = " 500 Internal Server Error";
 
+#[allow(clippy::redundant_pattern_matching)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (sender, receiver) = mpsc::channel::<String>();
     let mut client = mqtt_client::MQTTClient::new(sender);
@@ -83,6 +84,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[allow(clippy::redundant_pattern_matching)]
 fn handle_connection(mut stream: TcpStream, messages: Arc<Mutex<Vec<String>>>) -> Result<(), Box<dyn std::error::Error>> {
     let request = Request::read_from(&mut stream)?;
     let response;
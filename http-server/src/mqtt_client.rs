@@ -3,9 +3,10 @@ use common::all_packets::connack::{CONNACK_CONNECTION_ACCEPTED};
 use common::all_packets::puback::{Puback};
 use common::all_packets::subscribe::{Subscribe};
 use common::all_packets::suback::{SubackReturnCode};
+use common::stream::MqttStream;
+use common::tls::TlsClientConfig;
 use common::packet::{WritePacket, Packet, Qos, Subscription};
 use std::io::{Error, ErrorKind};
-use std::net::TcpStream;
 use std::thread;
 use std::sync::mpsc::Sender;
 
@@ -16,7 +17,7 @@ const ERROR_SUBACK_NOT_RECEIVED: &str = "Didn't received the suback packet";
 const ERROR_FAILED_SUBSCRIPTION: &str = "Failure in suback return code";
 
 pub struct MQTTClient {
-    socket: Option<TcpStream>,
+    socket: Option<MqttStream>,
     sender: Sender<String>
 }
 
@@ -28,8 +29,9 @@ impl MQTTClient {
         }
     }
 
+    #[allow(clippy::io_other_error)]
     pub fn connect_to(&mut self, address: String) -> Result<(), Box<dyn std::error::Error>>{
-        let mut socket = TcpStream::connect(address)?;
+        let mut socket = MqttStream::connect(&address, TlsClientConfig::from_env().as_ref())?;
         
         let connect_packet = Connect::new(
             ConnectPayload::new(
@@ -62,6 +64,7 @@ impl MQTTClient {
         Ok(())
     }
 
+    #[allow(clippy::get_first, clippy::io_other_error)]
     pub fn subscribe_to(&mut self, topic: String, qos: Qos) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(socket) = &mut self.socket {
             let mut subscribe = Subscribe::new(73);
@@ -27,6 +27,7 @@ impl Response {
     Ok(())
   }
 
+  #[allow(clippy::inherent_to_string)]
   pub fn to_string(&self) -> String {
     let mut response_string = format!(
       "{} {}",
@@ -0,0 +1,600 @@
+/*
+Recorre los bytes con el formato de MQTT 3.1.1 campo por campo, anotando dónde empieza cada
+uno. Así, cuando algo está mal, se puede decir exactamente en qué byte. MQTT 3.1.1 no tiene
+properties: lo que se muestra del variable header son sus campos fijos. Además cada paquete se
+decodifica con Packet::read_from, que es lo que haría el broker con esos mismos bytes.
+*/
+
+use common::packet::Packet;
+use std::str;
+
+const PACKET_TYPE_NAMES: [&str; 16] = [
+    "Reserved",
+    "Connect",
+    "Connack",
+    "Publish",
+    "Puback",
+    "Pubrec",
+    "Pubrel",
+    "Pubcomp",
+    "Subscribe",
+    "Suback",
+    "Unsubscribe",
+    "Unsuback",
+    "Pingreq",
+    "Pingresp",
+    "Disconnect",
+    "Reserved",
+];
+const MAX_REMAINING_LENGTH_BYTES: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub struct Field {
+    // Desde el principio de la entrada, no del paquete
+    pub offset: usize,
+    pub len: usize,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Malformation {
+    pub offset: usize,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct Dissection {
+    pub offset: usize,
+    pub len: usize,
+    pub packet_type: &'static str,
+    pub fields: Vec<Field>,
+    pub malformation: Option<Malformation>,
+    // Lo que devuelve Packet::read_from con los bytes del paquete
+    pub decoded: Result<Packet, String>,
+}
+
+// Separa la entrada en paquetes. Si un paquete no dice bien cuánto mide (remaining length
+// roto o más largo que lo que queda) no se puede saber dónde empieza el siguiente, así que
+// es el último
+pub fn dissect(bytes: &[u8], preview: usize) -> Vec<Dissection> {
+    let mut dissections = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (dissection, complete) = dissect_packet(bytes, offset, preview);
+        offset += dissection.len;
+        dissections.push(dissection);
+        if !complete {
+            break;
+        }
+    }
+    dissections
+}
+
+fn dissect_packet(bytes: &[u8], offset: usize, preview: usize) -> (Dissection, bool) {
+    let mut walker = Walker {
+        bytes,
+        pos: offset,
+        end: bytes.len(),
+        fields: vec![],
+        preview,
+    };
+    let first_byte = bytes[offset];
+    let packet_type = PACKET_TYPE_NAMES[(first_byte >> 4) as usize];
+    let flags_problem = check_fixed_header_flags(first_byte);
+    walker.push(
+        offset,
+        1,
+        "packet type",
+        format!(
+            "{} (flags {})",
+            packet_type,
+            describe_fixed_header_flags(first_byte)
+        ),
+    );
+    walker.pos += 1;
+
+    let (len, malformation, complete) = match walker.remaining_length() {
+        Err(malformation) => (bytes.len() - offset, Some(malformation), false),
+        Ok(remaining_length) => {
+            let body = walker.pos;
+            if body + remaining_length > bytes.len() {
+                let malformation = walker.fail(
+                    offset + 1,
+                    format!(
+                        "remaining length is {} but only {} bytes follow",
+                        remaining_length,
+                        bytes.len() - body
+                    ),
+                );
+                (bytes.len() - offset, Some(malformation), false)
+            } else {
+                walker.end = body + remaining_length;
+                let malformation = match flags_problem {
+                    Some(message) => Some(walker.fail(offset, message)),
+                    None => walker.body(first_byte).err(),
+                };
+                (walker.end - offset, malformation, true)
+            }
+        }
+    };
+
+    let decoded = Packet::read_from(&mut &bytes[offset..offset + len]).map_err(|e| e.to_string());
+    let dissection = Dissection {
+        offset,
+        len,
+        packet_type,
+        fields: walker.fields,
+        malformation,
+        decoded,
+    };
+    (dissection, complete)
+}
+
+// Los bits 3-0 del primer byte son fijos en todos los paquetes menos Publish
+fn check_fixed_header_flags(first_byte: u8) -> Option<String> {
+    let flags = first_byte & 0x0f;
+    match first_byte >> 4 {
+        0 | 15 => Some(format!("reserved packet type {}", first_byte >> 4)),
+        3 if flags & 0b0110 == 0b0110 => Some("QoS 3 is not allowed".to_string()),
+        3 if flags & 0b1110 == 0b1000 => Some("QoS 0 messages must have DUP = 0".to_string()),
+        3 => None,
+        6 | 8 | 10 if flags != 0b0010 => Some(format!("flags must be 0010, found {:04b}", flags)),
+        6 | 8 | 10 => None,
+        _ if flags != 0 => Some(format!("flags must be 0000, found {:04b}", flags)),
+        _ => None,
+    }
+}
+
+fn describe_fixed_header_flags(first_byte: u8) -> String {
+    if first_byte >> 4 == 3 {
+        format!(
+            "{:04b}: dup={} qos={} retain={}",
+            first_byte & 0x0f,
+            (first_byte >> 3) & 1,
+            (first_byte >> 1) & 0b11,
+            first_byte & 1
+        )
+    } else {
+        format!("{:04b}", first_byte & 0x0f)
+    }
+}
+
+struct Walker<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Fin del paquete, según el remaining length
+    end: usize,
+    fields: Vec<Field>,
+    preview: usize,
+}
+
+impl<'a> Walker<'a> {
+    fn push(&mut self, offset: usize, len: usize, name: &str, value: String) {
+        self.fields.push(Field {
+            offset,
+            len,
+            name: name.to_string(),
+            value,
+        });
+    }
+
+    fn fail(&self, offset: usize, message: String) -> Malformation {
+        Malformation { offset, message }
+    }
+
+    fn left(&self) -> usize {
+        self.end - self.pos
+    }
+
+    fn take(&mut self, len: usize, name: &str) -> Result<&'a [u8], Malformation> {
+        if self.left() < len {
+            return Err(self.fail(
+                self.pos,
+                format!(
+                    "packet ends in the middle of the {}: it needs {} bytes and {} are left",
+                    name,
+                    len,
+                    self.left()
+                ),
+            ));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn remaining_length(&mut self) -> Result<usize, Malformation> {
+        let start = self.pos;
+        let mut value = 0;
+        let mut multiplier = 1;
+        loop {
+            if self.pos - start == MAX_REMAINING_LENGTH_BYTES {
+                return Err(self.fail(start, "remaining length uses more than 4 bytes".into()));
+            }
+            let byte = self.take(1, "remaining length")?[0];
+            value += (byte & 0x7f) as usize * multiplier;
+            multiplier *= 0x80;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        self.push(
+            start,
+            self.pos - start,
+            "remaining length",
+            value.to_string(),
+        );
+        Ok(value)
+    }
+
+    fn byte(&mut self, name: &str) -> Result<u8, Malformation> {
+        Ok(self.take(1, name)?[0])
+    }
+
+    fn u16(&mut self, name: &str) -> Result<u16, Malformation> {
+        let bytes = self.take(2, name)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn packet_id(&mut self) -> Result<u16, Malformation> {
+        let offset = self.pos;
+        let packet_id = self.u16("packet id")?;
+        if packet_id == 0 {
+            return Err(self.fail(offset, "packet id must not be 0".into()));
+        }
+        self.push(offset, 2, "packet id", packet_id.to_string());
+        Ok(packet_id)
+    }
+
+    // Los strings y los datos binarios de MQTT van precedidos por su largo en 2 bytes
+    fn prefixed(&mut self, name: &str) -> Result<(usize, &'a [u8]), Malformation> {
+        let offset = self.pos;
+        let len = self.u16(&format!("{} length", name))? as usize;
+        if len > self.left() {
+            return Err(self.fail(
+                offset,
+                format!(
+                    "{} length is {} but only {} bytes are left in the packet",
+                    name,
+                    len,
+                    self.left()
+                ),
+            ));
+        }
+        Ok((offset, self.take(len, name)?))
+    }
+
+    fn string(&mut self, name: &str) -> Result<String, Malformation> {
+        let (offset, bytes) = self.prefixed(name)?;
+        match str::from_utf8(bytes) {
+            Ok(text) => {
+                self.push(offset, bytes.len() + 2, name, format!("{:?}", text));
+                Ok(text.to_string())
+            }
+            Err(e) => Err(self.fail(
+                offset + 2 + e.valid_up_to(),
+                format!("{} is not valid UTF-8", name),
+            )),
+        }
+    }
+
+    fn binary(&mut self, name: &str, hidden: bool) -> Result<(), Malformation> {
+        let (offset, bytes) = self.prefixed(name)?;
+        let value = if hidden {
+            format!("({} bytes)", bytes.len())
+        } else {
+            preview(bytes, self.preview)
+        };
+        self.push(offset, bytes.len() + 2, name, value);
+        Ok(())
+    }
+
+    fn body(&mut self, first_byte: u8) -> Result<(), Malformation> {
+        match first_byte >> 4 {
+            1 => self.connect()?,
+            2 => self.connack()?,
+            3 => self.publish(first_byte)?,
+            4 | 5 | 6 | 7 | 11 => {
+                self.packet_id()?;
+            }
+            8 => self.subscribe()?,
+            9 => self.suback()?,
+            10 => self.unsubscribe()?,
+            _ => {}
+        }
+        if self.left() > 0 {
+            return Err(self.fail(
+                self.pos,
+                format!("{} unexpected bytes at the end of the packet", self.left()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self) -> Result<(), Malformation> {
+        let offset = self.pos;
+        if self.string("protocol name")? != "MQTT" {
+            return Err(self.fail(offset, "protocol name must be \"MQTT\"".into()));
+        }
+
+        let offset = self.pos;
+        let level = self.byte("protocol level")?;
+        if level != 4 {
+            return Err(self.fail(
+                offset,
+                format!("protocol level {} is not MQTT 3.1.1 (4)", level),
+            ));
+        }
+        self.push(offset, 1, "protocol level", "4 (MQTT 3.1.1)".to_string());
+
+        let offset = self.pos;
+        let flags = self.byte("connect flags")?;
+        self.push(offset, 1, "connect flags", describe_connect_flags(flags));
+        let will = flags & 0b0000_0100 != 0;
+        let problem = if flags & 1 != 0 {
+            Some("reserved bit of the connect flags must be 0")
+        } else if flags & 0b0001_1000 == 0b0001_1000 {
+            Some("will QoS 3 is not allowed")
+        } else if !will && flags & 0b0011_1000 != 0 {
+            Some("will QoS and will retain must be 0 without a will")
+        } else if flags & 0b1100_0000 == 0b0100_0000 {
+            Some("password flag is set without the username flag")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            return Err(self.fail(offset, problem.to_string()));
+        }
+
+        let offset = self.pos;
+        let keep_alive = self.u16("keep alive")?;
+        self.push(offset, 2, "keep alive", format!("{} s", keep_alive));
+
+        self.string("client id")?;
+        if will {
+            self.string("will topic")?;
+            self.binary("will message", false)?;
+        }
+        if flags & 0b1000_0000 != 0 {
+            self.string("username")?;
+        }
+        if flags & 0b0100_0000 != 0 {
+            self.binary("password", true)?;
+        }
+        Ok(())
+    }
+
+    fn connack(&mut self) -> Result<(), Malformation> {
+        let offset = self.pos;
+        let flags = self.byte("acknowledge flags")?;
+        if flags & !1 != 0 {
+            return Err(self.fail(offset, "bits 7-1 of the acknowledge flags must be 0".into()));
+        }
+        let session_present = if flags == 1 { "session present" } else { "-" };
+        self.push(offset, 1, "acknowledge flags", session_present.to_string());
+
+        let offset = self.pos;
+        let return_code = self.byte("return code")?;
+        let meaning = match return_code {
+            0 => "accepted",
+            1 => "unacceptable protocol version",
+            2 => "identifier rejected",
+            3 => "server unavailable",
+            4 => "bad username or password",
+            5 => "not authorized",
+            _ => return Err(self.fail(offset, format!("reserved return code {}", return_code))),
+        };
+        self.push(
+            offset,
+            1,
+            "return code",
+            format!("{} ({})", return_code, meaning),
+        );
+        Ok(())
+    }
+
+    fn publish(&mut self, first_byte: u8) -> Result<(), Malformation> {
+        let offset = self.pos;
+        let topic = self.string("topic name")?;
+        if topic.contains('+') || topic.contains('#') {
+            return Err(self.fail(offset, "topic name contains a wildcard".into()));
+        }
+        if first_byte & 0b0110 != 0 {
+            self.packet_id()?;
+        }
+        let offset = self.pos;
+        let payload = self.take(self.left(), "payload")?;
+        self.push(
+            offset,
+            payload.len(),
+            "payload",
+            preview(payload, self.preview),
+        );
+        Ok(())
+    }
+
+    fn subscribe(&mut self) -> Result<(), Malformation> {
+        self.packet_id()?;
+        if self.left() == 0 {
+            return Err(self.fail(
+                self.pos,
+                "subscribe must contain at least one topic filter".into(),
+            ));
+        }
+        while self.left() > 0 {
+            self.string("topic filter")?;
+            let offset = self.pos;
+            let qos = self.byte("requested QoS")?;
+            if qos & 0b1111_1100 != 0 {
+                return Err(self.fail(offset, "upper 6 bits of the requested QoS must be 0".into()));
+            }
+            if qos == 3 {
+                return Err(self.fail(offset, "requested QoS 3 is not allowed".into()));
+            }
+            self.push(offset, 1, "requested QoS", qos.to_string());
+        }
+        Ok(())
+    }
+
+    fn suback(&mut self) -> Result<(), Malformation> {
+        self.packet_id()?;
+        while self.left() > 0 {
+            let offset = self.pos;
+            let return_code = self.byte("return code")?;
+            let meaning = match return_code {
+                0..=2 => format!("success, QoS {}", return_code),
+                0x80 => "failure".to_string(),
+                _ => {
+                    return Err(self.fail(
+                        offset,
+                        format!("invalid suback return code 0x{:02x}", return_code),
+                    ))
+                }
+            };
+            self.push(
+                offset,
+                1,
+                "return code",
+                format!("0x{:02x} ({})", return_code, meaning),
+            );
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self) -> Result<(), Malformation> {
+        self.packet_id()?;
+        if self.left() == 0 {
+            return Err(self.fail(
+                self.pos,
+                "unsubscribe must contain at least one topic filter".into(),
+            ));
+        }
+        while self.left() > 0 {
+            self.string("topic filter")?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_connect_flags(flags: u8) -> String {
+    let mut names = vec![];
+    if flags & 0b0000_0010 != 0 {
+        names.push("clean_session".to_string());
+    }
+    if flags & 0b0000_0100 != 0 {
+        names.push(format!("will qos={}", (flags >> 3) & 0b11));
+        if flags & 0b0010_0000 != 0 {
+            names.push("will_retain".to_string());
+        }
+    }
+    if flags & 0b1000_0000 != 0 {
+        names.push("username".to_string());
+    }
+    if flags & 0b0100_0000 != 0 {
+        names.push("password".to_string());
+    }
+    format!("{:08b} {}", flags, names.join(" "))
+        .trim_end()
+        .to_string()
+}
+
+// El texto entre comillas si es UTF-8 imprimible, y si no los bytes en hexadecimal
+pub fn preview(bytes: &[u8], max: usize) -> String {
+    let shown = &bytes[..bytes.len().min(max)];
+    let more = if shown.len() < bytes.len() { "..." } else { "" };
+    let text = match str::from_utf8(bytes) {
+        Ok(text)
+            if !text
+                .chars()
+                .any(|c| c.is_control() && c != '\n' && c != '\t') =>
+        {
+            let text: String = text.chars().take(max).collect();
+            format!("{:?}{}", text, more)
+        }
+        _ => format!("{}{}", common::capture::to_hex(shown), more),
+    };
+    format!("{} ({} bytes)", text, bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::capture::from_hex;
+
+    fn field<'a>(dissection: &'a Dissection, name: &str) -> &'a Field {
+        dissection.fields.iter().find(|f| f.name == name).unwrap()
+    }
+
+    #[test]
+    fn a_publish_is_split_into_its_fields() {
+        // Publish QoS 1 retain, tópico "a/b", packet id 7, payload "on"
+        let bytes = from_hex("33 09 0003 612f62 0007 6f6e").unwrap();
+        let dissections = dissect(&bytes, 32);
+        assert_eq!(dissections.len(), 1);
+        let publish = &dissections[0];
+        assert_eq!(publish.packet_type, "Publish");
+        assert_eq!(publish.len, 11);
+        assert_eq!(publish.malformation, None);
+        assert_eq!(
+            field(publish, "packet type").value,
+            "Publish (flags 0011: dup=0 qos=1 retain=1)"
+        );
+        assert_eq!(field(publish, "remaining length").value, "9");
+        let topic = field(publish, "topic name");
+        assert_eq!((topic.offset, topic.len), (2, 5));
+        assert_eq!(topic.value, "\"a/b\"");
+        assert_eq!(field(publish, "packet id").offset, 7);
+        assert_eq!(field(publish, "payload").value, "\"on\" (2 bytes)");
+        assert!(matches!(&publish.decoded, Ok(Packet::Publish(p)) if p.topic_name == "a/b"));
+    }
+
+    #[test]
+    fn malformations_point_to_the_offending_byte() {
+        // El largo del tópico (offset 2) dice 0x30 pero quedan 7 bytes
+        let bytes = from_hex("30 09 0030 612f62 0007 6f6e").unwrap();
+        let malformation = dissect(&bytes, 32).remove(0).malformation.unwrap();
+        assert_eq!(malformation.offset, 2);
+        assert_eq!(
+            malformation.message,
+            "topic name length is 48 but only 7 bytes are left in the packet"
+        );
+
+        // Connect con el bit reservado de los flags (offset 9) en 1
+        let bytes = from_hex("10 0c 00044d515454 04 03 003c 0000").unwrap();
+        let connect = dissect(&bytes, 32).remove(0);
+        assert_eq!(connect.malformation.unwrap().offset, 9);
+        assert!(connect.decoded.is_err());
+
+        // Subscribe con los flags del fixed header en 0000
+        let bytes = from_hex("80 06 0001 0001 61 00").unwrap();
+        let malformation = dissect(&bytes, 32).remove(0).malformation.unwrap();
+        assert_eq!(malformation.offset, 0);
+        assert_eq!(malformation.message, "flags must be 0010, found 0000");
+    }
+
+    #[test]
+    fn consecutive_packets_are_dissected_until_one_is_truncated() {
+        let bytes = from_hex("c000 e000 30 7f 0001").unwrap();
+        let dissections = dissect(&bytes, 32);
+        let types: Vec<&str> = dissections.iter().map(|d| d.packet_type).collect();
+        assert_eq!(types, vec!["Pingreq", "Disconnect", "Publish"]);
+        assert_eq!(dissections[1].offset, 2);
+        assert_eq!(
+            dissections[2].malformation,
+            Some(Malformation {
+                offset: 5,
+                message: "remaining length is 127 but only 2 bytes follow".to_string()
+            })
+        );
+
+        let trailing = dissect(&from_hex("c001 00").unwrap(), 32).remove(0);
+        assert_eq!(trailing.malformation.unwrap().offset, 2);
+    }
+
+    #[test]
+    fn binary_payloads_are_shown_in_hex() {
+        assert_eq!(preview(&[0x00, 0xff, 0x10], 2), "00ff... (3 bytes)");
+        assert_eq!(preview(b"hola mundo", 4), "\"hola\"... (10 bytes)");
+    }
+}
@@ -0,0 +1,314 @@
+/*
+Muestra campo por campo los paquetes MQTT de un volcado en hexadecimal, de un archivo binario
+o de un archivo de captura del server ([capture] en su configuración), y marca el byte exacto
+donde un paquete está mal formado.
+*/
+
+mod dissector;
+
+use common::capture::{self, CaptureRecord};
+use common::logging::timestamp;
+use common::packet::Packet;
+use dissector::Dissection;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+const DEFAULT_PREVIEW: usize = 32;
+const SHOWN_FIELD_BYTES: usize = 8;
+const POINTER_LINE_BYTES: usize = 16;
+const USAGE: &str =
+    "Usage: mqtt-dissect [--preview bytes] (--hex <hex>... | --capture <file> | <file>)
+
+Inputs:
+  --hex <hex>...      Hex dump, as one or more arguments. Spaces, commas, colons and 0x
+                      prefixes are ignored. With --hex - the dump is read from stdin
+  --capture <file>    Capture file written by the server
+  <file>              Raw bytes, as they went through the socket
+
+--preview sets how much of each payload is shown (32 by default). The exit status is 1 if
+any packet is malformed or rejected by the decoder.";
+
+#[derive(Debug, PartialEq)]
+enum Input {
+    Hex(String),
+    Capture(String),
+    Raw(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    preview: usize,
+    input: Input,
+}
+
+fn main() {
+    if env::args().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = parse_args(env::args().skip(1).collect()).unwrap_or_else(|err| {
+        eprintln!("mqtt-dissect: {}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+    match run(options) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("mqtt-dissect: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut preview = DEFAULT_PREVIEW;
+    let mut hex = false;
+    let mut capture = None;
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--preview" => {
+                let bytes = value("--preview")?;
+                preview = bytes
+                    .parse()
+                    .map_err(|_| format!("invalid --preview size '{}'", bytes))?;
+            }
+            "--hex" => hex = true,
+            "--capture" => capture = Some(value("--capture")?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+    let input = match (hex, capture, positional.as_slice()) {
+        (true, None, []) => return Err("--hex needs a value".to_string()),
+        (true, None, _) => Input::Hex(positional.join(" ")),
+        (false, Some(path), []) => Input::Capture(path),
+        (false, None, [path]) => Input::Raw(path.clone()),
+        (false, None, []) => return Err("missing input".to_string()),
+        _ => return Err("give only one input".to_string()),
+    };
+    Ok(Options { preview, input })
+}
+
+// Devuelve false si algún paquete está mal formado
+fn run(options: Options) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut all_valid = true;
+    let mut show = |bytes: &[u8]| {
+        for (index, dissection) in dissector::dissect(bytes, options.preview)
+            .iter()
+            .enumerate()
+        {
+            println!("{}", render(index + 1, bytes, dissection));
+            all_valid &= dissection.malformation.is_none() && dissection.decoded.is_ok();
+        }
+    };
+    match &options.input {
+        Input::Hex(text) => {
+            let text = if text == "-" {
+                let mut stdin = String::new();
+                io::stdin().read_to_string(&mut stdin)?;
+                stdin
+            } else {
+                text.clone()
+            };
+            show(&capture::from_hex(&clean_hex(&text))?);
+        }
+        Input::Raw(path) => show(&fs::read(path).map_err(|e| format!("{}: {}", path, e))?),
+        Input::Capture(path) => {
+            let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            let records = capture::parse(&contents).map_err(|e| format!("{}: {}", path, e))?;
+            for (index, record) in records.iter().enumerate() {
+                println!("{}", record_header(index + 1, record));
+                show(&record.bytes);
+            }
+        }
+    }
+    Ok(all_valid)
+}
+
+// Los volcados de los firmwares suelen venir como "0x10, 0x0c" o "10:0c"
+fn clean_hex(text: &str) -> String {
+    text.replace("0x", "")
+        .replace("0X", "")
+        .replace([',', ':'], " ")
+}
+
+fn record_header(number: usize, record: &CaptureRecord) -> String {
+    format!(
+        "== record {}: {} connection {} client {} {}",
+        number,
+        timestamp::rfc3339(record.time),
+        record.connection,
+        record.client_id,
+        record.direction
+    )
+}
+
+fn render(number: usize, bytes: &[u8], dissection: &Dissection) -> String {
+    let mut text = format!(
+        "packet {} at offset {} ({} bytes): {}\n",
+        number, dissection.offset, dissection.len, dissection.packet_type
+    );
+    text += &format!(
+        "  {:>6}  {:<26} {:<18} {}\n",
+        "offset", "bytes", "field", "value"
+    );
+    for field in &dissection.fields {
+        text += &format!(
+            "  {:>6}  {:<26} {:<18} {}\n",
+            field.offset,
+            hex_bytes(&bytes[field.offset..field.offset + field.len]),
+            field.name,
+            field.value
+        );
+    }
+    if let Some(malformation) = &dissection.malformation {
+        text += &format!(
+            "  malformed at offset {}: {}\n",
+            malformation.offset, malformation.message
+        );
+        text += &pointer(bytes, malformation.offset);
+    }
+    match &dissection.decoded {
+        Ok(packet) => text += &format!("  decoded: {}\n", summary(packet)),
+        Err(err) => text += &format!("  rejected by the decoder: {}\n", err),
+    }
+    text
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let shown: Vec<String> = bytes
+        .iter()
+        .take(SHOWN_FIELD_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let more = if bytes.len() > SHOWN_FIELD_BYTES {
+        " .."
+    } else {
+        ""
+    };
+    shown.join(" ") + more
+}
+
+// La línea de 16 bytes donde está el offset, con ^^ debajo del byte. Un offset igual al
+// largo de la entrada (faltan bytes) se marca justo después del último
+fn pointer(bytes: &[u8], offset: usize) -> String {
+    let start = offset - offset % POINTER_LINE_BYTES;
+    let line: Vec<String> = bytes
+        .iter()
+        .skip(start)
+        .take(POINTER_LINE_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "  {:>6}  {}\n  {:>6}  {}^^\n",
+        start,
+        line.join(" "),
+        "",
+        " ".repeat(3 * (offset - start))
+    )
+}
+
+fn summary(packet: &Packet) -> String {
+    match packet {
+        Packet::Connect(connect) => format!(
+            "Connect client_id={:?} keep_alive={} clean_session={} will_topic={:?} username={:?}",
+            connect.connect_payload.client_id,
+            connect.keep_alive_seconds,
+            connect.clean_session,
+            connect.connect_payload.last_will_topic,
+            connect.connect_payload.username
+        ),
+        Packet::Connack(connack) => format!(
+            "Connack session_present={} return_code={}",
+            connack.session_present, connack.connect_return_code
+        ),
+        Packet::Publish(publish) => format!(
+            "Publish topic={:?} qos={} retain={} dup={} packet_id={:?} payload={}",
+            publish.topic_name,
+            publish.flags.qos_level as u8,
+            publish.flags.retain,
+            publish.flags.duplicate,
+            publish.packet_id,
+            dissector::preview(publish.application_message.as_bytes(), DEFAULT_PREVIEW)
+        ),
+        Packet::Puback(puback) => format!("Puback packet_id={}", puback.packet_id),
+        Packet::Subscribe(subscribe) => {
+            let filters: Vec<String> = subscribe
+                .subscriptions
+                .iter()
+                .map(|s| format!("{}@{}", s.topic_filter, s.max_qos as u8))
+                .collect();
+            format!(
+                "Subscribe packet_id={} filters={}",
+                subscribe.packet_id,
+                filters.join(",")
+            )
+        }
+        Packet::Suback(suback) => format!(
+            "Suback packet_id={} return_codes={:?}",
+            suback.packet_id, suback.return_codes
+        ),
+        Packet::Unsubscribe(unsubscribe) => format!(
+            "Unsubscribe packet_id={} filters={}",
+            unsubscribe.packet_id,
+            unsubscribe.topics.join(",")
+        ),
+        Packet::Unsuback(unsuback) => format!("Unsuback packet_id={}", unsuback.packet_id),
+        Packet::Pingreq(_) => "Pingreq".to_string(),
+        Packet::Pingresp(_) => "Pingresp".to_string(),
+        Packet::Disconnect(_) => "Disconnect".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn inputs_are_parsed() {
+        assert_eq!(
+            parse_args(args("--preview 8 --hex 30 02 00 00")),
+            Ok(Options {
+                preview: 8,
+                input: Input::Hex("30 02 00 00".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_args(args("--capture captura.txt")).unwrap().input,
+            Input::Capture("captura.txt".to_string())
+        );
+        assert_eq!(
+            parse_args(args("volcado.bin")).unwrap().input,
+            Input::Raw("volcado.bin".to_string())
+        );
+        assert!(parse_args(args("--hex")).is_err());
+        assert!(parse_args(args("--capture a.txt b.bin")).is_err());
+        assert!(parse_args(args("--preview muchos a.bin")).is_err());
+    }
+
+    #[test]
+    fn firmware_dumps_are_accepted() {
+        assert_eq!(
+            capture::from_hex(&clean_hex("0xC0, 0x00 e0:00")).unwrap(),
+            vec![0xc0, 0x00, 0xe0, 0x00]
+        );
+    }
+
+    #[test]
+    fn the_pointer_marks_the_malformed_byte() {
+        let bytes: Vec<u8> = (0..20).collect();
+        assert_eq!(
+            pointer(&bytes, 17),
+            "      16  10 11 12 13\n             ^^\n"
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use common::capture::{from_hex, CaptureRecord, Direction};
+use std::env;
+use std::fs;
+use std::process::{Command, Output};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn temp_file(name: &str) -> String {
+    env::temp_dir()
+        .join(format!("mqtt_dissect_test_{}_{}", std::process::id(), name))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn mqtt_dissect(args: &[&str]) -> (Output, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_mqtt-dissect"))
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout.clone()).unwrap();
+    (output, stdout)
+}
+
+#[test]
+fn capture_records_are_dissected_and_malformations_located() {
+    let record = |direction: Direction, hex: &str| {
+        CaptureRecord {
+            time: UNIX_EPOCH + Duration::from_micros(1_636_137_000_250_123),
+            connection: 17,
+            client_id: "sensor1".to_string(),
+            direction,
+            bytes: from_hex(hex).unwrap(),
+        }
+        .to_line()
+    };
+    let capture_file = temp_file("capture.txt");
+    fs::write(
+        &capture_file,
+        record(Direction::Outbound, "20 02 00 00") + &record(Direction::Inbound, "82 04 0001 0001"),
+    )
+    .unwrap();
+
+    let (output, stdout) = mqtt_dissect(&["--capture", &capture_file]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stdout.contains("== record 1: 2021-11-05T18:30:00.250Z connection 17 client sensor1 out\n")
+    );
+    assert!(stdout.contains("decoded: Connack session_present=false return_code=0\n"));
+    assert!(
+        stdout.contains("== record 2: 2021-11-05T18:30:00.250Z connection 17 client sensor1 in\n")
+    );
+    // El filtro dice medir 1 byte y quedan 0: el error está en su largo, en el offset 4
+    assert!(stdout.contains(
+        "malformed at offset 4: topic filter length is 1 but only 0 bytes are left in the packet\n"
+    ));
+}
+
+#[test]
+fn raw_files_with_valid_packets_exit_successfully() {
+    let raw_file = temp_file("raw.bin");
+    fs::write(&raw_file, from_hex("c000 3005 0003 612f62").unwrap()).unwrap();
+
+    let (output, stdout) = mqtt_dissect(&[&raw_file]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout.contains("packet 1 at offset 0 (2 bytes): Pingreq\n"));
+    assert!(stdout.contains("packet 2 at offset 2 (7 bytes): Publish\n"));
+    assert!(stdout.contains("payload            \"\" (0 bytes)"));
+}
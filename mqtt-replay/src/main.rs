@@ -0,0 +1,132 @@
+/*
+Vuelve a mandar a un broker el tráfico de los clientes guardado en un archivo de captura
+([capture] en la configuración del server), con los mismos tiempos, para reproducir un bug.
+*/
+
+mod replay;
+
+use std::env;
+use std::fs;
+use std::process;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+const USAGE: &str = "Usage: mqtt-replay [--address host:port] [--client client_id]... [--speed factor] <capture-file>
+
+Opens one connection per captured connection and sends the packets the clients sent, with
+the original timing. --speed 2 replays twice as fast. With --client only the connections of
+those clients are replayed. The address defaults to 127.0.0.1:8080.";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    address: String,
+    clients: Vec<String>,
+    speed: f64,
+    capture_file: String,
+}
+
+fn main() {
+    if env::args().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = parse_args(env::args().skip(1).collect()).unwrap_or_else(|err| {
+        eprintln!("mqtt-replay: {}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+    if let Err(err) = run(options) {
+        eprintln!("mqtt-replay: {}", err);
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut clients = vec![];
+    let mut speed = 1.0;
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--address" => address = value("--address")?,
+            "--client" => clients.push(value("--client")?),
+            "--speed" => {
+                let factor = value("--speed")?;
+                match factor.parse::<f64>() {
+                    Ok(factor) if factor > 0.0 && factor.is_finite() => speed = factor,
+                    _ => return Err(format!("invalid --speed factor '{}'", factor)),
+                }
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+    let capture_file = match positional.as_slice() {
+        [capture_file] => capture_file.clone(),
+        [] => return Err("missing capture file".to_string()),
+        _ => return Err(format!("unexpected arguments '{}'", positional.join(" "))),
+    };
+    Ok(Options {
+        address,
+        clients,
+        speed,
+        capture_file,
+    })
+}
+
+fn run(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(&options.capture_file)
+        .map_err(|e| format!("{}: {}", options.capture_file, e))?;
+    let records = common::capture::parse(&contents)
+        .map_err(|e| format!("{}: {}", options.capture_file, e))?;
+    let connections = replay::plan(&records, &options.clients, options.speed);
+    if connections.is_empty() {
+        return Err("nothing to replay".into());
+    }
+
+    let mut failed = false;
+    for report in replay::replay(&options.address, connections) {
+        match report {
+            Ok(report) => println!(
+                "connection {} ({}): sent {} packets, received {} bytes",
+                report.connection, report.client_id, report.packets_sent, report.bytes_received
+            ),
+            Err(err) => {
+                eprintln!("mqtt-replay: {}", err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return Err("some connections could not be replayed".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        assert_eq!(
+            parse_args(args(
+                "--client sensor1 --speed 2.5 --client sensor2 captura.txt"
+            )),
+            Ok(Options {
+                address: DEFAULT_ADDRESS.to_string(),
+                clients: vec!["sensor1".to_string(), "sensor2".to_string()],
+                speed: 2.5,
+                capture_file: "captura.txt".to_string(),
+            })
+        );
+        assert!(parse_args(args("")).is_err());
+        assert!(parse_args(args("--speed 0 captura.txt")).is_err());
+        assert!(parse_args(args("--address")).is_err());
+        assert!(parse_args(args("a.txt b.txt")).is_err());
+    }
+}
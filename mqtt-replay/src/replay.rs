@@ -0,0 +1,189 @@
+use common::capture::{CaptureRecord, Direction};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Lo que se vuelve a mandar de una conexión capturada. Los tiempos son desde el primer
+// registro elegido, ya divididos por la velocidad
+#[derive(Debug, PartialEq)]
+pub struct ReplayConnection {
+    pub connection: u32,
+    pub client_id: String,
+    pub packets: Vec<(Duration, Vec<u8>)>,
+    // La conexión se cierra recién cuando pasa el último registro, entrante o saliente
+    pub end: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ReplayReport {
+    pub connection: u32,
+    pub client_id: String,
+    pub packets_sent: usize,
+    pub bytes_received: usize,
+}
+
+// Agrupa los registros por conexión, en el orden en que aparece cada una. Si `clients` no
+// está vacío, solo quedan las conexiones de esos client ids y los tiempos se cuentan desde
+// el primero de sus registros
+pub fn plan(records: &[CaptureRecord], clients: &[String], speed: f64) -> Vec<ReplayConnection> {
+    let selected: Vec<&CaptureRecord> = records
+        .iter()
+        .filter(|record| clients.is_empty() || clients.contains(&record.client_id))
+        .collect();
+    let first = match selected.iter().map(|record| record.micros()).min() {
+        Some(first) => first,
+        None => return vec![],
+    };
+    let offset = |record: &CaptureRecord| {
+        Duration::from_micros(((record.micros() - first) as f64 / speed) as u64)
+    };
+    let mut connections: Vec<ReplayConnection> = vec![];
+    for record in selected {
+        let index = match connections
+            .iter()
+            .position(|c| c.connection == record.connection)
+        {
+            Some(index) => index,
+            None => {
+                connections.push(ReplayConnection {
+                    connection: record.connection,
+                    client_id: record.client_id.clone(),
+                    packets: vec![],
+                    end: Duration::default(),
+                });
+                connections.len() - 1
+            }
+        };
+        let connection = &mut connections[index];
+        let at = offset(record);
+        if record.direction == Direction::Inbound {
+            connection.packets.push((at, record.bytes.clone()));
+        }
+        connection.end = connection.end.max(at);
+    }
+    connections.retain(|connection| !connection.packets.is_empty());
+    connections
+}
+
+// Abre una conexión al broker por cada conexión capturada y le manda los paquetes del
+// cliente respetando los tiempos. Lo que contesta el broker se lee y se descarta
+pub fn replay(
+    address: &str,
+    connections: Vec<ReplayConnection>,
+) -> Vec<Result<ReplayReport, String>> {
+    let start = Instant::now();
+    let handles: Vec<_> = connections
+        .into_iter()
+        .map(|connection| {
+            let address = address.to_string();
+            thread::spawn(move || replay_connection(&address, connection, start))
+        })
+        .collect();
+    handles
+        .into_iter()
+        .map(|handle| {
+            handle
+                .join()
+                .unwrap_or_else(|_| Err("replay thread panicked".to_string()))
+        })
+        .collect()
+}
+
+fn replay_connection(
+    address: &str,
+    connection: ReplayConnection,
+    start: Instant,
+) -> Result<ReplayReport, String> {
+    let describe = |e: std::io::Error| {
+        format!(
+            "connection {} ({}): {}",
+            connection.connection, connection.client_id, e
+        )
+    };
+    sleep_until(start + connection.packets[0].0);
+    let mut stream = TcpStream::connect(address).map_err(describe)?;
+    let mut reader = stream.try_clone().map_err(describe)?;
+    let drain = thread::spawn(move || {
+        let mut received = 0;
+        let mut buffer = [0u8; 4096];
+        while let Ok(read) = reader.read(&mut buffer) {
+            if read == 0 {
+                break;
+            }
+            received += read;
+        }
+        received
+    });
+
+    let mut packets_sent = 0;
+    for (at, bytes) in &connection.packets {
+        sleep_until(start + *at);
+        // El broker pudo haber cerrado la conexión, como en la captura original
+        if stream.write_all(bytes).is_err() {
+            break;
+        }
+        packets_sent += 1;
+    }
+    sleep_until(start + connection.end);
+    let _ = stream.shutdown(Shutdown::Both);
+    let bytes_received = drain.join().unwrap_or(0);
+    Ok(ReplayReport {
+        connection: connection.connection,
+        client_id: connection.client_id,
+        packets_sent,
+        bytes_received,
+    })
+}
+
+fn sleep_until(instant: Instant) {
+    let now = Instant::now();
+    if instant > now {
+        thread::sleep(instant - now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn record(millis: u64, connection: u32, direction: Direction, byte: u8) -> CaptureRecord {
+        CaptureRecord {
+            time: UNIX_EPOCH + Duration::from_millis(1_636_137_000_000 + millis),
+            connection,
+            client_id: format!("cliente{}", connection),
+            direction,
+            bytes: vec![byte],
+        }
+    }
+
+    #[test]
+    fn records_are_grouped_by_connection_and_scaled() {
+        let records = vec![
+            record(0, 3, Direction::Inbound, 0x10),
+            record(10, 3, Direction::Outbound, 0x20),
+            record(100, 4, Direction::Inbound, 0x10),
+            record(200, 3, Direction::Inbound, 0xe0),
+            record(400, 4, Direction::Outbound, 0x20),
+            record(500, 5, Direction::Outbound, 0x30),
+        ];
+        let connections = plan(&records, &[], 2.0);
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].connection, 3);
+        assert_eq!(
+            connections[0].packets,
+            vec![
+                (Duration::from_millis(0), vec![0x10]),
+                (Duration::from_millis(100), vec![0xe0])
+            ]
+        );
+        assert_eq!(connections[0].end, Duration::from_millis(100));
+        assert_eq!(connections[1].end, Duration::from_millis(200));
+
+        let only = plan(&records, &["cliente4".to_string()], 1.0);
+        assert_eq!(only.len(), 1);
+        assert_eq!(only[0].packets[0].0, Duration::from_millis(0));
+        assert_eq!(only[0].end, Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,96 @@
+use common::all_packets::connect::{Connect, ConnectPayload};
+use common::all_packets::disconnect::Disconnect;
+use common::all_packets::publish::{Publish, PublishFlags};
+use common::capture::{CaptureRecord, Direction};
+use common::packet::{Packet, Qos};
+use server::{LocalClientOptions, ServerBuilder};
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
+
+fn temp_file(name: &str) -> String {
+    env::temp_dir()
+        .join(format!("mqtt_replay_test_{}_{}", std::process::id(), name))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn record(start: SystemTime, millis: u64, direction: Direction, packet: Packet) -> String {
+    let mut bytes = vec![];
+    packet.write_to(&mut bytes).unwrap();
+    CaptureRecord {
+        time: start + Duration::from_millis(millis),
+        connection: 1,
+        client_id: "sensor1".to_string(),
+        direction,
+        bytes,
+    }
+    .to_line()
+}
+
+#[test]
+fn a_capture_is_replayed_into_the_broker_with_its_timing() {
+    let accounts_file = temp_file("accounts.txt");
+    fs::write(&accounts_file, "").unwrap();
+    let server = ServerBuilder::new()
+        .accounts_file(&accounts_file)
+        .log_file(&temp_file("replay.log"))
+        .sys_interval_seconds(0)
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+
+    let start = SystemTime::now();
+    let connect = Connect::new(
+        ConnectPayload::new("sensor1".to_string(), None, None, None, None),
+        60,
+        true,
+        false,
+        false,
+    );
+    let publish = Publish::new(
+        PublishFlags::new(0b0011_0001),
+        "casa/luz".to_string(),
+        None,
+        "on".to_string(),
+    );
+    let capture_file = temp_file("capture.txt");
+    let capture = [
+        record(start, 0, Direction::Inbound, Packet::Connect(connect)),
+        // Lo que mandó el server no se vuelve a mandar
+        "2021-11-05T18:30:00.250Z 1636137000250123 9 otro out 20020000\n".to_string(),
+        record(start, 200, Direction::Inbound, Packet::Publish(publish)),
+        record(
+            start,
+            400,
+            Direction::Inbound,
+            Packet::Disconnect(Disconnect::new()),
+        ),
+    ]
+    .concat();
+    fs::write(&capture_file, capture).unwrap();
+
+    let began = Instant::now();
+    let output = Command::new(env!("CARGO_BIN_EXE_mqtt-replay"))
+        .arg("--address")
+        .arg(server.local_address().to_string())
+        .arg("--client")
+        .arg("sensor1")
+        .arg(&capture_file)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(began.elapsed() >= Duration::from_millis(400));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "connection 1 (sensor1): sent 3 packets, received 4 bytes\n"
+    );
+
+    let mut client = server
+        .connect_local(LocalClientOptions::new("monitor"))
+        .unwrap();
+    client.subscribe(&[("casa/luz", Qos::AtMostOnce)]).unwrap();
+    let retained = client.recv(Duration::from_secs(2)).unwrap().unwrap();
+    assert_eq!(retained.application_message, "on");
+}
@@ -0,0 +1,100 @@
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Cliente HTTP mínimo para la API de administración del server. Cada request abre su propia
+// conexión, igual que la cierra el server después de responder
+pub struct AdminClient {
+    address: String,
+    token: String,
+}
+
+impl AdminClient {
+    pub fn new(address: String, token: String) -> AdminClient {
+        AdminClient { address, token }
+    }
+
+    // Devuelve el body de la respuesta, o el "error" que mandó el server si no fue un 200
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut socket = TcpStream::connect(&self.address)
+            .map_err(|e| format!("could not connect to {}: {}", self.address, e))?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        write!(
+            socket,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            self.address,
+            self.token,
+            body.len(),
+            body
+        )?;
+        socket.flush()?;
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response)?;
+        let (status, body) = parse_response(&response)?;
+        let body: Value = serde_json::from_str(body)
+            .map_err(|e| format!("invalid JSON in the response ({}): {}", status, e))?;
+        if status != 200 {
+            let message = body["error"].as_str().unwrap_or("unknown error");
+            return Err(format!("{} ({})", message, status).into());
+        }
+        Ok(body)
+    }
+}
+
+// Devuelve el código de estado y el body
+fn parse_response(response: &str) -> Result<(u16, &str), Box<dyn std::error::Error>> {
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("incomplete HTTP response")?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or("invalid HTTP status line")?
+        .parse()?;
+    Ok((status, body))
+}
+
+// Escapa un client id o un tópico para usarlo como segmento del path
+pub fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_characters_are_escaped() {
+        assert_eq!(encode_path_segment("casa/luz 1"), "casa%2Fluz%201");
+        assert_eq!(encode_path_segment("sensor-1.a_b"), "sensor-1.a_b");
+        assert_eq!(encode_path_segment("ñ"), "%C3%B1");
+    }
+
+    #[test]
+    fn status_and_body_are_split() {
+        let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(response).unwrap(), (404, "{}"));
+        assert!(parse_response("HTTP/1.1 200 OK\r\n").is_err());
+    }
+}
@@ -0,0 +1,344 @@
+/*
+Herramienta de línea de comandos para administrar el server a través de su API de
+administración ([admin] en la configuración del server).
+*/
+
+mod admin_client;
+mod table;
+
+use admin_client::{encode_path_segment, AdminClient};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9235";
+const ADDRESS_ENV_VAR: &str = "MQTTCTL_ADDRESS";
+const TOKEN_ENV_VAR: &str = "MQTTCTL_TOKEN";
+const USAGE: &str = "Usage: mqttctl [--address host:port] [--token-file path] [--json] <command>
+
+Commands:
+  clients                      List connected clients and persistent sessions
+  kick <client_id>             Disconnect a client
+  subscriptions <client_id>    Show the subscriptions of a client
+  retained                     Dump the retained messages
+  clear-retained [topic]       Delete one retained message, or all of them
+  reload                       Reload the server configuration
+  stats [--watch <seconds>]    Show the broker statistics, again every <seconds>
+
+The address defaults to $MQTTCTL_ADDRESS or 127.0.0.1:9235. Without --token-file the
+token is read from $MQTTCTL_TOKEN.";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Clients,
+    Kick(String),
+    Subscriptions(String),
+    Retained,
+    ClearRetained(Option<String>),
+    Reload,
+    Stats { watch: Option<u64> },
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    address: Option<String>,
+    token_file: Option<String>,
+    json: bool,
+    command: Command,
+}
+
+fn main() {
+    if env::args().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = parse_args(env::args().skip(1).collect()).unwrap_or_else(|err| {
+        eprintln!("mqttctl: {}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+    if let Err(err) = run(options) {
+        eprintln!("mqttctl: {}", err);
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut address = None;
+    let mut token_file = None;
+    let mut json = false;
+    let mut watch = None;
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--address" => address = Some(value("--address")?),
+            "--token-file" => token_file = Some(value("--token-file")?),
+            "--json" => json = true,
+            "--watch" => {
+                let seconds = value("--watch")?;
+                match seconds.parse() {
+                    Ok(seconds) if seconds > 0 => watch = Some(seconds),
+                    _ => return Err(format!("invalid --watch interval '{}'", seconds)),
+                }
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let positional: Vec<&str> = positional.iter().map(|arg| arg.as_str()).collect();
+    let command = match positional.as_slice() {
+        ["clients"] => Command::Clients,
+        ["kick", client_id] => Command::Kick(client_id.to_string()),
+        ["subscriptions", client_id] => Command::Subscriptions(client_id.to_string()),
+        ["retained"] => Command::Retained,
+        ["clear-retained"] => Command::ClearRetained(None),
+        ["clear-retained", topic] => Command::ClearRetained(Some(topic.to_string())),
+        ["reload"] => Command::Reload,
+        ["stats"] => Command::Stats { watch },
+        [] => return Err("missing command".to_string()),
+        _ => return Err(format!("invalid command '{}'", positional.join(" "))),
+    };
+    if watch.is_some() && !matches!(command, Command::Stats { .. }) {
+        return Err("--watch only applies to stats".to_string());
+    }
+    Ok(Options {
+        address,
+        token_file,
+        json,
+        command,
+    })
+}
+
+fn run(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    let address = options
+        .address
+        .or_else(|| env::var(ADDRESS_ENV_VAR).ok())
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let token = match &options.token_file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path, e))?
+            .trim()
+            .to_string(),
+        None => env::var(TOKEN_ENV_VAR)
+            .map_err(|_| format!("no token: use --token-file or set {}", TOKEN_ENV_VAR))?,
+    };
+    let client = AdminClient::new(address, token);
+
+    let (response, text) = match &options.command {
+        Command::Clients => {
+            let clients = client.request("GET", "/api/clients", None)?;
+            let text = clients_table(&clients);
+            (clients, text)
+        }
+        Command::Kick(client_id) => {
+            let path = format!("/api/clients/{}/disconnect", encode_path_segment(client_id));
+            let response = client.request("POST", &path, None)?;
+            (response, format!("Disconnected {}\n", client_id))
+        }
+        Command::Subscriptions(client_id) => {
+            let path = format!("/api/clients/{}", encode_path_segment(client_id));
+            let client = client.request("GET", &path, None)?;
+            let text = subscriptions_table(&client);
+            (client["subscriptions"].clone(), text)
+        }
+        Command::Retained => {
+            let retained = client.request("GET", "/api/retained", None)?;
+            let text = retained_table(&retained);
+            (retained, text)
+        }
+        Command::ClearRetained(topic) => {
+            let path = match topic {
+                Some(topic) => format!("/api/retained/{}", encode_path_segment(topic)),
+                None => "/api/retained".to_string(),
+            };
+            let response = client.request("DELETE", &path, None)?;
+            let text = format!("Deleted {} retained messages\n", response["deleted"]);
+            (response, text)
+        }
+        Command::Reload => {
+            let response = client.request("POST", "/api/reload", None)?;
+            (response, "Configuration reloaded\n".to_string())
+        }
+        Command::Stats { watch: None } => {
+            let stats = client.request("GET", "/api/stats", None)?;
+            let text = stats_table(&stats);
+            (stats, text)
+        }
+        // Con --json sale un objeto por línea, así se puede seguir con otras herramientas
+        Command::Stats {
+            watch: Some(seconds),
+        } => loop {
+            let stats = client.request("GET", "/api/stats", None)?;
+            if options.json {
+                println!("{}", stats);
+            } else {
+                println!("{}", stats_table(&stats));
+            }
+            thread::sleep(Duration::from_secs(*seconds));
+        },
+    };
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+    } else {
+        print!("{}", text);
+    }
+    Ok(())
+}
+
+fn clients_table(clients: &Value) -> String {
+    let rows: Vec<Vec<String>> = array(clients)
+        .iter()
+        .map(|client| {
+            vec![
+                text(&client["client_id"]),
+                yes_no(&client["connected"]),
+                text(&client["address"]),
+                text(&client["username"]),
+                array(&client["subscriptions"]).len().to_string(),
+                text(&client["in_flight"]),
+            ]
+        })
+        .collect();
+    table::render(
+        &[
+            "CLIENT ID",
+            "CONNECTED",
+            "ADDRESS",
+            "USERNAME",
+            "SUBSCRIPTIONS",
+            "IN FLIGHT",
+        ],
+        &rows,
+    )
+}
+
+fn subscriptions_table(client: &Value) -> String {
+    let rows: Vec<Vec<String>> = array(&client["subscriptions"])
+        .iter()
+        .map(|s| vec![text(&s["topic_filter"]), text(&s["qos"])])
+        .collect();
+    table::render(&["TOPIC FILTER", "QOS"], &rows)
+}
+
+fn retained_table(retained: &Value) -> String {
+    let rows: Vec<Vec<String>> = array(retained)
+        .iter()
+        .map(|m| vec![text(&m["topic"]), text(&m["qos"]), text(&m["message"])])
+        .collect();
+    table::render(&["TOPIC", "QOS", "MESSAGE"], &rows)
+}
+
+// Los objetos anidados (paquetes por tipo, latencia) quedan como una fila por campo
+fn stats_table(stats: &Value) -> String {
+    let mut rows = vec![];
+    flatten("", stats, &mut rows);
+    table::render(&["STATISTIC", "VALUE"], &rows)
+}
+
+fn flatten(prefix: &str, value: &Value, rows: &mut Vec<Vec<String>>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&name, value, rows);
+            }
+        }
+        value => rows.push(vec![prefix.to_string(), text(value)]),
+    }
+}
+
+fn array(value: &Value) -> &[Value] {
+    value.as_array().map(|a| a.as_slice()).unwrap_or(&[])
+}
+
+// Los strings sin comillas y null como -
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn yes_no(value: &Value) -> String {
+    match value.as_bool() {
+        Some(true) => "yes".to_string(),
+        _ => "no".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_and_commands_are_parsed() {
+        assert_eq!(
+            parse_args(args("--address 10.0.0.1:9235 --json kick sensor-1")),
+            Ok(Options {
+                address: Some("10.0.0.1:9235".to_string()),
+                token_file: None,
+                json: true,
+                command: Command::Kick("sensor-1".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_args(args("stats --watch 2")).unwrap().command,
+            Command::Stats { watch: Some(2) }
+        );
+        assert_eq!(
+            parse_args(args("clear-retained casa/luz")).unwrap().command,
+            Command::ClearRetained(Some("casa/luz".to_string()))
+        );
+        assert!(parse_args(args("kick")).is_err());
+        assert!(parse_args(args("clients --watch 2")).is_err());
+        assert!(parse_args(args("stats --watch 0")).is_err());
+        assert!(parse_args(args("--token-file")).is_err());
+    }
+
+    #[test]
+    fn clients_are_shown_one_per_row() {
+        let clients = json!([{
+            "client_id": "sensor-1",
+            "connected": true,
+            "address": "10.0.0.7:50123",
+            "username": null,
+            "subscriptions": [{"topic_filter": "casa/#", "qos": 1}],
+            "in_flight": 2,
+        }]);
+        let table = clients_table(&clients);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "CLIENT ID  CONNECTED  ADDRESS         USERNAME  SUBSCRIPTIONS  IN FLIGHT"
+        );
+        assert_eq!(
+            lines[1],
+            "sensor-1   yes        10.0.0.7:50123  -         1              2"
+        );
+    }
+
+    #[test]
+    fn nested_stats_are_flattened() {
+        let stats = json!({"bytes_sent": 10, "packets_received": {"connect": 1}});
+        assert_eq!(
+            stats_table(&stats),
+            "STATISTIC                 VALUE\nbytes_sent                10\npackets_received.connect  1\n"
+        );
+    }
+}
@@ -0,0 +1,39 @@
+// Arma una tabla de texto con las columnas alineadas a la izquierda
+pub fn render(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    let mut out = String::new();
+    for row in std::iter::once(&headers).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_are_as_wide_as_their_widest_cell() {
+        let rows = vec![
+            vec!["sensor-1".to_string(), "yes".to_string()],
+            vec!["a".to_string(), "no".to_string()],
+        ];
+        assert_eq!(
+            render(&["CLIENT ID", "CONNECTED"], &rows),
+            "CLIENT ID  CONNECTED\nsensor-1   yes\na          no\n"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use common::packet::Qos;
+use server::config::Config;
+use server::{LocalClientOptions, ServerBuilder, ServerHandle};
+use std::env;
+use std::fs;
+use std::process::{Command, Output};
+use std::time::Duration;
+
+fn temp_file(name: &str) -> String {
+    env::temp_dir()
+        .join(format!("mqttctl_test_{}_{}", std::process::id(), name))
+        .to_string_lossy()
+        .to_string()
+}
+
+// Con un archivo de configuración, así reload tiene qué volver a leer
+fn start_server(name: &str) -> (ServerHandle, String) {
+    let token_file = temp_file(&format!("{}.token", name));
+    fs::write(&token_file, "s3cret").unwrap();
+    let accounts_file = temp_file(&format!("{}.accounts", name));
+    fs::write(&accounts_file, "").unwrap();
+    let config_file = temp_file(&format!("{}.conf", name));
+    fs::write(
+        &config_file,
+        format!(
+            "[server]\naddress = 127.0.0.1\nport = 0\n\n[log]\nfile = {}\n\n[auth]\naccounts_file = {}\n\n[sys]\ninterval_seconds = 0\n\n[admin]\nenabled = true\naddress = 127.0.0.1\nport = 0\ntoken_file = {}\n",
+            temp_file(&format!("{}.log", name)),
+            accounts_file,
+            token_file
+        ),
+    )
+    .unwrap();
+    let server = ServerBuilder::from_config(Config::load(Some(config_file)).unwrap())
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    (server, token_file)
+}
+
+fn mqttctl(server: &ServerHandle, token_file: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mqttctl"))
+        .arg("--address")
+        .arg(server.admin_address().unwrap().to_string())
+        .arg("--token-file")
+        .arg(token_file)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn clients_subscriptions_and_kick() {
+    let (server, token_file) = start_server("clients");
+    let mut client = server
+        .connect_local(LocalClientOptions::new("sensor-1"))
+        .unwrap();
+    client.subscribe(&[("casa/#", Qos::AtLeastOnce)]).unwrap();
+
+    let clients = stdout(&mqttctl(&server, &token_file, &["clients"]));
+    let lines: Vec<&str> = clients.lines().collect();
+    assert!(lines[0].starts_with("CLIENT ID"));
+    assert!(lines[1].starts_with("sensor-1"));
+    assert!(lines[1].contains("yes"));
+
+    let subscriptions = stdout(&mqttctl(
+        &server,
+        &token_file,
+        &["--json", "subscriptions", "sensor-1"],
+    ));
+    let subscriptions: serde_json::Value = serde_json::from_str(&subscriptions).unwrap();
+    assert_eq!(
+        subscriptions,
+        serde_json::json!([{"topic_filter": "casa/#", "qos": 1}])
+    );
+
+    assert_eq!(
+        stdout(&mqttctl(&server, &token_file, &["kick", "sensor-1"])),
+        "Disconnected sensor-1\n"
+    );
+    let output = mqttctl(&server, &token_file, &["kick", "sensor-1"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not connected (404)"));
+
+    server.shutdown();
+}
+
+#[test]
+fn retained_messages_reload_and_stats() {
+    let (server, token_file) = start_server("retained");
+    let mut client = server
+        .connect_local(LocalClientOptions::new("lampara"))
+        .unwrap();
+    // Con QoS 1 publish vuelve cuando el server ya lo procesó
+    client
+        .publish("casa/luz", "on", Qos::AtLeastOnce, true)
+        .unwrap();
+    client
+        .publish("casa/puerta", "cerrada", Qos::AtLeastOnce, true)
+        .unwrap();
+
+    let retained = stdout(&mqttctl(&server, &token_file, &["retained"]));
+    assert_eq!(
+        retained,
+        "TOPIC        QOS  MESSAGE\ncasa/luz     1    on\ncasa/puerta  1    cerrada\n"
+    );
+    assert_eq!(
+        stdout(&mqttctl(
+            &server,
+            &token_file,
+            &["clear-retained", "casa/luz"]
+        )),
+        "Deleted 1 retained messages\n"
+    );
+    assert_eq!(
+        stdout(&mqttctl(&server, &token_file, &["clear-retained"])),
+        "Deleted 1 retained messages\n"
+    );
+
+    assert_eq!(
+        stdout(&mqttctl(&server, &token_file, &["reload"])),
+        "Configuration reloaded\n"
+    );
+    let stats = stdout(&mqttctl(&server, &token_file, &["--json", "stats"]));
+    let stats: serde_json::Value = serde_json::from_str(&stats).unwrap();
+    assert_eq!(stats["packets_received"]["publish"], 2);
+
+    let wrong_token = temp_file("wrong.token");
+    fs::write(&wrong_token, "otro").unwrap();
+    let output = mqttctl(&server, &wrong_token, &["clients"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("(401)"));
+
+    server.shutdown();
+}
@@ -2,9 +2,10 @@ use common::all_packets::connack::CONNACK_CONNECTION_ACCEPTED;
 use common::all_packets::connect::{Connect, ConnectPayload};
 use common::all_packets::publish::{Publish, PublishFlags};
 use common::packet::{Packet, WritePacket};
+use common::stream::MqttStream;
+use common::tls::TlsClientConfig;
 use rand::prelude::*;
 use std::io::{Error, ErrorKind};
-use std::net::TcpStream;
 use std::thread;
 use std::time::Duration;
 
@@ -13,7 +14,7 @@ const ERROR_IN_CONNECTION: &str = "Connection not accepted";
 const ERROR_CONNACK_NOT_RECEIVED: &str = "Didn't received the connack packet";
 
 pub struct Thermostat {
-    socket: Option<TcpStream>,
+    socket: Option<MqttStream>,
     topics: Vec<String>,
     intervals: u16,
 }
@@ -27,8 +28,9 @@ impl Thermostat {
         }
     }
 
+    #[allow(clippy::io_other_error)]
     pub fn connect_to(&mut self, address: String) -> Result<(), Box<dyn std::error::Error>> {
-        let mut socket = TcpStream::connect(address)?;
+        let mut socket = MqttStream::connect(&address, TlsClientConfig::from_env().as_ref())?;
 
         let connect_packet = Connect::new(
             ConnectPayload::new("Thermostat".to_string(), None, None, None, None),
@@ -64,6 +66,7 @@ impl Thermostat {
         self.topics.push(topic);
     }
 
+    #[allow(clippy::io_other_error)]
     pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         println!("Empecé a correr. Publico cada {} segundos", self.intervals);
 
@@ -0,0 +1,468 @@
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::listener::ConnectionLimiter;
+use crate::packet_processor::Message;
+use crate::session::Session;
+use crate::stats::BrokerStats;
+use serde_json::{json, Value};
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const DEFAULT_ADMIN_ADDRESS: &str = "127.0.0.1";
+const DEFAULT_ADMIN_PORT: u16 = 9235;
+const JSON_CONTENT_TYPE: &str = "application/json";
+// Lo que espera un request a que el PacketProcessor lo atienda
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const CLIENTS_PATH: &str = "/api/clients";
+const RETAINED_PATH: &str = "/api/retained";
+const PUBLISH_PATH: &str = "/api/publish";
+const RELOAD_PATH: &str = "/api/reload";
+const STATS_PATH: &str = "/api/stats";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub port: u16,
+    // Archivo con el token que hay que mandar en el header Authorization: Bearer <token>
+    pub token_file: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            address: DEFAULT_ADMIN_ADDRESS.to_string(),
+            port: DEFAULT_ADMIN_PORT,
+            token_file: None,
+        }
+    }
+}
+
+impl AdminConfig {
+    pub fn read_token(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let path = self
+            .token_file
+            .as_ref()
+            .ok_or("admin.token_file no puede estar vacio")?;
+        let token = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path, e))?
+            .trim()
+            .to_string();
+        if token.is_empty() {
+            return Err(format!("{}: el token de administracion esta vacio", path).into());
+        }
+        Ok(token)
+    }
+}
+
+// Lo que se le puede pedir a la API de administración. Reload lo atiende el Reloader, Stats
+// se arma con BrokerStats y el resto lo atiende el PacketProcessor, que es el dueño de las
+// sesiones y de los retenidos
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminCommand {
+    Stats,
+    Reload,
+    ListClients,
+    GetClient(String),
+    DisconnectClient(String),
+    ListRetained,
+    DeleteRetained(String),
+    ClearRetained,
+    Publish {
+        topic: String,
+        message: String,
+        qos: u8,
+        retain: bool,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AdminError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl AdminError {
+    pub fn bad_request(message: String) -> AdminError {
+        AdminError {
+            status: 400,
+            message,
+        }
+    }
+
+    pub fn not_found(message: String) -> AdminError {
+        AdminError {
+            status: 404,
+            message,
+        }
+    }
+}
+
+pub type AdminReply = Result<Value, AdminError>;
+pub type AdminRequest = (AdminCommand, Sender<AdminReply>);
+
+// Las partes del server a las que la API les pasa cada comando
+pub struct AdminBackend {
+    pub processor_tx: Sender<AdminRequest>,
+    pub reload_tx: Sender<Sender<bool>>,
+    pub stats: Arc<BrokerStats>,
+    pub connection_limiter: ConnectionLimiter,
+}
+
+impl AdminBackend {
+    fn handle(&self, command: AdminCommand) -> AdminReply {
+        match command {
+            AdminCommand::Stats => Ok(stats_json(&self.stats, &self.connection_limiter)),
+            AdminCommand::Reload => {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                self.reload_tx.send(reply_tx).map_err(|_| unavailable())?;
+                match reply_rx.recv_timeout(REPLY_TIMEOUT) {
+                    Ok(true) => Ok(json!({ "reloaded": true })),
+                    // El motivo queda en el log del server
+                    Ok(false) => Err(AdminError {
+                        status: 500,
+                        message: "Reload failed, previous configuration kept (see the server log)"
+                            .to_string(),
+                    }),
+                    Err(_) => Err(unavailable()),
+                }
+            }
+            command => {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                self.processor_tx
+                    .send((command, reply_tx))
+                    .map_err(|_| unavailable())?;
+                reply_rx
+                    .recv_timeout(REPLY_TIMEOUT)
+                    .map_err(|_| unavailable())?
+            }
+        }
+    }
+}
+
+// Sirve la API JSON de administración. Cada request se traduce a un AdminCommand
+pub fn spawn(
+    listener: TcpListener,
+    token: String,
+    backend: AdminBackend,
+    shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    http::serve(listener, shutdown, move |request: &HttpRequest| {
+        if !is_authorized(request, &token) {
+            return error_response(AdminError {
+                status: 401,
+                message: "Missing or invalid admin token".to_string(),
+            });
+        }
+        let command = match parse_command(request) {
+            Ok(command) => command,
+            Err(error) => return error_response(error),
+        };
+        match backend.handle(command) {
+            Ok(body) => HttpResponse::new(200, JSON_CONTENT_TYPE, format!("{}\n", body)),
+            Err(error) => error_response(error),
+        }
+    })
+}
+
+pub fn parse_command(request: &HttpRequest) -> Result<AdminCommand, AdminError> {
+    let method = request.method.as_str();
+    let path = request.path.as_str();
+    let not_allowed = || AdminError {
+        status: 405,
+        message: format!("{} not allowed on {}", method, path),
+    };
+
+    if path == CLIENTS_PATH {
+        return match method {
+            "GET" => Ok(AdminCommand::ListClients),
+            _ => Err(not_allowed()),
+        };
+    }
+    if let Some(rest) = path
+        .strip_prefix(CLIENTS_PATH)
+        .and_then(|p| p.strip_prefix('/'))
+    {
+        return match (method, rest.strip_suffix("/disconnect")) {
+            ("POST", Some(client_id)) => Ok(AdminCommand::DisconnectClient(decode(client_id)?)),
+            ("GET", None) => Ok(AdminCommand::GetClient(decode(rest)?)),
+            _ => Err(not_allowed()),
+        };
+    }
+    if path == RETAINED_PATH {
+        return match method {
+            "GET" => Ok(AdminCommand::ListRetained),
+            "DELETE" => Ok(AdminCommand::ClearRetained),
+            _ => Err(not_allowed()),
+        };
+    }
+    // El tópico puede tener /, así que es todo lo que sigue al prefijo
+    if let Some(topic) = path
+        .strip_prefix(RETAINED_PATH)
+        .and_then(|p| p.strip_prefix('/'))
+    {
+        return match method {
+            "DELETE" => Ok(AdminCommand::DeleteRetained(decode(topic)?)),
+            _ => Err(not_allowed()),
+        };
+    }
+    if path == STATS_PATH {
+        return match method {
+            "GET" => Ok(AdminCommand::Stats),
+            _ => Err(not_allowed()),
+        };
+    }
+    if path == RELOAD_PATH {
+        return match method {
+            "POST" => Ok(AdminCommand::Reload),
+            _ => Err(not_allowed()),
+        };
+    }
+    if path == PUBLISH_PATH {
+        return match method {
+            "POST" => parse_publish(&request.body),
+            _ => Err(not_allowed()),
+        };
+    }
+    Err(AdminError::not_found(format!("Unknown path {}", path)))
+}
+
+// Body: {"topic": "...", "message": "...", "qos": 0 | 1, "retain": bool}
+fn parse_publish(body: &[u8]) -> Result<AdminCommand, AdminError> {
+    let body: Value = serde_json::from_slice(body)
+        .map_err(|e| AdminError::bad_request(format!("Invalid JSON body: {}", e)))?;
+    let topic = match body["topic"].as_str() {
+        Some(topic) if !topic.is_empty() => topic.to_string(),
+        _ => return Err(AdminError::bad_request("Missing topic".to_string())),
+    };
+    let message = match &body["message"] {
+        Value::Null => String::new(),
+        Value::String(message) => message.clone(),
+        _ => {
+            return Err(AdminError::bad_request(
+                "message must be a string".to_string(),
+            ))
+        }
+    };
+    let qos = match &body["qos"] {
+        Value::Null => 0,
+        qos => match qos.as_u64() {
+            Some(qos) if qos <= 1 => qos as u8,
+            _ => return Err(AdminError::bad_request("qos must be 0 or 1".to_string())),
+        },
+    };
+    let retain = match &body["retain"] {
+        Value::Null => false,
+        retain => retain
+            .as_bool()
+            .ok_or_else(|| AdminError::bad_request("retain must be a boolean".to_string()))?,
+    };
+    Ok(AdminCommand::Publish {
+        topic,
+        message,
+        qos,
+        retain,
+    })
+}
+
+// Sesión (conectada o persistente) con sus suscripciones y los QoS 1 sin Puback
+pub fn client_json(session: &Session, address: Option<SocketAddr>) -> Value {
+    let subscriptions: Vec<Value> = session
+        .get_subscriptions()
+        .iter()
+        .map(|s| json!({"topic_filter": s.topic_filter, "qos": s.max_qos as u8}))
+        .collect();
+    json!({
+        "client_id": session.get_client_id(),
+        "connected": session.is_active(),
+        "address": address.map(|address| address.to_string()),
+        "username": session.get_username(),
+        "connected_at": session.connected_at(),
+        "clean_session": session.is_clean_session,
+        "subscriptions": subscriptions,
+        "in_flight": session.unacknowledged_messages.len(),
+    })
+}
+
+pub fn retained_json(topic: &str, message: &Message) -> Value {
+    json!({"topic": topic, "message": message.message, "qos": message.qos as u8})
+}
+
+// Los mismos contadores que /metrics, como un objeto
+pub fn stats_json(stats: &BrokerStats, connection_limiter: &ConnectionLimiter) -> Value {
+    let by_type = |counts: Vec<(&str, u64)>| -> Value {
+        counts
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), json!(count)))
+            .collect::<serde_json::Map<String, Value>>()
+            .into()
+    };
+    let gauges = stats.gauges();
+    let latency = stats.publish_latency();
+    json!({
+        "packets_received": by_type(stats.packets_received()),
+        "packets_sent": by_type(stats.packets_sent()),
+        "bytes_received": stats.bytes_received(),
+        "bytes_sent": stats.bytes_sent(),
+        "messages_dropped": stats.messages_dropped(),
+        "retransmissions": stats.retransmissions(),
+        "auth_failures": stats.auth_failures(),
+        "connections_active": connection_limiter.active(),
+        "connections_rejected": connection_limiter.rejected(),
+        "clients_connected": gauges.clients_connected,
+        "clients_disconnected": gauges.clients_disconnected,
+        "subscriptions": gauges.subscriptions,
+        "retained_messages": gauges.retained_messages,
+        "publish_latency": {
+            "count": latency.count(),
+            "sum_seconds": latency.sum_seconds(),
+        },
+    })
+}
+
+// Compara sin cortar en el primer byte distinto, para no dar pistas por el tiempo de respuesta
+fn is_authorized(request: &HttpRequest, token: &str) -> bool {
+    let given = match request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(given) => given.trim().as_bytes(),
+        None => return false,
+    };
+    let expected = token.as_bytes();
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn decode(segment: &str) -> Result<String, AdminError> {
+    http::percent_decode(segment).map_err(|e| AdminError::bad_request(e.to_string()))
+}
+
+fn unavailable() -> AdminError {
+    AdminError {
+        status: 503,
+        message: "Server is shutting down".to_string(),
+    }
+}
+
+fn error_response(error: AdminError) -> HttpResponse {
+    HttpResponse::new(
+        error.status,
+        JSON_CONTENT_TYPE,
+        format!("{}\n", json!({ "error": error.message })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str) -> HttpRequest {
+        http::read_request(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn paths_and_methods_map_to_commands() {
+        let parse = |raw: &str| parse_command(&request(raw));
+        assert_eq!(
+            parse("GET /api/clients HTTP/1.1\r\n\r\n"),
+            Ok(AdminCommand::ListClients)
+        );
+        assert_eq!(
+            parse("GET /api/clients/sensor%201 HTTP/1.1\r\n\r\n"),
+            Ok(AdminCommand::GetClient("sensor 1".to_string()))
+        );
+        assert_eq!(
+            parse("POST /api/clients/a%2Fb/disconnect HTTP/1.1\r\n\r\n"),
+            Ok(AdminCommand::DisconnectClient("a/b".to_string()))
+        );
+        assert_eq!(
+            parse("DELETE /api/retained/casa/luz HTTP/1.1\r\n\r\n"),
+            Ok(AdminCommand::DeleteRetained("casa/luz".to_string()))
+        );
+        assert_eq!(
+            parse("DELETE /api/retained HTTP/1.1\r\n\r\n"),
+            Ok(AdminCommand::ClearRetained)
+        );
+        assert_eq!(
+            parse("GET /api/stats HTTP/1.1\r\n\r\n"),
+            Ok(AdminCommand::Stats)
+        );
+        assert_eq!(
+            parse("POST /api/reload HTTP/1.1\r\n\r\n"),
+            Ok(AdminCommand::Reload)
+        );
+        assert_eq!(
+            parse("PUT /api/clients HTTP/1.1\r\n\r\n")
+                .unwrap_err()
+                .status,
+            405
+        );
+        assert_eq!(
+            parse("GET /api/otro HTTP/1.1\r\n\r\n").unwrap_err().status,
+            404
+        );
+    }
+
+    #[test]
+    fn publish_body_is_validated() {
+        let publish = |body: &str| {
+            parse_command(&request(&format!(
+                "POST /api/publish HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )))
+        };
+        assert_eq!(
+            publish(r#"{"topic": "a/b", "message": "hola", "qos": 1, "retain": true}"#),
+            Ok(AdminCommand::Publish {
+                topic: "a/b".to_string(),
+                message: "hola".to_string(),
+                qos: 1,
+                retain: true,
+            })
+        );
+        assert_eq!(
+            publish(r#"{"topic": "a"}"#),
+            Ok(AdminCommand::Publish {
+                topic: "a".to_string(),
+                message: String::new(),
+                qos: 0,
+                retain: false,
+            })
+        );
+        assert_eq!(publish(r#"{"message": "x"}"#).unwrap_err().status, 400);
+        assert_eq!(
+            publish(r#"{"topic": "a", "qos": 2}"#).unwrap_err().status,
+            400
+        );
+        assert_eq!(publish("no es json").unwrap_err().status, 400);
+    }
+
+    #[test]
+    fn only_the_exact_bearer_token_is_accepted() {
+        let with_header = |header: &str| request(&format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header));
+        assert!(is_authorized(
+            &with_header("Authorization: Bearer s3cret"),
+            "s3cret"
+        ));
+        assert!(!is_authorized(
+            &with_header("Authorization: Bearer s3cre"),
+            "s3cret"
+        ));
+        assert!(!is_authorized(
+            &with_header("Authorization: s3cret"),
+            "s3cret"
+        ));
+        assert!(!is_authorized(&with_header("X-Token: s3cret"), "s3cret"));
+    }
+}
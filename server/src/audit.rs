@@ -0,0 +1,203 @@
+use crate::topic_filters;
+use common::all_packets::publish::Publish;
+use common::logging::logger::{log_global, LogMessage};
+use common::logging::rotating_file::{RotatingFile, RotationConfig};
+use common::logging::timestamp;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
+
+const DEFAULT_AUDIT_FILE: &str = "audit.log";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub file: String,
+    // Solo se auditan los tópicos que matchean con alguno de estos filtros
+    pub topics: Vec<String>,
+    // Agrega el SHA-256 del payload a cada publicación
+    pub hash_payload: bool,
+    pub rotation: RotationConfig,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: false,
+            file: DEFAULT_AUDIT_FILE.to_string(),
+            topics: vec!["#".to_string()],
+            hash_payload: false,
+            rotation: RotationConfig::default(),
+        }
+    }
+}
+
+// Quién acusó recibo: el cliente de una entrega, o el server de una publicación
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PubackDirection {
+    Received,
+    Sent,
+}
+
+// Registro de auditoría de publicaciones y entregas, aparte del log. Cada registro es un
+// objeto JSON por línea, en un archivo al que solo se le agrega al final
+pub struct AuditLog {
+    topics: Vec<String>,
+    hash_payload: bool,
+    sender: Mutex<Sender<(String, SystemTime)>>,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> Result<AuditLog, Box<dyn std::error::Error>> {
+        let mut file = RotatingFile::open(&config.file, config.rotation.clone())
+            .map_err(|e| format!("No se pudo abrir {}: {}", config.file, e))?;
+        let (sender, receiver) = mpsc::channel::<(String, SystemTime)>();
+        thread::spawn(move || {
+            while let Ok((line, time)) = receiver.recv() {
+                if let Err(e) = file.write_line(&line, time) {
+                    log_global(LogMessage::error(
+                        format!("Could not write to the audit file: {}", e),
+                        "".to_string(),
+                    ));
+                }
+            }
+        });
+        Ok(AuditLog {
+            topics: config.topics.clone(),
+            hash_payload: config.hash_payload,
+            sender: Mutex::new(sender),
+        })
+    }
+
+    // Un PUBLISH de un cliente que el server aceptó
+    pub fn publish(&self, client_id: &str, username: Option<&String>, publish: &Publish) {
+        if !self.audits(&publish.topic_name) {
+            return;
+        }
+        let mut record = json!({
+            "event": "publish",
+            "client_id": client_id,
+            "username": username,
+            "topic": publish.topic_name,
+            "qos": publish.flags.qos_level as u8,
+            "retain": publish.flags.retain,
+            "packet_id": publish.packet_id,
+            "payload_size": publish.application_message.len(),
+        });
+        if self.hash_payload {
+            record["payload_sha256"] = json!(sha256_hex(publish.application_message.as_bytes()));
+        }
+        self.write(record);
+    }
+
+    // Un PUBLISH que el server le manda a un suscriptor
+    pub fn deliver(&self, client_id: &str, username: Option<&String>, publish: &Publish) {
+        if !self.audits(&publish.topic_name) {
+            return;
+        }
+        self.write(json!({
+            "event": "deliver",
+            "client_id": client_id,
+            "username": username,
+            "topic": publish.topic_name,
+            "qos": publish.flags.qos_level as u8,
+            "retain": publish.flags.retain,
+            "packet_id": publish.packet_id,
+            "payload_size": publish.application_message.len(),
+        }));
+    }
+
+    pub fn puback(&self, direction: PubackDirection, client_id: &str, topic: &str, packet_id: u16) {
+        if !self.audits(topic) {
+            return;
+        }
+        let direction = match direction {
+            PubackDirection::Received => "received",
+            PubackDirection::Sent => "sent",
+        };
+        self.write(json!({
+            "event": "puback",
+            "direction": direction,
+            "client_id": client_id,
+            "topic": topic,
+            "packet_id": packet_id,
+        }));
+    }
+
+    fn audits(&self, topic: &str) -> bool {
+        self.topics
+            .iter()
+            .any(|filter| topic_filters::filter_matches_topic(filter, topic))
+    }
+
+    fn write(&self, mut record: Value) {
+        let now = SystemTime::now();
+        record["timestamp"] = json!(timestamp::rfc3339(now));
+        if let Ok(sender) = self.sender.lock() {
+            let _ = sender.send((record.to_string() + "\n", now));
+        }
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::all_packets::publish::PublishFlags;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn only_configured_topics_are_audited() {
+        let path = env::temp_dir()
+            .join(format!("audit_{}_topics.log", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_file(&path);
+        let config = AuditConfig {
+            enabled: true,
+            file: path.clone(),
+            topics: vec!["pagos/#".to_string()],
+            hash_payload: true,
+            ..AuditConfig::default()
+        };
+        let audit = AuditLog::open(&config).unwrap();
+        let publish = Publish::new(
+            PublishFlags::new(0b0011_0011),
+            "pagos/tarjeta".to_string(),
+            Some(7),
+            "abc".to_string(),
+        );
+        audit.publish("caja-1", None, &publish);
+        audit.puback(PubackDirection::Sent, "caja-1", "pagos/tarjeta", 7);
+        audit.puback(PubackDirection::Sent, "sensor", "casa/luz", 8);
+        thread::sleep(Duration::from_millis(100));
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let records: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["event"], "publish");
+        assert_eq!(records[0]["qos"], 1);
+        assert_eq!(records[0]["retain"], true);
+        assert_eq!(records[0]["payload_size"], 3);
+        assert_eq!(
+            records[0]["payload_sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(records[1]["direction"], "sent");
+        let _ = fs::remove_file(path);
+    }
+}
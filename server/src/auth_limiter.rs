@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FAILURES: u32 = 5;
+const DEFAULT_BAN_SECONDS: u64 = 60;
+const DEFAULT_MAX_BAN_SECONDS: u64 = 3600;
+
+#[derive(Clone, Debug)]
+pub struct AuthLimiterConfig {
+    // Cantidad de Connect fallidos seguidos antes de bloquear. Con 0 no se bloquea nunca
+    pub max_failures: u32,
+    pub ban_seconds: u64,
+    pub max_ban_seconds: u64,
+}
+
+impl Default for AuthLimiterConfig {
+    fn default() -> Self {
+        AuthLimiterConfig {
+            max_failures: DEFAULT_MAX_FAILURES,
+            ban_seconds: DEFAULT_BAN_SECONDS,
+            max_ban_seconds: DEFAULT_MAX_BAN_SECONDS,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Ban {
+    Ip(IpAddr, Duration),
+    Username(String, Duration),
+}
+
+#[derive(Debug, Default)]
+struct FailureRecord {
+    failures: u32,
+    bans: u32,
+    banned_until: Option<Instant>,
+}
+
+impl FailureRecord {
+    fn is_banned(&self, now: Instant) -> bool {
+        matches!(self.banned_until, Some(until) if now < until)
+    }
+
+    // Suma un fallo. Si se llegó al máximo, devuelve la duración del ban, que se duplica
+    // con cada ban consecutivo hasta max_ban_seconds
+    fn add_failure(&mut self, config: &AuthLimiterConfig, now: Instant) -> Option<Duration> {
+        if config.max_failures == 0 || self.is_banned(now) {
+            return None;
+        }
+        self.failures += 1;
+        if self.failures < config.max_failures {
+            return None;
+        }
+
+        let multiplier = 2u64.saturating_pow(self.bans);
+        let seconds = config
+            .ban_seconds
+            .saturating_mul(multiplier)
+            .min(config.max_ban_seconds);
+        let duration = Duration::from_secs(seconds);
+        self.failures = 0;
+        self.bans += 1;
+        self.banned_until = Some(now + duration);
+        Some(duration)
+    }
+
+    // Un registro sin fallos cuyo último ban terminó hace más de max_ban_seconds ya no aporta
+    fn is_stale(&self, config: &AuthLimiterConfig, now: Instant) -> bool {
+        self.failures == 0
+            && match self.banned_until {
+                Some(until) => now >= until + Duration::from_secs(config.max_ban_seconds),
+                None => true,
+            }
+    }
+}
+
+// Lleva la cuenta de los Connect rechazados por IP y por username, y bloquea temporalmente
+// a los que fallan demasiadas veces. La configuración llega con cada fallo: si se recarga,
+// los bans vigentes se mantienen y la nueva aplica a los próximos fallos
+#[derive(Default)]
+pub struct AuthLimiter {
+    by_ip: HashMap<IpAddr, FailureRecord>,
+    by_username: HashMap<String, FailureRecord>,
+}
+
+impl AuthLimiter {
+    pub fn ip_is_banned(&self, ip: &IpAddr, now: Instant) -> bool {
+        self.by_ip
+            .get(ip)
+            .is_some_and(|record| record.is_banned(now))
+    }
+
+    pub fn username_is_banned(&self, username: &str, now: Instant) -> bool {
+        self.by_username
+            .get(username)
+            .is_some_and(|record| record.is_banned(now))
+    }
+
+    // Registra un Connect rechazado y devuelve los bans que se generaron
+    pub fn record_failure(
+        &mut self,
+        config: &AuthLimiterConfig,
+        ip: Option<IpAddr>,
+        username: Option<&str>,
+        now: Instant,
+    ) -> Vec<Ban> {
+        self.purge_stale(config, now);
+        let mut bans = vec![];
+
+        if let Some(ip) = ip {
+            let record = self.by_ip.entry(ip).or_default();
+            if let Some(duration) = record.add_failure(config, now) {
+                bans.push(Ban::Ip(ip, duration));
+            }
+        }
+        if let Some(username) = username {
+            let record = self.by_username.entry(username.to_string()).or_default();
+            if let Some(duration) = record.add_failure(config, now) {
+                bans.push(Ban::Username(username.to_string(), duration));
+            }
+        }
+
+        bans
+    }
+
+    // Un Connect aceptado borra el historial de fallos de esa IP y ese username
+    pub fn record_success(&mut self, ip: Option<IpAddr>, username: Option<&str>) {
+        if let Some(ip) = ip {
+            self.by_ip.remove(&ip);
+        }
+        if let Some(username) = username {
+            self.by_username.remove(username);
+        }
+    }
+
+    fn purge_stale(&mut self, config: &AuthLimiterConfig, now: Instant) {
+        self.by_ip.retain(|_, record| !record.is_stale(config, now));
+        self.by_username
+            .retain(|_, record| !record.is_stale(config, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CONFIG: AuthLimiterConfig = AuthLimiterConfig {
+        max_failures: 3,
+        ban_seconds: 10,
+        max_ban_seconds: 25,
+    };
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn ip_is_banned_after_max_failures() {
+        let mut limiter = AuthLimiter::default();
+        let now = Instant::now();
+        assert!(limiter
+            .record_failure(&CONFIG, Some(IP), None, now)
+            .is_empty());
+        assert!(limiter
+            .record_failure(&CONFIG, Some(IP), None, now)
+            .is_empty());
+        let bans = limiter.record_failure(&CONFIG, Some(IP), None, now);
+        assert_eq!(bans, vec![Ban::Ip(IP, Duration::from_secs(10))]);
+        assert!(limiter.ip_is_banned(&IP, now));
+        assert!(!limiter.ip_is_banned(&IP, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn username_is_banned_independently_of_ip() {
+        let mut limiter = AuthLimiter::default();
+        let now = Instant::now();
+        for i in 1..=3 {
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, i));
+            limiter.record_failure(&CONFIG, Some(ip), Some("agus"), now);
+        }
+        assert!(limiter.username_is_banned("agus", now));
+        assert!(!limiter.ip_is_banned(&IP, now));
+    }
+
+    #[test]
+    fn ban_duration_doubles_up_to_max() {
+        let mut limiter = AuthLimiter::default();
+        let mut now = Instant::now();
+        let mut durations = vec![];
+        for _ in 0..3 {
+            for _ in 0..3 {
+                for ban in limiter.record_failure(&CONFIG, Some(IP), None, now) {
+                    if let Ban::Ip(_, duration) = ban {
+                        durations.push(duration.as_secs());
+                    }
+                }
+            }
+            now += Duration::from_secs(30);
+        }
+        assert_eq!(durations, vec![10, 20, 25]);
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let mut limiter = AuthLimiter::default();
+        let now = Instant::now();
+        limiter.record_failure(&CONFIG, Some(IP), Some("agus"), now);
+        limiter.record_failure(&CONFIG, Some(IP), Some("agus"), now);
+        limiter.record_success(Some(IP), Some("agus"));
+        assert!(limiter
+            .record_failure(&CONFIG, Some(IP), Some("agus"), now)
+            .is_empty());
+        assert!(!limiter.ip_is_banned(&IP, now));
+    }
+
+    #[test]
+    fn zero_max_failures_disables_bans() {
+        let mut limiter = AuthLimiter::default();
+        let config = AuthLimiterConfig {
+            max_failures: 0,
+            ..AuthLimiterConfig::default()
+        };
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(limiter
+                .record_failure(&config, Some(IP), None, now)
+                .is_empty());
+        }
+    }
+}
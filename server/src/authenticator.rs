@@ -1,17 +1,96 @@
+use crate::jwt::{JwtValidator, TokenClaims};
+use common::all_packets::connack::{CONNACK_BAD_USERNAME_OR_PASSWORD, CONNACK_NOT_AUTHORIZED};
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::io::{BufRead, BufReader, Error};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Política para los Connect que no traen credenciales completas
+#[derive(Clone, Debug)]
+pub struct AuthPolicy {
+    pub allow_anonymous: bool,
+    pub require_credentials: bool,
+    pub users_without_password: Vec<String>,
+}
+
+impl Default for AuthPolicy {
+    fn default() -> Self {
+        AuthPolicy {
+            allow_anonymous: true,
+            require_credentials: false,
+            users_without_password: vec![],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthenticationError {
+    AnonymousNotAllowed,
+    MissingPassword(String),
+    InvalidCredentials(String),
+    InvalidToken(String),
+}
+
+impl AuthenticationError {
+    pub fn return_code(&self) -> u8 {
+        match self {
+            AuthenticationError::InvalidCredentials(_) | AuthenticationError::InvalidToken(_) => {
+                CONNACK_BAD_USERNAME_OR_PASSWORD
+            }
+            _ => CONNACK_NOT_AUTHORIZED,
+        }
+    }
+}
+
+impl fmt::Display for AuthenticationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthenticationError::AnonymousNotAllowed => {
+                write!(f, "anonymous connections are not allowed")
+            }
+            AuthenticationError::MissingPassword(username) => {
+                write!(f, "user {} did not send a password", username)
+            }
+            AuthenticationError::InvalidCredentials(username) => {
+                write!(f, "invalid password for user {}", username)
+            }
+            AuthenticationError::InvalidToken(reason) => write!(f, "invalid token: {}", reason),
+        }
+    }
+}
 
 pub struct Authenticator {
     accounts: HashMap<String, String>,
+    policy: AuthPolicy,
+    jwt_validator: Option<JwtValidator>,
 }
 
 impl Authenticator {
-    fn new(accounts: HashMap<String, String>) -> Authenticator {
-        Authenticator { accounts }
+    fn new(
+        accounts: HashMap<String, String>,
+        policy: AuthPolicy,
+        jwt_validator: Option<JwtValidator>,
+    ) -> Authenticator {
+        Authenticator {
+            accounts,
+            policy,
+            jwt_validator,
+        }
+    }
+
+    pub fn without_accounts(
+        policy: AuthPolicy,
+        jwt_validator: Option<JwtValidator>,
+    ) -> Authenticator {
+        Authenticator::new(HashMap::new(), policy, jwt_validator)
     }
 
-    pub fn from(filename: String) -> Result<Authenticator, Error> {
+    pub fn from(
+        filename: String,
+        policy: AuthPolicy,
+        jwt_validator: Option<JwtValidator>,
+    ) -> Result<Authenticator, Error> {
         let mut hash: HashMap<String, String> = HashMap::new();
         let file = File::open(filename)?;
         let reader = BufReader::new(file);
@@ -20,19 +99,87 @@ impl Authenticator {
             let line = line?;
             let vec: Vec<&str> = line.split(';').collect();
             if vec.len() != 2 {
-                return Err(Error::new(ErrorKind::Other, "Incorrect format"));
+                return Err(Error::other("Incorrect format"));
             }
 
-            if hash.contains_key(&vec.get(0).unwrap().to_string()) {
-                return Err(Error::new(ErrorKind::Other, "Username already in use"));
+            if hash.contains_key(*vec.first().unwrap()) {
+                return Err(Error::other("Username already in use"));
             }
             hash.insert(
-                vec.get(0).unwrap().to_string(),
+                vec.first().unwrap().to_string(),
                 vec.get(1).unwrap().to_string(),
             );
         }
 
-        Ok(Authenticator::new(hash))
+        Ok(Authenticator::new(hash, policy, jwt_validator))
+    }
+
+    // Decide si se acepta un Connect según sus credenciales y la política configurada.
+    // Si está habilitada la autenticación con JWT, el password es el token y se devuelven sus claims
+    pub fn authenticate(
+        &self,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Option<TokenClaims>, AuthenticationError> {
+        match (username, password) {
+            (None, _) => {
+                if self.policy.require_credentials || !self.policy.allow_anonymous {
+                    return Err(AuthenticationError::AnonymousNotAllowed);
+                }
+            }
+            // Sin require_credentials se acepta como siempre, esté o no en users_without_password
+            (Some(_), None) if !self.policy.require_credentials => {}
+            (Some(username), None) => {
+                let passwordless = self
+                    .policy
+                    .users_without_password
+                    .iter()
+                    .any(|user| user == username);
+                if !passwordless {
+                    return Err(AuthenticationError::MissingPassword(username.to_string()));
+                }
+            }
+            (Some(username), Some(token)) if self.jwt_validator.is_some() => {
+                return self.validate_token(username, token).map(Some);
+            }
+            (Some(username), Some(password)) => {
+                if !self.account_is_valid(username, password) {
+                    return Err(AuthenticationError::InvalidCredentials(
+                        username.to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn validate_token(
+        &self,
+        username: &str,
+        token: &str,
+    ) -> Result<TokenClaims, AuthenticationError> {
+        let validator = self.jwt_validator.as_ref().unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let claims = validator
+            .validate(token, now)
+            .map_err(AuthenticationError::InvalidToken)?;
+
+        // Si el token indica a quién pertenece, tiene que coincidir con el username del Connect
+        if let Some(subject) = &claims.subject {
+            if subject != username {
+                return Err(AuthenticationError::InvalidToken(
+                    "subject does not match username".to_string(),
+                ));
+            }
+        }
+        Ok(claims)
+    }
+
+    pub fn policy(&self) -> &AuthPolicy {
+        &self.policy
     }
 
     pub fn account_is_valid(&self, username: &str, password: &str) -> bool {
@@ -55,10 +202,9 @@ mod tests {
         hash.insert("usuario1".to_string(), "contraseña1".to_string());
         hash.insert("usuario2".to_string(), "contraseña2".to_string());
         hash.insert("usuario3".to_string(), "contraseña3".to_string());
-        let authenticator = Authenticator::new(hash);
-        let to_test =
-            authenticator.account_is_valid(&"usuario2".to_string(), &"contraseña2".to_string());
-        assert_eq!(to_test, true);
+        let authenticator = Authenticator::new(hash, AuthPolicy::default(), None);
+        let to_test = authenticator.account_is_valid("usuario2", "contraseña2");
+        assert!(to_test);
     }
 
     #[test]
@@ -67,10 +213,9 @@ mod tests {
         hash.insert("usuario1".to_string(), "contraseña1".to_string());
         hash.insert("usuario2".to_string(), "contraseña2".to_string());
         hash.insert("usuario3".to_string(), "contraseña3".to_string());
-        let authenticator = Authenticator::new(hash);
-        let to_test =
-            authenticator.account_is_valid(&"usuario4".to_string(), &"contraseña4".to_string());
-        assert_eq!(to_test, false);
+        let authenticator = Authenticator::new(hash, AuthPolicy::default(), None);
+        let to_test = authenticator.account_is_valid("usuario4", "contraseña4");
+        assert!(!to_test);
     }
 
     #[test]
@@ -79,9 +224,93 @@ mod tests {
         hash.insert("usuario1".to_string(), "contraseña1".to_string());
         hash.insert("usuario2".to_string(), "contraseña2".to_string());
         hash.insert("usuario3".to_string(), "contraseña3".to_string());
-        let authenticator = Authenticator::new(hash);
-        let to_test =
-            authenticator.account_is_valid(&"usuario2".to_string(), &"contraseña3".to_string());
-        assert_eq!(to_test, false);
+        let authenticator = Authenticator::new(hash, AuthPolicy::default(), None);
+        let to_test = authenticator.account_is_valid("usuario2", "contraseña3");
+        assert!(!to_test);
+    }
+
+    fn policy_test_authenticator(policy: AuthPolicy) -> Authenticator {
+        let mut hash: HashMap<String, String> = HashMap::new();
+        hash.insert("usuario1".to_string(), "contraseña1".to_string());
+        Authenticator::new(hash, policy, None)
+    }
+
+    #[test]
+    fn default_policy_accepts_anonymous_and_username_only() {
+        let authenticator = policy_test_authenticator(AuthPolicy::default());
+        assert_eq!(authenticator.authenticate(None, None), Ok(None));
+        assert_eq!(authenticator.authenticate(Some("usuario1"), None), Ok(None));
+    }
+
+    #[test]
+    fn anonymous_rejected_when_not_allowed() {
+        let authenticator = policy_test_authenticator(AuthPolicy {
+            allow_anonymous: false,
+            ..AuthPolicy::default()
+        });
+        let to_test = authenticator.authenticate(None, None);
+        assert_eq!(to_test, Err(AuthenticationError::AnonymousNotAllowed));
+        assert_eq!(to_test.unwrap_err().return_code(), CONNACK_NOT_AUTHORIZED);
+    }
+
+    #[test]
+    fn require_credentials_rejects_missing_password() {
+        let authenticator = policy_test_authenticator(AuthPolicy {
+            require_credentials: true,
+            ..AuthPolicy::default()
+        });
+        assert_eq!(
+            authenticator.authenticate(None, None),
+            Err(AuthenticationError::AnonymousNotAllowed)
+        );
+        assert_eq!(
+            authenticator.authenticate(Some("usuario1"), None),
+            Err(AuthenticationError::MissingPassword("usuario1".to_string()))
+        );
+        assert_eq!(
+            authenticator.authenticate(Some("usuario1"), Some("contraseña1")),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn require_credentials_allows_listed_users_without_password() {
+        let authenticator = policy_test_authenticator(AuthPolicy {
+            allow_anonymous: false,
+            require_credentials: true,
+            users_without_password: vec!["sensor".to_string()],
+        });
+        assert_eq!(authenticator.authenticate(Some("sensor"), None), Ok(None));
+    }
+
+    #[test]
+    fn username_without_password_depends_on_require_credentials() {
+        for require_credentials in [false, true] {
+            let authenticator = policy_test_authenticator(AuthPolicy {
+                allow_anonymous: false,
+                require_credentials,
+                users_without_password: vec!["sensor".to_string()],
+            });
+            assert_eq!(authenticator.authenticate(Some("sensor"), None), Ok(None));
+            let unlisted = authenticator.authenticate(Some("usuario1"), None);
+            if require_credentials {
+                assert_eq!(
+                    unlisted,
+                    Err(AuthenticationError::MissingPassword("usuario1".to_string()))
+                );
+            } else {
+                assert_eq!(unlisted, Ok(None));
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_password_returns_bad_username_or_password() {
+        let authenticator = policy_test_authenticator(AuthPolicy::default());
+        let to_test = authenticator.authenticate(Some("usuario1"), Some("otra"));
+        assert_eq!(
+            to_test.unwrap_err().return_code(),
+            CONNACK_BAD_USERNAME_OR_PASSWORD
+        );
     }
 }
@@ -0,0 +1,227 @@
+use common::capture::{CaptureRecord, Direction};
+use common::logging::logger::{log_global, LogMessage};
+use common::logging::rotating_file::{RotatingFile, RotationConfig};
+use common::packet::Packet;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+const DEFAULT_CAPTURE_FILE: &str = "capture.txt";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureConfig {
+    pub enabled: bool,
+    pub file: String,
+    // Patrones de client id (con * y ?) de los clientes que se capturan
+    pub clients: Vec<String>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            enabled: false,
+            file: DEFAULT_CAPTURE_FILE.to_string(),
+            clients: vec!["*".to_string()],
+        }
+    }
+}
+
+// Escribe en el archivo de captura los paquetes de las conexiones elegidas, con el formato de
+// common::capture
+pub struct Capture {
+    clients: Vec<String>,
+    sender: Mutex<Sender<CaptureRecord>>,
+}
+
+impl Capture {
+    pub fn open(config: &CaptureConfig) -> Result<Capture, Box<dyn std::error::Error>> {
+        let mut file = RotatingFile::open(&config.file, RotationConfig::default())
+            .map_err(|e| format!("No se pudo abrir {}: {}", config.file, e))?;
+        let (sender, receiver) = mpsc::channel::<CaptureRecord>();
+        thread::spawn(move || {
+            while let Ok(record) = receiver.recv() {
+                if let Err(e) = file.write_line(&record.to_line(), record.time) {
+                    log_global(LogMessage::error(
+                        format!("Could not write to the capture file: {}", e),
+                        "".to_string(),
+                    ));
+                }
+            }
+        });
+        Ok(Capture {
+            clients: config.clients.clone(),
+            sender: Mutex::new(sender),
+        })
+    }
+
+    fn selects(&self, client_id: &str) -> bool {
+        self.clients
+            .iter()
+            .any(|pattern| pattern_matches(pattern, client_id))
+    }
+
+    fn record(&self, record: CaptureRecord) {
+        if let Ok(sender) = self.sender.lock() {
+            let _ = sender.send(record);
+        }
+    }
+}
+
+// Lo que comparten el reader y el writer de un client handler. Hasta que llega el Connect no
+// se sabe el client id, así que el Connect decide si la conexión se captura
+pub struct ConnectionCapture {
+    capture: Arc<Capture>,
+    connection: u32,
+    client_id: Mutex<Option<String>>,
+}
+
+impl ConnectionCapture {
+    pub fn new(capture: Arc<Capture>, connection: u32) -> ConnectionCapture {
+        ConnectionCapture {
+            capture,
+            connection,
+            client_id: Mutex::new(None),
+        }
+    }
+
+    // `packet` es None si los bytes no forman un paquete válido
+    pub fn inbound(&self, bytes: &[u8], packet: Option<&Packet>) {
+        if let Some(Packet::Connect(connect)) = packet {
+            let client_id = &connect.connect_payload.client_id;
+            let mut selected = self.client_id.lock().unwrap();
+            if selected.is_none() && self.capture.selects(client_id) {
+                *selected = Some(client_id.clone());
+            }
+        }
+        self.record(Direction::Inbound, bytes);
+    }
+
+    pub fn outbound(&self, bytes: &[u8]) {
+        self.record(Direction::Outbound, bytes);
+    }
+
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if let Some(client_id) = self.client_id.lock().unwrap().as_ref() {
+            self.capture.record(CaptureRecord {
+                time: SystemTime::now(),
+                connection: self.connection,
+                client_id: client_id.clone(),
+                direction,
+                bytes: bytes.to_vec(),
+            });
+        }
+    }
+}
+
+// Guarda todo lo que se lee, para capturar los bytes tal como llegaron
+pub struct RecordingReader<'a> {
+    inner: &'a mut dyn Read,
+    pub bytes: Vec<u8>,
+}
+
+impl<'a> RecordingReader<'a> {
+    pub fn new(inner: &'a mut dyn Read) -> RecordingReader<'a> {
+        RecordingReader {
+            inner,
+            bytes: vec![],
+        }
+    }
+}
+
+impl Read for RecordingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// * es cualquier secuencia (incluso vacía) y ? es un carácter cualquiera
+fn pattern_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::all_packets::connect::{Connect, ConnectPayload};
+    use common::all_packets::pingreq::Pingreq;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn client_id_patterns() {
+        assert!(pattern_matches("sensor-*", "sensor-12"));
+        assert!(pattern_matches("*", ""));
+        assert!(pattern_matches("s?n*r-*2", "sensor-12"));
+        assert!(!pattern_matches("sensor-?", "sensor-12"));
+        assert!(!pattern_matches("sensor", "sensor-1"));
+    }
+
+    #[test]
+    fn only_connections_of_selected_clients_are_recorded() {
+        let path = env::temp_dir()
+            .join(format!("capture_{}_selected.txt", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = fs::remove_file(&path);
+        let config = CaptureConfig {
+            enabled: true,
+            file: path.clone(),
+            clients: vec!["sensor-*".to_string()],
+        };
+        let capture = Arc::new(Capture::open(&config).unwrap());
+        let connect = |client_id: &str| {
+            Packet::Connect(Connect::new(
+                ConnectPayload::new(client_id.to_string(), None, None, None, None),
+                60,
+                true,
+                false,
+                false,
+            ))
+        };
+        let sensor = ConnectionCapture::new(capture.clone(), 1);
+        let other = ConnectionCapture::new(capture, 2);
+        // Lo que llega antes del Connect no se puede atribuir a nadie
+        sensor.inbound(&[0xc0, 0x00], Some(&Packet::Pingreq(Pingreq::new())));
+        sensor.inbound(&[0x10, 0x01], Some(&connect("sensor-1")));
+        other.inbound(&[0x10, 0x02], Some(&connect("otro")));
+        sensor.outbound(&[0x20, 0x02, 0x00, 0x00]);
+        other.outbound(&[0x20, 0x02, 0x00, 0x00]);
+        thread::sleep(Duration::from_millis(100));
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let records = common::capture::parse(&contents).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].client_id, "sensor-1");
+        assert_eq!(records[0].direction, Direction::Inbound);
+        assert_eq!(records[0].bytes, vec![0x10, 0x01]);
+        assert_eq!(records[1].direction, Direction::Outbound);
+        let _ = fs::remove_file(path);
+    }
+}
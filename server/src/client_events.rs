@@ -0,0 +1,152 @@
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CLIENTS_TOPIC_PREFIX: &str = "$SYS/clients/";
+
+// Por qué se conectó o se desconectó un cliente
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventReason {
+    // Lo desconectó un administrador desde la API de administración
+    AdminDisconnect,
+    // Connect aceptado sin otra conexión con el mismo client id
+    Connect,
+    // El cliente mandó Disconnect
+    Disconnect,
+    KeepAliveTimeout,
+    // Se cortó la conexión o el cliente mandó algo inválido
+    SocketError,
+    // Otra conexión con el mismo client id reemplazó a esta (o esta reemplazó a otra)
+    Takeover,
+    TokenExpired,
+}
+
+impl EventReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventReason::AdminDisconnect => "admin_disconnect",
+            EventReason::Connect => "connect",
+            EventReason::Disconnect => "disconnect",
+            EventReason::KeepAliveTimeout => "keep_alive_timeout",
+            EventReason::SocketError => "socket_error",
+            EventReason::Takeover => "takeover",
+            EventReason::TokenExpired => "token_expired",
+        }
+    }
+}
+
+// Datos de la conexión que viajan en los eventos
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    pub client_id: String,
+    // None si entró por un socket Unix
+    pub address: Option<SocketAddr>,
+    pub username: Option<String>,
+    // Segundos desde epoch
+    pub connected_at: u64,
+}
+
+// Evento de $SYS/clients/<client_id>/connected
+pub fn connected(info: &ConnectionInfo, reason: EventReason) -> (String, String) {
+    (
+        topic(&info.client_id, "connected"),
+        payload(info, reason).to_string(),
+    )
+}
+
+// Evento de $SYS/clients/<client_id>/disconnected
+pub fn disconnected(
+    info: &ConnectionInfo,
+    reason: EventReason,
+    disconnected_at: u64,
+) -> (String, String) {
+    let mut payload = payload(info, reason);
+    payload["disconnected_at"] = json!(disconnected_at);
+    (topic(&info.client_id, "disconnected"), payload.to_string())
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Un client id con / o comodines no puede ser un nivel de un tópico: esos caracteres se
+// reemplazan por _ (en el payload va el client id original)
+fn topic(client_id: &str, event: &str) -> String {
+    let level: String = client_id
+        .chars()
+        .map(|c| if matches!(c, '/' | '+' | '#') { '_' } else { c })
+        .collect();
+    format!("{}{}/{}", CLIENTS_TOPIC_PREFIX, level, event)
+}
+
+fn payload(info: &ConnectionInfo, reason: EventReason) -> Value {
+    json!({
+        "client_id": info.client_id,
+        "reason": reason.name(),
+        "address": info.address.map(|address| address.to_string()),
+        "username": info.username,
+        "connected_at": info.connected_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> ConnectionInfo {
+        ConnectionInfo {
+            client_id: "sensor-1".to_string(),
+            address: Some("10.0.0.7:50123".parse().unwrap()),
+            username: Some("planta".to_string()),
+            connected_at: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn connected_event_carries_address_username_and_time() {
+        let (topic, payload) = connected(&info(), EventReason::Takeover);
+        assert_eq!(topic, "$SYS/clients/sensor-1/connected");
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload,
+            json!({
+                "client_id": "sensor-1",
+                "reason": "takeover",
+                "address": "10.0.0.7:50123",
+                "username": "planta",
+                "connected_at": 1_700_000_000u64,
+            })
+        );
+    }
+
+    #[test]
+    fn disconnected_event_adds_the_disconnection_time() {
+        let info = ConnectionInfo {
+            address: None,
+            username: None,
+            ..info()
+        };
+        let (topic, payload) = disconnected(&info, EventReason::KeepAliveTimeout, 1_700_000_090);
+        assert_eq!(topic, "$SYS/clients/sensor-1/disconnected");
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["reason"], "keep_alive_timeout");
+        assert_eq!(payload["address"], Value::Null);
+        assert_eq!(payload["username"], Value::Null);
+        assert_eq!(payload["connected_at"], 1_700_000_000u64);
+        assert_eq!(payload["disconnected_at"], 1_700_000_090u64);
+    }
+
+    #[test]
+    fn wildcards_and_slashes_do_not_leak_into_the_topic() {
+        let info = ConnectionInfo {
+            client_id: "a/b+#".to_string(),
+            ..info()
+        };
+        let (topic, payload) = connected(&info, EventReason::Connect);
+        assert_eq!(topic, "$SYS/clients/a_b__/connected");
+        assert!(payload.contains("\"client_id\":\"a/b+#\""));
+    }
+}
@@ -1,33 +1,86 @@
-use crate::server::{ArcSenderPacket, PacketResult};
-use common::all_packets::connack::Connack;
+use crate::capture::{Capture, ConnectionCapture, RecordingReader};
+use crate::listener::ConnectionSlot;
+use crate::proxy_protocol;
+use crate::server::{ArcSenderPacket, ClientAddresses, PacketResult};
+use crate::stream::{ClientStream, DeadlineReader};
+use common::all_packets::connack::{Connack, CONNACK_NOT_AUTHORIZED};
 use common::all_packets::connect::{
-    INCORRECT_PROTOCOL_LEVEL_ERROR_MSG, INCORRECT_PROTOCOL_LEVEL_RETURN_CODE,
+    Connect, INCORRECT_PROTOCOL_LEVEL_ERROR_MSG, INCORRECT_PROTOCOL_LEVEL_RETURN_CODE,
 };
-use common::packet::Packet;
+use common::logging::logger::{log_global, LogMessage};
+use common::packet::{Packet, SOCKET_CLOSED_ERROR_MSG};
+use common::parser::{decode_remaining_length, encode_remaining_length};
 use std::collections::HashMap;
-use std::net::TcpStream;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::sync::mpsc::{Receiver, SendError, Sender};
 use std::sync::{mpsc, Mutex};
 use std::sync::{Arc, RwLock};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const SOCKET_DISCONNECT_ERROR_MSG: &str = "Disconnectiong Socket due to Error";
+pub const CONNECT_TIMEOUT_ERROR_MSG: &str = "CONNECT not received in time";
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 5;
+// [MQTT-3.1.2-24]: una vez y media el keep alive
+const DEFAULT_KEEP_ALIVE_FACTOR: f64 = 1.5;
+
+#[derive(Clone, Debug)]
+pub struct ClientHandlerConfig {
+    // Tiempo que tiene el cliente para mandar el Connect después de abrir la conexión
+    pub connect_timeout: Duration,
+    // Se desconecta al cliente si no manda nada en keep_alive * keep_alive_factor segundos
+    pub keep_alive_factor: f64,
+    // Los dos siguientes dependen del listener por el que entró la conexión.
+    // Tamaño máximo de un paquete en bytes, contando el header (0 = sin límite)
+    pub max_packet_size: usize,
+    // Si es true se exigen credenciales aunque la política de [auth] no lo haga
+    pub require_auth: bool,
+    // Con require_auth, los únicos que pueden entrar sin password (auth.users_without_password)
+    pub users_without_password: Vec<String>,
+    // Si es true el usuario del Connect pasa a ser el UID del proceso que se conectó
+    pub peer_uid_as_username: bool,
+    // Si es true, antes del primer paquete viene un header PROXY con la dirección real
+    pub proxy_protocol: bool,
+    // Si es true el usuario del Connect pasa a ser el CN del certificado del cliente (TLS)
+    pub cert_cn_as_username: bool,
+}
+
+impl Default for ClientHandlerConfig {
+    fn default() -> Self {
+        ClientHandlerConfig {
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            keep_alive_factor: DEFAULT_KEEP_ALIVE_FACTOR,
+            max_packet_size: 0,
+            require_auth: false,
+            users_without_password: vec![],
+            peer_uid_as_username: false,
+            proxy_protocol: false,
+            cert_cn_as_username: false,
+        }
+    }
+}
 
 pub struct ClientHandler {
     id: u32,
-    stream: Option<TcpStream>,
+    stream: Option<Box<dyn ClientStream>>,
     sender: Option<Sender<(u32, PacketResult)>>,
     receiver: Option<Receiver<PacketResult>>,
     reader_to_writer_tx: Sender<PacketResult>,
+    config: ClientHandlerConfig,
+    client_addresses: ClientAddresses,
+    connection_slot: Option<ConnectionSlot>,
+    capture: Option<Arc<Capture>>,
 }
 
 impl ClientHandler {
     pub fn new(
         id: u32,
-        stream: TcpStream,
+        stream: Box<dyn ClientStream>,
         senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
         sender: Sender<(u32, PacketResult)>,
+        config: ClientHandlerConfig,
+        client_addresses: ClientAddresses,
     ) -> ClientHandler {
         let (server_tx, c_h_writer_rx) = mpsc::channel::<PacketResult>();
         let sender_from_c_h_reader_to_c_h_w = server_tx.clone();
@@ -40,37 +93,79 @@ impl ClientHandler {
             sender: Some(sender),
             receiver: Some(c_h_writer_rx),
             reader_to_writer_tx: sender_from_c_h_reader_to_c_h_w,
+            config,
+            client_addresses,
+            connection_slot: None,
+            capture: None,
         }
     }
 
+    // El lugar en el listener se libera cuando terminan el reader y el writer
+    pub fn hold_slot(mut self, slot: ConnectionSlot) -> ClientHandler {
+        self.connection_slot = Some(slot);
+        self
+    }
+
+    // Los paquetes de la conexión se capturan si el client id del Connect está entre los
+    // elegidos
+    pub fn capture(mut self, capture: Arc<Capture>) -> ClientHandler {
+        self.capture = Some(capture);
+        self
+    }
+
     pub fn run(mut self) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
         let stream = self.stream.take().unwrap();
         //stream shutdown
 
         let receiver = self.receiver.take().unwrap();
         let sender = self.sender.take().unwrap();
+        let connection_slot = self.connection_slot.take();
+        let capture = self
+            .capture
+            .take()
+            .map(|capture| Arc::new(ConnectionCapture::new(capture, self.id)));
 
-        let mut client_handler_writer = ClientHandlerWriter::new(stream.try_clone()?, receiver);
-        let mut client_handler_reader =
-            ClientHandlerReader::new(self.id, stream, sender, self.reader_to_writer_tx.clone());
+        let mut client_handler_writer =
+            ClientHandlerWriter::new(stream.try_clone()?, receiver, capture.clone());
+        let mut client_handler_reader = ClientHandlerReader::new(
+            self.id,
+            stream,
+            sender,
+            self.reader_to_writer_tx.clone(),
+            self.config.clone(),
+            self.client_addresses.clone(),
+            capture,
+        );
+        // El reader lo necesita para pasarlo a la IP del header PROXY
+        client_handler_reader.connection_slot = connection_slot;
 
+        let id = self.id;
         let writer_join_handle = thread::spawn(move || {
-            let reader_join_handle = thread::spawn(move || loop {
-                if client_handler_reader.receive_packet().is_err() {
-                    println!("se elimina el reader");
-                    break;
-                }
+            let reader_join_handle = thread::spawn(move || {
+                while client_handler_reader.receive_packet().is_ok() {}
+                log_global(LogMessage::trace(
+                    format!("Client handler {} reader finished", id),
+                    "".to_string(),
+                ));
+                client_handler_reader.connection_slot.take()
             });
 
             loop {
                 if client_handler_writer.send_packet().is_err() {
-                    println!("se elimina el writer");
+                    log_global(LogMessage::trace(
+                        format!("Client handler {} writer finished", id),
+                        "".to_string(),
+                    ));
                     break;
                 }
             }
 
-            reader_join_handle.join().unwrap();
-            println!("client handler {} destroyed", self.id);
+            let connection_slot = reader_join_handle.join().unwrap();
+            drop(connection_slot);
+            log_global(LogMessage::debug(
+                format!("Client handler {} destroyed", id),
+                "".to_string(),
+            ));
         });
 
         Ok(writer_join_handle)
@@ -80,21 +175,39 @@ impl ClientHandler {
 //LEE EN CHANNEL, ESCRIBE EN SOCKET
 struct ClientHandlerWriter {
     //Maneja la conexion del socket
-    socket: TcpStream,
+    socket: Box<dyn ClientStream>,
     receiver: Receiver<PacketResult>, //Por acá recibe los paquetes que escribe en el socket
+    capture: Option<Arc<ConnectionCapture>>,
 }
 
 impl ClientHandlerWriter {
-    pub fn new(socket: TcpStream, receiver: Receiver<PacketResult>) -> ClientHandlerWriter {
-        ClientHandlerWriter { socket, receiver }
+    pub fn new(
+        socket: Box<dyn ClientStream>,
+        receiver: Receiver<PacketResult>,
+        capture: Option<Arc<ConnectionCapture>>,
+    ) -> ClientHandlerWriter {
+        ClientHandlerWriter {
+            socket,
+            receiver,
+            capture,
+        }
     }
 
     pub fn send_packet(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if let Ok(packet) = self.receiver.recv()? {
-            packet.write_to(&mut self.socket)?;
+            // Se arma el paquete entero antes de escribirlo, así cada paquete sale en una sola
+            // escritura (y en un solo frame si el transporte es WebSocket)
+            let mut buffer = vec![];
+            packet.write_to(&mut buffer)?;
+            self.socket.write_all(&buffer)?;
+            self.socket.flush()?;
+            if let Some(capture) = &self.capture {
+                capture.outbound(&buffer);
+            }
             Ok(())
         } else {
-            self.socket.shutdown(std::net::Shutdown::Write).unwrap();
+            // Cerrando ambas mitades también se destraba el reader, que está bloqueado leyendo
+            let _ = self.socket.shutdown();
             Err("No se pudo enviar el packet".into())
         }
     }
@@ -103,60 +216,186 @@ impl ClientHandlerWriter {
 //LEE DE SOCKET, ESCRIBE EN CHANNEL
 struct ClientHandlerReader {
     id: u32,
-    socket: TcpStream,
+    socket: Box<dyn ClientStream>,
     sender: Sender<(u32, PacketResult)>, //Por acá manda paquetes al sv
     already_connected: bool,
     reader_to_writer_tx: Sender<PacketResult>,
+    config: ClientHandlerConfig,
+    client_addresses: ClientAddresses,
+    proxy_header_pending: bool,
+    // Hasta cuándo tiene el cliente para mandar el Connect completo
+    connect_deadline: Instant,
+    capture: Option<Arc<ConnectionCapture>>,
+    connection_slot: Option<ConnectionSlot>,
 }
 
 impl ClientHandlerReader {
     pub fn new(
         id: u32,
-        socket: TcpStream,
+        socket: Box<dyn ClientStream>,
         sender: Sender<(u32, PacketResult)>,
         reader_to_writer_tx: Sender<PacketResult>,
+        config: ClientHandlerConfig,
+        client_addresses: ClientAddresses,
+        capture: Option<Arc<ConnectionCapture>>,
     ) -> ClientHandlerReader {
-        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
         ClientHandlerReader {
             id,
             socket,
             sender,
             already_connected: false,
             reader_to_writer_tx,
+            proxy_header_pending: config.proxy_protocol,
+            connect_deadline: Instant::now() + config.connect_timeout,
+            config,
+            client_addresses,
+            capture,
+            connection_slot: None,
+        }
+    }
+
+    // La dirección del header reemplaza a la del balanceador, así los bans, los fallos de
+    // autenticación y los logs usan la del cliente real
+    fn read_proxy_header(&mut self) -> Result<Option<SocketAddr>, Box<dyn std::error::Error>> {
+        self.proxy_header_pending = false;
+        let mut reader = DeadlineReader::new(self.socket.as_mut(), self.connect_deadline);
+        let address = proxy_protocol::read_header(&mut reader)?;
+        let mut client_addresses = self.client_addresses.write().unwrap();
+        match address {
+            Some(address) => {
+                if let Some(proxy_address) = client_addresses.insert(self.id, address) {
+                    log_global(LogMessage::new(
+                        format!(
+                            "Client {} connected through proxy {}",
+                            address, proxy_address
+                        ),
+                        "".to_string(),
+                    ));
+                }
+            }
+            // Sin dirección real los fallos no se le cuentan al balanceador, que si no
+            // terminaría bloqueado para todos los clientes
+            None => {
+                client_addresses.remove(&self.id);
+            }
+        }
+        Ok(address)
+    }
+
+    // Los límites por IP se aplican a la del cliente real, no a la del balanceador
+    fn admit_proxied_client(&mut self, address: SocketAddr) -> bool {
+        let slot = match &mut self.connection_slot {
+            Some(slot) => slot,
+            None => return true,
+        };
+        match slot.assign_ip(address.ip()) {
+            Ok(()) => true,
+            Err(rejection) => {
+                log_global(LogMessage::warn(
+                    format!("Connection from {} refused: {}", address, rejection),
+                    "".to_string(),
+                ));
+                false
+            }
+        }
+    }
+
+    // Hasta que llega el Connect, las lecturas tienen que terminar antes del plazo
+    fn read_packet(&mut self) -> Result<Packet, Box<dyn std::error::Error>> {
+        let max_packet_size = self.config.max_packet_size;
+        let mut deadline_reader;
+        let stream: &mut dyn Read = if self.already_connected {
+            self.socket.as_mut()
+        } else {
+            deadline_reader = DeadlineReader::new(self.socket.as_mut(), self.connect_deadline);
+            &mut deadline_reader
+        };
+        match &self.capture {
+            None => read_limited_packet(stream, max_packet_size),
+            // Se capturan los bytes leídos aunque no formen un paquete válido
+            Some(capture) => {
+                let mut recorder = RecordingReader::new(stream);
+                let result = read_limited_packet(&mut recorder, max_packet_size);
+                capture.inbound(&recorder.bytes, result.as_ref().ok());
+                result
+            }
         }
     }
 
     pub fn receive_packet(&mut self) -> Result<(), Box<dyn std::error::Error + Send>> {
-        match Packet::read_from(&mut self.socket) {
-            Ok(packet) => {
+        if self.proxy_header_pending {
+            match self.read_proxy_header() {
+                Ok(Some(address)) if !self.admit_proxied_client(address) => {
+                    return Err(self.disconnect());
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    log_global(LogMessage::warn(
+                        format!("Invalid PROXY protocol header: {}", error),
+                        "".to_string(),
+                    ));
+                    return Err(self.disconnect_before_connect());
+                }
+            }
+        }
+        match self.read_packet() {
+            Ok(mut packet) => {
                 // [MQTT-3.1.0-2]: Si es un Connect y ya había recibido un Connect antes, es un PROTOCOL VIOLATION: desconecto al client
-                if let Packet::Connect(connect) = &packet {
+                if let Packet::Connect(connect) = &mut packet {
                     if self.already_connected {
-                        println!("PROTOCOL VIOLATION: Connect packet received twice");
-                        return Err(Box::new(SendError(
-                            "PROTOCOL VIOLATION: Connect packet received twice",
-                        )));
+                        log_global(LogMessage::warn(
+                            "PROTOCOL VIOLATION: Connect packet received twice".to_string(),
+                            "".to_string(),
+                        ));
+                        return Err(self.disconnect());
+                    }
+
+                    // La identidad la da el sistema operativo, no lo que mande el cliente. Sin
+                    // password, el Authenticator lo trata como un usuario sin password
+                    if self.config.peer_uid_as_username {
+                        match self.socket.peer_uid() {
+                            Some(uid) => {
+                                connect.connect_payload.username = Some(uid.to_string());
+                                connect.connect_payload.password = None;
+                            }
+                            None => return Err(self.refuse_connect()),
+                        }
+                    }
+                    if self.config.cert_cn_as_username {
+                        match self.socket.peer_certificate_cn() {
+                            Some(common_name) => {
+                                connect.connect_payload.username = Some(common_name);
+                                connect.connect_payload.password = None;
+                            }
+                            None => return Err(self.refuse_connect()),
+                        }
                     }
 
-                    //If the Keep Alive value is non-zero and the Server does not receive a Control Packet from the Client
-                    //within one and a half times the Keep Alive time period, it MUST disconnect the Network Connection to the Client as if the network had failed
-                    let mut keep_alive = connect.keep_alive_seconds as u64;
-                    if keep_alive != 0 {
-                        keep_alive *= 2;
-                        self.socket
-                            .set_read_timeout(Some(Duration::new(keep_alive, 0)))
-                            .unwrap();
-                    } else {
-                        self.socket.set_read_timeout(None).unwrap();
+                    // El listener exige credenciales: se contesta como lo haría el Authenticator
+                    // con require_credentials
+                    if self.config.require_auth && !self.has_credentials(connect) {
+                        log_global(LogMessage::warn(
+                            "Connect without credentials refused by the listener".to_string(),
+                            "".to_string(),
+                        ));
+                        return Err(self.refuse_connect());
                     }
+
+                    // El keep alive lo controla el PacketProcessor (KeepAliveTracker), que cierra
+                    // la conexión y publica el will. Desde acá ya no hay plazo para leer
+                    self.socket.set_read_timeout(None).unwrap();
+                    self.already_connected = true;
                 }
                 if let Err(error) = self.sender.send((self.id, Ok(packet))) {
                     return Err(Box::new(error));
                 }
             }
             Err(error) => {
+                log_global(LogMessage::debug(
+                    format!("Client handler {} read failed: {}", self.id, error),
+                    "".to_string(),
+                ));
                 if error.to_string() == INCORRECT_PROTOCOL_LEVEL_ERROR_MSG {
-                    println!("{}", error.to_string());
                     // [MQTT-3.1.2-2]. Enviamos un connack con 0x1 y desconectamos.
                     // [MQTT-3.2.2-4]. Por eso session_present = false
                     let connack = Connack::new(false, INCORRECT_PROTOCOL_LEVEL_RETURN_CODE);
@@ -167,16 +406,77 @@ impl ClientHandlerReader {
                     std::thread::sleep(std::time::Duration::from_millis(1000));
                 }
 
-                self.sender
-                    .send((
-                        self.id,
-                        Err(Box::new(SendError(SOCKET_DISCONNECT_ERROR_MSG))),
-                    ))
-                    .unwrap();
-                return Err(Box::new(SendError(SOCKET_DISCONNECT_ERROR_MSG)));
+                return Err(self.disconnect_before_connect());
             }
         }
 
         Ok(())
     }
+
+    fn has_credentials(&self, connect: &Connect) -> bool {
+        let payload = &connect.connect_payload;
+        match (&payload.username, &payload.password) {
+            (None, _) => false,
+            (Some(_), Some(_)) => true,
+            (Some(username), None) => self
+                .config
+                .users_without_password
+                .iter()
+                .any(|user| user == username),
+        }
+    }
+
+    // Contesta el Connect con "not authorized" y corta la conexión
+    fn refuse_connect(&self) -> Box<dyn std::error::Error + Send> {
+        let connack = Connack::new(false, CONNACK_NOT_AUTHORIZED);
+        let _ = self.reader_to_writer_tx.send(Ok(Packet::Connack(connack)));
+        self.disconnect()
+    }
+
+    // Si se venció el plazo del Connect, el PacketProcessor se entera del motivo para contarlo
+    fn disconnect_before_connect(&self) -> Box<dyn std::error::Error + Send> {
+        if self.already_connected || Instant::now() < self.connect_deadline {
+            return self.disconnect();
+        }
+        let error: Box<dyn std::error::Error + Send + Sync> = CONNECT_TIMEOUT_ERROR_MSG.into();
+        let _ = self.sender.send((self.id, Err(error)));
+        Box::new(SendError(SOCKET_DISCONNECT_ERROR_MSG))
+    }
+
+    // Avisa al PacketProcessor que se cae la conexión, que a su vez cierra el writer
+    fn disconnect(&self) -> Box<dyn std::error::Error + Send> {
+        // Si el server se está apagando, el PacketProcessor puede ya no estar escuchando
+        let _ = self.sender.send((
+            self.id,
+            Err(Box::new(SendError(SOCKET_DISCONNECT_ERROR_MSG))),
+        ));
+        Box::new(SendError(SOCKET_DISCONNECT_ERROR_MSG))
+    }
+}
+
+// Lee un paquete, rechazando los que superan max_packet_size antes de leer el contenido
+fn read_limited_packet(
+    stream: &mut dyn Read,
+    max_packet_size: usize,
+) -> Result<Packet, Box<dyn std::error::Error>> {
+    if max_packet_size == 0 {
+        return Packet::read_from(stream);
+    }
+
+    let mut identifier_byte = [0u8; 1];
+    if stream.read(&mut identifier_byte)? == 0 {
+        return Err(SOCKET_CLOSED_ERROR_MSG.into());
+    }
+    let remaining_length = decode_remaining_length(stream)?;
+    let mut fixed_header = identifier_byte.to_vec();
+    fixed_header.extend(encode_remaining_length(remaining_length));
+    let packet_size = fixed_header.len() + remaining_length as usize;
+    if packet_size > max_packet_size {
+        return Err(format!(
+            "Packet of {} bytes exceeds the maximum of {}",
+            packet_size, max_packet_size
+        )
+        .into());
+    }
+    Packet::read_from(&mut fixed_header.as_slice().chain(stream))
 }
@@ -1,64 +1,1187 @@
-use std::{
-    env,
-    fs::File,
-    io::{BufRead, BufReader},
-};
+use crate::admin::AdminConfig;
+use crate::audit::AuditConfig;
+use crate::auth_limiter::AuthLimiterConfig;
+use crate::authenticator::AuthPolicy;
+use crate::capture::CaptureConfig;
+use crate::client_handler::ClientHandlerConfig;
+use crate::jwt::JwtConfig;
+use crate::listener::{self, ConnectionLimits, ListenerConfig, Transport};
+use crate::metrics::MetricsConfig;
+use crate::topic_filters;
+use crate::websocket::{WebSocketConfig, DEFAULT_WEBSOCKET_PATH};
+use common::logging::level::LevelFilter;
+use common::logging::logger::LoggerConfig;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
 
 const DEFAULT_LOGFILE: &str = "logfile.txt";
+const DEFAULT_ACCOUNTS_FILE: &str = "accounts.txt";
 const DEFAULT_PORT: u16 = 8080;
-const DEFAULT_ADDRESS: &str = "0.0.0.0:";
+const DEFAULT_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_PACKET_IDS: u16 = 100;
+const DEFAULT_RELOAD_WATCH_SECONDS: u64 = 5;
+const DEFAULT_SYS_INTERVAL_SECONDS: u64 = 10;
+const CHECK_CONFIG_FLAG: &str = "--check-config";
+const ENV_PREFIX: &str = "MQTT";
+const LISTENER_SECTION_PREFIX: &str = "listener.";
 
+// Todas las claves que acepta el archivo de configuración, agrupadas por sección.
+// Cada una puede pisarse con la variable de entorno MQTT_<SECCION>_<CLAVE>
+const KNOWN_KEYS: &[(&str, &str)] = &[
+    ("server", "address"),
+    ("server", "port"),
+    ("server", "packet_ids"),
+    ("log", "file"),
+    ("log", "level"),
+    ("log", "modules"),
+    ("log", "format"),
+    ("log", "stdout"),
+    ("log", "rotation"),
+    ("log", "max_size_mb"),
+    ("log", "retention"),
+    ("connection", "connect_timeout_seconds"),
+    ("connection", "keep_alive_factor"),
+    ("connection", "max_connections"),
+    ("connection", "max_connections_per_ip"),
+    ("auth", "accounts_file"),
+    ("auth", "allow_anonymous"),
+    ("auth", "require_credentials"),
+    ("auth", "users_without_password"),
+    ("jwt", "hs256_secret_file"),
+    ("jwt", "rs256_public_key_file"),
+    ("auth_limiter", "max_failures"),
+    ("auth_limiter", "ban_seconds"),
+    ("auth_limiter", "max_ban_seconds"),
+    ("reload", "watch_interval_seconds"),
+    ("persistence", "file"),
+    ("websocket", "enabled"),
+    ("websocket", "port"),
+    ("websocket", "path"),
+    ("sys", "interval_seconds"),
+    ("metrics", "enabled"),
+    ("metrics", "address"),
+    ("metrics", "port"),
+    ("admin", "enabled"),
+    ("admin", "address"),
+    ("admin", "port"),
+    ("admin", "token_file"),
+    ("audit", "enabled"),
+    ("audit", "file"),
+    ("audit", "topics"),
+    ("audit", "hash_payload"),
+    ("audit", "rotation"),
+    ("audit", "max_size_mb"),
+    ("audit", "retention"),
+    ("capture", "enabled"),
+    ("capture", "file"),
+    ("capture", "clients"),
+];
+
+// Claves de cada sección [listener.<nombre>]. Se pisan con MQTT_LISTENER_<NOMBRE>_<CLAVE>
+const LISTENER_KEYS: &[&str] = &[
+    "type",
+    "address",
+    "port",
+    "path",
+    "permissions",
+    "peer_uid_as_username",
+    "proxy_protocol",
+    "max_connections",
+    "require_auth",
+    "max_packet_size",
+    "tls_cert_file",
+    "tls_key_file",
+    "tls_client_ca_file",
+    "cert_cn_as_username",
+];
+
+#[derive(Clone)]
 pub struct Config {
-    pub port: u16,
+    // Archivo del que se leyó la configuración, para poder volver a leerlo al recargar
+    pub file_path: Option<String>,
     pub address: String,
-    pub log_filename: String,
+    pub port: u16,
+    pub packet_ids: u16,
+    // Archivo, niveles (general y por módulo), formato y rotación del log
+    pub log: LoggerConfig,
+    // Sin archivo no hay cuentas: solo entran los anónimos o los tokens, según la política
+    pub accounts_filename: Option<String>,
+    pub connection: ClientHandlerConfig,
+    pub connection_limits: ConnectionLimits,
+    pub auth_policy: AuthPolicy,
+    pub jwt: Option<JwtConfig>,
+    pub auth_limiter: AuthLimiterConfig,
+    // Cada cuántos segundos se revisa si cambiaron los archivos vigilados. Con 0 solo se
+    // recarga al recibir SIGHUP
+    pub reload_watch_seconds: u64,
+    // Archivo donde se guardan los retenidos y las sesiones persistentes al apagar el server
+    pub persistence_file: Option<String>,
+    pub websocket: WebSocketConfig,
+    // Cada cuántos segundos se publica el árbol $SYS/broker/... (0 = no se publica)
+    pub sys_interval_seconds: u64,
+    // Listener HTTP con /metrics para Prometheus
+    pub metrics: MetricsConfig,
+    // API JSON de administración, protegida con un token
+    pub admin: AdminConfig,
+    // Registro de auditoría de publicaciones, entregas y Pubacks
+    pub audit: AuditConfig,
+    // Captura de los bytes que mandan y reciben algunos clientes, para reproducirlos después
+    pub capture: CaptureConfig,
+    // Listeners declarados con secciones [listener.<nombre>]. Si hay alguno, reemplazan al
+    // de [server] y al de [websocket]
+    pub listeners: Vec<ListenerConfig>,
+    // Si se pasó --check-config, solo hay que mostrar la configuración y salir
+    pub check_only: bool,
+    // Dónde apareció cada sección y cada clave del archivo
+    pub(crate) key_lines: KeyLines,
+}
+
+// Línea del archivo en la que aparece cada clave, para que los errores que involucran a más
+// de una clave digan dónde mirar igual que los de una sola
+#[derive(Clone, Debug, Default)]
+pub(crate) struct KeyLines {
+    file_name: String,
+    // (sección, clave, línea). Los headers de sección se guardan con la clave vacía
+    lines: Vec<(String, String, usize)>,
+}
+
+impl KeyLines {
+    fn add(&mut self, section: &str, key: &str, line_number: usize) {
+        self.lines
+            .push((section.to_string(), key.to_string(), line_number));
+    }
+
+    // Antepone archivo y línea de la última de las claves que esté en el archivo. Si ninguna
+    // está (valores por defecto, variables de entorno o ServerBuilder) el mensaje queda igual
+    fn locate(&self, keys: &[(&str, &str)], message: String) -> String {
+        let line = self
+            .lines
+            .iter()
+            .filter(|(section, key, _)| keys.iter().any(|(s, k)| s == section && k == key))
+            .map(|(_, _, line)| *line)
+            .max();
+        match line {
+            Some(line) => format!("{}:{}: {}", self.file_name, line, message),
+            None => message,
+        }
+    }
+
+    fn locate_section(&self, section: &str, message: String) -> String {
+        self.locate(&[(section, "")], message)
+    }
 }
 
 impl Config {
-    pub fn new(mut args: env::Args) -> Result<Config, Box<dyn std::error::Error>> {
-        args.next(); //args[0] no nos interesa, es el nombre del programa
-
-        if let Some(config_file_path) = args.next() {
-            let config_file = File::open(config_file_path)?;
-            let reader = BufReader::new(config_file);
-            let mut lines = reader.lines();
-
-            let port = match lines.next() {
-                Some(Ok(arg)) => match str::parse::<u16>(&arg) {
-                    Ok(port) => port,
-                    Err(_) => return Err("El puerto no es valido".into()),
-                },
-                Some(Err(_)) => return Err("El puerto no es valido".into()),
-                None => DEFAULT_PORT,
-            };
+    pub fn new(args: env::Args) -> Result<Config, Box<dyn std::error::Error>> {
+        let mut config_file_path = None;
+        let mut check_only = false;
+        //args[0] no nos interesa, es el nombre del programa
+        for arg in args.skip(1) {
+            if arg == CHECK_CONFIG_FLAG {
+                check_only = true;
+            } else if config_file_path.is_none() {
+                config_file_path = Some(arg);
+            } else {
+                return Err(format!("Parametro desconocido: {}", arg).into());
+            }
+        }
 
-            let log_filename = match lines.next() {
-                Some(Ok(arg)) => arg,
-                Some(Err(_)) => return Err("El logfile no es valido".into()),
-                None => DEFAULT_LOGFILE.to_string(),
+        let mut config = Config::load(config_file_path)?;
+        config.check_only = check_only;
+
+        Ok(config)
+    }
+
+    // Lee el archivo (si hay) y aplica las variables de entorno. Se usa al arrancar y al recargar
+    pub fn load(file_path: Option<String>) -> Result<Config, Box<dyn std::error::Error>> {
+        let mut config = match &file_path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("No se pudo leer {}: {}", path, e))?;
+                Config::from_str_with_name(&contents, path)?
+            }
+            None => Config::default(),
+        };
+        config.apply_env_overrides(env::vars())?;
+        config.validate()?;
+        config.file_path = file_path;
+
+        Ok(config)
+    }
+
+    // Parsea un archivo con secciones [seccion] y líneas clave = valor.
+    // Las líneas vacías y las que empiezan con # se ignoran
+    pub fn from_str_with_name(contents: &str, file_name: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        config.key_lines.file_name = file_name.to_string();
+        let mut section: Option<String> = None;
+        let mut seen_keys: Vec<(String, String)> = vec![];
+
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let line_error =
+                |message: String| format!("{}:{}: {}", file_name, line_number, message);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') || line.len() < 3 {
+                    return Err(line_error(format!("seccion invalida '{}'", line)));
+                }
+                let name = line[1..line.len() - 1].trim().to_string();
+                if let Some(listener_name) = name.strip_prefix(LISTENER_SECTION_PREFIX) {
+                    config.add_listener(listener_name).map_err(line_error)?;
+                } else if !KNOWN_KEYS.iter().any(|(s, _)| *s == name) {
+                    return Err(line_error(format!("seccion desconocida '{}'", name)));
+                }
+                config.key_lines.add(&name, "", line_number);
+                section = Some(name);
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), unquote(value.trim())),
+                None => return Err(line_error(format!("se esperaba clave = valor: '{}'", line))),
+            };
+            let section = match &section {
+                Some(section) => section,
+                None => return Err(line_error(format!("clave '{}' fuera de una seccion", key))),
             };
+            if seen_keys.contains(&(section.clone(), key.to_string())) {
+                return Err(line_error(format!("clave repetida {}.{}", section, key)));
+            }
+            seen_keys.push((section.clone(), key.to_string()));
+            config.key_lines.add(section, key, line_number);
 
-            return Ok(Config {
-                port,
-                address: DEFAULT_ADDRESS.to_string(),
-                log_filename,
-            });
+            config.set(section, key, value).map_err(line_error)?;
         }
 
-        Ok(Config {
-            port: DEFAULT_PORT,
-            address: DEFAULT_ADDRESS.to_string(),
-            log_filename: DEFAULT_LOGFILE.to_string(),
-        })
+        check_listener_sections(&config, &seen_keys)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env_overrides(
+        &mut self,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> Result<(), String> {
+        let vars: Vec<(String, String)> = vars.collect();
+        for (section, key) in KNOWN_KEYS {
+            let var_name = env_var_name(section, key);
+            if let Some((_, value)) = vars.iter().find(|(name, _)| *name == var_name) {
+                self.set(section, key, unquote(value.trim()))
+                    .map_err(|message| format!("{}: {}", var_name, message))?;
+            }
+        }
+        let listener_sections: Vec<String> = self
+            .listeners
+            .iter()
+            .map(|listener| format!("{}{}", LISTENER_SECTION_PREFIX, listener.name))
+            .collect();
+        for section in listener_sections {
+            for key in LISTENER_KEYS {
+                let var_name = env_var_name(&section, key);
+                if let Some((_, value)) = vars.iter().find(|(name, _)| *name == var_name) {
+                    self.set(&section, key, unquote(value.trim()))
+                        .map_err(|message| format!("{}: {}", var_name, message))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_listener(&mut self, name: &str) -> Result<(), String> {
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_name {
+            return Err(format!(
+                "nombre de listener invalido '{}' (solo a-z, 0-9 y _)",
+                name
+            ));
+        }
+        if !self.listeners.iter().any(|listener| listener.name == name) {
+            self.listeners
+                .push(ListenerConfig::tcp(name, DEFAULT_ADDRESS, DEFAULT_PORT));
+        }
+        Ok(())
+    }
+
+    fn set_listener(&mut self, name: &str, key: &str, value: &str) -> Result<(), String> {
+        let listener = match self.listeners.iter_mut().find(|l| l.name == name) {
+            Some(listener) => listener,
+            None => return Err(format!("listener desconocido '{}'", name)),
+        };
+        match key {
+            "type" => {
+                // El path ya leído se conserva si cambia el tipo
+                let path = match &listener.transport {
+                    Transport::Tcp => None,
+                    Transport::WebSocket(path) | Transport::Unix(path) => Some(path.clone()),
+                };
+                listener.transport = match value {
+                    "tcp" => Transport::Tcp,
+                    "websocket" => Transport::WebSocket(
+                        path.unwrap_or_else(|| DEFAULT_WEBSOCKET_PATH.to_string()),
+                    ),
+                    "unix" => {
+                        // Un socket Unix no tiene dirección IP
+                        listener.address = String::new();
+                        listener.port = 0;
+                        Transport::Unix(path.unwrap_or_default())
+                    }
+                    _ => {
+                        return Err(format!(
+                            "type tiene que ser tcp, websocket o unix: '{}'",
+                            value
+                        ))
+                    }
+                }
+            }
+            "address" => listener.address = non_empty(key, value)?,
+            "port" => listener.port = parse_value(key, value)?,
+            // Se valida en validate, cuando ya se sabe el tipo
+            "path" => {
+                listener.transport = match &listener.transport {
+                    Transport::Unix(_) => Transport::Unix(value.to_string()),
+                    _ => Transport::WebSocket(value.to_string()),
+                }
+            }
+            "permissions" => {
+                let permissions = u32::from_str_radix(value, 8)
+                    .map_err(|_| format!("permissions tiene que ser octal: '{}'", value))?;
+                if permissions > 0o777 {
+                    return Err(format!("permissions invalido: '{}'", value));
+                }
+                listener.permissions = Some(permissions);
+            }
+            "peer_uid_as_username" => listener.peer_uid_as_username = parse_value(key, value)?,
+            "proxy_protocol" => listener.proxy_protocol = parse_value(key, value)?,
+            "max_connections" => listener.max_connections = parse_value(key, value)?,
+            "require_auth" => listener.require_auth = parse_value(key, value)?,
+            "max_packet_size" => listener.max_packet_size = parse_value(key, value)?,
+            "tls_cert_file" => listener.tls_cert_file = Some(non_empty(key, value)?),
+            "tls_key_file" => listener.tls_key_file = Some(non_empty(key, value)?),
+            "tls_client_ca_file" => listener.tls_client_ca_file = Some(non_empty(key, value)?),
+            "cert_cn_as_username" => listener.cert_cn_as_username = parse_value(key, value)?,
+            _ => {
+                return Err(format!(
+                    "clave desconocida {}{}.{}",
+                    LISTENER_SECTION_PREFIX, name, key
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Los listeners en los que escucha el server: los declarados o, si no hay ninguno, el de
+    // [server] más el de [websocket] si está habilitado
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        let mut listeners = vec![ListenerConfig::default_tcp(&self.address, self.port)];
+        if self.websocket.enabled {
+            listeners.push(ListenerConfig::default_websocket(
+                &self.address,
+                self.websocket.port,
+                &self.websocket.path,
+            ));
+        }
+        listeners
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), String> {
+        if let Some(name) = section.strip_prefix(LISTENER_SECTION_PREFIX) {
+            return self.set_listener(name, key, value);
+        }
+        match (section, key) {
+            ("server", "address") => self.address = value.to_string(),
+            ("server", "port") => self.port = parse_value(key, value)?,
+            ("server", "packet_ids") => {
+                self.packet_ids = parse_value(key, value)?;
+                if self.packet_ids == 0 {
+                    return Err("packet_ids tiene que ser mayor a 0".to_string());
+                }
+            }
+            ("log", "file") => self.log.file = non_empty(key, value)?,
+            ("log", "level") => self.log.levels.default = parse_value(key, value)?,
+            ("log", "modules") => self.log.levels.modules = LevelFilter::parse_modules(value)?,
+            ("log", "format") => self.log.format = parse_value(key, value)?,
+            ("log", "stdout") => self.log.stdout = parse_value(key, value)?,
+            ("log", "rotation") => self.log.rotation.rotation = parse_value(key, value)?,
+            ("log", "max_size_mb") => {
+                self.log.rotation.max_size_bytes = parse_megabytes(key, value)?
+            }
+            ("log", "retention") => self.log.rotation.retention = parse_value(key, value)?,
+            ("connection", "connect_timeout_seconds") => {
+                let seconds: u64 = parse_value(key, value)?;
+                if seconds == 0 {
+                    return Err("connect_timeout_seconds tiene que ser mayor a 0".to_string());
+                }
+                self.connection.connect_timeout = Duration::from_secs(seconds);
+            }
+            ("connection", "keep_alive_factor") => {
+                let factor: f64 = parse_value(key, value)?;
+                if !(factor >= 1.0 && factor.is_finite()) {
+                    return Err("keep_alive_factor tiene que ser al menos 1".to_string());
+                }
+                self.connection.keep_alive_factor = factor;
+            }
+            ("connection", "max_connections") => {
+                self.connection_limits.max_connections = parse_value(key, value)?
+            }
+            ("connection", "max_connections_per_ip") => {
+                self.connection_limits.max_connections_per_ip = parse_value(key, value)?
+            }
+            ("auth", "accounts_file") => self.accounts_filename = Some(non_empty(key, value)?),
+            ("auth", "allow_anonymous") => {
+                self.auth_policy.allow_anonymous = parse_value(key, value)?
+            }
+            ("auth", "require_credentials") => {
+                self.auth_policy.require_credentials = parse_value(key, value)?
+            }
+            ("auth", "users_without_password") => {
+                self.auth_policy.users_without_password = parse_list(value)
+            }
+            ("jwt", "hs256_secret_file") => {
+                self.jwt
+                    .get_or_insert_with(JwtConfig::default)
+                    .hs256_secret_file = optional(value);
+            }
+            ("jwt", "rs256_public_key_file") => {
+                self.jwt
+                    .get_or_insert_with(JwtConfig::default)
+                    .rs256_public_key_file = optional(value);
+            }
+            ("auth_limiter", "max_failures") => {
+                self.auth_limiter.max_failures = parse_value(key, value)?
+            }
+            ("auth_limiter", "ban_seconds") => {
+                self.auth_limiter.ban_seconds = parse_value(key, value)?
+            }
+            ("auth_limiter", "max_ban_seconds") => {
+                self.auth_limiter.max_ban_seconds = parse_value(key, value)?
+            }
+            ("reload", "watch_interval_seconds") => {
+                self.reload_watch_seconds = parse_value(key, value)?
+            }
+            ("persistence", "file") => self.persistence_file = optional(value),
+            ("websocket", "enabled") => self.websocket.enabled = parse_value(key, value)?,
+            ("websocket", "port") => self.websocket.port = parse_value(key, value)?,
+            ("websocket", "path") => {
+                if !value.starts_with('/') {
+                    return Err("path tiene que empezar con /".to_string());
+                }
+                self.websocket.path = value.to_string();
+            }
+            ("sys", "interval_seconds") => self.sys_interval_seconds = parse_value(key, value)?,
+            ("metrics", "enabled") => self.metrics.enabled = parse_value(key, value)?,
+            ("metrics", "address") => self.metrics.address = non_empty(key, value)?,
+            ("metrics", "port") => self.metrics.port = parse_value(key, value)?,
+            ("admin", "enabled") => self.admin.enabled = parse_value(key, value)?,
+            ("admin", "address") => self.admin.address = non_empty(key, value)?,
+            ("admin", "port") => self.admin.port = parse_value(key, value)?,
+            ("admin", "token_file") => self.admin.token_file = optional(value),
+            ("audit", "enabled") => self.audit.enabled = parse_value(key, value)?,
+            ("audit", "file") => self.audit.file = non_empty(key, value)?,
+            ("audit", "topics") => self.audit.topics = parse_list(value),
+            ("audit", "hash_payload") => self.audit.hash_payload = parse_value(key, value)?,
+            ("audit", "rotation") => self.audit.rotation.rotation = parse_value(key, value)?,
+            ("audit", "max_size_mb") => {
+                self.audit.rotation.max_size_bytes = parse_megabytes(key, value)?
+            }
+            ("audit", "retention") => self.audit.rotation.retention = parse_value(key, value)?,
+            ("capture", "enabled") => self.capture.enabled = parse_value(key, value)?,
+            ("capture", "file") => self.capture.file = non_empty(key, value)?,
+            ("capture", "clients") => self.capture.clients = parse_list(value),
+            _ => return Err(format!("clave desconocida {}.{}", section, key)),
+        }
+        Ok(())
+    }
+
+    // Validaciones que dependen de más de una clave
+    pub(crate) fn validate(&mut self) -> Result<(), String> {
+        if let Some(jwt) = &self.jwt {
+            if jwt.hs256_secret_file.is_none() && jwt.rs256_public_key_file.is_none() {
+                self.jwt = None;
+            }
+        }
+        let key_lines = &self.key_lines;
+        if self.auth_limiter.max_ban_seconds < self.auth_limiter.ban_seconds {
+            return Err(key_lines.locate(
+                &[
+                    ("auth_limiter", "max_ban_seconds"),
+                    ("auth_limiter", "ban_seconds"),
+                ],
+                "auth_limiter.max_ban_seconds no puede ser menor que ban_seconds".to_string(),
+            ));
+        }
+        if self.admin.enabled && self.admin.token_file.is_none() {
+            return Err(key_lines.locate(
+                &[("admin", "enabled")],
+                "admin.token_file es obligatorio si admin.enabled = true".to_string(),
+            ));
+        }
+        if self.audit.topics.is_empty() {
+            return Err(key_lines.locate(
+                &[("audit", "topics")],
+                "audit.topics no puede estar vacio".to_string(),
+            ));
+        }
+        if let Some(filter) = self
+            .audit
+            .topics
+            .iter()
+            .find(|filter| !topic_filters::topic_filter_is_valid(filter))
+        {
+            return Err(key_lines.locate(
+                &[("audit", "topics")],
+                format!("audit.topics tiene un filtro invalido '{}'", filter),
+            ));
+        }
+        if self.capture.enabled && self.capture.clients.is_empty() {
+            return Err(key_lines.locate(
+                &[("capture", "enabled"), ("capture", "clients")],
+                "capture.clients no puede estar vacio".to_string(),
+            ));
+        }
+        if !self.listeners.is_empty() && self.websocket.enabled {
+            return Err(key_lines.locate(
+                &[("websocket", "enabled")],
+                "no se puede habilitar [websocket] si hay secciones [listener.*], declarar un listener con type = websocket"
+                    .to_string(),
+            ));
+        }
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let section = format!("{}{}", LISTENER_SECTION_PREFIX, listener.name);
+            if self.listeners[..index]
+                .iter()
+                .any(|other| other.name == listener.name)
+            {
+                return Err(key_lines
+                    .locate_section(&section, format!("listener repetido '{}'", listener.name)));
+            }
+            listener
+                .validate()
+                .map_err(|message| key_lines.locate_section(&section, message))?;
+        }
+        Ok(())
+    }
+
+    // Dirección de [server], con formato ip:puerto
+    pub fn listen_address(&self) -> String {
+        listener::bind_address(&self.address, self.port)
+    }
+}
+
+fn env_var_name(section: &str, key: &str) -> String {
+    format!("{}_{}_{}", ENV_PREFIX, section, key)
+        .replace('.', "_")
+        .to_uppercase()
+}
+
+// Cada sección [listener.*] tiene que decir su puerto (o su path si es unix), y path solo tiene
+// sentido en websocket y unix
+fn check_listener_sections(config: &Config, seen_keys: &[(String, String)]) -> Result<(), String> {
+    for listener in &config.listeners {
+        let section = format!("{}{}", LISTENER_SECTION_PREFIX, listener.name);
+        let has_key = |key: &str| seen_keys.contains(&(section.clone(), key.to_string()));
+        let required = match listener.transport {
+            Transport::Unix(_) => "path",
+            _ => "port",
+        };
+        if !has_key(required) {
+            return Err(config
+                .key_lines
+                .locate_section(&section, format!("falta {}.{}", section, required)));
+        }
+        if has_key("path") && listener.transport == Transport::Tcp {
+            return Err(config.key_lines.locate(
+                &[(&section, "path"), (&section, "type")],
+                format!("{}.path solo vale con type = websocket o unix", section),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("el valor de {} no es valido: '{}'", key, value))
+}
+
+fn parse_megabytes(key: &str, value: &str) -> Result<u64, String> {
+    let megabytes: u64 = parse_value(key, value)?;
+    if megabytes == 0 {
+        return Err(format!("{} tiene que ser mayor a 0", key));
+    }
+    Ok(megabytes * 1024 * 1024)
+}
+
+fn non_empty(key: &str, value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Err(format!("{} no puede estar vacio", key));
+    }
+    Ok(value.to_string())
+}
+
+fn optional(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+// Acepta tanto "a, b" como ["a", "b"]
+fn parse_list(value: &str) -> Vec<String> {
+    let value = value.trim_start_matches('[').trim_end_matches(']');
+    value
+        .split(',')
+        .map(|item| unquote(item.trim()).to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn unquote(value: &str) -> &str {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+// Muestra la configuración resuelta con el mismo formato que el archivo
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let jwt = self.jwt.clone().unwrap_or_default();
+        let sections: Vec<(&str, Vec<(&str, String)>)> = vec![
+            (
+                "server",
+                vec![
+                    ("address", self.address.clone()),
+                    ("port", self.port.to_string()),
+                    ("packet_ids", self.packet_ids.to_string()),
+                ],
+            ),
+            (
+                "log",
+                vec![
+                    ("file", self.log.file.clone()),
+                    ("level", self.log.levels.default.to_string()),
+                    ("modules", self.log.levels.modules_to_string()),
+                    ("format", self.log.format.to_string()),
+                    ("stdout", self.log.stdout.to_string()),
+                    ("rotation", self.log.rotation.rotation.to_string()),
+                    (
+                        "max_size_mb",
+                        (self.log.rotation.max_size_bytes / (1024 * 1024)).to_string(),
+                    ),
+                    ("retention", self.log.rotation.retention.to_string()),
+                ],
+            ),
+            (
+                "connection",
+                vec![
+                    (
+                        "connect_timeout_seconds",
+                        self.connection.connect_timeout.as_secs().to_string(),
+                    ),
+                    (
+                        "keep_alive_factor",
+                        self.connection.keep_alive_factor.to_string(),
+                    ),
+                    (
+                        "max_connections",
+                        self.connection_limits.max_connections.to_string(),
+                    ),
+                    (
+                        "max_connections_per_ip",
+                        self.connection_limits.max_connections_per_ip.to_string(),
+                    ),
+                ],
+            ),
+            (
+                "auth",
+                vec![
+                    (
+                        "accounts_file",
+                        self.accounts_filename.clone().unwrap_or_default(),
+                    ),
+                    (
+                        "allow_anonymous",
+                        self.auth_policy.allow_anonymous.to_string(),
+                    ),
+                    (
+                        "require_credentials",
+                        self.auth_policy.require_credentials.to_string(),
+                    ),
+                    (
+                        "users_without_password",
+                        self.auth_policy.users_without_password.join(", "),
+                    ),
+                ],
+            ),
+            (
+                "jwt",
+                vec![
+                    (
+                        "hs256_secret_file",
+                        jwt.hs256_secret_file.unwrap_or_default(),
+                    ),
+                    (
+                        "rs256_public_key_file",
+                        jwt.rs256_public_key_file.unwrap_or_default(),
+                    ),
+                ],
+            ),
+            (
+                "auth_limiter",
+                vec![
+                    ("max_failures", self.auth_limiter.max_failures.to_string()),
+                    ("ban_seconds", self.auth_limiter.ban_seconds.to_string()),
+                    (
+                        "max_ban_seconds",
+                        self.auth_limiter.max_ban_seconds.to_string(),
+                    ),
+                ],
+            ),
+            (
+                "reload",
+                vec![(
+                    "watch_interval_seconds",
+                    self.reload_watch_seconds.to_string(),
+                )],
+            ),
+            (
+                "persistence",
+                vec![("file", self.persistence_file.clone().unwrap_or_default())],
+            ),
+            (
+                "websocket",
+                vec![
+                    ("enabled", self.websocket.enabled.to_string()),
+                    ("port", self.websocket.port.to_string()),
+                    ("path", self.websocket.path.clone()),
+                ],
+            ),
+            (
+                "sys",
+                vec![("interval_seconds", self.sys_interval_seconds.to_string())],
+            ),
+            (
+                "metrics",
+                vec![
+                    ("enabled", self.metrics.enabled.to_string()),
+                    ("address", self.metrics.address.clone()),
+                    ("port", self.metrics.port.to_string()),
+                ],
+            ),
+            (
+                "admin",
+                vec![
+                    ("enabled", self.admin.enabled.to_string()),
+                    ("address", self.admin.address.clone()),
+                    ("port", self.admin.port.to_string()),
+                    (
+                        "token_file",
+                        self.admin.token_file.clone().unwrap_or_default(),
+                    ),
+                ],
+            ),
+            (
+                "audit",
+                vec![
+                    ("enabled", self.audit.enabled.to_string()),
+                    ("file", self.audit.file.clone()),
+                    ("topics", self.audit.topics.join(", ")),
+                    ("hash_payload", self.audit.hash_payload.to_string()),
+                    ("rotation", self.audit.rotation.rotation.to_string()),
+                    (
+                        "max_size_mb",
+                        (self.audit.rotation.max_size_bytes / (1024 * 1024)).to_string(),
+                    ),
+                    ("retention", self.audit.rotation.retention.to_string()),
+                ],
+            ),
+            (
+                "capture",
+                vec![
+                    ("enabled", self.capture.enabled.to_string()),
+                    ("file", self.capture.file.clone()),
+                    ("clients", self.capture.clients.join(", ")),
+                ],
+            ),
+        ];
+        let listener_sections: Vec<(String, Vec<(&str, String)>)> = self
+            .listeners
+            .iter()
+            .map(|listener| {
+                let mut entries = vec![("type", listener.transport.name().to_string())];
+                match &listener.transport {
+                    Transport::Unix(path) => {
+                        entries.push(("path", path.clone()));
+                        if let Some(permissions) = listener.permissions {
+                            entries.push(("permissions", format!("{:o}", permissions)));
+                        }
+                        entries.push((
+                            "peer_uid_as_username",
+                            listener.peer_uid_as_username.to_string(),
+                        ));
+                    }
+                    transport => {
+                        entries.push(("address", listener.address.clone()));
+                        entries.push(("port", listener.port.to_string()));
+                        if let Transport::WebSocket(path) = transport {
+                            entries.push(("path", path.clone()));
+                        }
+                    }
+                }
+                let tls_files = [
+                    ("tls_cert_file", &listener.tls_cert_file),
+                    ("tls_key_file", &listener.tls_key_file),
+                    ("tls_client_ca_file", &listener.tls_client_ca_file),
+                ];
+                for (key, file) in tls_files.iter() {
+                    if let Some(file) = file {
+                        entries.push((key, file.clone()));
+                    }
+                }
+                if listener.is_tls() {
+                    entries.push((
+                        "cert_cn_as_username",
+                        listener.cert_cn_as_username.to_string(),
+                    ));
+                }
+                entries.push(("max_connections", listener.max_connections.to_string()));
+                entries.push(("require_auth", listener.require_auth.to_string()));
+                entries.push(("max_packet_size", listener.max_packet_size.to_string()));
+                entries.push(("proxy_protocol", listener.proxy_protocol.to_string()));
+                (
+                    format!("{}{}", LISTENER_SECTION_PREFIX, listener.name),
+                    entries,
+                )
+            })
+            .collect();
+        let sections = sections
+            .into_iter()
+            .map(|(section, entries)| (section.to_string(), entries))
+            .chain(listener_sections);
+
+        for (index, (section, entries)) in sections.enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "[{}]", section)?;
+            for (key, value) in entries {
+                writeln!(f, "{}", format!("{} = {}", key, value).trim_end())?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            port: DEFAULT_PORT,
+            file_path: None,
             address: DEFAULT_ADDRESS.to_string(),
-            log_filename: DEFAULT_LOGFILE.to_string(),
+            port: DEFAULT_PORT,
+            packet_ids: DEFAULT_PACKET_IDS,
+            log: LoggerConfig::new(DEFAULT_LOGFILE),
+            accounts_filename: Some(DEFAULT_ACCOUNTS_FILE.to_string()),
+            connection: ClientHandlerConfig::default(),
+            connection_limits: ConnectionLimits::default(),
+            auth_policy: AuthPolicy::default(),
+            jwt: None,
+            auth_limiter: AuthLimiterConfig::default(),
+            reload_watch_seconds: DEFAULT_RELOAD_WATCH_SECONDS,
+            persistence_file: None,
+            websocket: WebSocketConfig::default(),
+            sys_interval_seconds: DEFAULT_SYS_INTERVAL_SECONDS,
+            metrics: MetricsConfig::default(),
+            admin: AdminConfig::default(),
+            audit: AuditConfig::default(),
+            capture: CaptureConfig::default(),
+            listeners: vec![],
+            check_only: false,
+            key_lines: KeyLines::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::logging::level::Level;
+    use common::logging::logger::LogFormat;
+    use common::logging::rotating_file::{Rotation, RotationConfig};
+
+    #[test]
+    fn parses_sections_and_keys() {
+        let contents = "# comentario\n[server]\nport = 1883\naddress = \"127.0.0.1\"\n\n[auth]\nallow_anonymous = false\nusers_without_password = [\"a\", \"b\"]\n";
+        let config = Config::from_str_with_name(contents, "config.txt").unwrap();
+        assert_eq!(config.port, 1883);
+        assert_eq!(config.listen_address(), "127.0.0.1:1883");
+        assert!(!config.auth_policy.allow_anonymous);
+        assert_eq!(
+            config.auth_policy.users_without_password,
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert_eq!(config.log.file, DEFAULT_LOGFILE);
+    }
+
+    #[test]
+    fn invalid_value_reports_line_number() {
+        let contents = "[server]\n\nport = abc\n";
+        let error = Config::from_str_with_name(contents, "config.txt")
+            .err()
+            .unwrap();
+        assert_eq!(error, "config.txt:3: el valor de port no es valido: 'abc'");
+    }
+
+    #[test]
+    fn errors_between_keys_report_the_line_of_the_last_one() {
+        let contents = "[auth_limiter]\nmax_ban_seconds = 10\n\nban_seconds = 60\n";
+        let error = Config::from_str_with_name(contents, "config.txt")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            "config.txt:4: auth_limiter.max_ban_seconds no puede ser menor que ban_seconds"
+        );
+
+        let contents = "[listener.tls]\nport = 8883\ntls_cert_file = server.pem\n";
+        let error = Config::from_str_with_name(contents, "config.txt")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            "config.txt:1: listener tls: tls_cert_file y tls_key_file van juntos"
+        );
+    }
+
+    #[test]
+    fn invalid_log_modules_are_errors() {
+        let contents = "[log]\nmodules = server=verbose\n";
+        let error = Config::from_str_with_name(contents, "config.txt")
+            .err()
+            .unwrap();
+        assert_eq!(error, "config.txt:2: nivel de log invalido 'verbose'");
+    }
+
+    #[test]
+    fn unknown_key_is_an_error() {
+        let contents = "[server]\nprot = 1883\n";
+        let error = Config::from_str_with_name(contents, "config.txt")
+            .err()
+            .unwrap();
+        assert_eq!(error, "config.txt:2: clave desconocida server.prot");
+    }
+
+    #[test]
+    fn key_outside_section_is_an_error() {
+        let error = Config::from_str_with_name("port = 1883\n", "config.txt")
+            .err()
+            .unwrap();
+        assert_eq!(error, "config.txt:1: clave 'port' fuera de una seccion");
+    }
+
+    #[test]
+    fn env_overrides_file_values() {
+        let mut config = Config::from_str_with_name("[server]\nport = 1883\n", "c").unwrap();
+        let vars = vec![
+            ("MQTT_SERVER_PORT".to_string(), "1884".to_string()),
+            ("MQTT_LOG_FILE".to_string(), "otro.txt".to_string()),
+        ];
+        config.apply_env_overrides(vars.into_iter()).unwrap();
+        assert_eq!(config.port, 1884);
+        assert_eq!(config.log.file, "otro.txt");
+    }
+
+    #[test]
+    fn invalid_env_override_names_the_variable() {
+        let mut config = Config::default();
+        let vars = vec![("MQTT_SERVER_PORT".to_string(), "-1".to_string())];
+        let error = config.apply_env_overrides(vars.into_iter()).unwrap_err();
+        assert_eq!(
+            error,
+            "MQTT_SERVER_PORT: el valor de port no es valido: '-1'"
+        );
+    }
+
+    #[test]
+    fn displayed_config_can_be_parsed_back() {
+        let config = Config {
+            port: 1999,
+            auth_policy: AuthPolicy {
+                users_without_password: vec!["sensor".to_string()],
+                ..AuthPolicy::default()
+            },
+            connection_limits: ConnectionLimits {
+                max_connections: 1000,
+                max_connections_per_ip: 10,
+            },
+            sys_interval_seconds: 0,
+            log: LoggerConfig {
+                levels: LevelFilter {
+                    default: Level::Warn,
+                    modules: vec![("packet_processor".to_string(), Level::Trace)],
+                },
+                format: LogFormat::Json,
+                stdout: true,
+                rotation: RotationConfig {
+                    rotation: Rotation::Size,
+                    max_size_bytes: 5 * 1024 * 1024,
+                    retention: 3,
+                },
+                ..LoggerConfig::new("broker.log")
+            },
+            metrics: MetricsConfig {
+                enabled: true,
+                address: "::".to_string(),
+                port: 9999,
+            },
+            admin: AdminConfig {
+                enabled: true,
+                port: 9998,
+                token_file: Some("/etc/mqtt/admin.token".to_string()),
+                ..AdminConfig::default()
+            },
+            audit: AuditConfig {
+                enabled: true,
+                topics: vec!["pagos/#".to_string(), "+/alarma".to_string()],
+                hash_payload: true,
+                ..AuditConfig::default()
+            },
+            capture: CaptureConfig {
+                enabled: true,
+                file: "/tmp/captura.txt".to_string(),
+                clients: vec!["sensor-*".to_string(), "debug?".to_string()],
+            },
+            ..Config::default()
+        };
+        let parsed = Config::from_str_with_name(&config.to_string(), "c").unwrap();
+        assert_eq!(parsed.port, 1999);
+        assert_eq!(parsed.connection_limits, config.connection_limits);
+        assert_eq!(parsed.sys_interval_seconds, 0);
+        assert_eq!(parsed.log, config.log);
+        assert_eq!(parsed.metrics, config.metrics);
+        assert_eq!(parsed.admin, config.admin);
+        assert_eq!(parsed.audit, config.audit);
+        assert_eq!(parsed.capture, config.capture);
+        assert_eq!(parsed.auth_policy.users_without_password, vec!["sensor"]);
+        assert!(parsed.jwt.is_none());
+    }
+
+    #[test]
+    fn listener_sections_replace_the_server_address() {
+        let contents = "[server]\nport = 1883\n\n[listener.lan]\naddress = ::\nport = 1884\nmax_connections = 10\n\n[listener.public]\npath = /ws\ntype = websocket\nport = 9001\nrequire_auth = true\nmax_packet_size = 1024\n";
+        let config = Config::from_str_with_name(contents, "c").unwrap();
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].bind_address(), "[::]:1884");
+        assert_eq!(listeners[0].max_connections, 10);
+        assert_eq!(
+            listeners[1].transport,
+            Transport::WebSocket("/ws".to_string())
+        );
+        assert!(listeners[1].require_auth);
+        assert_eq!(listeners[1].max_packet_size, 1024);
+    }
+
+    #[test]
+    fn without_listener_sections_server_and_websocket_are_used() {
+        let contents = "[server]\nport = 1883\n\n[websocket]\nenabled = true\n";
+        let listeners = Config::from_str_with_name(contents, "c")
+            .unwrap()
+            .listeners();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].port, 1883);
+        assert!(listeners[1].is_websocket());
+    }
+
+    #[test]
+    fn invalid_listener_sections_are_errors() {
+        let missing_port = "[listener.lan]\naddress = 10.0.0.1\n";
+        assert_eq!(
+            Config::from_str_with_name(missing_port, "c").err().unwrap(),
+            "c:1: falta listener.lan.port"
+        );
+        let path_on_tcp = "[listener.lan]\nport = 1\npath = /mqtt\ntype = tcp\n";
+        assert_eq!(
+            Config::from_str_with_name(path_on_tcp, "c").err().unwrap(),
+            "c:4: listener.lan.path solo vale con type = websocket o unix"
+        );
+        let bad_name = "[listener.LAN]\nport = 1\n";
+        assert!(Config::from_str_with_name(bad_name, "c").is_err());
+    }
+
+    #[test]
+    fn env_overrides_listener_keys() {
+        let mut config = Config::from_str_with_name("[listener.lan]\nport = 1883\n", "c").unwrap();
+        let vars = vec![("MQTT_LISTENER_LAN_PORT".to_string(), "1884".to_string())];
+        config.apply_env_overrides(vars.into_iter()).unwrap();
+        assert_eq!(config.listeners()[0].port, 1884);
+    }
+
+    #[test]
+    fn displayed_listeners_can_be_parsed_back() {
+        let config = Config {
+            listeners: vec![
+                ListenerConfig::tcp("lan", "::1", 1883),
+                ListenerConfig {
+                    max_connections: 3,
+                    require_auth: true,
+                    ..ListenerConfig::websocket("public", "0.0.0.0", 9001, "/mqtt")
+                },
+                ListenerConfig {
+                    proxy_protocol: true,
+                    ..ListenerConfig::tcp("balanced", "0.0.0.0", 1884)
+                },
+                ListenerConfig {
+                    tls_cert_file: Some("server.pem".to_string()),
+                    tls_key_file: Some("server.key".to_string()),
+                    tls_client_ca_file: Some("ca.pem".to_string()),
+                    cert_cn_as_username: true,
+                    ..ListenerConfig::tcp("secure", "0.0.0.0", 8883)
+                },
+                ListenerConfig {
+                    permissions: Some(0o660),
+                    peer_uid_as_username: true,
+                    ..ListenerConfig::unix("local", "/run/mqtt.sock")
+                },
+            ],
+            ..Config::default()
+        };
+        let parsed = Config::from_str_with_name(&config.to_string(), "c").unwrap();
+        assert_eq!(parsed.listeners(), config.listeners());
+    }
+
+    #[test]
+    fn unix_listener_needs_a_path_instead_of_a_port() {
+        let contents = "[listener.local]\npath = mqtt.sock\ntype = unix\npermissions = 660\npeer_uid_as_username = true\n";
+        let listener = Config::from_str_with_name(contents, "c")
+            .unwrap()
+            .listeners()
+            .remove(0);
+        assert_eq!(listener.transport, Transport::Unix("mqtt.sock".to_string()));
+        assert_eq!(listener.permissions, Some(0o660));
+        assert!(listener.peer_uid_as_username);
+
+        let missing_path = "[listener.local]\ntype = unix\n";
+        assert_eq!(
+            Config::from_str_with_name(missing_path, "c").err().unwrap(),
+            "c:1: falta listener.local.path"
+        );
+        let uid_on_tcp = "[listener.lan]\nport = 1883\npeer_uid_as_username = true\n";
+        assert!(Config::from_str_with_name(uid_on_tcp, "c").is_err());
+    }
+}
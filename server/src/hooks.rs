@@ -0,0 +1,279 @@
+use crate::client_events::{ConnectionInfo, EventReason};
+use common::all_packets::connect::Connect;
+use common::all_packets::publish::Publish;
+use common::packet::Subscription;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookDecision {
+    Continue,
+    Deny,
+}
+
+// Lógica propia en los puntos clave del broker, sin tocar el PacketProcessor. Los hooks se
+// registran con ServerBuilder::hook y se llaman en el orden en que se registraron: cada uno ve
+// los cambios de los anteriores y el primer Deny corta la cadena. Corren en el thread del
+// PacketProcessor, así que no deberían bloquearse
+pub trait BrokerHook: Send + Sync {
+    // Después de autenticar y antes de crear la sesión. Con Deny el Connack sale con "not
+    // authorized". `client.username` es el del Connect
+    fn on_connect(&self, _client: &ConnectionInfo, _connect: &Connect) -> HookDecision {
+        HookDecision::Continue
+    }
+
+    // Por cada filtro de un Subscribe, antes de validarlo. Puede cambiar el filtro o la QoS;
+    // con Deny el Suback lleva Failure para ese filtro
+    fn on_subscribe(
+        &self,
+        _client: &ConnectionInfo,
+        _subscription: &mut Subscription,
+    ) -> HookDecision {
+        HookDecision::Continue
+    }
+
+    // Por cada Publish de un cliente, antes de controlar sus permisos. Puede cambiar el
+    // mensaje, o el tópico para mandarlo a otro lado. Con Deny se descarta (si es QoS 1 el
+    // cliente igual recibe su Puback)
+    fn on_publish(&self, _client: &ConnectionInfo, _publish: &mut Publish) -> HookDecision {
+        HookDecision::Continue
+    }
+
+    // Por cada Publish que se le manda a un suscriptor, incluidos los retenidos
+    fn on_deliver(&self, _client: &ConnectionInfo, _publish: &Publish) {}
+
+    // Cuando se cierra la conexión de un cliente que había sido aceptado. No se llama al
+    // apagar el server
+    fn on_disconnect(&self, _client: &ConnectionInfo, _reason: EventReason) {}
+}
+
+/* ------------------------------ Hooks de ejemplo ------------------------------ */
+
+// Solo acepta los client ids que empiezan con alguno de los prefijos
+pub struct ClientIdPrefix {
+    prefixes: Vec<String>,
+}
+
+impl ClientIdPrefix {
+    pub fn new(prefixes: &[&str]) -> ClientIdPrefix {
+        ClientIdPrefix {
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+        }
+    }
+}
+
+impl BrokerHook for ClientIdPrefix {
+    fn on_connect(&self, client: &ConnectionInfo, _connect: &Connect) -> HookDecision {
+        if self
+            .prefixes
+            .iter()
+            .any(|prefix| client.client_id.starts_with(prefix.as_str()))
+        {
+            HookDecision::Continue
+        } else {
+            HookDecision::Deny
+        }
+    }
+}
+
+// Mueve un árbol de tópicos: lo que se publica y las suscripciones que empiezan con `from`
+// pasan a empezar con `to`. Sirve para migrar dispositivos viejos sin reconfigurarlos
+pub struct TopicRewrite {
+    from: String,
+    to: String,
+}
+
+impl TopicRewrite {
+    pub fn new(from: &str, to: &str) -> TopicRewrite {
+        TopicRewrite {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn rewrite(&self, topic: &mut String) {
+        if let Some(rest) = topic.strip_prefix(self.from.as_str()) {
+            *topic = format!("{}{}", self.to, rest);
+        }
+    }
+}
+
+impl BrokerHook for TopicRewrite {
+    fn on_subscribe(
+        &self,
+        _client: &ConnectionInfo,
+        subscription: &mut Subscription,
+    ) -> HookDecision {
+        self.rewrite(&mut subscription.topic_filter);
+        HookDecision::Continue
+    }
+
+    fn on_publish(&self, _client: &ConnectionInfo, publish: &mut Publish) -> HookDecision {
+        self.rewrite(&mut publish.topic_name);
+        HookDecision::Continue
+    }
+}
+
+// Descarta los mensajes más grandes que `max_bytes`
+pub struct MaxPayloadSize {
+    max_bytes: usize,
+}
+
+impl MaxPayloadSize {
+    pub fn new(max_bytes: usize) -> MaxPayloadSize {
+        MaxPayloadSize { max_bytes }
+    }
+}
+
+impl BrokerHook for MaxPayloadSize {
+    fn on_publish(&self, _client: &ConnectionInfo, publish: &mut Publish) -> HookDecision {
+        if publish.application_message.len() > self.max_bytes {
+            HookDecision::Deny
+        } else {
+            HookDecision::Continue
+        }
+    }
+}
+
+// Cuenta los eventos que le llegan. Registrado al final, cuenta solo lo que dejaron pasar
+// los hooks anteriores
+#[derive(Default)]
+pub struct EventCounter {
+    connects: AtomicU64,
+    subscriptions: AtomicU64,
+    publishes: AtomicU64,
+    deliveries: AtomicU64,
+    disconnects: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EventCounts {
+    pub connects: u64,
+    pub subscriptions: u64,
+    pub publishes: u64,
+    pub deliveries: u64,
+    pub disconnects: u64,
+}
+
+impl EventCounter {
+    pub fn new() -> EventCounter {
+        EventCounter::default()
+    }
+
+    pub fn counts(&self) -> EventCounts {
+        EventCounts {
+            connects: self.connects.load(Ordering::Relaxed),
+            subscriptions: self.subscriptions.load(Ordering::Relaxed),
+            publishes: self.publishes.load(Ordering::Relaxed),
+            deliveries: self.deliveries.load(Ordering::Relaxed),
+            disconnects: self.disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl BrokerHook for EventCounter {
+    fn on_connect(&self, _client: &ConnectionInfo, _connect: &Connect) -> HookDecision {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        HookDecision::Continue
+    }
+
+    fn on_subscribe(
+        &self,
+        _client: &ConnectionInfo,
+        _subscription: &mut Subscription,
+    ) -> HookDecision {
+        self.subscriptions.fetch_add(1, Ordering::Relaxed);
+        HookDecision::Continue
+    }
+
+    fn on_publish(&self, _client: &ConnectionInfo, _publish: &mut Publish) -> HookDecision {
+        self.publishes.fetch_add(1, Ordering::Relaxed);
+        HookDecision::Continue
+    }
+
+    fn on_deliver(&self, _client: &ConnectionInfo, _publish: &Publish) {
+        self.deliveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_disconnect(&self, _client: &ConnectionInfo, _reason: EventReason) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::all_packets::connect::ConnectPayload;
+    use common::all_packets::publish::PublishFlags;
+    use common::packet::Qos;
+
+    fn client(client_id: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            client_id: client_id.to_string(),
+            address: None,
+            username: None,
+            connected_at: 0,
+        }
+    }
+
+    fn publish(topic: &str, message: &str) -> Publish {
+        Publish::new(
+            PublishFlags::new(0b0011_0000),
+            topic.to_string(),
+            None,
+            message.to_string(),
+        )
+    }
+
+    #[test]
+    fn client_ids_without_an_allowed_prefix_are_rejected() {
+        let hook = ClientIdPrefix::new(&["sensor", "gw"]);
+        let connect = |client_id: &str| {
+            Connect::new(
+                ConnectPayload::new(client_id.to_string(), None, None, None, None),
+                60,
+                true,
+                false,
+                false,
+            )
+        };
+        assert_eq!(
+            hook.on_connect(&client("gw1"), &connect("gw1")),
+            HookDecision::Continue
+        );
+        assert_eq!(
+            hook.on_connect(&client("otro"), &connect("otro")),
+            HookDecision::Deny
+        );
+    }
+
+    #[test]
+    fn topics_and_filters_under_the_prefix_are_moved() {
+        let hook = TopicRewrite::new("legacy/", "devices/");
+        let mut moved = publish("legacy/temp", "21");
+        let mut kept = publish("casa/legacy/temp", "21");
+        hook.on_publish(&client("a"), &mut moved);
+        hook.on_publish(&client("a"), &mut kept);
+        assert_eq!(moved.topic_name, "devices/temp");
+        assert_eq!(kept.topic_name, "casa/legacy/temp");
+
+        let mut subscription = Subscription {
+            topic_filter: "legacy/#".to_string(),
+            max_qos: Qos::AtLeastOnce,
+        };
+        hook.on_subscribe(&client("a"), &mut subscription);
+        assert_eq!(subscription.topic_filter, "devices/#");
+    }
+
+    #[test]
+    fn large_payloads_are_dropped() {
+        let hook = MaxPayloadSize::new(4);
+        assert_eq!(
+            hook.on_publish(&client("a"), &mut publish("t", "1234")),
+            HookDecision::Continue
+        );
+        assert_eq!(
+            hook.on_publish(&client("a"), &mut publish("t", "12345")),
+            HookDecision::Deny
+        );
+    }
+}
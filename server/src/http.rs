@@ -0,0 +1,223 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const MAX_HEAD_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Request HTTP/1.1 mínimo para los listeners internos del server (métricas y administración).
+// Cada conexión lleva un solo request
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    // Sin la query string
+    pub path: String,
+    pub query: Option<String>,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    // Los nombres de los headers no distinguen mayúsculas
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn not_found() -> HttpResponse {
+        HttpResponse::new(404, "text/plain", "Not Found\n".to_string())
+    }
+}
+
+pub fn read_request(stream: &mut dyn BufRead) -> Result<HttpRequest, Box<dyn std::error::Error>> {
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err("HTTP request headers too large".into());
+        }
+        let limit = (MAX_HEAD_SIZE - head.len()) as u64;
+        if (&mut *stream).take(limit).read_until(b'\n', &mut head)? == 0 {
+            return Err("Connection closed before the end of the HTTP headers".into());
+        }
+    }
+    let head = String::from_utf8(head)?;
+    let mut lines = head.split("\r\n");
+    let start_line: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+    if start_line.len() != 3 || !start_line[2].starts_with("HTTP/1.") {
+        return Err("Invalid HTTP request line".into());
+    }
+    let (path, query) = match start_line[1].split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (start_line[1], None),
+    };
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = HttpRequest {
+        method: start_line[0].to_string(),
+        path: path.to_string(),
+        query,
+        headers,
+        body: vec![],
+    };
+    if let Some(length) = request.header("Content-Length") {
+        let length: usize = length.parse()?;
+        if length > MAX_BODY_SIZE {
+            return Err("HTTP request body too large".into());
+        }
+        request.body = vec![0; length];
+        stream.read_exact(&mut request.body)?;
+    }
+    Ok(request)
+}
+
+pub fn write_response(stream: &mut dyn Write, response: &HttpResponse) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+// Decodifica los %XX de un segmento del path
+pub fn percent_decode(segment: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment
+                .get(i + 1..i + 3)
+                .ok_or("Truncated percent escape in path")?;
+            decoded.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+// Atiende los requests de a uno en un thread hasta que se apaga el server
+pub fn serve<F>(listener: TcpListener, shutdown: Arc<AtomicBool>, handler: F) -> JoinHandle<()>
+where
+    F: Fn(&HttpRequest) -> HttpResponse + Send + 'static,
+{
+    thread::spawn(move || {
+        if listener.set_nonblocking(true).is_err() {
+            return;
+        }
+        while !shutdown.load(Ordering::SeqCst) {
+            let socket = match listener.accept() {
+                Ok((socket, _)) => socket,
+                Err(_) => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+            };
+            if socket.set_nonblocking(false).is_err()
+                || socket.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err()
+            {
+                continue;
+            }
+            let mut writer = match socket.try_clone() {
+                Ok(writer) => writer,
+                Err(_) => continue,
+            };
+            let response = match read_request(&mut BufReader::new(socket)) {
+                Ok(request) => handler(&request),
+                Err(error) => HttpResponse::new(400, "text/plain", format!("{}\n", error)),
+            };
+            let _ = write_response(&mut writer, &response);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_with_query_headers_and_body_is_parsed() {
+        let raw = "POST /api/publish?retain=true HTTP/1.1\r\nhost: x\r\nContent-Length: 4\r\n\r\nholaEXTRA";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/publish");
+        assert_eq!(request.query.as_deref(), Some("retain=true"));
+        assert_eq!(request.header("HOST"), Some("x"));
+        assert_eq!(request.body, b"hola");
+    }
+
+    #[test]
+    fn truncated_or_invalid_requests_are_errors() {
+        assert!(read_request(&mut "GET / HTTP/1.1\r\n".as_bytes()).is_err());
+        assert!(read_request(&mut "GET /\r\n\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("casa%2Fluz%20A").unwrap(), "casa/luz A");
+        assert_eq!(percent_decode("%C3%B1").unwrap(), "ñ");
+        assert!(percent_decode("a%2").is_err());
+        assert!(percent_decode("a%zz").is_err());
+    }
+
+    #[test]
+    fn response_has_length_and_closes_the_connection() {
+        let mut out = vec![];
+        write_response(
+            &mut out,
+            &HttpResponse::new(200, "text/plain", "ok".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+}
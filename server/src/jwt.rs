@@ -0,0 +1,265 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::signature::Verifier;
+use rsa::RsaPublicKey;
+use serde_json::Value;
+use sha2::Sha256;
+use std::convert::TryFrom;
+use std::fs;
+
+const MALFORMED_TOKEN_ERROR_MSG: &str = "malformed token";
+const UNSUPPORTED_ALGORITHM_ERROR_MSG: &str = "unsupported signing algorithm";
+const INVALID_SIGNATURE_ERROR_MSG: &str = "invalid token signature";
+const EXPIRED_TOKEN_ERROR_MSG: &str = "token expired";
+const MISSING_EXPIRATION_ERROR_MSG: &str = "token has no expiration";
+const TOKEN_NOT_YET_VALID_ERROR_MSG: &str = "token not valid yet";
+
+#[derive(Clone, Debug, Default)]
+pub struct JwtConfig {
+    pub hs256_secret_file: Option<String>,
+    pub rs256_public_key_file: Option<String>,
+}
+
+// Claims del token que le interesan al server. Si el token no trae las listas de tópicos,
+// el cliente no tiene restricciones de publish/subscribe. Todo token tiene que vencer
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenClaims {
+    pub subject: Option<String>,
+    pub expires_at: u64,
+    pub publish_topics: Option<Vec<String>>,
+    pub subscribe_topics: Option<Vec<String>>,
+}
+
+// Valida tokens HS256/RS256 recibidos en el campo password del Connect
+pub struct JwtValidator {
+    hs256_secret: Option<Vec<u8>>,
+    rs256_key: Option<RsaPublicKey>,
+}
+
+impl JwtValidator {
+    pub fn new(hs256_secret: Option<Vec<u8>>, rs256_key: Option<RsaPublicKey>) -> JwtValidator {
+        JwtValidator {
+            hs256_secret,
+            rs256_key,
+        }
+    }
+
+    pub fn from_config(config: &JwtConfig) -> Result<JwtValidator, Box<dyn std::error::Error>> {
+        let hs256_secret = match &config.hs256_secret_file {
+            Some(path) => Some(fs::read_to_string(path)?.trim().as_bytes().to_vec()),
+            None => None,
+        };
+        let rs256_key = match &config.rs256_public_key_file {
+            Some(path) => Some(RsaPublicKey::from_public_key_pem(&fs::read_to_string(
+                path,
+            )?)?),
+            None => None,
+        };
+        if hs256_secret.is_none() && rs256_key.is_none() {
+            return Err("JWT authentication needs at least one key".into());
+        }
+
+        Ok(JwtValidator::new(hs256_secret, rs256_key))
+    }
+
+    // Verifica la firma y las fechas del token. `now` son los segundos desde UNIX_EPOCH
+    pub fn validate(&self, token: &str, now: u64) -> Result<TokenClaims, String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(MALFORMED_TOKEN_ERROR_MSG.into());
+        }
+
+        let header = decode_json_part(parts[0])?;
+        let signed_data = format!("{}.{}", parts[0], parts[1]);
+        let signature = URL_SAFE_NO_PAD
+            .decode(parts[2])
+            .map_err(|_| MALFORMED_TOKEN_ERROR_MSG.to_string())?;
+
+        match header.get("alg").and_then(Value::as_str) {
+            Some("HS256") => self.verify_hs256(signed_data.as_bytes(), &signature)?,
+            Some("RS256") => self.verify_rs256(signed_data.as_bytes(), &signature)?,
+            _ => return Err(UNSUPPORTED_ALGORITHM_ERROR_MSG.into()),
+        }
+
+        let payload = decode_json_part(parts[1])?;
+        // Un token sin exp no vencería nunca, ni cortaría la conexión de quien lo usó
+        let expires_at = payload
+            .get("exp")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| MISSING_EXPIRATION_ERROR_MSG.to_string())?;
+        let claims = TokenClaims {
+            subject: payload
+                .get("sub")
+                .and_then(Value::as_str)
+                .map(|s| s.to_string()),
+            expires_at,
+            publish_topics: string_list(&payload, "publish"),
+            subscribe_topics: string_list(&payload, "subscribe"),
+        };
+
+        if now >= claims.expires_at {
+            return Err(EXPIRED_TOKEN_ERROR_MSG.into());
+        }
+        if let Some(nbf) = payload.get("nbf").and_then(Value::as_u64) {
+            if now < nbf {
+                return Err(TOKEN_NOT_YET_VALID_ERROR_MSG.into());
+            }
+        }
+
+        Ok(claims)
+    }
+
+    fn verify_hs256(&self, data: &[u8], signature: &[u8]) -> Result<(), String> {
+        let secret = self
+            .hs256_secret
+            .as_ref()
+            .ok_or_else(|| UNSUPPORTED_ALGORITHM_ERROR_MSG.to_string())?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+            .map_err(|_| INVALID_SIGNATURE_ERROR_MSG.to_string())?;
+        mac.update(data);
+        mac.verify_slice(signature)
+            .map_err(|_| INVALID_SIGNATURE_ERROR_MSG.to_string())
+    }
+
+    fn verify_rs256(&self, data: &[u8], signature: &[u8]) -> Result<(), String> {
+        let key = self
+            .rs256_key
+            .as_ref()
+            .ok_or_else(|| UNSUPPORTED_ALGORITHM_ERROR_MSG.to_string())?;
+        let verifying_key = VerifyingKey::<Sha256>::new(key.clone());
+        let signature =
+            Signature::try_from(signature).map_err(|_| INVALID_SIGNATURE_ERROR_MSG.to_string())?;
+        verifying_key
+            .verify(data, &signature)
+            .map_err(|_| INVALID_SIGNATURE_ERROR_MSG.to_string())
+    }
+}
+
+fn decode_json_part(part: &str) -> Result<Value, String> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| MALFORMED_TOKEN_ERROR_MSG.to_string())?;
+    serde_json::from_slice(&bytes).map_err(|_| MALFORMED_TOKEN_ERROR_MSG.to_string())
+}
+
+fn string_list(payload: &Value, claim: &str) -> Option<Vec<String>> {
+    payload.get(claim).and_then(Value::as_array).map(|list| {
+        list.iter()
+            .filter_map(Value::as_str)
+            .map(|s| s.to_string())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs1v15::SigningKey;
+    use rsa::signature::{SignatureEncoding, Signer};
+    use rsa::RsaPrivateKey;
+
+    const SECRET: &[u8] = b"secreto";
+
+    fn encode_part(json: &str) -> String {
+        URL_SAFE_NO_PAD.encode(json.as_bytes())
+    }
+
+    fn hs256_token(payload: &str, secret: &[u8]) -> String {
+        let signed_data = format!(
+            "{}.{}",
+            encode_part(r#"{"alg":"HS256","typ":"JWT"}"#),
+            encode_part(payload)
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(signed_data.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}", signed_data, signature)
+    }
+
+    #[test]
+    fn valid_hs256_token_returns_claims() {
+        let validator = JwtValidator::new(Some(SECRET.to_vec()), None);
+        let token = hs256_token(
+            r#"{"sub":"sensor1","exp":2000,"publish":["sensors/1/#"]}"#,
+            SECRET,
+        );
+        let claims = validator.validate(&token, 1000).unwrap();
+        assert_eq!(claims.subject, Some("sensor1".to_string()));
+        assert_eq!(claims.expires_at, 2000);
+        assert_eq!(claims.publish_topics, Some(vec!["sensors/1/#".to_string()]));
+        assert_eq!(claims.subscribe_topics, None);
+    }
+
+    #[test]
+    fn hs256_token_with_wrong_secret_is_rejected() {
+        let validator = JwtValidator::new(Some(SECRET.to_vec()), None);
+        let token = hs256_token(r#"{"exp":2000}"#, b"otro secreto");
+        assert_eq!(
+            validator.validate(&token, 1000),
+            Err(INVALID_SIGNATURE_ERROR_MSG.to_string())
+        );
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let validator = JwtValidator::new(Some(SECRET.to_vec()), None);
+        let token = hs256_token(r#"{"exp":2000}"#, SECRET);
+        assert_eq!(
+            validator.validate(&token, 2000),
+            Err(EXPIRED_TOKEN_ERROR_MSG.to_string())
+        );
+    }
+
+    #[test]
+    fn token_without_exp_is_rejected() {
+        let validator = JwtValidator::new(Some(SECRET.to_vec()), None);
+        let token = hs256_token(r#"{"sub":"sensor1"}"#, SECRET);
+        assert_eq!(
+            validator.validate(&token, 1000),
+            Err(MISSING_EXPIRATION_ERROR_MSG.to_string())
+        );
+    }
+
+    #[test]
+    fn token_before_nbf_is_rejected() {
+        let validator = JwtValidator::new(Some(SECRET.to_vec()), None);
+        let token = hs256_token(r#"{"nbf":1500,"exp":2000}"#, SECRET);
+        assert_eq!(
+            validator.validate(&token, 1000),
+            Err(TOKEN_NOT_YET_VALID_ERROR_MSG.to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_token_is_rejected() {
+        let validator = JwtValidator::new(Some(SECRET.to_vec()), None);
+        assert_eq!(
+            validator.validate("contraseña", 1000),
+            Err(MALFORMED_TOKEN_ERROR_MSG.to_string())
+        );
+    }
+
+    #[test]
+    fn rs256_token_is_validated_with_public_key() {
+        let mut rng = rand::thread_rng();
+        let private_key = RsaPrivateKey::new(&mut rng, 1024).unwrap();
+        let validator = JwtValidator::new(None, Some(RsaPublicKey::from(&private_key)));
+
+        let signed_data = format!(
+            "{}.{}",
+            encode_part(r#"{"alg":"RS256","typ":"JWT"}"#),
+            encode_part(r#"{"exp":2000}"#)
+        );
+        let signing_key = SigningKey::<Sha256>::new(private_key);
+        let signature = signing_key.sign(signed_data.as_bytes()).to_vec();
+        let token = format!("{}.{}", signed_data, URL_SAFE_NO_PAD.encode(signature));
+
+        assert!(validator.validate(&token, 1000).is_ok());
+        // Sin clave HS256 configurada, un token HS256 no se acepta
+        let hs256 = hs256_token(r#"{"exp":2000}"#, SECRET);
+        assert!(validator.validate(&hs256, 1000).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// De dónde sacan la hora los timers del server. En los tests se usa un ManualClock para no
+// tener que esperar los plazos de verdad
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Reloj que solo avanza cuando se lo pide
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+// [MQTT-3.1.2-24]: si el cliente no manda ningún paquete en keep alive * 1.5 segundos, el
+// server tiene que cerrar la conexión como si se hubiera caído la red (y publicar el will).
+// Guarda cuándo se recibió el último paquete de cada client handler
+pub struct KeepAliveTracker {
+    clock: Arc<dyn Clock>,
+    // c_h_id -> (plazo sin recibir paquetes, último paquete recibido)
+    clients: HashMap<u32, (Duration, Instant)>,
+}
+
+impl KeepAliveTracker {
+    pub fn new(clock: Arc<dyn Clock>) -> KeepAliveTracker {
+        KeepAliveTracker {
+            clock,
+            clients: HashMap::new(),
+        }
+    }
+
+    // Empieza a controlar al cliente cuando se acepta su Connect. Con keep alive 0 no hay plazo
+    pub fn start(&mut self, c_h_id: u32, keep_alive_seconds: u16, factor: f64) {
+        if keep_alive_seconds == 0 {
+            self.clients.remove(&c_h_id);
+            return;
+        }
+        let timeout = Duration::from_secs_f64(keep_alive_seconds as f64 * factor);
+        self.clients.insert(c_h_id, (timeout, self.clock.now()));
+    }
+
+    // Cualquier paquete del cliente reinicia el plazo
+    pub fn packet_received(&mut self, c_h_id: u32) {
+        let now = self.clock.now();
+        if let Some((_, last_packet)) = self.clients.get_mut(&c_h_id) {
+            *last_packet = now;
+        }
+    }
+
+    pub fn stop(&mut self, c_h_id: u32) {
+        self.clients.remove(&c_h_id);
+    }
+
+    // Devuelve los clientes vencidos junto con su plazo, y deja de controlarlos
+    pub fn take_expired(&mut self) -> Vec<(u32, Duration)> {
+        let now = self.clock.now();
+        let expired: Vec<(u32, Duration)> = self
+            .clients
+            .iter()
+            .filter(|(_, (timeout, last_packet))| now.duration_since(*last_packet) > *timeout)
+            .map(|(c_h_id, (timeout, _))| (*c_h_id, *timeout))
+            .collect();
+        for (c_h_id, _) in &expired {
+            self.clients.remove(c_h_id);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> (KeepAliveTracker, ManualClock) {
+        let clock = ManualClock::new();
+        (KeepAliveTracker::new(Arc::new(clock.clone())), clock)
+    }
+
+    #[test]
+    fn client_expires_after_one_and_a_half_times_the_keep_alive() {
+        let (mut tracker, clock) = tracker();
+        tracker.start(1, 10, 1.5);
+
+        clock.advance(Duration::from_secs(15));
+        assert!(tracker.take_expired().is_empty());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(tracker.take_expired(), vec![(1, Duration::from_secs(15))]);
+        // Ya se informó, no se vuelve a devolver
+        assert!(tracker.take_expired().is_empty());
+    }
+
+    #[test]
+    fn each_packet_restarts_the_timer() {
+        let (mut tracker, clock) = tracker();
+        tracker.start(1, 10, 1.5);
+        tracker.start(2, 10, 1.5);
+
+        clock.advance(Duration::from_secs(10));
+        tracker.packet_received(1);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(tracker.take_expired(), vec![(2, Duration::from_secs(15))]);
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(tracker.take_expired(), vec![(1, Duration::from_secs(15))]);
+    }
+
+    #[test]
+    fn zero_keep_alive_and_stopped_clients_never_expire() {
+        let (mut tracker, clock) = tracker();
+        tracker.start(1, 0, 1.5);
+        tracker.start(2, 1, 1.5);
+        tracker.stop(2);
+
+        clock.advance(Duration::from_secs(3600));
+        assert!(tracker.take_expired().is_empty());
+    }
+}
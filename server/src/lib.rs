@@ -1,8 +1,32 @@
+pub mod admin;
+pub mod audit;
+pub mod auth_limiter;
 pub mod authenticator;
+pub mod capture;
+pub mod client_events;
 pub mod client_handler;
 pub mod config;
+pub mod hooks;
+pub mod http;
+pub mod jwt;
+pub mod keep_alive;
+pub mod listener;
+pub mod local_client;
+pub mod metrics;
 pub mod packet_processor;
+pub mod persistence;
+pub mod proxy_protocol;
 pub mod puback_processor;
+pub mod reloader;
 pub mod server;
+pub mod server_builder;
 pub mod session;
+pub mod stats;
+pub mod stream;
+pub mod sys_topics;
 pub mod topic_filters;
+pub mod websocket;
+
+pub use local_client::{LastWill, LocalClient, LocalClientOptions};
+pub use server::ServerHandle;
+pub use server_builder::ServerBuilder;
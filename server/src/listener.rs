@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_LISTENER_NAME: &str = "default";
+const DEFAULT_WEBSOCKET_LISTENER_NAME: &str = "websocket";
+
+// Protocolo que habla cada listener arriba de TCP
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    // Incluye el path en el que se acepta el upgrade
+    WebSocket(String),
+    // Incluye el path del socket. No usa address ni port
+    Unix(String),
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::WebSocket(_) => "websocket",
+            Transport::Unix(_) => "unix",
+        }
+    }
+}
+
+// Un puerto en el que escucha el server, con sus propios límites
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListenerConfig {
+    // Nombre de la sección [listener.<nombre>], se usa en los logs
+    pub name: String,
+    pub transport: Transport,
+    // IPv4 o IPv6, sin corchetes
+    pub address: String,
+    pub port: u16,
+    // Conexiones abiertas al mismo tiempo en este listener (0 = sin límite)
+    pub max_connections: usize,
+    // Si es true se rechazan los Connect sin usuario aunque el server acepte anónimos
+    pub require_auth: bool,
+    // Tamaño máximo de un paquete en bytes, contando el header (0 = sin límite)
+    pub max_packet_size: usize,
+    // Solo para unix: permisos del archivo del socket, en octal (None = los del umask)
+    pub permissions: Option<u32>,
+    // Solo para unix: el username del Connect se reemplaza por el UID del proceso que se conecta
+    pub peer_uid_as_username: bool,
+    // Cada conexión empieza con un header PROXY v1 o v2 con la dirección real del cliente
+    pub proxy_protocol: bool,
+    // Solo para tcp: certificado y clave en PEM. Con los dos el listener habla TLS
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // CA con la que se validan los certificados de los clientes. Si está, el certificado es
+    // obligatorio
+    pub tls_client_ca_file: Option<String>,
+    // El username del Connect se reemplaza por el CN del certificado del cliente
+    pub cert_cn_as_username: bool,
+}
+
+impl ListenerConfig {
+    pub fn new(name: &str, transport: Transport, address: &str, port: u16) -> ListenerConfig {
+        ListenerConfig {
+            name: name.to_string(),
+            transport,
+            address: address.to_string(),
+            port,
+            max_connections: 0,
+            require_auth: false,
+            max_packet_size: 0,
+            permissions: None,
+            peer_uid_as_username: false,
+            proxy_protocol: false,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_client_ca_file: None,
+            cert_cn_as_username: false,
+        }
+    }
+
+    pub fn tcp(name: &str, address: &str, port: u16) -> ListenerConfig {
+        ListenerConfig::new(name, Transport::Tcp, address, port)
+    }
+
+    pub fn websocket(name: &str, address: &str, port: u16, path: &str) -> ListenerConfig {
+        ListenerConfig::new(name, Transport::WebSocket(path.to_string()), address, port)
+    }
+
+    pub fn unix(name: &str, path: &str) -> ListenerConfig {
+        ListenerConfig::new(name, Transport::Unix(path.to_string()), "", 0)
+    }
+
+    // El listener que sale de [server] cuando no hay secciones [listener.*]
+    pub fn default_tcp(address: &str, port: u16) -> ListenerConfig {
+        ListenerConfig::tcp(DEFAULT_LISTENER_NAME, address, port)
+    }
+
+    // El listener que sale de [websocket] cuando no hay secciones [listener.*]
+    pub fn default_websocket(address: &str, port: u16, path: &str) -> ListenerConfig {
+        ListenerConfig::websocket(DEFAULT_WEBSOCKET_LISTENER_NAME, address, port, path)
+    }
+
+    // Dirección con formato ip:puerto, con corchetes si es IPv6
+    pub fn bind_address(&self) -> String {
+        bind_address(&self.address, self.port)
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        matches!(self.transport, Transport::WebSocket(_))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls_cert_file.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let unix = matches!(self.transport, Transport::Unix(_));
+        match &self.transport {
+            Transport::WebSocket(path) if !path.starts_with('/') => {
+                return Err(format!(
+                    "listener {}: path tiene que empezar con /",
+                    self.name
+                ))
+            }
+            Transport::Unix(path) if path.is_empty() => {
+                return Err(format!("listener {}: falta el path del socket", self.name))
+            }
+            _ => {}
+        }
+        if !unix && (self.permissions.is_some() || self.peer_uid_as_username) {
+            return Err(format!(
+                "listener {}: permissions y peer_uid_as_username solo valen con type = unix",
+                self.name
+            ));
+        }
+        if self.proxy_protocol && self.is_websocket() {
+            return Err(format!(
+                "listener {}: proxy_protocol no se puede usar con type = websocket",
+                self.name
+            ));
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return Err(format!(
+                "listener {}: tls_cert_file y tls_key_file van juntos",
+                self.name
+            ));
+        }
+        if self.is_tls() && self.transport != Transport::Tcp {
+            return Err(format!(
+                "listener {}: TLS solo vale con type = tcp",
+                self.name
+            ));
+        }
+        if self.is_tls() && self.proxy_protocol {
+            return Err(format!(
+                "listener {}: proxy_protocol no se puede usar con TLS",
+                self.name
+            ));
+        }
+        if (self.tls_client_ca_file.is_some() || self.cert_cn_as_username) && !self.is_tls() {
+            return Err(format!(
+                "listener {}: tls_client_ca_file y cert_cn_as_username necesitan TLS",
+                self.name
+            ));
+        }
+        if self.cert_cn_as_username && self.tls_client_ca_file.is_none() {
+            return Err(format!(
+                "listener {}: cert_cn_as_username necesita tls_client_ca_file",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn bind_address(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+// Cuenta las conexiones abiertas de un listener
+#[derive(Clone)]
+pub struct ConnectionCounter {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+// Lugar ocupado en un listener y en los límites globales. Se libera solo cuando se termina
+// la conexión
+pub struct ConnectionSlot {
+    active: Arc<AtomicUsize>,
+    limiter: Option<(Arc<Mutex<LimiterState>>, Option<IpAddr>)>,
+    // El límite por IP vigente cuando se tomó el lugar, para cuando se asigna la IP después
+    max_connections_per_ip: usize,
+}
+
+impl ConnectionCounter {
+    pub fn new(max: usize) -> ConnectionCounter {
+        ConnectionCounter {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    // Devuelve None si el listener ya está lleno
+    pub fn try_acquire(&self) -> Option<ConnectionSlot> {
+        let acquired = self
+            .active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                if self.max != 0 && active >= self.max {
+                    None
+                } else {
+                    Some(active + 1)
+                }
+            });
+        acquired.ok().map(|_| ConnectionSlot {
+            active: self.active.clone(),
+            limiter: None,
+            max_connections_per_ip: 0,
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+impl ConnectionSlot {
+    // En los listeners con PROXY protocol la IP real llega recién con el header: el lugar se
+    // toma sin IP y el límite por IP se aplica cuando se la conoce
+    pub fn assign_ip(&mut self, ip: IpAddr) -> Result<(), Rejection> {
+        let (state, slot_ip) = match &mut self.limiter {
+            Some((state, slot_ip)) => (state, slot_ip),
+            None => return Ok(()),
+        };
+        let mut state = state.lock().unwrap();
+        let max = self.max_connections_per_ip;
+        let active = state.active_per_ip.get(&ip).copied().unwrap_or(0);
+        if max != 0 && active >= max {
+            return Err(state.reject(Rejection::IpFull(ip, max)));
+        }
+        *state.active_per_ip.entry(ip).or_insert(0) += 1;
+        if let Some(previous) = slot_ip.replace(ip) {
+            state.release_ip(previous);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        if let Some((state, ip)) = &self.limiter {
+            state.lock().unwrap().release(*ip);
+        }
+    }
+}
+
+// Límites que valen para todas las conexiones, entren por el listener que entren
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionLimits {
+    // Conexiones abiertas en todo el server (0 = sin límite)
+    pub max_connections: usize,
+    // Conexiones abiertas desde una misma IP (0 = sin límite)
+    pub max_connections_per_ip: usize,
+}
+
+// Por qué no se aceptó una conexión
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    ServerFull(usize),
+    IpFull(IpAddr, usize),
+    ListenerFull(usize),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::ServerFull(max) => write!(f, "server is full ({} connections)", max),
+            Rejection::IpFull(ip, max) => write!(f, "{} already has {} connections", ip, max),
+            Rejection::ListenerFull(max) => write!(f, "listener is full ({} connections)", max),
+        }
+    }
+}
+
+// Cuenta las conexiones de todos los listeners, en total y por IP, y cuántas se rechazaron
+// o se cerraron antes del Connect. Los límites llegan con cada conexión, así se pueden
+// cambiar sin reiniciar
+#[derive(Clone, Default)]
+pub struct ConnectionLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    active: usize,
+    active_per_ip: HashMap<IpAddr, usize>,
+    rejected: u64,
+}
+
+impl LimiterState {
+    fn reject(&mut self, rejection: Rejection) -> Rejection {
+        self.rejected += 1;
+        rejection
+    }
+
+    fn release(&mut self, ip: Option<IpAddr>) {
+        self.active -= 1;
+        if let Some(ip) = ip {
+            self.release_ip(ip);
+        }
+    }
+
+    fn release_ip(&mut self, ip: IpAddr) {
+        if let Some(active) = self.active_per_ip.get_mut(&ip) {
+            *active -= 1;
+            if *active == 0 {
+                self.active_per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    // Ocupa un lugar en los límites globales y en el del listener. Las conexiones sin IP
+    // (sockets Unix) solo cuentan para el total
+    pub fn try_acquire(
+        &self,
+        limits: ConnectionLimits,
+        listener: &ConnectionCounter,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionSlot, Rejection> {
+        let mut state = self.state.lock().unwrap();
+        if limits.max_connections != 0 && state.active >= limits.max_connections {
+            return Err(state.reject(Rejection::ServerFull(limits.max_connections)));
+        }
+        if let Some(ip) = ip {
+            let active = state.active_per_ip.get(&ip).copied().unwrap_or(0);
+            if limits.max_connections_per_ip != 0 && active >= limits.max_connections_per_ip {
+                return Err(state.reject(Rejection::IpFull(ip, limits.max_connections_per_ip)));
+            }
+        }
+        let mut slot = match listener.try_acquire() {
+            Some(slot) => slot,
+            None => return Err(state.reject(Rejection::ListenerFull(listener.max))),
+        };
+
+        state.active += 1;
+        if let Some(ip) = ip {
+            *state.active_per_ip.entry(ip).or_insert(0) += 1;
+        }
+        slot.limiter = Some((self.state.clone(), ip));
+        slot.max_connections_per_ip = limits.max_connections_per_ip;
+        Ok(slot)
+    }
+
+    // Para los rechazos que no pasan por try_acquire: IPs bloqueadas y conexiones que no
+    // mandan el Connect a tiempo
+    pub fn record_rejection(&self) {
+        self.state.lock().unwrap().rejected += 1;
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.state.lock().unwrap().rejected
+    }
+
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap().active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_addresses_are_bracketed() {
+        assert_eq!(
+            ListenerConfig::tcp("lan", "::1", 1883).bind_address(),
+            "[::1]:1883"
+        );
+        assert_eq!(
+            ListenerConfig::tcp("lan", "10.0.0.1", 1883).bind_address(),
+            "10.0.0.1:1883"
+        );
+    }
+
+    #[test]
+    fn tls_options_are_validated() {
+        let mut listener = ListenerConfig::tcp("tls", "0.0.0.0", 8883);
+        listener.tls_cert_file = Some("server.pem".to_string());
+        assert!(listener.validate().is_err());
+        listener.tls_key_file = Some("server.key".to_string());
+        assert!(listener.validate().is_ok());
+
+        listener.cert_cn_as_username = true;
+        assert!(listener.validate().is_err());
+        listener.tls_client_ca_file = Some("ca.pem".to_string());
+        assert!(listener.validate().is_ok());
+
+        listener.proxy_protocol = true;
+        assert!(listener.validate().is_err());
+        listener.proxy_protocol = false;
+        listener.transport = Transport::WebSocket("/mqtt".to_string());
+        assert!(listener.validate().is_err());
+    }
+
+    #[test]
+    fn counter_refuses_connections_over_the_limit_until_a_slot_is_released() {
+        let counter = ConnectionCounter::new(2);
+        let first = counter.try_acquire().unwrap();
+        let _second = counter.try_acquire().unwrap();
+        assert!(counter.try_acquire().is_none());
+
+        drop(first);
+        assert_eq!(counter.active(), 1);
+        assert!(counter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn limiter_enforces_the_global_and_per_ip_caps_and_counts_rejections() {
+        let limiter = ConnectionLimiter::default();
+        let limits = ConnectionLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+        };
+        let listener = ConnectionCounter::new(0);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "203.0.113.8".parse().unwrap();
+
+        let first = limiter.try_acquire(limits, &listener, Some(ip)).unwrap();
+        let _second = limiter.try_acquire(limits, &listener, Some(ip)).unwrap();
+        assert_eq!(
+            limiter.try_acquire(limits, &listener, Some(ip)).err(),
+            Some(Rejection::IpFull(ip, 2))
+        );
+        let _third = limiter.try_acquire(limits, &listener, Some(other)).unwrap();
+        assert_eq!(
+            limiter.try_acquire(limits, &listener, None).err(),
+            Some(Rejection::ServerFull(3))
+        );
+        assert_eq!(limiter.rejected(), 2);
+        assert_eq!(listener.active(), 3);
+
+        drop(first);
+        assert_eq!(limiter.active(), 2);
+        assert!(limiter.try_acquire(limits, &listener, Some(ip)).is_ok());
+    }
+
+    #[test]
+    fn a_slot_taken_without_ip_counts_for_the_ip_assigned_later() {
+        let limiter = ConnectionLimiter::default();
+        let limits = ConnectionLimits {
+            max_connections: 0,
+            max_connections_per_ip: 1,
+        };
+        let listener = ConnectionCounter::new(0);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let mut first = limiter.try_acquire(limits, &listener, None).unwrap();
+        let mut second = limiter.try_acquire(limits, &listener, None).unwrap();
+        assert!(first.assign_ip(ip).is_ok());
+        assert_eq!(second.assign_ip(ip), Err(Rejection::IpFull(ip, 1)));
+        assert_eq!(limiter.rejected(), 1);
+
+        drop(first);
+        assert!(second.assign_ip(ip).is_ok());
+    }
+
+    #[test]
+    fn a_full_listener_does_not_take_a_global_slot() {
+        let limiter = ConnectionLimiter::default();
+        let limits = ConnectionLimits::default();
+        let listener = ConnectionCounter::new(1);
+        let _slot = limiter.try_acquire(limits, &listener, None).unwrap();
+        assert_eq!(
+            limiter.try_acquire(limits, &listener, None).err(),
+            Some(Rejection::ListenerFull(1))
+        );
+        assert_eq!(limiter.active(), 1);
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let counter = ConnectionCounter::new(0);
+        let slots: Vec<ConnectionSlot> = (0..100).filter_map(|_| counter.try_acquire()).collect();
+        assert_eq!(slots.len(), 100);
+    }
+}
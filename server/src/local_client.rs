@@ -0,0 +1,417 @@
+use crate::server::{ArcSenderPacket, PacketResult};
+use common::all_packets::connack::CONNACK_CONNECTION_ACCEPTED;
+use common::all_packets::connect::{Connect, ConnectPayload};
+use common::all_packets::disconnect::Disconnect;
+use common::all_packets::puback::Puback;
+use common::all_packets::publish::{Publish, PublishFlags};
+use common::all_packets::suback::SubackReturnCode;
+use common::all_packets::subscribe::Subscribe;
+use common::all_packets::unsubscribe::Unsubscribe;
+use common::packet::{Packet, Qos, Subscription};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_RESPONSE_TIMEOUT_SECONDS: u64 = 5;
+const LOCAL_CLIENT_DROPPED_MSG: &str = "Local client dropped";
+const CONNECTION_CLOSED_ERROR_MSG: &str = "Connection closed by the server";
+const RESPONSE_TIMEOUT_ERROR_MSG: &str = "Timed out waiting for the server";
+const INVALID_TOPIC_ERROR_MSG: &str = "Invalid topic name";
+
+#[derive(Clone, Debug)]
+pub struct LastWill {
+    pub topic: String,
+    pub message: String,
+    pub qos: Qos,
+    pub retain: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct LocalClientOptions {
+    pub client_id: String,
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub last_will: Option<LastWill>,
+    // Cuánto se espera el Connack, Suback, Unsuback o Puback de cada operación
+    pub response_timeout: Duration,
+}
+
+impl LocalClientOptions {
+    pub fn new(client_id: &str) -> LocalClientOptions {
+        LocalClientOptions {
+            client_id: client_id.to_string(),
+            clean_session: true,
+            username: None,
+            password: None,
+            last_will: None,
+            response_timeout: Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECONDS),
+        }
+    }
+}
+
+// Cliente que vive en el mismo proceso que el server. Le habla al PacketProcessor por el
+// mismo channel que usan los ClientHandlerReader, así que tiene la misma semántica de sesión
+// que un cliente TCP. Si se dropea sin llamar a disconnect, se publica su last will
+pub struct LocalClient {
+    id: u32,
+    sender: Sender<(u32, PacketResult)>,
+    receiver: Receiver<PacketResult>,
+    inbox: VecDeque<Publish>,
+    next_packet_id: u16,
+    response_timeout: Duration,
+    connected: bool,
+}
+
+impl LocalClient {
+    pub(crate) fn connect(
+        id: u32,
+        senders_to_c_h_writers: &Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
+        sender: Sender<(u32, PacketResult)>,
+        options: LocalClientOptions,
+    ) -> Result<LocalClient, Box<dyn std::error::Error>> {
+        let (server_tx, receiver) = mpsc::channel::<PacketResult>();
+        senders_to_c_h_writers
+            .write()
+            .unwrap()
+            .insert(id, Arc::new(Mutex::new(server_tx)));
+
+        let mut client = LocalClient {
+            id,
+            sender,
+            receiver,
+            inbox: VecDeque::new(),
+            next_packet_id: 1,
+            response_timeout: options.response_timeout,
+            connected: true,
+        };
+
+        let (will_topic, will_message, will_qos, will_retain) = match options.last_will {
+            Some(will) => (
+                Some(will.topic),
+                Some(will.message),
+                will.qos == Qos::AtLeastOnce,
+                will.retain,
+            ),
+            None => (None, None, false, false),
+        };
+        let connect = Connect::new(
+            ConnectPayload::new(
+                options.client_id,
+                will_topic,
+                will_message,
+                options.username,
+                options.password,
+            ),
+            0,
+            options.clean_session,
+            will_retain,
+            will_qos,
+        );
+        client.send(Packet::Connect(connect))?;
+
+        let connack = client.wait_for(|packet| match packet {
+            Packet::Connack(connack) => Some(connack.connect_return_code),
+            _ => None,
+        })?;
+        if connack != CONNACK_CONNECTION_ACCEPTED {
+            // El PacketProcessor no lo cuenta como conectado y no va a cerrarlo: se saca el
+            // sender como en close_client_handler
+            client.connected = false;
+            senders_to_c_h_writers.write().unwrap().remove(&id);
+            return Err(format!("Connection refused with return code {}", connack).into());
+        }
+        Ok(client)
+    }
+
+    // Con QoS 1 vuelve recién cuando llega el Puback del server
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        message: &str,
+        qos: Qos,
+        retain: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if topic.is_empty() || topic.contains('+') || topic.contains('#') {
+            return Err(INVALID_TOPIC_ERROR_MSG.into());
+        }
+        let qos = if qos == Qos::AtMostOnce {
+            Qos::AtMostOnce
+        } else {
+            Qos::AtLeastOnce
+        };
+        let packet_id = match qos {
+            Qos::AtMostOnce => None,
+            _ => Some(self.take_packet_id()),
+        };
+        let publish = Publish::new(
+            PublishFlags {
+                duplicate: false,
+                qos_level: qos,
+                retain,
+            },
+            topic.to_string(),
+            packet_id,
+            message.to_string(),
+        );
+        self.send(Packet::Publish(publish))?;
+
+        if let Some(packet_id) = packet_id {
+            self.wait_for(|packet| match packet {
+                Packet::Puback(puback) if puback.packet_id == packet_id => Some(()),
+                _ => None,
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn subscribe(
+        &mut self,
+        topic_filters: &[(&str, Qos)],
+    ) -> Result<Vec<SubackReturnCode>, Box<dyn std::error::Error>> {
+        let packet_id = self.take_packet_id();
+        let mut subscribe = Subscribe::new(packet_id);
+        for (topic_filter, max_qos) in topic_filters {
+            subscribe.add_subscription(Subscription {
+                topic_filter: topic_filter.to_string(),
+                max_qos: *max_qos,
+            });
+        }
+        self.send(Packet::Subscribe(subscribe))?;
+
+        self.wait_for(|packet| match packet {
+            Packet::Suback(suback) if suback.packet_id == packet_id => {
+                Some(suback.return_codes.clone())
+            }
+            _ => None,
+        })
+    }
+
+    pub fn unsubscribe(
+        &mut self,
+        topic_filters: &[&str],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let packet_id = self.take_packet_id();
+        let mut unsubscribe = Unsubscribe::new(packet_id);
+        for topic_filter in topic_filters {
+            unsubscribe.add_topic(topic_filter.to_string());
+        }
+        self.send(Packet::Unsubscribe(unsubscribe))?;
+
+        self.wait_for(|packet| match packet {
+            Packet::Unsuback(unsuback) if unsuback.packet_id == packet_id => Some(()),
+            _ => None,
+        })
+    }
+
+    // Devuelve el próximo mensaje recibido en alguna suscripción, o None si no llegó nada
+    // en `timeout`. Los mensajes QoS 1 se confirman al server automáticamente
+    pub fn recv(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Publish>, Box<dyn std::error::Error>> {
+        if let Some(publish) = self.inbox.pop_front() {
+            return Ok(Some(publish));
+        }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(Ok(Packet::Publish(publish))) => {
+                self.acknowledge(&publish)?;
+                Ok(Some(publish))
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => Ok(None),
+            Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => {
+                self.connected = false;
+                Err(CONNECTION_CLOSED_ERROR_MSG.into())
+            }
+        }
+    }
+
+    // Desconexión prolija: el server descarta el last will
+    pub fn disconnect(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.connected = false;
+        self.send(Packet::Disconnect(Disconnect::new()))
+    }
+
+    fn send(&self, packet: Packet) -> Result<(), Box<dyn std::error::Error>> {
+        self.sender
+            .send((self.id, Ok(packet)))
+            .map_err(|_| CONNECTION_CLOSED_ERROR_MSG)?;
+        Ok(())
+    }
+
+    fn acknowledge(&self, publish: &Publish) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(packet_id) = publish.packet_id {
+            self.send(Packet::Puback(Puback::new(packet_id)))?;
+        }
+        Ok(())
+    }
+
+    // Espera la respuesta que reconoce `matches`. Los Publish que lleguen mientras tanto se
+    // guardan para devolverlos en recv
+    fn wait_for<T>(
+        &mut self,
+        matches: impl Fn(&Packet) -> Option<T>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let deadline = Instant::now() + self.response_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let packet = match self.receiver.recv_timeout(remaining) {
+                Ok(Ok(packet)) => packet,
+                Err(RecvTimeoutError::Timeout) => return Err(RESPONSE_TIMEOUT_ERROR_MSG.into()),
+                Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => {
+                    self.connected = false;
+                    return Err(CONNECTION_CLOSED_ERROR_MSG.into());
+                }
+            };
+            if let Some(response) = matches(&packet) {
+                return Ok(response);
+            }
+            if let Packet::Publish(publish) = packet {
+                self.acknowledge(&publish)?;
+                self.inbox.push_back(publish);
+            }
+        }
+    }
+
+    fn take_packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        packet_id
+    }
+}
+
+impl Drop for LocalClient {
+    // Para el PacketProcessor es lo mismo que un socket que se cortó
+    fn drop(&mut self) {
+        if self.connected {
+            let _ = self
+                .sender
+                .send((self.id, Err(Box::new(SendError(LOCAL_CLIENT_DROPPED_MSG)))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerHandle;
+    use crate::server_builder::ServerBuilder;
+    use common::all_packets::connack::{Connack, CONNACK_NOT_AUTHORIZED};
+    use std::env;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn start_server(name: &str) -> ServerHandle {
+        let log_file = env::temp_dir()
+            .join(format!("local_client_{}_{}.log", std::process::id(), name))
+            .to_string_lossy()
+            .to_string();
+        ServerBuilder::new().log_file(&log_file).start().unwrap()
+    }
+
+    #[test]
+    fn publish_reaches_local_subscriber() {
+        let server = start_server("publish");
+        let mut subscriber = server
+            .connect_local(LocalClientOptions::new("subscriber"))
+            .unwrap();
+        let return_codes = subscriber
+            .subscribe(&[("casa/#", Qos::AtLeastOnce)])
+            .unwrap();
+        assert_eq!(return_codes, vec![SubackReturnCode::SuccessAtLeastOnce]);
+
+        let mut publisher = server
+            .connect_local(LocalClientOptions::new("publisher"))
+            .unwrap();
+        publisher
+            .publish("casa/temperatura", "21", Qos::AtLeastOnce, false)
+            .unwrap();
+
+        let received = subscriber.recv(TIMEOUT).unwrap().unwrap();
+        assert_eq!(received.topic_name, "casa/temperatura");
+        assert_eq!(received.application_message, "21");
+        assert_eq!(received.flags.qos_level, Qos::AtLeastOnce);
+
+        subscriber.disconnect().unwrap();
+        publisher.disconnect().unwrap();
+        server.shutdown();
+    }
+
+    #[test]
+    fn dropping_client_publishes_last_will() {
+        let server = start_server("will");
+        let mut watcher = server
+            .connect_local(LocalClientOptions::new("watcher"))
+            .unwrap();
+        watcher.subscribe(&[("estado/#", Qos::AtMostOnce)]).unwrap();
+
+        let mut options = LocalClientOptions::new("gateway");
+        options.last_will = Some(LastWill {
+            topic: "estado/gateway".to_string(),
+            message: "offline".to_string(),
+            qos: Qos::AtMostOnce,
+            retain: false,
+        });
+        let gateway = server.connect_local(options).unwrap();
+        drop(gateway);
+
+        let will = watcher.recv(TIMEOUT).unwrap().unwrap();
+        assert_eq!(will.topic_name, "estado/gateway");
+        assert_eq!(will.application_message, "offline");
+        server.shutdown();
+    }
+
+    #[test]
+    fn unsubscribed_client_receives_nothing() {
+        let server = start_server("unsubscribe");
+        let mut client = server
+            .connect_local(LocalClientOptions::new("client"))
+            .unwrap();
+        client.subscribe(&[("a", Qos::AtMostOnce)]).unwrap();
+        client.unsubscribe(&["a"]).unwrap();
+        client.publish("a", "hola", Qos::AtMostOnce, false).unwrap();
+        assert!(client.recv(Duration::from_millis(300)).unwrap().is_none());
+        server.shutdown();
+    }
+
+    #[test]
+    fn refused_connect_removes_its_sender() {
+        let senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>> =
+            Arc::new(RwLock::new(HashMap::new()));
+        let (tx, rx) = mpsc::channel::<(u32, PacketResult)>();
+        let senders = senders_to_c_h_writers.clone();
+        // Hace de PacketProcessor: rechaza el Connect
+        let processor = thread::spawn(move || {
+            let (id, _connect) = rx.recv().unwrap();
+            let sender = senders.read().unwrap().get(&id).cloned().unwrap();
+            let connack = Connack::new(false, CONNACK_NOT_AUTHORIZED);
+            sender
+                .lock()
+                .unwrap()
+                .send(Ok(Packet::Connack(connack)))
+                .unwrap();
+        });
+
+        let result = LocalClient::connect(
+            7,
+            &senders_to_c_h_writers,
+            tx,
+            LocalClientOptions::new("client"),
+        );
+        processor.join().unwrap();
+        assert!(result.is_err());
+        assert!(senders_to_c_h_writers.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wildcard_topic_cannot_be_published() {
+        let server = start_server("wildcard");
+        let mut client = server
+            .connect_local(LocalClientOptions::new("client"))
+            .unwrap();
+        assert!(client.publish("a/#", "x", Qos::AtMostOnce, false).is_err());
+        server.shutdown();
+    }
+}
@@ -1,4 +1,4 @@
-use common::logging::logger::Logger;
+use common::logging::logger::{self, Logger};
 use server::config::Config;
 use server::server::Server;
 use std::env;
@@ -10,8 +10,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         eprintln!("Error al leer parametros: {}", err);
         process::exit(1);
     });
-    let logger = Logger::new(&config.log_filename);
-    let server = Server::new(config, Arc::new(logger?))?;
+    if config.check_only {
+        print!("{}", config);
+        return Ok(());
+    }
+    let logger = Arc::new(Logger::from_config(&config.log)?);
+    logger::set_global_logger(logger.clone());
+    let server = Server::new(config, logger)?;
     server.server_run()?;
 
     Ok(())
@@ -0,0 +1,239 @@
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::listener::ConnectionLimiter;
+use crate::stats::BrokerStats;
+use std::fmt::Write;
+use std::net::TcpListener;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+const DEFAULT_METRICS_ADDRESS: &str = "127.0.0.1";
+const DEFAULT_METRICS_PORT: u16 = 9234;
+const METRICS_PATH: &str = "/metrics";
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            address: DEFAULT_METRICS_ADDRESS.to_string(),
+            port: DEFAULT_METRICS_PORT,
+        }
+    }
+}
+
+// Sirve GET /metrics en el formato de texto de Prometheus
+pub fn spawn(
+    listener: TcpListener,
+    stats: Arc<BrokerStats>,
+    connection_limiter: ConnectionLimiter,
+    shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    http::serve(listener, shutdown, move |request: &HttpRequest| {
+        if request.path != METRICS_PATH {
+            return HttpResponse::not_found();
+        }
+        if request.method != "GET" {
+            return HttpResponse::new(405, "text/plain", "Method Not Allowed\n".to_string());
+        }
+        HttpResponse::new(
+            200,
+            PROMETHEUS_CONTENT_TYPE,
+            render(&stats, &connection_limiter),
+        )
+    })
+}
+
+pub fn render(stats: &BrokerStats, connection_limiter: &ConnectionLimiter) -> String {
+    let gauges = stats.gauges();
+    let mut out = String::new();
+    let by_type = |counts: Vec<(&str, u64)>| -> Vec<(String, String)> {
+        counts
+            .into_iter()
+            .map(|(name, count)| (format!("type=\"{}\"", name), count.to_string()))
+            .collect()
+    };
+
+    family(
+        &mut out,
+        "mqtt_packets_received_total",
+        "counter",
+        "MQTT packets received from clients, by type",
+        by_type(stats.packets_received()),
+    );
+    family(
+        &mut out,
+        "mqtt_packets_sent_total",
+        "counter",
+        "MQTT packets sent to clients, by type",
+        by_type(stats.packets_sent()),
+    );
+    let simple: Vec<(&str, &str, &str, String)> = vec![
+        (
+            "mqtt_bytes_received_total",
+            "counter",
+            "Bytes of MQTT packets received",
+            stats.bytes_received().to_string(),
+        ),
+        (
+            "mqtt_bytes_sent_total",
+            "counter",
+            "Bytes of MQTT packets sent",
+            stats.bytes_sent().to_string(),
+        ),
+        (
+            "mqtt_messages_dropped_total",
+            "counter",
+            "Messages that could not be delivered to someone",
+            stats.messages_dropped().to_string(),
+        ),
+        (
+            "mqtt_retransmissions_total",
+            "counter",
+            "QoS 1 publishes sent again for lack of a PUBACK",
+            stats.retransmissions().to_string(),
+        ),
+        (
+            "mqtt_auth_failures_total",
+            "counter",
+            "CONNECT packets refused by the authenticator",
+            stats.auth_failures().to_string(),
+        ),
+        (
+            "mqtt_connections_active",
+            "gauge",
+            "Open connections on all listeners",
+            connection_limiter.active().to_string(),
+        ),
+        (
+            "mqtt_connections_rejected_total",
+            "counter",
+            "Connections refused by the connection limits or closed before CONNECT",
+            connection_limiter.rejected().to_string(),
+        ),
+        (
+            "mqtt_clients_connected",
+            "gauge",
+            "Clients with an accepted CONNECT",
+            gauges.clients_connected.to_string(),
+        ),
+        (
+            "mqtt_clients_disconnected",
+            "gauge",
+            "Persistent sessions without a connected client",
+            gauges.clients_disconnected.to_string(),
+        ),
+        (
+            "mqtt_subscriptions",
+            "gauge",
+            "Subscriptions of all sessions",
+            gauges.subscriptions.to_string(),
+        ),
+        (
+            "mqtt_retained_messages",
+            "gauge",
+            "Retained messages",
+            gauges.retained_messages.to_string(),
+        ),
+    ];
+    for (name, kind, help, value) in simple {
+        family(&mut out, name, kind, help, vec![(String::new(), value)]);
+    }
+    family(
+        &mut out,
+        "mqtt_client_queued_messages",
+        "gauge",
+        "QoS 1 publishes waiting for the PUBACK of each client",
+        gauges
+            .client_queues
+            .iter()
+            .map(|(client_id, queued)| {
+                (
+                    format!("client_id=\"{}\"", escape_label(client_id)),
+                    queued.to_string(),
+                )
+            })
+            .collect(),
+    );
+
+    let latency = stats.publish_latency();
+    let name = "mqtt_publish_latency_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time to hand a PUBLISH to every subscriber",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, count) in latency.cumulative_buckets() {
+        let bound = if bound.is_infinite() {
+            "+Inf".to_string()
+        } else {
+            bound.to_string()
+        };
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+    }
+    let _ = writeln!(out, "{}_sum {}", name, latency.sum_seconds());
+    let _ = writeln!(out, "{}_count {}", name, latency.count());
+    out
+}
+
+// Escribe HELP, TYPE y una línea por cada par (labels, valor)
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, String)>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::BrokerGauges;
+    use common::all_packets::pingreq::Pingreq;
+    use common::packet::Packet;
+    use std::time::Duration;
+
+    #[test]
+    fn render_uses_the_prometheus_text_format() {
+        let stats = BrokerStats::new();
+        stats.packet_received(&Packet::Pingreq(Pingreq::new()));
+        stats.auth_failure();
+        stats.publish_processed(Duration::from_micros(300));
+        stats.set_gauges(BrokerGauges {
+            client_queues: vec![("sensor \"1\"".to_string(), 3)],
+            ..BrokerGauges::default()
+        });
+        let limiter = ConnectionLimiter::default();
+
+        let text = render(&stats, &limiter);
+        assert!(text.contains("# TYPE mqtt_packets_received_total counter\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"pingreq\"} 1\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"publish\"} 0\n"));
+        assert!(text.contains("mqtt_auth_failures_total 1\n"));
+        assert!(text.contains("mqtt_connections_active 0\n"));
+        assert!(text.contains("mqtt_client_queued_messages{client_id=\"sensor \\\"1\\\"\"} 3\n"));
+        assert!(text.contains("mqtt_publish_latency_seconds_bucket{le=\"0.0001\"} 0\n"));
+        assert!(text.contains("mqtt_publish_latency_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("mqtt_publish_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("mqtt_publish_latency_seconds_count 1\n"));
+    }
+}
@@ -1,11 +1,19 @@
-use crate::authenticator::Authenticator;
+use crate::admin::{self, AdminCommand, AdminError, AdminReply, AdminRequest};
+use crate::audit::{AuditLog, PubackDirection};
+use crate::auth_limiter::Ban;
+use crate::client_events::{self, ConnectionInfo, EventReason};
+use crate::client_handler::CONNECT_TIMEOUT_ERROR_MSG;
+use crate::hooks::{BrokerHook, HookDecision};
+use crate::keep_alive::{Clock, KeepAliveTracker, SystemClock};
+use crate::persistence::{self, PersistedSession, PersistedState};
 use crate::puback_processor::PubackProcessor;
-use crate::server::{ArcSenderPacket, PacketResult};
+use crate::reloader::ReloadableState;
+use crate::server::{ArcSenderPacket, ClientAddresses, PacketResult};
 use crate::session::Session;
+use crate::stats::{BrokerGauges, BrokerStats};
+use crate::sys_topics::{self, SysTopics};
 use crate::topic_filters;
-use common::all_packets::connack::{
-    Connack, CONNACK_BAD_USERNAME_OR_PASSWORD, CONNACK_CONNECTION_ACCEPTED,
-};
+use common::all_packets::connack::{Connack, CONNACK_CONNECTION_ACCEPTED, CONNACK_NOT_AUTHORIZED};
 use common::all_packets::connect::Connect;
 use common::all_packets::pingreq::Pingreq;
 use common::all_packets::pingresp::Pingresp;
@@ -18,14 +26,21 @@ use common::all_packets::unsubscribe::Unsubscribe;
 use common::logging::logger::{LogMessage, Logger};
 use common::packet::{Packet, Qos};
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
+use std::io::Error;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, SendError, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SendError, Sender};
 use std::sync::{Arc, RwLock};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const PACKETS_ID: u16 = 100;
+const RECV_TIMEOUT: Duration = Duration::from_millis(250);
+const SHUTDOWN_DISCONNECT_MSG: &str = "Server shutting down";
+// Cada cuánto se actualizan los valores de BrokerStats que salen de las sesiones
+const GAUGES_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     pub message: String,
     pub qos: Qos,
@@ -34,13 +49,32 @@ pub struct Message {
 pub struct PacketProcessor {
     sessions: HashMap<String, Session>,
     rx: Receiver<(u32, PacketResult)>,
-    tx_to_puback_processor: Sender<(u32, PacketResult)>,
+    tx_to_puback_processor: Option<Sender<(u32, PacketResult)>>,
     rx_from_packet_processor: Option<Receiver<(u32, PacketResult)>>,
     senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
     logger: Arc<Logger>,
     retained_messages: HashMap<String, Message>,
     packets_id: HashMap<u16, bool>,
-    authenticator: Authenticator,
+    // Configuración recargable, bans y conexiones abiertas
+    state: ReloadableState,
+    clock: Arc<dyn Clock>,
+    keep_alives: KeepAliveTracker,
+    stats: Arc<BrokerStats>,
+    gauges_refreshed_at: Option<Instant>,
+    // Con None no se publica el árbol $SYS/broker/...
+    sys_topics: Option<SysTopics>,
+    // Eventos $SYS/clients/... que se publican recién después de mandar la respuesta, así un
+    // cliente no recibe un Publish antes que su Connack
+    pending_events: Vec<(String, String)>,
+    // Pedidos de la API de administración, si está habilitada
+    admin_rx: Option<Receiver<AdminRequest>>,
+    // Registro de publicaciones y entregas, si está habilitado
+    audit: Option<AuditLog>,
+    // Se llaman en este orden, ver hooks::BrokerHook
+    hooks: Vec<Arc<dyn BrokerHook>>,
+    client_addresses: ClientAddresses,
+    shutdown: Arc<AtomicBool>,
+    persistence_file: Option<String>,
 }
 
 impl PacketProcessor {
@@ -48,54 +82,227 @@ impl PacketProcessor {
         rx: Receiver<(u32, PacketResult)>,
         senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
         logger: Arc<Logger>,
+        state: ReloadableState,
+        client_addresses: ClientAddresses,
+        packet_ids: u16,
+        shutdown: Arc<AtomicBool>,
     ) -> PacketProcessor {
         let (tx_to_puback_processor, rx_from_packet_processor) = mpsc::channel();
         let mut packets: HashMap<u16, bool> = HashMap::new();
-        for i in 0..PACKETS_ID {
+        for i in 0..packet_ids {
             packets.insert(i, false);
         }
-        let authenticator = Authenticator::from("accounts.txt".to_string()).unwrap();
         PacketProcessor {
             sessions: HashMap::<String, Session>::new(),
             rx,
-            tx_to_puback_processor,
+            tx_to_puback_processor: Some(tx_to_puback_processor),
             rx_from_packet_processor: Some(rx_from_packet_processor),
             senders_to_c_h_writers,
             logger,
             retained_messages: HashMap::<String, Message>::new(),
             packets_id: packets,
-            authenticator,
+            state,
+            clock: Arc::new(SystemClock),
+            keep_alives: KeepAliveTracker::new(Arc::new(SystemClock)),
+            stats: Arc::new(BrokerStats::new()),
+            gauges_refreshed_at: None,
+            sys_topics: None,
+            pending_events: vec![],
+            admin_rx: None,
+            audit: None,
+            hooks: vec![],
+            client_addresses,
+            shutdown,
+            persistence_file: None,
         }
     }
 
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.keep_alives = KeepAliveTracker::new(clock.clone());
+        self.clock = clock;
+    }
+
+    // Las mismas estadísticas que se publican en $SYS, para el listener de métricas
+    pub fn stats(&self) -> Arc<BrokerStats> {
+        self.stats.clone()
+    }
+
+    // Publica las estadísticas en $SYS/broker/... cada `interval`, como mensajes retenidos
+    pub fn enable_sys_topics(&mut self, interval: Duration) {
+        self.sys_topics = Some(SysTopics::new(interval, self.clock.now()));
+    }
+
+    pub fn enable_audit(&mut self, audit: AuditLog) {
+        self.audit = Some(audit);
+    }
+
+    pub fn add_hook(&mut self, hook: Arc<dyn BrokerHook>) {
+        self.hooks.push(hook);
+    }
+
+    // Devuelve por dónde el listener de administración le manda sus pedidos
+    pub fn enable_admin(&mut self) -> Sender<AdminRequest> {
+        let (tx, rx) = mpsc::channel();
+        self.admin_rx = Some(rx);
+        tx
+    }
+
+    // Carga el estado guardado en el último apagado (si existe) y lo vuelve a guardar ahí
+    // al apagar el server
+    pub fn enable_persistence(&mut self, path: String) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(state) = persistence::load(&path)? {
+            for (topic, message) in state.retained_messages {
+                self.retained_messages.insert(topic, message);
+            }
+            for persisted in state.sessions {
+                for publish in &persisted.unacknowledged_messages {
+                    if let Some(packet_id) = publish.packet_id {
+                        self.packets_id.insert(packet_id, true);
+                    }
+                }
+                let session = Session::restore(
+                    persisted.client_id.clone(),
+                    persisted.subscriptions(),
+                    persisted.unacknowledged_messages,
+                );
+                self.sessions.insert(persisted.client_id, session);
+            }
+            self.logger.log_msg(LogMessage::new(
+                format!("State restored from {}", path),
+                "".to_string(),
+            ))?;
+        }
+        self.persistence_file = Some(path);
+        Ok(())
+    }
+
     pub fn run(mut self) -> JoinHandle<()> {
         thread::spawn(move || {
             let senders_to_c_h_writers = self.senders_to_c_h_writers.clone();
             let rx_from_packet_processor = self.rx_from_packet_processor.take().unwrap();
+            let stats = self.stats.clone();
             let puback_proc_handle = thread::spawn(move || {
                 let puback_processor =
-                    PubackProcessor::new(senders_to_c_h_writers, rx_from_packet_processor);
+                    PubackProcessor::new(senders_to_c_h_writers, rx_from_packet_processor, stats);
                 puback_processor.run();
             });
 
-            while let Ok((c_h_id, packet)) = self.rx.recv() {
-                match packet {
-                    Ok(packet) => {
-                        if self.process_packet(packet, c_h_id).is_err() {
-                            self.handle_disconnect_error(c_h_id);
-                        }
-                    }
-                    Err(_e) => {
-                        self.handle_disconnect_error(c_h_id);
-                    }
+            while !self.shutdown.load(Ordering::SeqCst) {
+                match self.rx.recv_timeout(RECV_TIMEOUT) {
+                    Ok((c_h_id, packet)) => self.handle_received(c_h_id, packet),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
                 }
+                self.handle_admin_requests();
+                self.disconnect_expired_sessions();
+                self.disconnect_expired_keep_alives();
+                self.publish_sys_topics();
+                self.refresh_gauges();
             }
 
+            self.shutdown();
+            // Sin sender, el PubackProcessor sale de su loop
+            drop(self.tx_to_puback_processor.take());
             puback_proc_handle.join().unwrap();
         })
     }
 
-    pub fn handle_disconnect(&mut self, c_h_id: u32) {
+    fn handle_received(&mut self, c_h_id: u32, packet: PacketResult) {
+        match packet {
+            Ok(packet) => {
+                self.keep_alives.packet_received(c_h_id);
+                self.stats.packet_received(&packet);
+                if self.process_packet(packet, c_h_id).is_err() {
+                    self.handle_disconnect_error(c_h_id, EventReason::SocketError);
+                }
+            }
+            Err(e) => {
+                if e.to_string() == CONNECT_TIMEOUT_ERROR_MSG {
+                    self.log_connect_timeout(c_h_id);
+                }
+                self.handle_disconnect_error(c_h_id, EventReason::SocketError);
+            }
+        }
+    }
+
+    // Las conexiones que no mandan el Connect a tiempo cuentan como rechazadas
+    fn log_connect_timeout(&self, c_h_id: u32) {
+        self.state.connection_limiter.record_rejection();
+        let address = match self.client_addresses.read().unwrap().get(&c_h_id) {
+            Some(address) => address.to_string(),
+            None => "a unix socket".to_string(),
+        };
+        let _ = self.logger.log_msg(LogMessage::warn(
+            format!(
+                "Connection from {} closed: {} ({} rejected so far)",
+                address,
+                CONNECT_TIMEOUT_ERROR_MSG,
+                self.state.connection_limiter.rejected()
+            ),
+            "".to_string(),
+        ));
+    }
+
+    // Procesa lo que ya habían mandado los clientes, guarda el estado y cierra cada client
+    // handler. Los writers mandan todo lo que tienen encolado antes de cerrar el socket
+    fn shutdown(&mut self) {
+        let _ = self.logger.log_msg(LogMessage::new(
+            "Shutting down, closing client connections".to_string(),
+            "".to_string(),
+        ));
+        while let Ok((c_h_id, packet)) = self.rx.try_recv() {
+            self.handle_received(c_h_id, packet);
+        }
+
+        if let Some(path) = self.persistence_file.clone() {
+            let result = persistence::save(&path, &self.persisted_state());
+            let msg = match result {
+                Ok(()) => format!("State saved to {}", path),
+                Err(e) => format!("Could not save state to {}: {}", path, e),
+            };
+            let _ = self.logger.log_msg(LogMessage::new(msg, "".to_string()));
+        }
+
+        // El server cierra la conexión a propósito, así que no se publican los last will
+        let mut senders_hash = self.senders_to_c_h_writers.write().unwrap();
+        for (_, sender) in senders_hash.drain() {
+            let _ = sender
+                .lock()
+                .unwrap()
+                .send(Err(Box::new(SendError(SHUTDOWN_DISCONNECT_MSG))));
+        }
+        self.client_addresses.write().unwrap().clear();
+    }
+
+    fn persisted_state(&self) -> PersistedState {
+        PersistedState {
+            // Los $SYS se vuelven a publicar al arrancar
+            retained_messages: self
+                .retained_messages
+                .iter()
+                .filter(|(topic, _)| !sys_topics::is_sys_topic(topic))
+                .map(|(topic, message)| (topic.clone(), message.clone()))
+                .collect(),
+            sessions: self
+                .sessions
+                .values()
+                .filter(|session| !session.is_clean_session)
+                .map(|session| PersistedSession {
+                    client_id: session.get_client_id().clone(),
+                    subscriptions: session
+                        .get_subscriptions()
+                        .iter()
+                        .map(|s| (s.topic_filter.clone(), s.max_qos))
+                        .collect(),
+                    unacknowledged_messages: session.unacknowledged_messages.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn handle_disconnect(&mut self, c_h_id: u32, reason: EventReason) {
+        self.keep_alives.stop(c_h_id);
+        let connection = self.connection_info(c_h_id);
         // La session que tenía dicho c_h_id y era clean, debe eliminarse
         self.sessions.retain(|_, session| {
             !(session.get_client_handler_id() == Some(c_h_id) && session.is_clean_session)
@@ -108,20 +315,296 @@ impl PacketProcessor {
             .filter(|(_, session)| session.get_client_handler_id() == Some(c_h_id))
             .for_each(|(_, session)| session.disconnect());
 
+        self.close_client_handler(c_h_id);
+        // Se publica con la sesión ya cerrada, así el evento no le llega al cliente que se fue
+        if let Some(connection) = connection {
+            for hook in &self.hooks {
+                hook.on_disconnect(&connection, reason);
+            }
+            let (topic, payload) =
+                client_events::disconnected(&connection, reason, client_events::unix_timestamp());
+            self.publish_from_server(topic, payload, false);
+        }
+    }
+
+    fn close_client_handler(&mut self, c_h_id: u32) {
         // Eliminamos el sender al c_h del hash ya que se va a dropear ese c_h
         let mut senders_hash = self.senders_to_c_h_writers.write().unwrap();
         if let Some(sender) = senders_hash.remove(&c_h_id) {
             // Le mandamos al c_h_w que se cierre
-            sender
+            // Si el c_h ya no existe (por ejemplo, un cliente local que se dropeó) no hay a quién avisarle
+            let _ = sender
                 .lock()
                 .unwrap()
-                .send(Err(Box::new(SendError("Socket Disconnect"))))
-                .unwrap();
-            println!("se mando el error este pp, linea 114");
+                .send(Err(Box::new(SendError("Socket Disconnect"))));
+            let _ = self.logger.log_msg(LogMessage::trace(
+                format!("Client handler {} asked to close", c_h_id),
+                "".to_string(),
+            ));
+        }
+        self.client_addresses.write().unwrap().remove(&c_h_id);
+    }
+
+    // Desconecta a los clientes cuyo token JWT ya venció
+    fn disconnect_expired_sessions(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let expired: Vec<(String, u32)> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_active() && session.token_expired(now))
+            .map(|(client_id, session)| {
+                (client_id.clone(), session.get_client_handler_id().unwrap())
+            })
+            .collect();
+
+        for (client_id, c_h_id) in expired {
+            let _ = self.logger.log_msg(LogMessage::warn(
+                "Token expired, disconnecting:".to_string(),
+                client_id,
+            ));
+            self.handle_disconnect_error(c_h_id, EventReason::TokenExpired);
+        }
+    }
+
+    // Se cierra la conexión como si se hubiera caído la red, así que se publica el will
+    fn disconnect_expired_keep_alives(&mut self) {
+        for (c_h_id, timeout) in self.keep_alives.take_expired() {
+            let client_id = self
+                .get_client_id_from_handler_id(c_h_id)
+                .unwrap_or_default();
+            let _ = self.logger.log_msg(LogMessage::warn(
+                format!(
+                    "Keep alive expired (no packets in {:.1}s), disconnecting:",
+                    timeout.as_secs_f64()
+                ),
+                client_id,
+            ));
+            self.handle_disconnect_error(c_h_id, EventReason::KeepAliveTimeout);
+        }
+    }
+
+    fn publish_sys_topics(&mut self) {
+        let now = self.clock.now();
+        let due = match &mut self.sys_topics {
+            Some(sys_topics) => sys_topics.due(now),
+            None => false,
+        };
+        if !due {
+            return;
+        }
+        let gauges = self.gauges();
+        let messages = match &self.sys_topics {
+            Some(sys_topics) => sys_topics.messages(now, &self.stats, &gauges),
+            None => return,
+        };
+        for (topic, value) in messages {
+            self.publish_from_server(topic, value, true);
+        }
+    }
+
+    // Publica con QoS 0 un mensaje generado por el server (tópicos $SYS)
+    fn publish_from_server(&mut self, topic: String, message: String, retain: bool) {
+        let publish_packet = Publish::new(
+            PublishFlags {
+                duplicate: false,
+                qos_level: Qos::AtMostOnce,
+                retain,
+            },
+            topic,
+            None,
+            message,
+        );
+        let _ = self.handle_publish_packet(publish_packet);
+    }
+
+    fn publish_pending_events(&mut self) {
+        for (topic, payload) in std::mem::take(&mut self.pending_events) {
+            self.publish_from_server(topic, payload, false);
+        }
+    }
+
+    fn handle_admin_requests(&mut self) {
+        let requests: Vec<AdminRequest> = match &self.admin_rx {
+            Some(rx) => rx.try_iter().collect(),
+            None => return,
+        };
+        for (command, reply_tx) in requests {
+            let reply = self.handle_admin_command(command);
+            // Si el listener ya no espera la respuesta no hay a quién avisarle
+            let _ = reply_tx.send(reply);
+        }
+    }
+
+    fn handle_admin_command(&mut self, command: AdminCommand) -> AdminReply {
+        let _ = self.logger.log_msg(LogMessage::new(
+            format!("Admin request: {:?}", command),
+            "".to_string(),
+        ));
+        match command {
+            AdminCommand::ListClients => {
+                let mut sessions: Vec<&Session> = self.sessions.values().collect();
+                sessions.sort_by(|a, b| a.get_client_id().cmp(b.get_client_id()));
+                let addresses = self.client_addresses.read().unwrap();
+                let clients: Vec<serde_json::Value> = sessions
+                    .into_iter()
+                    .map(|session| {
+                        let address = session
+                            .get_client_handler_id()
+                            .and_then(|c_h_id| addresses.get(&c_h_id).copied());
+                        admin::client_json(session, address)
+                    })
+                    .collect();
+                Ok(clients.into())
+            }
+            AdminCommand::GetClient(client_id) => {
+                let session = self
+                    .sessions
+                    .get(&client_id)
+                    .ok_or_else(|| unknown_client(&client_id))?;
+                let address = session
+                    .get_client_handler_id()
+                    .and_then(|c_h_id| self.client_addresses.read().unwrap().get(&c_h_id).copied());
+                Ok(admin::client_json(session, address))
+            }
+            AdminCommand::DisconnectClient(client_id) => {
+                let c_h_id = self
+                    .sessions
+                    .get(&client_id)
+                    .and_then(|session| session.get_client_handler_id())
+                    .ok_or_else(|| {
+                        AdminError::not_found(format!("Client {} is not connected", client_id))
+                    })?;
+                let _ = self.logger.log_msg(LogMessage::new(
+                    "Disconnected by an administrator:".to_string(),
+                    client_id.clone(),
+                ));
+                // Como con cualquier corte que no pidió el cliente, se publica el will
+                self.handle_disconnect_error(c_h_id, EventReason::AdminDisconnect);
+                Ok(serde_json::json!({ "disconnected": client_id }))
+            }
+            AdminCommand::ListRetained => {
+                let mut topics: Vec<&String> = self.retained_messages.keys().collect();
+                topics.sort();
+                let retained: Vec<serde_json::Value> = topics
+                    .into_iter()
+                    .map(|topic| admin::retained_json(topic, &self.retained_messages[topic]))
+                    .collect();
+                Ok(retained.into())
+            }
+            AdminCommand::DeleteRetained(topic) => match self.retained_messages.remove(&topic) {
+                Some(_) => Ok(serde_json::json!({ "deleted": 1 })),
+                None => Err(AdminError::not_found(format!(
+                    "No retained message on {}",
+                    topic
+                ))),
+            },
+            AdminCommand::ClearRetained => {
+                let deleted = self.retained_messages.len();
+                self.retained_messages.clear();
+                Ok(serde_json::json!({ "deleted": deleted }))
+            }
+            AdminCommand::Publish {
+                topic,
+                message,
+                qos,
+                retain,
+            } => {
+                // $SYS sigue reservado para lo que genera el server
+                if sys_topics::is_sys_topic(&topic) {
+                    return Err(AdminError::bad_request(format!(
+                        "{} is reserved for the server",
+                        topic
+                    )));
+                }
+                if qos == 1 {
+                    self.publish_qos1_from_server(topic.clone(), message, retain)?;
+                } else {
+                    self.publish_from_server(topic.clone(), message, retain);
+                }
+                Ok(serde_json::json!({ "published": topic }))
+            }
+            // Los atiende el listener de administración sin pasar por acá
+            AdminCommand::Stats | AdminCommand::Reload => Err(AdminError {
+                status: 500,
+                message: format!("{:?} is not handled by the packet processor", command),
+            }),
+        }
+    }
+
+    // Los QoS 1 necesitan un packet id libre, como los will
+    fn publish_qos1_from_server(
+        &mut self,
+        topic: String,
+        message: String,
+        retain: bool,
+    ) -> Result<(), AdminError> {
+        let packet_id = PacketProcessor::find_key_for_value(self.packets_id.clone(), false)
+            .ok_or_else(|| AdminError {
+                status: 503,
+                message: "No free packet ids".to_string(),
+            })?;
+        self.packets_id.insert(packet_id, true);
+        let publish_packet = Publish::new(
+            PublishFlags {
+                duplicate: false,
+                qos_level: Qos::AtLeastOnce,
+                retain,
+            },
+            topic,
+            Some(packet_id),
+            message,
+        );
+        let _ = self.handle_publish_packet(publish_packet);
+        Ok(())
+    }
+
+    fn connection_info(&self, c_h_id: u32) -> Option<ConnectionInfo> {
+        let session = self
+            .sessions
+            .values()
+            .find(|session| session.get_client_handler_id() == Some(c_h_id))?;
+        Some(session_connection_info(session, &self.client_addresses))
+    }
+
+    fn refresh_gauges(&mut self) {
+        let now = Instant::now();
+        if let Some(refreshed_at) = self.gauges_refreshed_at {
+            if now.duration_since(refreshed_at) < GAUGES_REFRESH_INTERVAL {
+                return;
+            }
+        }
+        self.gauges_refreshed_at = Some(now);
+        self.stats.set_gauges(self.gauges());
+    }
+
+    fn gauges(&self) -> BrokerGauges {
+        let connected = self.sessions.values().filter(|s| s.is_active()).count();
+        BrokerGauges {
+            clients_connected: connected,
+            clients_disconnected: self.sessions.len() - connected,
+            subscriptions: self
+                .sessions
+                .values()
+                .map(|session| session.get_subscriptions().len())
+                .sum(),
+            retained_messages: self.retained_messages.len(),
+            client_queues: self
+                .sessions
+                .values()
+                .map(|session| {
+                    (
+                        session.get_client_id().clone(),
+                        session.unacknowledged_messages.len(),
+                    )
+                })
+                .collect(),
         }
     }
 
-    pub fn handle_disconnect_error(&mut self, c_h_id: u32) {
+    pub fn handle_disconnect_error(&mut self, c_h_id: u32, reason: EventReason) {
         // Obtenemos la session del c_h_id. Si no existe, es porque ya se desconectó el client con Disconnect
         let session = match self
             .sessions
@@ -129,13 +612,32 @@ impl PacketProcessor {
             .find(|(_id, session)| session.get_client_handler_id() == Some(c_h_id))
         {
             Some((_client_id, session)) => session,
-            None => return,
+            // Igual hay que cerrar el writer, por ejemplo si se cortó la conexión antes del Connect
+            None => {
+                self.handle_disconnect(c_h_id, reason);
+                return;
+            }
         };
 
-        println!("Session: {:?}", session);
+        let _ = self.logger.log_msg(LogMessage::trace(
+            format!("Session: {:?}", session),
+            session.get_client_id().clone(),
+        ));
+
+        // El will tampoco puede ir a $SYS
+        let will_topic = session.last_will_topic.clone().unwrap_or_default();
+        if session.last_will_msg.is_some() && sys_topics::is_sys_topic(&will_topic) {
+            let _ = self.logger.log_msg(LogMessage::warn(
+                format!("Last will to {} denied for:", will_topic),
+                session.get_client_id().clone(),
+            ));
+            self.stats.message_dropped();
+            self.handle_disconnect(c_h_id, reason);
+            return;
+        }
 
         // Si hay last will
-        if session.last_will_msg.is_some() {
+        if let Some(last_will_msg) = &session.last_will_msg {
             // Mandamos el publish con el last will msg al last will topic
             let mut p = None;
             if let Some(level) = session.last_will_qos {
@@ -159,15 +661,18 @@ impl PacketProcessor {
                 },
                 session.last_will_topic.as_ref().unwrap().clone(),
                 p,
-                session.last_will_msg.as_ref().unwrap().clone(),
+                last_will_msg.clone(),
             );
 
-            print!("Voy a mandar el publish last will {:?}", publish_packet);
+            let _ = self.logger.log_msg(LogMessage::debug(
+                format!("Sending the last will to {} of:", publish_packet.topic_name),
+                session.get_client_id().clone(),
+            ));
 
             self.handle_publish_packet(publish_packet).unwrap();
         }
 
-        self.handle_disconnect(c_h_id);
+        self.handle_disconnect(c_h_id, reason);
     }
 
     pub fn process_packet(
@@ -180,37 +685,76 @@ impl PacketProcessor {
             client_id = c_id;
         }
 
+        let mut keep_alive_seconds = 0;
         let response_packet = match packet {
             Packet::Connect(connect_packet) => {
+                keep_alive_seconds = connect_packet.keep_alive_seconds;
+                // Con PROXY protocol, la dirección ya es la del cliente real
+                let message = match self.client_addresses.read().unwrap().get(&c_h_id) {
+                    Some(address) => format!("Connect Packet received ({}) from:", address),
+                    None => "Connect Packet received from:".to_string(),
+                };
                 self.logger.log_msg(LogMessage::new(
-                    "Connect Packet received from:".to_string(),
+                    message,
                     connect_packet.connect_payload.client_id.clone(),
                 ))?;
-                println!("Recibi el Connect (en process_pracket)");
                 let connack_packet = self.handle_connect_packet(connect_packet, c_h_id)?;
                 Some(Ok(Packet::Connack(connack_packet)))
             }
 
-            Packet::Publish(publish_packet) => {
-                self.logger.log_msg(LogMessage::new(
+            Packet::Publish(mut publish_packet) => {
+                self.logger.log_msg(LogMessage::debug(
                     "Publish Packet received from:".to_string(),
-                    client_id,
+                    client_id.clone(),
                 ))?;
-                let puback_packet = self.handle_publish_packet(publish_packet)?;
-                puback_packet.map(|puback_packet| Ok(Packet::Puback(puback_packet)))
+                let dropped_by_hook =
+                    self.run_publish_hooks(c_h_id, &mut publish_packet) == HookDecision::Deny;
+                if !dropped_by_hook && self.client_can_publish(c_h_id, &publish_packet.topic_name) {
+                    let topic_name = publish_packet.topic_name.clone();
+                    self.audit_publish(&client_id, &publish_packet);
+                    let started = Instant::now();
+                    let puback_packet = self.handle_publish_packet(publish_packet)?;
+                    self.stats.publish_processed(started.elapsed());
+                    if let (Some(audit), Some(puback)) = (&self.audit, &puback_packet) {
+                        audit.puback(
+                            PubackDirection::Sent,
+                            &client_id,
+                            &topic_name,
+                            puback.packet_id,
+                        );
+                    }
+                    puback_packet.map(|puback_packet| Ok(Packet::Puback(puback_packet)))
+                } else {
+                    // Lo descartó un hook, el token no lo habilita a publicar en este tópico, o
+                    // es $SYS
+                    self.stats.message_dropped();
+                    let outcome = if dropped_by_hook {
+                        "dropped by a hook"
+                    } else {
+                        "denied"
+                    };
+                    self.logger.log_msg(LogMessage::warn(
+                        format!("Publish to {} {} for:", publish_packet.topic_name, outcome),
+                        client_id,
+                    ))?;
+                    publish_packet
+                        .packet_id
+                        .map(|packet_id| Ok(Packet::Puback(Puback::new(packet_id))))
+                }
             }
 
             Packet::Puback(puback_packet) => {
-                self.logger.log_msg(LogMessage::new(
+                self.logger.log_msg(LogMessage::debug(
                     "Puback Packet received from:".to_string(),
-                    client_id,
+                    client_id.clone(),
                 ))?;
+                self.audit_puback_received(&client_id, puback_packet.packet_id);
                 self.handle_puback_packet(puback_packet)?;
                 None
             }
 
             Packet::Subscribe(subscribe_packet) => {
-                self.logger.log_msg(LogMessage::new(
+                self.logger.log_msg(LogMessage::debug(
                     "Subscribe Packet received from:".to_string(),
                     client_id,
                 ))?;
@@ -219,7 +763,7 @@ impl PacketProcessor {
             }
 
             Packet::Unsubscribe(unsubscribe_packet) => {
-                self.logger.log_msg(LogMessage::new(
+                self.logger.log_msg(LogMessage::debug(
                     "Unsubscribe Packet received from:".to_string(),
                     client_id,
                 ))?;
@@ -228,7 +772,7 @@ impl PacketProcessor {
             }
 
             Packet::Pingreq(pingreq_packet) => {
-                self.logger.log_msg(LogMessage::new(
+                self.logger.log_msg(LogMessage::debug(
                     "Pingreq Packet received from:".to_string(),
                     client_id,
                 ))?;
@@ -237,11 +781,11 @@ impl PacketProcessor {
             }
 
             Packet::Disconnect(_disconnect_packet) => {
-                self.logger.log_msg(LogMessage::new(
+                self.logger.log_msg(LogMessage::debug(
                     "Disconnect Packet received from:".to_string(),
                     client_id,
                 ))?;
-                self.handle_disconnect(c_h_id);
+                self.handle_disconnect(c_h_id, EventReason::Disconnect);
                 None
             }
 
@@ -257,14 +801,18 @@ impl PacketProcessor {
                     let conn = connack.clone();
                     self.send_packet_to_client_handler(c_h_id, response_packet)?;
                     if conn.connect_return_code != CONNACK_CONNECTION_ACCEPTED {
-                        self.handle_disconnect(c_h_id);
+                        // No se creó sesión: no hay evento de desconexión
+                        self.close_client_handler(c_h_id);
                     } else {
+                        let factor = self.state.settings().connection.keep_alive_factor;
+                        self.keep_alives.start(c_h_id, keep_alive_seconds, factor);
                         self.send_unacknowledged_messages(c_h_id);
                     }
                 }
                 _ => self.send_packet_to_client_handler(c_h_id, response_packet)?,
             }
         }
+        self.publish_pending_events();
 
         Ok(())
     }
@@ -275,34 +823,98 @@ impl PacketProcessor {
         client_handler_id: u32,
     ) -> Result<Connack, Box<dyn std::error::Error>> {
         //Authentication
-        if let Some(password) = &connect_packet.connect_payload.password {
-            match &connect_packet.connect_payload.username {
-                None => {
-                    return Err(Box::new(Error::new(
-                        ErrorKind::Other,
-                        "Invalid Packet: Contains password but no username",
-                    )))
-                }
-                Some(username) => {
-                    if !self.authenticator.account_is_valid(username, password) {
-                        println!("Invalid Acount: sending Connack packet with error code");
-                        return Ok(Connack::new(false, CONNACK_BAD_USERNAME_OR_PASSWORD));
-                    }
-                }
-            }
+        let username = connect_packet.connect_payload.username.as_deref();
+        let password = connect_packet.connect_payload.password.as_deref();
+        if password.is_some() && username.is_none() {
+            return Err(Box::new(Error::other(
+                "Invalid Packet: Contains password but no username",
+            )));
+        }
+        let client_ip = self.get_client_ip(client_handler_id);
+        let now = Instant::now();
+        // Las cuentas y los límites de los bans salen de la misma versión de la configuración
+        let settings = self.state.settings();
+        let banned = {
+            let auth_limiter = self.state.auth_limiter.lock().unwrap();
+            client_ip.is_some_and(|ip| auth_limiter.ip_is_banned(&ip, now))
+                || username.is_some_and(|username| auth_limiter.username_is_banned(username, now))
+        };
+        if banned {
+            self.logger.log_msg(LogMessage::warn(
+                "Connection refused (banned after too many failed attempts):".to_string(),
+                connect_packet.connect_payload.client_id.clone(),
+            ))?;
+            return Ok(Connack::new(false, CONNACK_NOT_AUTHORIZED));
         }
-        println!("Valid Account");
 
+        let token_claims = match settings.authenticator.authenticate(username, password) {
+            Ok(token_claims) => {
+                self.state
+                    .auth_limiter
+                    .lock()
+                    .unwrap()
+                    .record_success(client_ip, username);
+                token_claims
+            }
+            Err(error) => {
+                self.stats.auth_failure();
+                self.logger.log_msg(LogMessage::warn(
+                    match client_ip {
+                        Some(ip) => format!("Connection refused from {} ({}):", ip, error),
+                        None => format!("Connection refused ({}):", error),
+                    },
+                    connect_packet.connect_payload.client_id.clone(),
+                ))?;
+                let bans = self.state.auth_limiter.lock().unwrap().record_failure(
+                    &settings.auth_limiter,
+                    client_ip,
+                    username,
+                    now,
+                );
+                self.log_bans(bans)?;
+                return Ok(Connack::new(false, error.return_code()));
+            }
+        };
+        if !self.hooks.is_empty() {
+            let connection = ConnectionInfo {
+                client_id: connect_packet.connect_payload.client_id.clone(),
+                address: self
+                    .client_addresses
+                    .read()
+                    .unwrap()
+                    .get(&client_handler_id)
+                    .copied(),
+                username: connect_packet.connect_payload.username.clone(),
+                connected_at: client_events::unix_timestamp(),
+            };
+            if self
+                .hooks
+                .iter()
+                .any(|hook| hook.on_connect(&connection, &connect_packet) == HookDecision::Deny)
+            {
+                self.logger.log_msg(LogMessage::warn(
+                    "Connection refused by a hook:".to_string(),
+                    connection.client_id,
+                ))?;
+                return Ok(Connack::new(false, CONNACK_NOT_AUTHORIZED));
+            }
+        }
         let client_id = connect_packet.connect_payload.client_id.to_owned();
+        let username = connect_packet.connect_payload.username.clone();
+        let mut reason = EventReason::Connect;
         let clean_session = connect_packet.clean_session;
         let exists_previous_session = self.sessions.contains_key(&client_id);
 
         // Si hay un cliente con mismo client_id conectado, desconectamos la sesión del client anterior
         if let Some(existing_session) = self.sessions.get_mut(&client_id) {
-            println!("\n Session existente ------> {:?} \n ", existing_session);
+            self.logger.log_msg(LogMessage::trace(
+                format!("Existing session: {:?}", existing_session),
+                client_id.clone(),
+            ))?;
             if existing_session.is_active() {
                 let existing_handler_id = existing_session.get_client_handler_id().unwrap();
-                self.handle_disconnect_error(existing_handler_id);
+                self.handle_disconnect_error(existing_handler_id, EventReason::Takeover);
+                reason = EventReason::Takeover;
                 self.logger.log_msg(LogMessage::new(
                     "El cliente ya estaba conectado. Se remplazó la sesión por la nueva"
                         .to_string(),
@@ -321,19 +933,23 @@ impl PacketProcessor {
                 .insert(new_session.get_client_id().to_string(), new_session);
         }
         let current_session = self.sessions.get_mut(&client_id).unwrap();
-        current_session.connect(client_handler_id);
+        current_session.connect(client_handler_id, username, client_events::unix_timestamp());
+        current_session.set_token_claims(token_claims);
+        if let Some(connection) = self.connection_info(client_handler_id) {
+            self.pending_events
+                .push(client_events::connected(&connection, reason));
+        }
 
         // Enviamos el connack con 0 return code y el correspondiente flag de session_present:
         // si hay clean_session, session_present debe ser false. Sino, depende de si ya teníamos sesión
-        let session_present;
-        if clean_session {
-            session_present = false;
+        let session_present = if clean_session {
+            false
         } else {
-            session_present = exists_previous_session;
-        } // TODO: revisar esto, línea 683 pdf
+            exists_previous_session
+        }; // TODO: revisar esto, línea 683 pdf
 
         let connack_packet = Connack::new(session_present, 0);
-        self.logger.log_msg(LogMessage::new(
+        self.logger.log_msg(LogMessage::debug(
             "Connack packet send it to:".to_string(),
             client_id.to_string(),
         ))?;
@@ -345,8 +961,6 @@ impl PacketProcessor {
         _pingreq_packet: Pingreq,
         _c_h_id: u32,
     ) -> Result<Pingresp, Box<dyn std::error::Error>> {
-        println!("Se recibió el pingreq packet");
-
         Ok(Pingresp::new())
     }
 
@@ -355,8 +969,6 @@ impl PacketProcessor {
         unsubscribe_packet: Unsubscribe,
         c_h_id: u32,
     ) -> Result<Unsuback, Box<dyn std::error::Error>> {
-        println!("Se recibió el unsubscribe packet");
-
         let client_id = self.get_client_id_from_handler_id(c_h_id);
         let session;
         if let Some(client_id) = client_id {
@@ -378,8 +990,6 @@ impl PacketProcessor {
         subscribe_packet: Subscribe,
         c_h_id: u32,
     ) -> Result<Suback, Box<dyn std::error::Error>> {
-        println!("Se recibió el subscribe packet");
-
         let client_id = self.get_client_id_from_handler_id(c_h_id);
         let session;
         if let Some(client_id) = client_id {
@@ -388,11 +998,19 @@ impl PacketProcessor {
             return Err("Client not found".into());
         }
 
+        let connection = session_connection_info(session, &self.client_addresses);
         let mut suback_packet = Suback::new(subscribe_packet.packet_id);
-        for subscription in subscribe_packet.subscriptions {
-            if !topic_filters::topic_filter_is_valid(&subscription.topic_filter) {
+        for mut subscription in subscribe_packet.subscriptions {
+            let denied_by_hook = self.hooks.iter().any(|hook| {
+                hook.on_subscribe(&connection, &mut subscription) == HookDecision::Deny
+            });
+            if denied_by_hook
+                || !topic_filters::topic_filter_is_valid(&subscription.topic_filter)
+                || !session.can_subscribe_to(&subscription.topic_filter)
+            {
                 let return_code = SubackReturnCode::Failure;
                 suback_packet.add_return_code(return_code);
+                continue;
             } else {
                 let return_code = match subscription.max_qos {
                     Qos::AtMostOnce => SubackReturnCode::SuccessAtMostOnce,
@@ -432,14 +1050,27 @@ impl PacketProcessor {
                         .message
                         .to_string(),
                 );
-                println!("Publish a mandar: {:?}", &publish_packet);
+                let _ = self.logger.log_msg(LogMessage::trace(
+                    format!("Sending retained message: {:?}", publish_packet),
+                    "".to_string(),
+                ));
+                if let Some(audit) = &self.audit {
+                    audit.deliver(
+                        session.get_client_id(),
+                        session.get_username(),
+                        &publish_packet,
+                    );
+                }
+                for hook in &self.hooks {
+                    hook.on_deliver(&connection, &publish_packet);
+                }
 
+                let packet = Packet::Publish(publish_packet);
+                self.stats.packet_sent(&packet);
                 let senders_hash = self.senders_to_c_h_writers.read().unwrap();
                 let sender = senders_hash.get(&c_h_id).unwrap();
                 let sender_mutex_guard = sender.lock().unwrap();
-                sender_mutex_guard
-                    .send(Ok(Packet::Publish(publish_packet)))
-                    .unwrap();
+                sender_mutex_guard.send(Ok(packet)).unwrap();
             }
         }
         Ok(suback_packet)
@@ -449,7 +1080,6 @@ impl PacketProcessor {
         &mut self,
         publish_packet: Publish,
     ) -> Result<Option<Puback>, Box<dyn std::error::Error>> {
-        println!("Se recibió el publish packet");
         //Sacamos el packet_id del pubblish
         //Sacar info del publish
         //Mandamos el puback al client.
@@ -490,11 +1120,16 @@ impl PacketProcessor {
                 .is_subscribed_to(&publish_packet.topic_name)
                 .is_some()
             {
-                if let Some(client_handler_id) = session.get_client_handler_id() {
-                    self.send_packet_to_client_handler(
-                        client_handler_id,
-                        Ok(Packet::Publish(publish_send.clone())),
-                    )?;
+                match session.get_client_handler_id() {
+                    Some(client_handler_id) => {
+                        self.send_packet_to_client_handler(
+                            client_handler_id,
+                            Ok(Packet::Publish(publish_send.clone())),
+                        )?;
+                        self.notify_delivery(session, &publish_send);
+                    }
+                    // Sesión persistente sin cliente: los QoS 0 no se guardan
+                    None => self.stats.message_dropped(),
                 }
             }
         }
@@ -529,17 +1164,16 @@ impl PacketProcessor {
                             client_handler_id,
                             Ok(Packet::Publish(publish_send_2.clone())),
                         )?;
-                        self.tx_to_puback_processor
-                            .send((
-                                client_handler_id,
-                                Ok(Packet::Publish(publish_send_2.clone())),
-                            ))
-                            .unwrap();
+                        self.send_to_puback_processor(
+                            client_handler_id,
+                            Packet::Publish(publish_send_2.clone()),
+                        )?;
+                        self.notify_delivery(session, &publish_send_2);
                     }
                 }
 
-                Some(Qos::AtMostOnce) => {
-                    if let Some(client_handler_id) = session.get_client_handler_id() {
+                Some(Qos::AtMostOnce) => match session.get_client_handler_id() {
+                    Some(client_handler_id) => {
                         let mut publish_send_2 = publish_send.clone();
                         publish_send_2.packet_id = None;
                         publish_send_2.flags.qos_level = Qos::AtMostOnce;
@@ -547,14 +1181,15 @@ impl PacketProcessor {
                             client_handler_id,
                             Ok(Packet::Publish(publish_send_2.clone())),
                         )?;
+                        self.notify_delivery(session, &publish_send_2);
                     }
-                }
+                    None => self.stats.message_dropped(),
+                },
 
                 _ => (),
             }
         }
 
-        println!("Se envio correctamente el PUBACK");
         Ok(Some(Puback::new(packet_id.unwrap())))
     }
 
@@ -564,25 +1199,142 @@ impl PacketProcessor {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let puback_packet_id = puback_packet.packet_id;
 
-        self.tx_to_puback_processor
-            .send((0, Ok(Packet::Puback(puback_packet))))?;
+        self.send_to_puback_processor(0, Packet::Puback(puback_packet))?;
+        self.logger.log_msg(LogMessage::trace(
+            format!(
+                "Removing packet {} from the in-flight messages",
+                puback_packet_id
+            ),
+            "".to_string(),
+        ))?;
 
         for session in self.sessions.values_mut() {
             if session.is_active() {
-                session.unacknowledged_messages.retain(|publish_packet| {
-                    println!(
-                        "packetid_publish: {:?} || packetid_puback: {:?}",
-                        publish_packet.packet_id.unwrap(),
-                        puback_packet_id
-                    );
-                    publish_packet.packet_id.unwrap() != puback_packet_id
-                })
+                session
+                    .unacknowledged_messages
+                    .retain(|publish_packet| publish_packet.packet_id.unwrap() != puback_packet_id)
             }
         }
 
         Ok(())
     }
 
+    fn audit_publish(&self, client_id: &str, publish: &Publish) {
+        if let Some(audit) = &self.audit {
+            let username = self
+                .sessions
+                .get(client_id)
+                .and_then(|session| session.get_username());
+            audit.publish(client_id, username, publish);
+        }
+    }
+
+    // Cada entrega a un suscriptor va al registro de auditoría y a los hooks
+    fn notify_delivery(&self, session: &Session, publish: &Publish) {
+        if let Some(audit) = &self.audit {
+            audit.deliver(session.get_client_id(), session.get_username(), publish);
+        }
+        if !self.hooks.is_empty() {
+            let connection = session_connection_info(session, &self.client_addresses);
+            for hook in &self.hooks {
+                hook.on_deliver(&connection, publish);
+            }
+        }
+    }
+
+    // Devuelve Deny si algún hook descartó el mensaje
+    fn run_publish_hooks(&self, c_h_id: u32, publish: &mut Publish) -> HookDecision {
+        if self.hooks.is_empty() {
+            return HookDecision::Continue;
+        }
+        let connection = match self.connection_info(c_h_id) {
+            Some(connection) => connection,
+            None => return HookDecision::Continue,
+        };
+        if self
+            .hooks
+            .iter()
+            .any(|hook| hook.on_publish(&connection, publish) == HookDecision::Deny)
+        {
+            HookDecision::Deny
+        } else {
+            HookDecision::Continue
+        }
+    }
+
+    // El Puback no trae el tópico: sale del mensaje en vuelo que confirma
+    fn audit_puback_received(&self, client_id: &str, packet_id: u16) {
+        let audit = match &self.audit {
+            Some(audit) => audit,
+            None => return,
+        };
+        let in_flight = self.sessions.get(client_id).and_then(|session| {
+            session
+                .unacknowledged_messages
+                .iter()
+                .find(|publish| publish.packet_id == Some(packet_id))
+        });
+        if let Some(publish) = in_flight {
+            audit.puback(
+                PubackDirection::Received,
+                client_id,
+                &publish.topic_name,
+                packet_id,
+            );
+        }
+    }
+
+    fn log_bans(&self, bans: Vec<Ban>) -> Result<(), Box<dyn std::error::Error>> {
+        for ban in bans {
+            let message = match ban {
+                Ban::Ip(ip, duration) => format!(
+                    "IP {} banned for {} seconds after too many failed connections",
+                    ip,
+                    duration.as_secs()
+                ),
+                Ban::Username(username, duration) => format!(
+                    "Username {} banned for {} seconds after too many failed connections",
+                    username,
+                    duration.as_secs()
+                ),
+            };
+            self.logger
+                .log_msg(LogMessage::warn(message, "".to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn get_client_ip(&self, c_h_id: u32) -> Option<IpAddr> {
+        self.client_addresses
+            .read()
+            .unwrap()
+            .get(&c_h_id)
+            .map(|address| address.ip())
+    }
+
+    fn client_can_publish(&self, c_h_id: u32, topic_name: &str) -> bool {
+        if sys_topics::is_sys_topic(topic_name) {
+            return false;
+        }
+        match self.get_client_id_from_handler_id(c_h_id) {
+            Some(client_id) => self.sessions[&client_id].can_publish_to(topic_name),
+            None => true,
+        }
+    }
+
+    fn send_to_puback_processor(
+        &self,
+        c_h_id: u32,
+        packet: Packet,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let tx = self
+            .tx_to_puback_processor
+            .as_ref()
+            .ok_or("PubackProcessor already stopped")?;
+        tx.send((c_h_id, Ok(packet)))?;
+        Ok(())
+    }
+
     fn get_client_id_from_handler_id(&self, c_h_id: u32) -> Option<String> {
         for (client_id, session) in &self.sessions {
             if session.is_active() && session.get_client_handler_id().unwrap() == c_h_id {
@@ -597,10 +1349,19 @@ impl PacketProcessor {
         c_h_id: u32,
         packet: PacketResult,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Ok(packet) = &packet {
+            self.stats.packet_sent(packet);
+        }
+        let is_publish = matches!(packet, Ok(Packet::Publish(_)));
         let senders_hash = self.senders_to_c_h_writers.read().unwrap();
         let sender = senders_hash.get(&c_h_id).unwrap();
         let sender_mutex_guard = sender.lock().unwrap();
-        sender_mutex_guard.send(packet)?;
+        if let Err(error) = sender_mutex_guard.send(packet) {
+            if is_publish {
+                self.stats.message_dropped();
+            }
+            return Err(error.into());
+        }
         Ok(())
     }
 
@@ -618,7 +1379,10 @@ impl PacketProcessor {
             let current_session = self.sessions.get_mut(&client_id).unwrap();
             let mut unacknowledged_messages_copy = current_session.unacknowledged_messages.clone();
             unacknowledged_messages_copy.retain(|publish| {
-                println!("Envio el publish: {:?}", publish.application_message);
+                let _ = self.logger.log_msg(LogMessage::trace(
+                    format!("Resending publish: {:?}", publish.application_message),
+                    client_id.clone(),
+                ));
                 self.send_packet_to_client_handler(c_h_id, Ok(Packet::Publish(publish.clone())))
                     .is_err()
             });
@@ -629,3 +1393,19 @@ impl PacketProcessor {
         }
     }
 }
+
+fn session_connection_info(session: &Session, addresses: &ClientAddresses) -> ConnectionInfo {
+    let address = session
+        .get_client_handler_id()
+        .and_then(|c_h_id| addresses.read().unwrap().get(&c_h_id).copied());
+    ConnectionInfo {
+        client_id: session.get_client_id().clone(),
+        address,
+        username: session.get_username().cloned(),
+        connected_at: session.connected_at(),
+    }
+}
+
+fn unknown_client(client_id: &str) -> AdminError {
+    AdminError::not_found(format!("Unknown client {}", client_id))
+}
@@ -0,0 +1,205 @@
+use crate::packet_processor::Message;
+use common::all_packets::publish::{Publish, PublishFlags};
+use common::packet::{Qos, Subscription};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::ErrorKind;
+
+const INVALID_STATE_FILE_ERROR_MSG: &str = "Invalid state file";
+
+// Lo que se guarda al apagar el server: los mensajes retenidos y las sesiones persistentes
+// (clean_session = false) con sus suscripciones y los publish QoS 1 sin Puback
+#[derive(Debug, Default, PartialEq)]
+pub struct PersistedState {
+    pub retained_messages: Vec<(String, Message)>,
+    pub sessions: Vec<PersistedSession>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PersistedSession {
+    pub client_id: String,
+    pub subscriptions: Vec<(String, Qos)>,
+    pub unacknowledged_messages: Vec<Publish>,
+}
+
+// Se escribe primero en un archivo temporal y después se renombra, para no dejar un
+// archivo a medio escribir si el proceso muere en el medio
+pub fn save(path: &str, state: &PersistedState) -> Result<(), Box<dyn std::error::Error>> {
+    let retained: Vec<Value> = state
+        .retained_messages
+        .iter()
+        .map(|(topic, message)| {
+            json!({"topic": topic, "message": message.message, "qos": message.qos as u8})
+        })
+        .collect();
+    let sessions: Vec<Value> = state
+        .sessions
+        .iter()
+        .map(|session| {
+            let subscriptions: Vec<Value> = session
+                .subscriptions
+                .iter()
+                .map(|(filter, qos)| json!({"topic_filter": filter, "qos": *qos as u8}))
+                .collect();
+            let unacknowledged: Vec<Value> = session
+                .unacknowledged_messages
+                .iter()
+                .map(|publish| {
+                    json!({
+                        "topic": publish.topic_name,
+                        "message": publish.application_message,
+                        "qos": publish.flags.qos_level as u8,
+                        "retain": publish.flags.retain,
+                        "packet_id": publish.packet_id,
+                    })
+                })
+                .collect();
+            json!({
+                "client_id": session.client_id,
+                "subscriptions": subscriptions,
+                "unacknowledged_messages": unacknowledged,
+            })
+        })
+        .collect();
+
+    let contents = json!({"retained_messages": retained, "sessions": sessions});
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_string_pretty(&contents)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Devuelve None si todavía no hay un archivo de estado
+pub fn load(path: &str) -> Result<Option<PersistedState>, Box<dyn std::error::Error>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let value: Value = serde_json::from_str(&contents)?;
+
+    let mut state = PersistedState::default();
+    for retained in array(&value, "retained_messages")? {
+        state.retained_messages.push((
+            string(retained, "topic")?,
+            Message {
+                message: string(retained, "message")?,
+                qos: qos(retained)?,
+            },
+        ));
+    }
+    for session in array(&value, "sessions")? {
+        let mut persisted = PersistedSession {
+            client_id: string(session, "client_id")?,
+            subscriptions: vec![],
+            unacknowledged_messages: vec![],
+        };
+        for subscription in array(session, "subscriptions")? {
+            persisted
+                .subscriptions
+                .push((string(subscription, "topic_filter")?, qos(subscription)?));
+        }
+        for publish in array(session, "unacknowledged_messages")? {
+            persisted.unacknowledged_messages.push(Publish::new(
+                PublishFlags {
+                    duplicate: true,
+                    qos_level: qos(publish)?,
+                    retain: publish
+                        .get("retain")
+                        .and_then(Value::as_bool)
+                        .unwrap_or(false),
+                },
+                string(publish, "topic")?,
+                publish
+                    .get("packet_id")
+                    .and_then(Value::as_u64)
+                    .map(|id| id as u16),
+                string(publish, "message")?,
+            ));
+        }
+        state.sessions.push(persisted);
+    }
+
+    Ok(Some(state))
+}
+
+impl PersistedSession {
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.subscriptions
+            .iter()
+            .map(|(topic_filter, max_qos)| Subscription {
+                topic_filter: topic_filter.clone(),
+                max_qos: *max_qos,
+            })
+            .collect()
+    }
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>, String> {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("{}: missing {}", INVALID_STATE_FILE_ERROR_MSG, key))
+}
+
+fn string(value: &Value, key: &str) -> Result<String, String> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("{}: missing {}", INVALID_STATE_FILE_ERROR_MSG, key))
+}
+
+fn qos(value: &Value) -> Result<Qos, String> {
+    match value.get("qos").and_then(Value::as_u64) {
+        Some(0) => Ok(Qos::AtMostOnce),
+        Some(1) => Ok(Qos::AtLeastOnce),
+        _ => Err(format!("{}: invalid qos", INVALID_STATE_FILE_ERROR_MSG)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn saved_state_can_be_loaded_back() {
+        let path = env::temp_dir()
+            .join(format!("persistence_{}.json", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let state = PersistedState {
+            retained_messages: vec![(
+                "casa/temperatura".to_string(),
+                Message {
+                    message: "21".to_string(),
+                    qos: Qos::AtLeastOnce,
+                },
+            )],
+            sessions: vec![PersistedSession {
+                client_id: "sensor".to_string(),
+                subscriptions: vec![("casa/#".to_string(), Qos::AtMostOnce)],
+                unacknowledged_messages: vec![Publish::new(
+                    PublishFlags {
+                        duplicate: true,
+                        qos_level: Qos::AtLeastOnce,
+                        retain: false,
+                    },
+                    "casa/luz".to_string(),
+                    Some(7),
+                    "on".to_string(),
+                )],
+            }],
+        };
+
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(state));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_loads_nothing() {
+        assert_eq!(load("no_existe_este_estado.json").unwrap(), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Header que mandan HAProxy y otros balanceadores antes de los datos del cliente, para que el
+// server sepa la dirección real. https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// Contando el \r\n final
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_FAMILY_INET: u8 = 0x10;
+const V2_FAMILY_INET6: u8 = 0x20;
+const V2_IPV4_LENGTH: usize = 12;
+const V2_IPV6_LENGTH: usize = 36;
+
+const INVALID_HEADER_ERROR_MSG: &str = "Invalid PROXY protocol header";
+
+// Lee el header (v1 o v2) y devuelve la dirección del cliente original. Devuelve None si el
+// balanceador no la informa (UNKNOWN en v1, LOCAL o una familia sin IP en v2), en cuyo caso vale
+// la dirección de la conexión. Lee exactamente el header, ni un byte del primer paquete MQTT
+pub fn read_header(
+    stream: &mut dyn Read,
+) -> Result<Option<SocketAddr>, Box<dyn std::error::Error>> {
+    let mut first_byte = [0u8; 1];
+    stream.read_exact(&mut first_byte)?;
+    match first_byte[0] {
+        b'P' => read_v1(stream),
+        b'\r' => read_v2(stream),
+        _ => Err(INVALID_HEADER_ERROR_MSG.into()),
+    }
+}
+
+// PROXY TCP4 <ip origen> <ip destino> <puerto origen> <puerto destino>\r\n
+fn read_v1(stream: &mut dyn Read) -> Result<Option<SocketAddr>, Box<dyn std::error::Error>> {
+    let mut line = b"P".to_vec();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(INVALID_HEADER_ERROR_MSG.into());
+        }
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    if !line.starts_with(V1_PREFIX) {
+        return Err(INVALID_HEADER_ERROR_MSG.into());
+    }
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| INVALID_HEADER_ERROR_MSG)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [protocol, source, _, source_port, _] if *protocol == "TCP4" || *protocol == "TCP6" => {
+            let ip: IpAddr = source.parse().map_err(|_| INVALID_HEADER_ERROR_MSG)?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(INVALID_HEADER_ERROR_MSG.into());
+            }
+            let port: u16 = source_port.parse().map_err(|_| INVALID_HEADER_ERROR_MSG)?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(INVALID_HEADER_ERROR_MSG.into()),
+    }
+}
+
+// Firma de 12 bytes, versión y comando, familia, largo (u16) y las direcciones
+fn read_v2(stream: &mut dyn Read) -> Result<Option<SocketAddr>, Box<dyn std::error::Error>> {
+    let mut header = [0u8; 15];
+    stream.read_exact(&mut header)?;
+    if header[..11] != V2_SIGNATURE[1..] {
+        return Err(INVALID_HEADER_ERROR_MSG.into());
+    }
+    let version_command = header[11];
+    let family = header[12] & 0xF0;
+    let length = u16::from_be_bytes([header[13], header[14]]) as usize;
+    if version_command & 0xF0 != V2_VERSION {
+        return Err(INVALID_HEADER_ERROR_MSG.into());
+    }
+
+    // El resto (direcciones y TLVs) se lee entero aunque no se use
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses)?;
+
+    match version_command & 0x0F {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        _ => return Err(INVALID_HEADER_ERROR_MSG.into()),
+    }
+    match family {
+        V2_FAMILY_INET if length >= V2_IPV4_LENGTH => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        V2_FAMILY_INET6 if length >= V2_IPV6_LENGTH => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        V2_FAMILY_INET | V2_FAMILY_INET6 => Err(INVALID_HEADER_ERROR_MSG.into()),
+        // AF_UNIX o sin especificar: no hay una IP que usar
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(V2_VERSION | command);
+        header.push(family | 0x01);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4_header_gives_the_source_address_and_leaves_the_rest() {
+        let mut input: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 1883\r\n\x10rest";
+        let address = read_header(&mut input).unwrap();
+        assert_eq!(address, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(input, b"\x10rest");
+    }
+
+    #[test]
+    fn v1_tcp6_and_unknown() {
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1883\r\n";
+        assert_eq!(
+            read_header(&mut input).unwrap(),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn v1_malformed_headers_are_rejected() {
+        let inputs: Vec<&[u8]> = vec![
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51000\r\n",
+            b"PROXY TCP4 2001:db8::1 10.0.0.1 51000 1883\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 99999 1883\r\n",
+            b"\x10\x0c\x00\x04MQTT",
+        ];
+        for mut input in inputs {
+            assert!(read_header(&mut input).is_err());
+        }
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(200));
+        assert!(read_header(&mut long.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn v2_ipv4_and_ipv6_headers() {
+        let mut addresses = vec![198, 51, 100, 9, 10, 0, 0, 1];
+        addresses.extend_from_slice(&1234u16.to_be_bytes());
+        addresses.extend_from_slice(&1883u16.to_be_bytes());
+        // Un TLV al final que hay que saltear
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0xFF]);
+        let mut input = v2_header(V2_COMMAND_PROXY, V2_FAMILY_INET, &addresses);
+        input.push(0x10);
+        let mut reader = input.as_slice();
+        assert_eq!(
+            read_header(&mut reader).unwrap(),
+            Some("198.51.100.9:1234".parse().unwrap())
+        );
+        assert_eq!(reader, [0x10]);
+
+        let mut addresses = "2001:db8::5".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&[0u8; 16]);
+        addresses.extend_from_slice(&4321u16.to_be_bytes());
+        addresses.extend_from_slice(&1883u16.to_be_bytes());
+        let input = v2_header(V2_COMMAND_PROXY, V2_FAMILY_INET6, &addresses);
+        assert_eq!(
+            read_header(&mut input.as_slice()).unwrap(),
+            Some("[2001:db8::5]:4321".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn v2_local_command_keeps_the_connection_address() {
+        let input = v2_header(V2_COMMAND_LOCAL, 0x00, &[]);
+        assert_eq!(read_header(&mut input.as_slice()).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_truncated_addresses_are_rejected() {
+        let input = v2_header(V2_COMMAND_PROXY, V2_FAMILY_INET, &[1, 2, 3, 4]);
+        assert!(read_header(&mut input.as_slice()).is_err());
+        let mut bad_signature = v2_header(V2_COMMAND_LOCAL, 0x00, &[]);
+        bad_signature[5] = b'X';
+        assert!(read_header(&mut bad_signature.as_slice()).is_err());
+    }
+}
@@ -1,9 +1,10 @@
 use crate::server::{ArcSenderPacket, PacketResult};
+use crate::stats::BrokerStats;
 use common::all_packets::puback::Puback;
 use common::all_packets::publish::Publish;
 use common::packet::Packet;
 use std::collections::HashMap;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime};
 
@@ -11,26 +12,33 @@ pub struct PubackProcessor {
     publish_packets: Vec<(SystemTime, u32, Publish)>,
     senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
     rx_from_packet_processor: Receiver<(u32, PacketResult)>,
+    stats: Arc<BrokerStats>,
 }
 
 impl PubackProcessor {
     pub fn new(
         senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
         rx_from_packet_processor: Receiver<(u32, PacketResult)>,
+        stats: Arc<BrokerStats>,
     ) -> PubackProcessor {
         PubackProcessor {
             senders_to_c_h_writers,
             rx_from_packet_processor,
+            stats,
             publish_packets: vec![],
         }
     }
 
+    // Corre hasta que el PacketProcessor suelta su sender
     pub fn run(mut self) {
         loop {
-            if let Ok(received) = self
+            let received = self
                 .rx_from_packet_processor
-                .recv_timeout(Duration::from_millis(1000))
-            {
+                .recv_timeout(Duration::from_millis(1000));
+            if let Err(RecvTimeoutError::Disconnected) = received {
+                break;
+            }
+            if let Ok(received) = received {
                 if let (id, Ok(packet)) = received {
                     match packet {
                         Packet::Publish(publish_packet) => {
@@ -76,6 +84,7 @@ impl PubackProcessor {
                 self.publish_packets.retain(|(_, c_h_id, _)| *c_h_id != id);
                 break;
             };
+            self.stats.retransmission();
         }
     }
 
@@ -87,9 +96,7 @@ impl PubackProcessor {
         let senders_hash = self.senders_to_c_h_writers.read().unwrap();
         let sender = senders_hash.get(&id).ok_or("Sender not found")?;
         let sender_mutex_guard = sender.lock().unwrap();
-        sender_mutex_guard
-            .send(Ok(Packet::Publish(publish_packet)))
-            .unwrap();
+        sender_mutex_guard.send(Ok(Packet::Publish(publish_packet)))?;
         Ok(())
     }
 }
@@ -0,0 +1,398 @@
+use crate::auth_limiter::{AuthLimiter, AuthLimiterConfig};
+use crate::authenticator::Authenticator;
+use crate::client_handler::ClientHandlerConfig;
+use crate::config::Config;
+use crate::jwt::JwtValidator;
+use crate::listener::{ConnectionLimiter, ConnectionLimits};
+use common::logging::logger::{LogMessage, Logger};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+const RELOADER_TICK: Duration = Duration::from_millis(500);
+
+// Lo que se puede cambiar sin reiniciar el server. Una recarga arma uno nuevo y lo reemplaza
+// entero, así nadie ve las cuentas nuevas con los límites viejos
+pub struct ReloadableSettings {
+    pub authenticator: Authenticator,
+    pub auth_limiter: AuthLimiterConfig,
+    pub connection: ClientHandlerConfig,
+    pub connection_limits: ConnectionLimits,
+}
+
+impl ReloadableSettings {
+    pub fn from_config(config: &Config) -> Result<ReloadableSettings, Box<dyn std::error::Error>> {
+        Ok(ReloadableSettings {
+            authenticator: build_authenticator(config)?,
+            auth_limiter: config.auth_limiter.clone(),
+            connection: config.connection.clone(),
+            connection_limits: config.connection_limits,
+        })
+    }
+}
+
+// Se comparte con el PacketProcessor y con el loop que acepta conexiones. Los bans y las
+// conexiones abiertas sobreviven a las recargas, por eso van aparte de la configuración
+#[derive(Clone)]
+pub struct ReloadableState {
+    settings: Arc<RwLock<Arc<ReloadableSettings>>>,
+    pub auth_limiter: Arc<Mutex<AuthLimiter>>,
+    pub connection_limiter: ConnectionLimiter,
+}
+
+impl ReloadableState {
+    pub fn from_config(config: &Config) -> Result<ReloadableState, Box<dyn std::error::Error>> {
+        Ok(ReloadableState {
+            settings: Arc::new(RwLock::new(Arc::new(ReloadableSettings::from_config(
+                config,
+            )?))),
+            auth_limiter: Arc::new(Mutex::new(AuthLimiter::default())),
+            connection_limiter: ConnectionLimiter::default(),
+        })
+    }
+
+    // La configuración vigente. Quien la usa para varias cosas la pide una sola vez, así una
+    // recarga en el medio no le cambia la mitad
+    pub fn settings(&self) -> Arc<ReloadableSettings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    fn replace(&self, settings: ReloadableSettings) {
+        *self.settings.write().unwrap() = Arc::new(settings);
+    }
+}
+
+pub fn build_authenticator(config: &Config) -> Result<Authenticator, Box<dyn std::error::Error>> {
+    let jwt_validator = match &config.jwt {
+        Some(jwt_config) => Some(JwtValidator::from_config(jwt_config)?),
+        None => None,
+    };
+    let policy = config.auth_policy.clone();
+    let authenticator = match &config.accounts_filename {
+        Some(filename) => Authenticator::from(filename.clone(), policy, jwt_validator)
+            .map_err(|e| format!("{}: {}", filename, e))?,
+        None => Authenticator::without_accounts(policy, jwt_validator),
+    };
+    Ok(authenticator)
+}
+
+// Vuelve a leer la configuración, las cuentas y las claves JWT al recibir SIGHUP o cuando
+// cambia alguno de esos archivos. Si algo falla se sigue usando lo anterior
+pub struct Reloader {
+    config: Config,
+    state: ReloadableState,
+    logger: Arc<Logger>,
+    modified_times: HashMap<String, Option<SystemTime>>,
+    // Recargas pedidas desde la API de administración o con ServerHandle::reload. Por el
+    // Sender se avisa si salió bien
+    requests: Option<Receiver<Sender<bool>>>,
+    // Solo el binario lo conecta a SIGHUP, un server embebido no toca las señales del proceso
+    sighup_received: Arc<AtomicBool>,
+}
+
+impl Reloader {
+    pub fn new(config: Config, state: ReloadableState, logger: Arc<Logger>) -> Reloader {
+        let modified_times = modified_times(&config);
+        Reloader {
+            config,
+            state,
+            logger,
+            modified_times,
+            requests: None,
+            sighup_received: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn sighup_flag(&self) -> Arc<AtomicBool> {
+        self.sighup_received.clone()
+    }
+
+    // Devuelve por dónde pedir una recarga sin mandar SIGHUP
+    pub fn requests(&mut self) -> Sender<Sender<bool>> {
+        let (tx, rx) = mpsc::channel();
+        self.requests = Some(rx);
+        tx
+    }
+
+    // El thread termina cuando se activa `shutdown`
+    pub fn run(mut self, shutdown: Arc<AtomicBool>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut last_check = Instant::now();
+            let requests = self.requests.take();
+            while !shutdown.load(Ordering::SeqCst) {
+                match &requests {
+                    Some(requests) => match requests.recv_timeout(RELOADER_TICK) {
+                        Ok(reply_tx) => {
+                            self.log("Reload requested, reloading configuration".to_string());
+                            let _ = reply_tx.send(self.reload());
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => thread::sleep(RELOADER_TICK),
+                    },
+                    None => thread::sleep(RELOADER_TICK),
+                }
+                if self.sighup_received.swap(false, Ordering::SeqCst) {
+                    self.log("SIGHUP received, reloading configuration".to_string());
+                    self.reload();
+                    continue;
+                }
+
+                let watch_interval = Duration::from_secs(self.config.reload_watch_seconds);
+                if watch_interval.is_zero() || last_check.elapsed() < watch_interval {
+                    continue;
+                }
+                last_check = Instant::now();
+                let changed = self.changed_files();
+                if !changed.is_empty() {
+                    self.log(format!(
+                        "Watched files changed ({}), reloading configuration",
+                        changed.join(", ")
+                    ));
+                    self.reload();
+                }
+            }
+        })
+    }
+
+    // Arma todo lo nuevo antes de tocar el estado compartido, para que la recarga sea
+    // todo o nada. Las sesiones existentes no se tocan
+    pub fn reload(&mut self) -> bool {
+        // Un server armado con ServerBuilder no tiene archivo: se mantiene su configuración y
+        // se vuelven a leer las cuentas y las claves
+        let loaded = match &self.config.file_path {
+            Some(path) => Config::load(Some(path.clone())),
+            None => Ok(self.config.clone()),
+        };
+        let new_config = match loaded {
+            Ok(config) => config,
+            Err(e) => {
+                self.log(format!(
+                    "Reload failed, keeping previous configuration: {}",
+                    e
+                ));
+                self.modified_times = modified_times(&self.config);
+                return false;
+            }
+        };
+        let settings = match ReloadableSettings::from_config(&new_config) {
+            Ok(settings) => settings,
+            Err(e) => {
+                self.log(format!(
+                    "Reload failed, keeping previous configuration: {}",
+                    e
+                ));
+                self.modified_times = modified_times(&new_config);
+                return false;
+            }
+        };
+
+        self.state.replace(settings);
+        self.logger.set_levels(new_config.log.levels.clone());
+
+        for setting in restart_only_changes(&self.config, &new_config) {
+            self.log(format!(
+                "Setting {} changed but only takes effect after a restart",
+                setting
+            ));
+        }
+        self.modified_times = modified_times(&new_config);
+        self.config = new_config;
+        self.log("Configuration reloaded".to_string());
+        true
+    }
+
+    fn changed_files(&self) -> Vec<String> {
+        let current = modified_times(&self.config);
+        current
+            .iter()
+            .filter(|(path, time)| self.modified_times.get(*path) != Some(time))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    fn log(&self, msg: String) {
+        let _ = self.logger.log_msg(LogMessage::new(msg, "".to_string()));
+    }
+}
+
+fn watched_files(config: &Config) -> Vec<String> {
+    let mut files: Vec<String> = config.accounts_filename.iter().cloned().collect();
+    if let Some(path) = &config.file_path {
+        files.push(path.clone());
+    }
+    if let Some(jwt) = &config.jwt {
+        files.extend(jwt.hs256_secret_file.clone());
+        files.extend(jwt.rs256_public_key_file.clone());
+    }
+    files
+}
+
+fn modified_times(config: &Config) -> HashMap<String, Option<SystemTime>> {
+    watched_files(config)
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+fn restart_only_changes(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changes = vec![];
+    if old.address != new.address {
+        changes.push("server.address");
+    }
+    if old.port != new.port {
+        changes.push("server.port");
+    }
+    if old.listeners() != new.listeners() {
+        changes.push("listeners");
+    }
+    if old.packet_ids != new.packet_ids {
+        changes.push("server.packet_ids");
+    }
+    if old.log.file != new.log.file {
+        changes.push("log.file");
+    }
+    // Los niveles se aplican en caliente; el formato y la rotación no
+    if old.log.format != new.log.format
+        || old.log.stdout != new.log.stdout
+        || old.log.rotation != new.log.rotation
+    {
+        changes.push("log");
+    }
+    if old.sys_interval_seconds != new.sys_interval_seconds {
+        changes.push("sys.interval_seconds");
+    }
+    if old.metrics != new.metrics {
+        changes.push("metrics");
+    }
+    if old.admin != new.admin {
+        changes.push("admin");
+    }
+    if old.audit != new.audit {
+        changes.push("audit");
+    }
+    if old.capture != new.capture {
+        changes.push("capture");
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::logging::level::Level;
+    use std::env;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("reloader_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn test_reloader(name: &str, accounts: &str, config_extra: &str) -> (Reloader, String) {
+        let accounts_path = temp_path(&format!("{}_accounts.txt", name));
+        let config_path = temp_path(&format!("{}_config.txt", name));
+        fs::write(&accounts_path, accounts).unwrap();
+        fs::write(
+            &config_path,
+            format!(
+                "[auth]\naccounts_file = {}\n{}",
+                accounts_path, config_extra
+            ),
+        )
+        .unwrap();
+        let config = Config::load(Some(config_path.clone())).unwrap();
+        let state = ReloadableState::from_config(&config).unwrap();
+        let logger = Arc::new(Logger::new(&temp_path(&format!("{}_log.txt", name))).unwrap());
+        (Reloader::new(config, state, logger), config_path)
+    }
+
+    #[test]
+    fn reload_replaces_accounts() {
+        let (mut reloader, config_path) = test_reloader("accounts", "agus;1234\n", "");
+        let accounts_path = reloader.config.accounts_filename.clone().unwrap();
+        fs::write(&accounts_path, "agus;nueva\n").unwrap();
+
+        assert!(reloader.reload());
+        let settings = reloader.state.settings();
+        assert!(settings.authenticator.account_is_valid("agus", "nueva"));
+        assert!(!settings.authenticator.account_is_valid("agus", "1234"));
+        let _ = fs::remove_file(accounts_path);
+        let _ = fs::remove_file(config_path);
+    }
+
+    #[test]
+    fn settings_taken_before_a_reload_are_not_mixed_with_the_new_ones() {
+        let (mut reloader, config_path) = test_reloader("snapshot", "agus;1234\n", "");
+        let accounts_path = reloader.config.accounts_filename.clone().unwrap();
+        let before = reloader.state.settings();
+        fs::write(&accounts_path, "agus;nueva\n").unwrap();
+        fs::write(
+            &config_path,
+            format!(
+                "[auth]\naccounts_file = {}\n[auth_limiter]\nmax_failures = 9\n",
+                accounts_path
+            ),
+        )
+        .unwrap();
+
+        assert!(reloader.reload());
+        assert!(before.authenticator.account_is_valid("agus", "1234"));
+        assert_eq!(before.auth_limiter.max_failures, 5);
+        let after = reloader.state.settings();
+        assert!(after.authenticator.account_is_valid("agus", "nueva"));
+        assert_eq!(after.auth_limiter.max_failures, 9);
+        let _ = fs::remove_file(accounts_path);
+        let _ = fs::remove_file(config_path);
+    }
+
+    #[test]
+    fn failed_reload_keeps_previous_state() {
+        let (mut reloader, config_path) = test_reloader(
+            "invalid",
+            "agus;1234\n",
+            "[connection]\nkeep_alive_factor = 3\n",
+        );
+        fs::write(&config_path, "[connection]\nkeep_alive_factor = 0\n").unwrap();
+
+        assert!(!reloader.reload());
+        let settings = reloader.state.settings();
+        assert!(settings.authenticator.account_is_valid("agus", "1234"));
+        assert_eq!(settings.connection.keep_alive_factor, 3.0);
+        let _ = fs::remove_file(reloader.config.accounts_filename.as_ref().unwrap());
+        let _ = fs::remove_file(config_path);
+    }
+
+    #[test]
+    fn reload_applies_new_limits() {
+        let (mut reloader, config_path) = test_reloader("limits", "agus;1234\n", "");
+        let accounts_path = reloader.config.accounts_filename.clone().unwrap();
+        fs::write(
+            &config_path,
+            format!(
+                "[auth]\naccounts_file = {}\n[connection]\nconnect_timeout_seconds = 9\n[log]\nlevel = warn\nmodules = packet_processor=debug\n",
+                accounts_path
+            ),
+        )
+        .unwrap();
+
+        assert!(reloader.reload());
+        assert_eq!(
+            reloader.state.settings().connection.connect_timeout,
+            Duration::from_secs(9)
+        );
+        let levels = reloader.logger.levels();
+        assert_eq!(levels.default, Level::Warn);
+        assert!(levels.enabled(Level::Debug, "server::packet_processor"));
+        let _ = fs::remove_file(accounts_path);
+        let _ = fs::remove_file(config_path);
+    }
+}
@@ -1,78 +1,766 @@
-use crate::client_handler::ClientHandler;
+use crate::admin::{self, AdminBackend};
+use crate::audit::AuditLog;
+use crate::capture::Capture;
+use crate::client_handler::{ClientHandler, ClientHandlerConfig};
 use crate::config::Config;
+use crate::hooks::BrokerHook;
+use crate::keep_alive::{Clock, SystemClock};
+use crate::listener::{self, ConnectionCounter, ConnectionSlot, ListenerConfig, Transport};
+use crate::local_client::{LocalClient, LocalClientOptions};
+use crate::metrics;
 use crate::packet_processor::PacketProcessor;
+use crate::reloader::{ReloadableState, Reloader};
+use crate::stream::ClientStream;
+use crate::websocket;
 use common::packet::Packet;
+use common::tls::{self, TlsServerConfig, TlsStream};
 use std::collections::HashMap;
+use std::fs;
 use std::io;
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
 
 use common::logging::logger::{LogMessage, Logger};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Mutex};
+use std::sync::{mpsc, Condvar, Mutex};
 use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Cada cuánto se fija el loop de conexiones si hay que apagar el server
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 pub type PacketResult = Result<Packet, Box<dyn std::error::Error + Send>>;
 pub type ArcSenderPacket = Arc<Mutex<Sender<PacketResult>>>;
+pub type ClientAddresses = Arc<RwLock<HashMap<u32, SocketAddr>>>;
 
 pub struct Server {
     config: Config,
     logger: Arc<Logger>,
+    clock: Arc<dyn Clock>,
+    hooks: Vec<Arc<dyn BrokerHook>>,
+}
+
+// Permite apagar un server que ya está corriendo y esperar a que termine
+pub struct ServerHandle {
+    local_address: SocketAddr,
+    websocket_address: Option<SocketAddr>,
+    listener_addresses: Vec<(String, SocketAddr)>,
+    metrics_address: Option<SocketAddr>,
+    admin_address: Option<SocketAddr>,
+    shutdown: Arc<AtomicBool>,
+    ready: Arc<(Mutex<bool>, Condvar)>,
+    join_handle: JoinHandle<()>,
+    channels: ClientChannels,
+    reload_tx: Sender<Sender<bool>>,
+    sighup_received: Arc<AtomicBool>,
+}
+
+// Lo que necesita cualquier cliente nuevo, por socket o local, para hablar con el PacketProcessor
+#[derive(Clone)]
+struct ClientChannels {
+    next_client_id: Arc<AtomicU32>,
+    senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
+    c_h_reader_tx: Sender<(u32, PacketResult)>,
+}
+
+impl ClientChannels {
+    fn next_id(&self) -> u32 {
+        self.next_client_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+impl ServerHandle {
+    // La dirección real del primer listener TCP o WebSocket, útil si se configuró el puerto 0.
+    // Si solo hay listeners Unix es 0.0.0.0:0
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    // La dirección del primer listener WebSocket, si hay alguno
+    pub fn websocket_address(&self) -> Option<SocketAddr> {
+        self.websocket_address
+    }
+
+    // La dirección del listener de métricas, si está habilitado
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    // La dirección de la API de administración, si está habilitada
+    pub fn admin_address(&self) -> Option<SocketAddr> {
+        self.admin_address
+    }
+
+    // La dirección real del listener con ese nombre
+    pub fn listener_address(&self, name: &str) -> Option<SocketAddr> {
+        self.listener_addresses
+            .iter()
+            .find(|(listener_name, _)| listener_name == name)
+            .map(|(_, address)| *address)
+    }
+
+    // Espera a que el server empiece a aceptar conexiones. Devuelve false si no lo hizo
+    // dentro de `timeout`
+    pub fn wait_ready(&self, timeout: Duration) -> bool {
+        let (lock, condvar) = &*self.ready;
+        let ready = lock.lock().unwrap();
+        let (ready, _) = condvar
+            .wait_timeout_while(ready, timeout, |ready| !*ready)
+            .unwrap();
+        *ready
+    }
+
+    // Conecta un cliente que vive en este mismo proceso, sin pasar por un socket
+    pub fn connect_local(
+        &self,
+        options: LocalClientOptions,
+    ) -> Result<LocalClient, Box<dyn std::error::Error>> {
+        LocalClient::connect(
+            self.channels.next_id(),
+            &self.channels.senders_to_c_h_writers,
+            self.channels.c_h_reader_tx.clone(),
+            options,
+        )
+    }
+
+    // Vuelve a leer la configuración y las cuentas, igual que con SIGHUP en el binario.
+    // Devuelve false si falló y se sigue usando lo anterior
+    pub fn reload(&self) -> bool {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if self.reload_tx.send(reply_tx).is_err() {
+            return false;
+        }
+        reply_rx.recv().unwrap_or(false)
+    }
+
+    // Deja de aceptar conexiones, cierra los clientes y espera a que terminen todos los threads
+    pub fn shutdown(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wait();
+    }
+
+    pub fn wait(self) {
+        self.join_handle.join().unwrap();
+    }
 }
+
 impl Server {
     pub fn new(config: Config, logger: Arc<Logger>) -> io::Result<Self> {
-        Ok(Self { config, logger })
+        Ok(Self {
+            config,
+            logger,
+            clock: Arc::new(SystemClock),
+            hooks: vec![],
+        })
+    }
+
+    // Reloj de los timers de keep alive. Los tests lo reemplazan por un ManualClock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_hook(mut self, hook: Arc<dyn BrokerHook>) -> Self {
+        self.hooks.push(hook);
+        self
     }
 
+    // Corre hasta recibir SIGINT o SIGTERM. Un segundo SIGINT corta el proceso sin esperar, y
+    // SIGHUP recarga la configuración
     pub fn server_run(self) -> Result<(), Box<dyn std::error::Error>> {
+        let handle = self.start()?;
+        signal_hook::flag::register(SIGHUP, handle.sighup_received.clone())?;
+        signal_hook::flag::register_conditional_shutdown(SIGINT, 1, handle.shutdown.clone())?;
+        signal_hook::flag::register(SIGINT, handle.shutdown.clone())?;
+        signal_hook::flag::register(SIGTERM, handle.shutdown.clone())?;
+        handle.wait();
+        Ok(())
+    }
+
+    pub fn start(self) -> Result<ServerHandle, Box<dyn std::error::Error>> {
         //Inicializacion
-        let address = self.config.address.clone() + &self.config.port.to_string();
-        let listener = TcpListener::bind(&address)?;
-        println!("Servidor escuchando en: {} ", &address);
-        self.logger.log_msg(LogMessage::new(
-            format!("Servidor escuchando en: {}", &address),
-            "".to_string(),
-        ))?;
+        let mut listeners = vec![];
+        for listener_config in self.config.listeners() {
+            // Los certificados se cargan antes de escuchar, así un error no deja el puerto abierto
+            let tls = tls_config(&listener_config)
+                .map_err(|e| format!("listener {}: {}", listener_config.name, e))?;
+            let listener = self.bind(&listener_config)?;
+            let address = listener.local_address()?;
+            listeners.push((listener_config, listener, address, tls));
+        }
+        // Los listeners Unix no tienen dirección IP
+        let listener_addresses: Vec<(String, SocketAddr)> = listeners
+            .iter()
+            .filter_map(|(config, _, address, _)| address.map(|a| (config.name.clone(), a)))
+            .collect();
+        let local_address = listener_addresses
+            .first()
+            .map(|(_, address)| *address)
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let websocket_address = listeners
+            .iter()
+            .find(|(config, _, _, _)| config.is_websocket())
+            .and_then(|(_, _, address, _)| *address);
+        let metrics_listener = match &self.config.metrics {
+            metrics if metrics.enabled => Some(self.bind_http(
+                &metrics.address,
+                metrics.port,
+                "Prometheus metrics at http://{}/metrics",
+            )?),
+            _ => None,
+        };
+        let metrics_address = match &metrics_listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        // El token se lee antes de escuchar, así un error no deja el puerto abierto
+        let admin_listener = match &self.config.admin {
+            admin if admin.enabled => {
+                let token = admin.read_token()?;
+                let listener =
+                    self.bind_http(&admin.address, admin.port, "Admin API at http://{}/api")?;
+                Some((listener, token))
+            }
+            _ => None,
+        };
+        let admin_address = match &admin_listener {
+            Some((listener, _)) => Some(listener.local_addr()?),
+            None => None,
+        };
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        // El PacketProcessor se detiene recién cuando ya no se aceptan conexiones, así ningún
+        // client handler queda sin cerrar
+        let processor_shutdown = Arc::new(AtomicBool::new(false));
+        let state = ReloadableState::from_config(&self.config)?;
+        let client_addresses: ClientAddresses = Arc::new(RwLock::new(HashMap::new()));
         let senders_to_c_h_writers = Arc::new(RwLock::new(HashMap::<u32, ArcSenderPacket>::new()));
         let (c_h_reader_tx, packet_proc_rx) = mpsc::channel::<(u32, PacketResult)>();
 
-        let packet_processor = PacketProcessor::new(
+        let mut packet_processor = PacketProcessor::new(
             packet_proc_rx,
             senders_to_c_h_writers.clone(),
             self.logger.clone(),
+            state.clone(),
+            client_addresses.clone(),
+            self.config.packet_ids,
+            processor_shutdown.clone(),
         );
+        packet_processor.set_clock(self.clock.clone());
+        for hook in &self.hooks {
+            packet_processor.add_hook(hook.clone());
+        }
+        if self.config.sys_interval_seconds > 0 {
+            packet_processor
+                .enable_sys_topics(Duration::from_secs(self.config.sys_interval_seconds));
+        }
+        if let Some(path) = &self.config.persistence_file {
+            packet_processor.enable_persistence(path.clone())?;
+        }
+        if self.config.audit.enabled {
+            packet_processor.enable_audit(AuditLog::open(&self.config.audit)?);
+            self.logger
+                .log_msg(LogMessage::new(
+                    format!("Audit trail written to {}", self.config.audit.file),
+                    "".to_string(),
+                ))
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        let capture = if self.config.capture.enabled {
+            let capture = Capture::open(&self.config.capture)?;
+            self.logger
+                .log_msg(LogMessage::new(
+                    format!(
+                        "Capturing traffic of clients {} to {}",
+                        self.config.capture.clients.join(", "),
+                        self.config.capture.file
+                    ),
+                    "".to_string(),
+                ))
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            Some(Arc::new(capture))
+        } else {
+            None
+        };
+
+        let metrics_join_handle = metrics_listener.map(|listener| {
+            metrics::spawn(
+                listener,
+                packet_processor.stats(),
+                state.connection_limiter.clone(),
+                shutdown.clone(),
+            )
+        });
+
+        let mut reloader = Reloader::new(self.config.clone(), state.clone(), self.logger.clone());
+        let reload_tx = reloader.requests();
+        let sighup_received = reloader.sighup_flag();
+        let admin_join_handle = admin_listener.map(|(listener, token)| {
+            let backend = AdminBackend {
+                processor_tx: packet_processor.enable_admin(),
+                reload_tx: reload_tx.clone(),
+                stats: packet_processor.stats(),
+                connection_limiter: state.connection_limiter.clone(),
+            };
+            admin::spawn(listener, token, backend, shutdown.clone())
+        });
+
+        let reloader_join_handle = reloader.run(shutdown.clone());
         let packet_processor_join_handle = packet_processor.run();
 
-        self.handle_connections(listener, senders_to_c_h_writers, c_h_reader_tx);
+        let channels = ClientChannels {
+            next_client_id: Arc::new(AtomicU32::new(0)),
+            senders_to_c_h_writers,
+            c_h_reader_tx,
+        };
+        let context = ConnectionContext {
+            logger: self.logger.clone(),
+            capture,
+            channels: channels.clone(),
+            state,
+            client_addresses,
+            shutdown: shutdown.clone(),
+        };
+        let ready = Arc::new((Mutex::new(false), Condvar::new()));
+        let accept_ready = ready.clone();
+        let join_handle = thread::spawn(move || {
+            let listener_join_handles: Vec<JoinHandle<Vec<JoinHandle<()>>>> = listeners
+                .into_iter()
+                .map(|(config, listener, _, tls)| {
+                    spawn_listener(listener, config, tls, context.clone())
+                })
+                .collect();
 
-        packet_processor_join_handle.join().unwrap();
-        Ok(())
+            let (lock, condvar) = &*accept_ready;
+            *lock.lock().unwrap() = true;
+            condvar.notify_all();
+
+            let mut client_handler_join_handles = vec![];
+            for handle in listener_join_handles {
+                client_handler_join_handles.extend(handle.join().unwrap());
+            }
+
+            // El PacketProcessor cierra los client handlers antes de terminar
+            processor_shutdown.store(true, Ordering::SeqCst);
+            packet_processor_join_handle.join().unwrap();
+            for handle in client_handler_join_handles {
+                handle.join().unwrap();
+            }
+            reloader_join_handle.join().unwrap();
+            if let Some(handle) = metrics_join_handle {
+                handle.join().unwrap();
+            }
+            if let Some(handle) = admin_join_handle {
+                handle.join().unwrap();
+            }
+            let _ = self.logger.log_msg(LogMessage::new(
+                "Servidor detenido".to_string(),
+                "".to_string(),
+            ));
+        });
+
+        Ok(ServerHandle {
+            local_address,
+            websocket_address,
+            listener_addresses,
+            metrics_address,
+            admin_address,
+            shutdown,
+            ready,
+            join_handle,
+            channels,
+            reload_tx,
+            sighup_received,
+        })
     }
 
-    fn handle_connections(
+    fn bind(&self, config: &ListenerConfig) -> io::Result<BoundListener> {
+        let listener = match &config.transport {
+            Transport::Unix(path) => BoundListener::Unix(bind_unix(path, config.permissions)?),
+            _ => BoundListener::Tcp(TcpListener::bind(config.bind_address())?),
+        };
+        let description = match listener.local_address()? {
+            Some(address) => address.to_string(),
+            None => format!("unix:{}", listener.unix_path().unwrap_or_default()),
+        };
+        self.logger
+            .log_msg(LogMessage::new(
+                format!(
+                    "Servidor escuchando en: {} ({} {})",
+                    description,
+                    config.name,
+                    config.transport.name()
+                ),
+                "".to_string(),
+            ))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(listener)
+    }
+
+    // Listener de métricas o de administración. `description` lleva {} donde va la dirección
+    fn bind_http(&self, address: &str, port: u16, description: &str) -> io::Result<TcpListener> {
+        let listener = TcpListener::bind(listener::bind_address(address, port))?;
+        self.logger
+            .log_msg(LogMessage::new(
+                description.replace("{}", &listener.local_addr()?.to_string()),
+                "".to_string(),
+            ))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(listener)
+    }
+}
+
+fn tls_config(
+    config: &ListenerConfig,
+) -> Result<Option<TlsServerConfig>, Box<dyn std::error::Error>> {
+    match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => Ok(Some(tls::server_config(
+            cert_file,
+            key_file,
+            config.tls_client_ca_file.as_deref(),
+        )?)),
+        _ => Ok(None),
+    }
+}
+
+// Crea el socket, borrando antes el que pudo haber quedado de una ejecución anterior
+fn bind_unix(path: &str, permissions: Option<u32>) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} existe y no es un socket", path),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(permissions) = permissions {
+        fs::set_permissions(path, fs::Permissions::from_mode(permissions))?;
+    }
+    Ok(listener)
+}
+
+enum BoundListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    Unix(UnixStream),
+}
+
+impl BoundListener {
+    fn local_address(&self) -> io::Result<Option<SocketAddr>> {
+        match self {
+            BoundListener::Tcp(listener) => Ok(Some(listener.local_addr()?)),
+            BoundListener::Unix(_) => Ok(None),
+        }
+    }
+
+    fn unix_path(&self) -> Option<String> {
+        match self {
+            BoundListener::Unix(listener) => listener
+                .local_addr()
+                .ok()?
+                .as_pathname()
+                .map(|path| path.to_string_lossy().to_string()),
+            BoundListener::Tcp(_) => None,
+        }
+    }
+
+    // El listener no bloquea para poder revisar periódicamente si hay que apagar el server
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            BoundListener::Tcp(listener) => listener.set_nonblocking(true),
+            BoundListener::Unix(listener) => listener.set_nonblocking(true),
+        }
+    }
+
+    // Las conexiones aceptadas vuelven a ser bloqueantes
+    fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            BoundListener::Tcp(listener) => {
+                let (stream, address) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(Accepted::Tcp(stream, address))
+            }
+            BoundListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(Accepted::Unix(stream))
+            }
+        }
+    }
+}
+
+// Todo lo que necesita un listener para darle un client handler a cada conexión
+#[derive(Clone)]
+struct ConnectionContext {
+    logger: Arc<Logger>,
+    capture: Option<Arc<Capture>>,
+    channels: ClientChannels,
+    state: ReloadableState,
+    client_addresses: ClientAddresses,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl ConnectionContext {
+    fn log(&self, msg: String) {
+        let _ = self.logger.log_msg(LogMessage::new(msg, "".to_string()));
+    }
+
+    fn start_client_handler(
         &self,
-        listener: TcpListener,
-        senders_to_c_h_writers: Arc<RwLock<HashMap<u32, ArcSenderPacket>>>,
-        c_h_reader_tx: Sender<(u32, PacketResult)>,
-    ) {
-        let mut join_handles = vec![];
-
-        for (id, stream) in listener.incoming().flatten().enumerate() {
-            //if let Ok(stream) = stream {
-            let client_handler = ClientHandler::new(
-                id as u32,
-                stream,
-                senders_to_c_h_writers.clone(),
-                c_h_reader_tx.clone(),
-            );
-
-            if let Ok(join_handle) = client_handler.run() {
-                join_handles.push(join_handle);
-            };
-            //}
+        id: u32,
+        stream: Box<dyn ClientStream>,
+        listener: &ListenerConfig,
+        slot: ConnectionSlot,
+    ) -> Option<JoinHandle<()>> {
+        // Lo de la sección [connection] más los límites propios del listener
+        let settings = self.state.settings();
+        let users_without_password = if listener.require_auth {
+            settings
+                .authenticator
+                .policy()
+                .users_without_password
+                .clone()
+        } else {
+            vec![]
+        };
+        let config = ClientHandlerConfig {
+            max_packet_size: listener.max_packet_size,
+            require_auth: listener.require_auth,
+            users_without_password,
+            peer_uid_as_username: listener.peer_uid_as_username,
+            proxy_protocol: listener.proxy_protocol,
+            cert_cn_as_username: listener.cert_cn_as_username,
+            ..settings.connection.clone()
+        };
+        let mut client_handler = ClientHandler::new(
+            id,
+            stream,
+            self.channels.senders_to_c_h_writers.clone(),
+            self.channels.c_h_reader_tx.clone(),
+            config,
+            self.client_addresses.clone(),
+        )
+        .hold_slot(slot);
+        if let Some(capture) = &self.capture {
+            client_handler = client_handler.capture(capture.clone());
+        }
+        client_handler.run().ok()
+    }
+}
+
+// Cada listener acepta conexiones en su propio thread hasta que se apaga el server, y
+// devuelve los threads de los clientes que aceptó
+fn spawn_listener(
+    listener: BoundListener,
+    config: ListenerConfig,
+    tls: Option<TlsServerConfig>,
+    context: ConnectionContext,
+) -> JoinHandle<Vec<JoinHandle<()>>> {
+    thread::spawn(move || {
+        let join_handles = handle_connections(&listener, &config, tls, context);
+        if let Some(path) = listener.unix_path() {
+            let _ = fs::remove_file(path);
         }
+        join_handles
+    })
+}
 
-        for handle in join_handles {
-            handle.join().unwrap();
+fn handle_connections(
+    listener: &BoundListener,
+    config: &ListenerConfig,
+    tls: Option<TlsServerConfig>,
+    context: ConnectionContext,
+) -> Vec<JoinHandle<()>> {
+    let mut join_handles = vec![];
+    let counter = ConnectionCounter::new(config.max_connections);
+    if listener.set_nonblocking().is_err() {
+        return join_handles;
+    }
+
+    while !context.shutdown.load(Ordering::SeqCst) {
+        let accepted = match listener.accept() {
+            Ok(accepted) => accepted,
+            // No hay conexiones nuevas todavía
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            // Por ejemplo EMFILE: se sigue intentando, pero tiene que quedar en el log
+            Err(e) => {
+                let _ = context.logger.log_msg(LogMessage::error(
+                    format!(
+                        "Listener {} could not accept a connection: {}",
+                        config.name, e
+                    ),
+                    "".to_string(),
+                ));
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+        };
+        let peer_address = match &accepted {
+            Accepted::Tcp(_, address) => Some(*address),
+            Accepted::Unix(_) => None,
+        };
+        let peer = match peer_address {
+            Some(address) => address.to_string(),
+            None => format!("unix socket of listener {}", config.name),
+        };
+        // Los ids se comparten con los clientes locales de ServerHandle::connect_local
+        let id = context.channels.next_id();
+
+        // Las IPs bloqueadas por demasiados Connect fallidos no llegan a tener un client handler.
+        // Detrás de un balanceador la IP real llega con el header PROXY, y el ban se revisa
+        // en el Connect
+        if let Some(address) = peer_address.filter(|_| !config.proxy_protocol) {
+            if context
+                .state
+                .auth_limiter
+                .lock()
+                .unwrap()
+                .ip_is_banned(&address.ip(), Instant::now())
+            {
+                context.state.connection_limiter.record_rejection();
+                context.log(format!(
+                    "Connection from banned IP {} refused",
+                    address.ip()
+                ));
+                continue;
+            }
+        }
+        // Lo mismo con el límite por IP, que se aplica cuando el client handler lee el header
+        let limited_ip = match peer_address {
+            Some(address) if !config.proxy_protocol => Some(address.ip()),
+            _ => None,
+        };
+        let limiter = &context.state.connection_limiter;
+        let limits = context.state.settings().connection_limits;
+        let slot = match limiter.try_acquire(limits, &counter, limited_ip) {
+            Ok(slot) => slot,
+            Err(rejection) => {
+                context.log(format!(
+                    "Connection from {} refused by listener {}: {} ({} rejected so far)",
+                    peer,
+                    config.name,
+                    rejection,
+                    limiter.rejected()
+                ));
+                continue;
+            }
+        };
+        if let Some(address) = peer_address {
+            context
+                .client_addresses
+                .write()
+                .unwrap()
+                .insert(id, address);
         }
+
+        let join_handle = match (accepted, &config.transport, tls.clone()) {
+            // Los handshakes se hacen en otro thread para que un cliente lento no frene al resto
+            (Accepted::Tcp(stream, address), Transport::Tcp, Some(tls)) => {
+                let context = context.clone();
+                let config = config.clone();
+                Some(thread::spawn(move || {
+                    accept_tls(id, stream, address, &config, tls, slot, context)
+                }))
+            }
+            (Accepted::Tcp(stream, address), Transport::WebSocket(_), _) => {
+                let context = context.clone();
+                let config = config.clone();
+                Some(thread::spawn(move || {
+                    accept_websocket(id, stream, address, &config, slot, context)
+                }))
+            }
+            (Accepted::Tcp(stream, _), _, _) => {
+                context.start_client_handler(id, Box::new(stream), config, slot)
+            }
+            (Accepted::Unix(stream), _, _) => {
+                context.start_client_handler(id, Box::new(stream), config, slot)
+            }
+        };
+        // Solo se guardan los clientes que siguen conectados, para esperarlos al apagar
+        join_handles.retain(|handle| !handle.is_finished());
+        if let Some(join_handle) = join_handle {
+            join_handles.push(join_handle);
+        }
+    }
+
+    join_handles
+}
+
+fn accept_websocket(
+    id: u32,
+    stream: TcpStream,
+    peer_address: SocketAddr,
+    config: &ListenerConfig,
+    slot: ConnectionSlot,
+    context: ConnectionContext,
+) {
+    let path = match &config.transport {
+        Transport::WebSocket(path) => path,
+        _ => return,
+    };
+    let connect_timeout = context.state.settings().connection.connect_timeout;
+    let stream = match websocket::accept(stream, path, connect_timeout) {
+        Ok(stream) => stream,
+        Err(e) => {
+            context.client_addresses.write().unwrap().remove(&id);
+            context.log(format!(
+                "WebSocket handshake from {} failed: {}",
+                peer_address, e
+            ));
+            return;
+        }
+    };
+    start_after_handshake(id, Box::new(stream), config, slot, context);
+}
+
+fn accept_tls(
+    id: u32,
+    stream: TcpStream,
+    peer_address: SocketAddr,
+    config: &ListenerConfig,
+    tls: TlsServerConfig,
+    slot: ConnectionSlot,
+    context: ConnectionContext,
+) {
+    let connect_timeout = context.state.settings().connection.connect_timeout;
+    let stream = match TlsStream::accept(stream, tls, Some(connect_timeout)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            context.client_addresses.write().unwrap().remove(&id);
+            context.log(format!("TLS handshake from {} failed: {}", peer_address, e));
+            return;
+        }
+    };
+    start_after_handshake(id, Box::new(stream), config, slot, context);
+}
+
+fn start_after_handshake(
+    id: u32,
+    stream: Box<dyn ClientStream>,
+    config: &ListenerConfig,
+    slot: ConnectionSlot,
+    context: ConnectionContext,
+) {
+    // Si el server se apagó durante el handshake ya no hay quien atienda al cliente
+    if context.shutdown.load(Ordering::SeqCst) {
+        context.client_addresses.write().unwrap().remove(&id);
+        let _ = stream.shutdown();
+        return;
+    }
+    if let Some(join_handle) = context.start_client_handler(id, stream, config, slot) {
+        let _ = join_handle.join();
     }
 }
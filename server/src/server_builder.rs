@@ -0,0 +1,276 @@
+use crate::auth_limiter::AuthLimiterConfig;
+use crate::authenticator::AuthPolicy;
+use crate::client_handler::ClientHandlerConfig;
+use crate::config::Config;
+use crate::hooks::BrokerHook;
+use crate::jwt::JwtConfig;
+use crate::keep_alive::Clock;
+use crate::listener::{ConnectionLimits, ListenerConfig};
+use crate::server::{Server, ServerHandle};
+use common::logging::logger::Logger;
+use std::sync::Arc;
+
+const EMBEDDED_ADDRESS: &str = "127.0.0.1";
+
+// Arma y arranca un server desde código, por ejemplo para tests de integración.
+// Por defecto escucha en 127.0.0.1 con un puerto libre elegido por el sistema, no tiene
+// cuentas, salvo que se le pase un archivo con accounts_file, y no escribe ningún log
+// salvo que se le pase uno con log_file
+pub struct ServerBuilder {
+    config: Config,
+    logger: Option<Arc<Logger>>,
+    log_to_file: bool,
+    clock: Option<Arc<dyn Clock>>,
+    hooks: Vec<Arc<dyn BrokerHook>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        let mut builder = ServerBuilder::from_config(Config {
+            address: EMBEDDED_ADDRESS.to_string(),
+            port: 0,
+            // Un server embebido no depende del directorio de trabajo
+            accounts_filename: None,
+            ..Config::default()
+        });
+        // Ni escribe logfile.txt en él
+        builder.log_to_file = false;
+        builder
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn from_config(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            logger: None,
+            log_to_file: true,
+            clock: None,
+            hooks: vec![],
+        }
+    }
+
+    pub fn address(mut self, address: &str) -> ServerBuilder {
+        self.config.address = address.to_string();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> ServerBuilder {
+        self.config.port = port;
+        self
+    }
+
+    pub fn packet_ids(mut self, packet_ids: u16) -> ServerBuilder {
+        self.config.packet_ids = packet_ids;
+        self
+    }
+
+    pub fn accounts_file(mut self, path: &str) -> ServerBuilder {
+        self.config.accounts_filename = Some(path.to_string());
+        self
+    }
+
+    pub fn auth_policy(mut self, auth_policy: AuthPolicy) -> ServerBuilder {
+        self.config.auth_policy = auth_policy;
+        self
+    }
+
+    pub fn jwt(mut self, jwt: JwtConfig) -> ServerBuilder {
+        self.config.jwt = Some(jwt);
+        self
+    }
+
+    pub fn auth_limiter(mut self, auth_limiter: AuthLimiterConfig) -> ServerBuilder {
+        self.config.auth_limiter = auth_limiter;
+        self
+    }
+
+    pub fn connection(mut self, connection: ClientHandlerConfig) -> ServerBuilder {
+        self.config.connection = connection;
+        self
+    }
+
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> ServerBuilder {
+        self.config.connection_limits = limits;
+        self
+    }
+
+    pub fn persistence_file(mut self, path: &str) -> ServerBuilder {
+        self.config.persistence_file = Some(path.to_string());
+        self
+    }
+
+    // Habilita el listener WebSocket en este puerto (0 elige uno libre)
+    pub fn websocket_port(mut self, port: u16) -> ServerBuilder {
+        self.config.websocket.enabled = true;
+        self.config.websocket.port = port;
+        self
+    }
+
+    // Agrega un listener. Apenas se agrega uno dejan de usarse address, port y websocket_port
+    pub fn listener(mut self, listener: ListenerConfig) -> ServerBuilder {
+        self.config.listeners.push(listener);
+        self
+    }
+
+    // Habilita /metrics en este puerto (0 elige uno libre)
+    pub fn metrics_port(mut self, port: u16) -> ServerBuilder {
+        self.config.metrics.enabled = true;
+        self.config.metrics.address = EMBEDDED_ADDRESS.to_string();
+        self.config.metrics.port = port;
+        self
+    }
+
+    // Habilita la API de administración en este puerto (0 elige uno libre), con el token
+    // que está en `token_file`
+    pub fn admin_port(mut self, port: u16, token_file: &str) -> ServerBuilder {
+        self.config.admin.enabled = true;
+        self.config.admin.address = EMBEDDED_ADDRESS.to_string();
+        self.config.admin.port = port;
+        self.config.admin.token_file = Some(token_file.to_string());
+        self
+    }
+
+    // Registra en `path` las publicaciones, entregas y Pubacks de los tópicos que matchean
+    // con `topics`
+    pub fn audit_file(mut self, path: &str, topics: &[&str], hash_payload: bool) -> ServerBuilder {
+        self.config.audit.enabled = true;
+        self.config.audit.file = path.to_string();
+        self.config.audit.topics = topics.iter().map(|topic| topic.to_string()).collect();
+        self.config.audit.hash_payload = hash_payload;
+        self
+    }
+
+    // Captura en `path` el tráfico de los clientes cuyo client id matchea con `clients`
+    pub fn capture_file(mut self, path: &str, clients: &[&str]) -> ServerBuilder {
+        self.config.capture.enabled = true;
+        self.config.capture.file = path.to_string();
+        self.config.capture.clients = clients.iter().map(|client| client.to_string()).collect();
+        self
+    }
+
+    // Cada cuántos segundos se publica $SYS/broker/... (0 = nunca)
+    pub fn sys_interval_seconds(mut self, seconds: u64) -> ServerBuilder {
+        self.config.sys_interval_seconds = seconds;
+        self
+    }
+
+    // Si no se pasa un logger, se crea uno que escribe en este archivo
+    pub fn log_file(mut self, path: &str) -> ServerBuilder {
+        self.config.log.file = path.to_string();
+        self.log_to_file = true;
+        self
+    }
+
+    pub fn logger(mut self, logger: Arc<Logger>) -> ServerBuilder {
+        self.logger = Some(logger);
+        self
+    }
+
+    // Para controlar en los tests cuándo vencen los keep alive
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> ServerBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    // Los hooks se llaman en el orden en que se agregan
+    pub fn hook(mut self, hook: Arc<dyn BrokerHook>) -> ServerBuilder {
+        self.hooks.push(hook);
+        self
+    }
+
+    // Arranca el server en otros threads y devuelve el handle apenas está escuchando
+    pub fn start(mut self) -> Result<ServerHandle, Box<dyn std::error::Error>> {
+        self.config.validate()?;
+        let logger = match self.logger {
+            Some(logger) => logger,
+            None if self.log_to_file => Arc::new(Logger::from_config(&self.config.log)?),
+            None => Arc::new(Logger::without_file(&self.config.log)),
+        };
+        let mut server = Server::new(self.config, logger)?;
+        if let Some(clock) = self.clock {
+            server = server.with_clock(clock);
+        }
+        for hook in self.hooks {
+            server = server.with_hook(hook);
+        }
+        server.start()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    fn log_file(name: &str) -> String {
+        env::temp_dir()
+            .join(format!(
+                "server_builder_{}_{}.log",
+                std::process::id(),
+                name
+            ))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn port_zero_binds_a_free_port() {
+        let handle = ServerBuilder::new()
+            .log_file(&log_file("port"))
+            .start()
+            .unwrap();
+        assert_ne!(handle.local_address().port(), 0);
+        assert!(handle.wait_ready(Duration::from_secs(5)));
+        assert!(TcpStream::connect(handle.local_address()).is_ok());
+        handle.shutdown();
+    }
+
+    #[test]
+    fn two_servers_can_run_at_the_same_time() {
+        let first = ServerBuilder::new()
+            .log_file(&log_file("first"))
+            .start()
+            .unwrap();
+        let second = ServerBuilder::new()
+            .log_file(&log_file("second"))
+            .start()
+            .unwrap();
+        assert_ne!(first.local_address(), second.local_address());
+        first.shutdown();
+        second.shutdown();
+    }
+
+    #[test]
+    fn default_builder_only_logs_to_a_file_when_asked() {
+        assert!(!ServerBuilder::new().log_to_file);
+        assert!(
+            ServerBuilder::new()
+                .log_file(&log_file("asked"))
+                .log_to_file
+        );
+
+        let handle = ServerBuilder::new().start().unwrap();
+        assert!(handle.wait_ready(Duration::from_secs(5)));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let result = ServerBuilder::new()
+            .log_file(&log_file("invalid"))
+            .auth_limiter(AuthLimiterConfig {
+                max_failures: 3,
+                ban_seconds: 60,
+                max_ban_seconds: 10,
+            })
+            .start();
+        assert!(result.is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::jwt::TokenClaims;
 use crate::topic_filters;
 use common::all_packets::connect::Connect;
 use common::all_packets::publish::Publish;
@@ -15,6 +16,9 @@ pub struct Session {
     pub last_will_retain: bool,
     pub unacknowledged_messages: Vec<Publish>,
     pub is_clean_session: bool,
+    token_claims: Option<TokenClaims>,
+    // Segundos desde epoch de la última conexión
+    connected_at: u64,
 }
 
 impl Session {
@@ -42,9 +46,36 @@ impl Session {
             last_will_msg: packet_connect.connect_payload.last_will_message,
             last_will_topic: packet_connect.connect_payload.last_will_topic,
             last_will_retain: packet_connect.last_will_retain,
+            token_claims: None,
+            connected_at: 0,
         })
     }
 
+    // Sesión persistente leída del archivo de estado, sin cliente conectado
+    pub fn restore(
+        client_id: String,
+        client_subscriptions: Vec<Subscription>,
+        unacknowledged_messages: Vec<Publish>,
+    ) -> Session {
+        Session {
+            client_handler_id: None,
+            client_data: ClientData {
+                client_id,
+                username: None,
+                password: None,
+            },
+            client_subscriptions,
+            unacknowledged_messages,
+            is_clean_session: false,
+            last_will_qos: None,
+            last_will_msg: None,
+            last_will_topic: None,
+            last_will_retain: false,
+            token_claims: None,
+            connected_at: 0,
+        }
+    }
+
     pub fn get_client_id(&self) -> &String {
         &self.client_data.client_id
     }
@@ -57,8 +88,19 @@ impl Session {
         self.client_handler_id.is_some()
     }
 
-    pub fn connect(&mut self, client_handler_id: u32) {
+    pub fn get_username(&self) -> Option<&String> {
+        self.client_data.username.as_ref()
+    }
+
+    pub fn connected_at(&self) -> u64 {
+        self.connected_at
+    }
+
+    // Una sesión persistente puede retomarse con otro usuario
+    pub fn connect(&mut self, client_handler_id: u32, username: Option<String>, now: u64) {
         self.client_handler_id = Some(client_handler_id);
+        self.client_data.username = username;
+        self.connected_at = now;
     }
 
     pub fn disconnect(&mut self) {
@@ -91,6 +133,10 @@ impl Session {
         self.client_subscriptions.push(subscription);
     }
 
+    pub fn get_subscriptions(&self) -> &Vec<Subscription> {
+        &self.client_subscriptions
+    }
+
     pub fn remove_subscription(&mut self, topic_filter: String) {
         self.client_subscriptions
             .retain(|s| s.topic_filter != topic_filter);
@@ -100,6 +146,44 @@ impl Session {
         self.unacknowledged_messages.push(publish_packet);
     }
 
+    // Los claims del token con el que se autenticó la última conexión (si se usó JWT)
+    pub fn set_token_claims(&mut self, token_claims: Option<TokenClaims>) {
+        self.token_claims = token_claims;
+    }
+
+    pub fn token_expired(&self, now: u64) -> bool {
+        match &self.token_claims {
+            Some(claims) => now >= claims.expires_at,
+            None => false,
+        }
+    }
+
+    pub fn can_publish_to(&self, topic_name: &str) -> bool {
+        match self
+            .token_claims
+            .as_ref()
+            .and_then(|claims| claims.publish_topics.as_ref())
+        {
+            Some(allowed) => allowed
+                .iter()
+                .any(|filter| topic_filters::filter_matches_topic(filter, topic_name)),
+            None => true,
+        }
+    }
+
+    pub fn can_subscribe_to(&self, topic_filter: &str) -> bool {
+        match self
+            .token_claims
+            .as_ref()
+            .and_then(|claims| claims.subscribe_topics.as_ref())
+        {
+            Some(allowed) => allowed
+                .iter()
+                .any(|filter| topic_filters::filter_is_covered_by(filter, topic_filter)),
+            None => true,
+        }
+    }
+
     pub fn update_last_will(&mut self, connect_packet: &Connect) {
         self.last_will_msg = connect_packet.connect_payload.last_will_message.clone();
         self.last_will_topic = connect_packet.connect_payload.last_will_topic.clone();
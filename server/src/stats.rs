@@ -0,0 +1,286 @@
+use common::packet::Packet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Nombres de los tipos de paquete, en el orden de packet_type_index
+pub const PACKET_TYPES: [&str; 11] = [
+    "connect",
+    "connack",
+    "publish",
+    "puback",
+    "subscribe",
+    "suback",
+    "unsubscribe",
+    "unsuback",
+    "pingreq",
+    "pingresp",
+    "disconnect",
+];
+const PUBLISH_INDEX: usize = 2;
+
+// Límites superiores (en segundos) de los buckets del histograma de latencia de los Publish
+pub const PUBLISH_LATENCY_BUCKETS: &[f64] =
+    &[0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+fn packet_type_index(packet: &Packet) -> usize {
+    match packet {
+        Packet::Connect(_) => 0,
+        Packet::Connack(_) => 1,
+        Packet::Publish(_) => PUBLISH_INDEX,
+        Packet::Puback(_) => 3,
+        Packet::Subscribe(_) => 4,
+        Packet::Suback(_) => 5,
+        Packet::Unsubscribe(_) => 6,
+        Packet::Unsuback(_) => 7,
+        Packet::Pingreq(_) => 8,
+        Packet::Pingresp(_) => 9,
+        Packet::Disconnect(_) => 10,
+    }
+}
+
+// Valores que salen del estado del PacketProcessor (no son contadores)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BrokerGauges {
+    pub clients_connected: usize,
+    // Sesiones persistentes cuyo cliente no está conectado
+    pub clients_disconnected: usize,
+    pub subscriptions: usize,
+    pub retained_messages: usize,
+    // Publish QoS 1 de cada cliente que todavía esperan su Puback
+    pub client_queues: Vec<(String, usize)>,
+}
+
+// Histograma con buckets fijos. Cada bucket cuenta solo sus observaciones; los acumulados se
+// calculan al leerlo
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // Un contador por bucket y uno más para +Inf
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let index = self
+            .bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds.len());
+        add(&self.buckets[index], 1);
+        add(&self.count, 1);
+        add(&self.sum_micros, duration.as_micros() as u64);
+    }
+
+    // Pares (límite, observaciones menores o iguales) y al final (+Inf, total)
+    pub fn cumulative_buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .chain(std::iter::once(&f64::INFINITY))
+            .zip(&self.buckets)
+            .map(|(bound, bucket)| {
+                total += bucket.load(Ordering::Relaxed);
+                (*bound, total)
+            })
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum_seconds(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    }
+}
+
+// Estadísticas acumuladas desde que arrancó el server. Son atómicas porque las comparten el
+// PacketProcessor, el PubackProcessor y el listener de métricas
+#[derive(Debug)]
+pub struct BrokerStats {
+    packets_received: [AtomicU64; 11],
+    packets_sent: [AtomicU64; 11],
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_dropped: AtomicU64,
+    retransmissions: AtomicU64,
+    auth_failures: AtomicU64,
+    publish_latency: Histogram,
+    // Lo actualiza el PacketProcessor cada tanto
+    gauges: Mutex<BrokerGauges>,
+}
+
+impl Default for BrokerStats {
+    fn default() -> Self {
+        BrokerStats {
+            packets_received: Default::default(),
+            packets_sent: Default::default(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            messages_dropped: AtomicU64::new(0),
+            retransmissions: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            publish_latency: Histogram::new(PUBLISH_LATENCY_BUCKETS),
+            gauges: Mutex::new(BrokerGauges::default()),
+        }
+    }
+}
+
+impl BrokerStats {
+    pub fn new() -> BrokerStats {
+        BrokerStats::default()
+    }
+
+    pub fn packet_received(&self, packet: &Packet) {
+        add(&self.bytes_received, packet.encoded_len() as u64);
+        add(&self.packets_received[packet_type_index(packet)], 1);
+    }
+
+    // Paquetes que el PacketProcessor le pasa al writer de algún cliente
+    pub fn packet_sent(&self, packet: &Packet) {
+        add(&self.bytes_sent, packet.encoded_len() as u64);
+        add(&self.packets_sent[packet_type_index(packet)], 1);
+    }
+
+    // Un mensaje que no le llega a alguien: publicación rechazada, suscriptor QoS 0
+    // desconectado o client handler que ya no recibe
+    pub fn message_dropped(&self) {
+        add(&self.messages_dropped, 1);
+    }
+
+    // Publish QoS 1 que el PubackProcessor vuelve a mandar por no recibir el Puback
+    pub fn retransmission(&self) {
+        add(&self.retransmissions, 1);
+    }
+
+    pub fn auth_failure(&self) {
+        add(&self.auth_failures, 1);
+    }
+
+    // Tiempo que tardó el PacketProcessor en repartir un Publish a los suscriptores
+    pub fn publish_processed(&self, duration: Duration) {
+        self.publish_latency.observe(duration);
+    }
+
+    pub fn set_gauges(&self, gauges: BrokerGauges) {
+        *self.gauges.lock().unwrap() = gauges;
+    }
+
+    pub fn gauges(&self) -> BrokerGauges {
+        self.gauges.lock().unwrap().clone()
+    }
+
+    // Los mensajes son los Publish
+    pub fn messages_received(&self) -> u64 {
+        self.packets_received[PUBLISH_INDEX].load(Ordering::Relaxed)
+    }
+
+    pub fn messages_sent(&self) -> u64 {
+        self.packets_sent[PUBLISH_INDEX].load(Ordering::Relaxed)
+    }
+
+    // Pares (tipo de paquete, cantidad)
+    pub fn packets_received(&self) -> Vec<(&'static str, u64)> {
+        by_packet_type(&self.packets_received)
+    }
+
+    pub fn packets_sent(&self) -> Vec<(&'static str, u64)> {
+        by_packet_type(&self.packets_sent)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_dropped(&self) -> u64 {
+        self.messages_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions.load(Ordering::Relaxed)
+    }
+
+    pub fn auth_failures(&self) -> u64 {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn publish_latency(&self) -> &Histogram {
+        &self.publish_latency
+    }
+}
+
+fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+fn by_packet_type(counters: &[AtomicU64; 11]) -> Vec<(&'static str, u64)> {
+    PACKET_TYPES
+        .iter()
+        .zip(counters.iter())
+        .map(|(name, counter)| (*name, counter.load(Ordering::Relaxed)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::all_packets::pingreq::Pingreq;
+    use common::all_packets::publish::{Publish, PublishFlags};
+
+    #[test]
+    fn packets_are_counted_by_type_and_size() {
+        let stats = BrokerStats::new();
+        let publish = Packet::Publish(Publish::new(
+            PublishFlags::new(0b0011_0000),
+            "a".to_string(),
+            None,
+            "hola".to_string(),
+        ));
+        stats.packet_received(&publish);
+        stats.packet_received(&Packet::Pingreq(Pingreq::new()));
+        stats.packet_sent(&publish);
+
+        assert_eq!(stats.messages_received(), 1);
+        assert_eq!(stats.messages_sent(), 1);
+        let received = stats.packets_received();
+        assert!(received.contains(&("publish", 1)));
+        assert!(received.contains(&("pingreq", 1)));
+        assert!(received.contains(&("connect", 0)));
+        // Publish de 9 bytes (2 de header, 3 del tópico y 4 de mensaje) + Pingreq de 2
+        assert_eq!(stats.bytes_received(), 11);
+        assert_eq!(stats.bytes_sent(), 9);
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[0.001, 0.01]);
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(1));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_secs(2));
+
+        assert_eq!(
+            histogram.cumulative_buckets(),
+            vec![(0.001, 2), (0.01, 3), (f64::INFINITY, 4)]
+        );
+        assert_eq!(histogram.count(), 4);
+        assert!((histogram.sum_seconds() - 2.0065).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,190 @@
+use common::stream::time_left;
+use common::tls::{self, TlsStream};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+// Conexión de un cliente, sea cual sea el transporte. El ClientHandler necesita dos copias
+// (una para el reader y otra para el writer) y poder cortar la conexión desde cualquiera
+pub trait ClientStream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn shutdown(&self) -> io::Result<()>;
+    fn try_clone(&self) -> io::Result<Box<dyn ClientStream>>;
+
+    // Lee con un plazo total para todo lo que haga falta leer del socket. Si cada read es una
+    // sola lectura del socket alcanza con ajustar el timeout; TLS y WebSocket leen varias
+    // veces por read y controlan el plazo en cada una
+    fn read_before(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        self.set_read_timeout(Some(time_left(deadline)?))?;
+        self.read(buf)
+    }
+
+    // UID del proceso del otro lado, solo si el transporte lo permite saber
+    fn peer_uid(&self) -> Option<u32> {
+        None
+    }
+
+    // CN del certificado con el que se presentó el cliente, solo en listeners TLS
+    fn peer_certificate_cn(&self) -> Option<String> {
+        None
+    }
+}
+
+// Lee con un plazo total en vez de un timeout por lectura: un cliente que manda de a un byte
+// no puede estirarlo
+pub struct DeadlineReader<'a> {
+    stream: &'a mut dyn ClientStream,
+    deadline: Instant,
+}
+
+impl<'a> DeadlineReader<'a> {
+    pub fn new(stream: &'a mut dyn ClientStream, deadline: Instant) -> DeadlineReader<'a> {
+        DeadlineReader { stream, deadline }
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read_before(buf, self.deadline)
+    }
+}
+
+impl ClientStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+}
+
+impl ClientStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn peer_uid(&self) -> Option<u32> {
+        unix_peer_uid(self)
+    }
+}
+
+impl ClientStream for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TlsStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TlsStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(TlsStream::try_clone(self)?))
+    }
+
+    fn read_before(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        TlsStream::read_before(self, buf, deadline)
+    }
+
+    fn peer_certificate_cn(&self) -> Option<String> {
+        tls::certificate_common_name(&self.peer_certificate()?)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn unix_peer_uid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: el fd es válido mientras exista el stream y el buffer tiene el tamaño indicado
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result == 0 {
+        Some(credentials.uid)
+    } else {
+        None
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+fn unix_peer_uid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    // SAFETY: el fd es válido mientras exista el stream
+    let result = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if result == 0 {
+        Some(uid)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd"
+)))]
+fn unix_peer_uid(_stream: &UnixStream) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_stream_reports_the_uid_of_the_peer() {
+        let (first, _second) = UnixStream::pair().unwrap();
+        let own_uid = unsafe { libc::getuid() };
+        assert_eq!(ClientStream::peer_uid(&first), Some(own_uid));
+    }
+
+    #[test]
+    fn deadline_reader_gives_up_when_bytes_trickle_in() {
+        let (mut first, mut second) = UnixStream::pair().unwrap();
+        let writer = std::thread::spawn(move || {
+            for _ in 0..10 {
+                if second.write_all(b"x").is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(30));
+            }
+        });
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let mut reader = DeadlineReader::new(&mut first, deadline);
+        let mut buffer = [0u8; 10];
+        assert!(reader.read_exact(&mut buffer).is_err());
+        assert!(Instant::now() < deadline + Duration::from_millis(100));
+        drop(first);
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use crate::stats::{BrokerGauges, BrokerStats};
+use std::time::{Duration, Instant};
+
+pub const SYS_TOPIC_PREFIX: &str = "$SYS";
+const BROKER_TOPIC_PREFIX: &str = "$SYS/broker/";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Los tópicos $SYS los escribe solo el server: ningún cliente puede publicar ahí
+pub fn is_sys_topic(topic_name: &str) -> bool {
+    topic_name == SYS_TOPIC_PREFIX || topic_name.starts_with("$SYS/")
+}
+
+// Decide cuándo publicar el árbol $SYS/broker/... y arma sus mensajes
+pub struct SysTopics {
+    interval: Duration,
+    started: Instant,
+    next_publish: Instant,
+}
+
+impl SysTopics {
+    // La primera publicación es enseguida, así los retenidos existen desde el arranque
+    pub fn new(interval: Duration, now: Instant) -> SysTopics {
+        SysTopics {
+            interval,
+            started: now,
+            next_publish: now,
+        }
+    }
+
+    // Devuelve true si toca publicar, y en ese caso programa la siguiente publicación
+    pub fn due(&mut self, now: Instant) -> bool {
+        if now < self.next_publish {
+            return false;
+        }
+        self.next_publish = now + self.interval;
+        true
+    }
+
+    // Pares (tópico, valor) a publicar como retenidos
+    pub fn messages(
+        &self,
+        now: Instant,
+        stats: &BrokerStats,
+        gauges: &BrokerGauges,
+    ) -> Vec<(String, String)> {
+        let uptime = now.duration_since(self.started).as_secs();
+        vec![
+            ("version", VERSION.to_string()),
+            ("uptime", format!("{} seconds", uptime)),
+            ("clients/connected", gauges.clients_connected.to_string()),
+            (
+                "clients/disconnected",
+                gauges.clients_disconnected.to_string(),
+            ),
+            ("subscriptions/count", gauges.subscriptions.to_string()),
+            (
+                "retained messages/count",
+                gauges.retained_messages.to_string(),
+            ),
+            ("messages/received", stats.messages_received().to_string()),
+            ("messages/sent", stats.messages_sent().to_string()),
+            ("messages/dropped", stats.messages_dropped().to_string()),
+            ("bytes/received", stats.bytes_received().to_string()),
+            ("bytes/sent", stats.bytes_sent().to_string()),
+        ]
+        .into_iter()
+        .map(|(topic, value)| (format!("{}{}", BROKER_TOPIC_PREFIX, topic), value))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::all_packets::pingreq::Pingreq;
+    use common::all_packets::publish::{Publish, PublishFlags};
+    use common::packet::Packet;
+
+    #[test]
+    fn only_the_sys_tree_is_reserved() {
+        assert!(is_sys_topic("$SYS"));
+        assert!(is_sys_topic("$SYS/broker/uptime"));
+        assert!(!is_sys_topic("$SYSTEM/x"));
+        assert!(!is_sys_topic("casa/$SYS"));
+    }
+
+    #[test]
+    fn publishes_right_away_and_then_every_interval() {
+        let start = Instant::now();
+        let mut sys_topics = SysTopics::new(Duration::from_secs(10), start);
+
+        assert!(sys_topics.due(start));
+        assert!(!sys_topics.due(start));
+        assert!(!sys_topics.due(start + Duration::from_secs(9)));
+        assert!(sys_topics.due(start + Duration::from_secs(10)));
+        assert!(!sys_topics.due(start + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn messages_include_counters_and_gauges() {
+        let start = Instant::now();
+        let sys_topics = SysTopics::new(Duration::from_secs(10), start);
+        let stats = BrokerStats::new();
+        let publish = Packet::Publish(Publish::new(
+            PublishFlags::new(0b0011_0000),
+            "a".to_string(),
+            None,
+            "hola".to_string(),
+        ));
+        stats.packet_received(&publish);
+        stats.packet_received(&Packet::Pingreq(Pingreq::new()));
+        stats.packet_sent(&publish);
+        stats.message_dropped();
+        let gauges = BrokerGauges {
+            clients_connected: 2,
+            clients_disconnected: 1,
+            subscriptions: 3,
+            retained_messages: 4,
+            ..BrokerGauges::default()
+        };
+
+        let messages = sys_topics.messages(start + Duration::from_secs(42), &stats, &gauges);
+        let value = |topic: &str| {
+            messages
+                .iter()
+                .find(|(t, _)| t == &format!("$SYS/broker/{}", topic))
+                .map(|(_, value)| value.clone())
+                .unwrap()
+        };
+        assert_eq!(value("version"), VERSION);
+        assert_eq!(value("uptime"), "42 seconds");
+        assert_eq!(value("clients/connected"), "2");
+        assert_eq!(value("clients/disconnected"), "1");
+        assert_eq!(value("subscriptions/count"), "3");
+        assert_eq!(value("retained messages/count"), "4");
+        assert_eq!(value("messages/received"), "1");
+        assert_eq!(value("messages/sent"), "1");
+        assert_eq!(value("messages/dropped"), "1");
+        // Publish de 9 bytes (2 de header, 3 del tópico y 4 de mensaje) + Pingreq de 2
+        assert_eq!(value("bytes/received"), "11");
+        assert_eq!(value("bytes/sent"), "9");
+    }
+}
@@ -48,6 +48,31 @@ pub fn topic_filter_is_valid(filter: &str) -> bool {
     true
 }
 
+// Indica si todo tópico que matchea con `filter` también matchea con `allowed`
+pub fn filter_is_covered_by(allowed: &str, filter: &str) -> bool {
+    let allowed_levels: Vec<&str> = allowed.split('/').collect();
+    let filter_levels: Vec<&str> = filter.split('/').collect();
+
+    for (pos, level) in allowed_levels.iter().enumerate() {
+        if *level == "#" {
+            return !(pos == 0 && filter_levels[0].starts_with('$'));
+        }
+        match filter_levels.get(pos) {
+            None | Some(&"#") => return false,
+            Some(filter_level) => {
+                if *level != "+" && level != filter_level {
+                    return false;
+                }
+                if *level == "+" && filter_level.starts_with('$') {
+                    return false;
+                }
+            }
+        }
+    }
+
+    allowed_levels.len() == filter_levels.len()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -258,4 +283,31 @@ mod tests {
     fn test41() {
         assert!(!topic_filter_is_valid("abc+def"))
     }
+
+    // filter_is_covered_by tests:
+
+    #[test]
+    fn test42() {
+        assert!(filter_is_covered_by("abc/+", "abc/def"))
+    }
+
+    #[test]
+    fn test43() {
+        assert!(filter_is_covered_by("abc/#", "abc/+/ghi"))
+    }
+
+    #[test]
+    fn test44() {
+        assert!(!filter_is_covered_by("abc/+", "abc/#"))
+    }
+
+    #[test]
+    fn test45() {
+        assert!(!filter_is_covered_by("abc/def", "abc/+"))
+    }
+
+    #[test]
+    fn test46() {
+        assert!(!filter_is_covered_by("#", "$SYS/broker"))
+    }
 }
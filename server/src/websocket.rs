@@ -0,0 +1,517 @@
+use crate::stream::{ClientStream, DeadlineReader};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use common::stream::DeadlineSocket;
+use sha1::{Digest, Sha1};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_WEBSOCKET_PORT: u16 = 9001;
+pub const DEFAULT_WEBSOCKET_PATH: &str = "/mqtt";
+// GUID fijo del RFC 6455 para calcular Sec-WebSocket-Accept
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MQTT_SUBPROTOCOL: &str = "mqtt";
+const MAX_HANDSHAKE_SIZE: usize = 8192;
+const MAX_FRAME_SIZE: u64 = 16 * 1024 * 1024;
+// Los frames de control no pueden fragmentarse ni traer más que esto (RFC 6455, 5.5)
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+const BAD_REQUEST_RESPONSE: &str =
+    "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const NOT_FOUND_RESPONSE: &str =
+    "HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    pub port: u16,
+    // Path que tiene que pedir el cliente en el GET del handshake
+    pub path: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            enabled: false,
+            port: DEFAULT_WEBSOCKET_PORT,
+            path: DEFAULT_WEBSOCKET_PATH.to_string(),
+        }
+    }
+}
+
+// Hace el upgrade HTTP -> WebSocket. `timeout` limita cuánto puede tardar el cliente en
+// mandar el request completo
+pub fn accept(
+    mut socket: TcpStream,
+    path: &str,
+    timeout: Duration,
+) -> Result<WebSocketStream, Box<dyn std::error::Error>> {
+    // El timeout es para todo el handshake, así un cliente que manda de a un byte no lo estira
+    let mut reader = DeadlineReader::new(&mut socket, Instant::now() + timeout);
+    let request = read_handshake_request(&mut reader)?;
+
+    match handshake_response(&request, path) {
+        Ok(response) => {
+            socket.write_all(response.as_bytes())?;
+            Ok(WebSocketStream::new(socket))
+        }
+        Err((response, error)) => {
+            let _ = socket.write_all(response.as_bytes());
+            let _ = socket.shutdown(Shutdown::Both);
+            Err(error.into())
+        }
+    }
+}
+
+// Lee byte a byte hasta el fin de los headers, para no consumir nada del primer frame
+fn read_handshake_request(socket: &mut dyn Read) -> Result<String, Box<dyn std::error::Error>> {
+    let mut request = vec![];
+    let mut byte = [0u8; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_HANDSHAKE_SIZE {
+            return Err("WebSocket handshake too large".into());
+        }
+        socket.read_exact(&mut byte)?;
+        request.push(byte[0]);
+    }
+    Ok(String::from_utf8(request)?)
+}
+
+// Devuelve la respuesta 101, o la respuesta de error junto con el motivo
+fn handshake_response(request: &str, path: &str) -> Result<String, (&'static str, String)> {
+    let bad_request = |msg: &str| (BAD_REQUEST_RESPONSE, msg.to_string());
+    let mut lines = request.split("\r\n");
+    let start_line: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+    if start_line.len() != 3 || start_line[0] != "GET" || !start_line[2].starts_with("HTTP/1.1") {
+        return Err(bad_request("invalid WebSocket request line"));
+    }
+    let request_path = start_line[1].split('?').next().unwrap_or("");
+    if request_path != path {
+        return Err((
+            NOT_FOUND_RESPONSE,
+            format!("unknown WebSocket path {}", request_path),
+        ));
+    }
+
+    let headers: Vec<(String, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim()))
+        .collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header_name, _)| header_name == name)
+            .map(|(_, value)| *value)
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Err(bad_request("missing WebSocket upgrade headers"));
+    }
+    if header("sec-websocket-version") != Some("13") {
+        return Err(bad_request("unsupported WebSocket version"));
+    }
+    let key = match header("sec-websocket-key") {
+        Some(key) if !key.is_empty() => key,
+        _ => return Err(bad_request("missing Sec-WebSocket-Key")),
+    };
+    if !has_token("sec-websocket-protocol", MQTT_SUBPROTOCOL) {
+        return Err(bad_request("client did not offer the mqtt subprotocol"));
+    }
+
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+        accept_key(key),
+        MQTT_SUBPROTOCOL
+    ))
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Un error de protocolo se le informa al cliente con un close antes de cortar
+#[derive(Debug)]
+enum FrameError {
+    Io(io::Error),
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(error: io::Error) -> Self {
+        FrameError::Io(error)
+    }
+}
+
+// Los frames del cliente siempre vienen enmascarados (RFC 6455, 5.1)
+fn read_frame(stream: &mut dyn Read) -> Result<Frame, FrameError> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0F;
+    let masked = header[1] & 0x80 != 0;
+    if header[0] & 0x70 != 0 || !masked {
+        return Err(FrameError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "invalid WebSocket frame header",
+        ));
+    }
+
+    let length = match header[1] & 0x7F {
+        126 => {
+            let mut bytes = [0u8; 2];
+            stream.read_exact(&mut bytes)?;
+            u16::from_be_bytes(bytes) as u64
+        }
+        127 => {
+            let mut bytes = [0u8; 8];
+            stream.read_exact(&mut bytes)?;
+            u64::from_be_bytes(bytes)
+        }
+        length => length as u64,
+    };
+    if opcode & 0x8 != 0 && (!fin || length > MAX_CONTROL_PAYLOAD) {
+        return Err(FrameError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "invalid WebSocket control frame",
+        ));
+    }
+    if length > MAX_FRAME_SIZE {
+        return Err(FrameError::Protocol(
+            CLOSE_MESSAGE_TOO_BIG,
+            "WebSocket frame too large",
+        ));
+    }
+
+    let mut mask = [0u8; 4];
+    stream.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+// Los frames del server van sin máscara y siempre con FIN
+fn write_frame(stream: &mut dyn Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+// Conexión MQTT sobre WebSocket: lo que se lee es el contenido de los mensajes binarios y
+// cada escritura sale como un frame binario. El reader y el writer del ClientHandler tienen
+// cada uno su copia, y comparten un lock para que los pong no se mezclen con otros frames
+pub struct WebSocketStream {
+    socket: TcpStream,
+    write_lock: Arc<Mutex<()>>,
+    buffer: Vec<u8>,
+    position: usize,
+    // Mensaje fragmentado que se está armando, hasta que llegue el frame con FIN
+    fragments: Option<Vec<u8>>,
+}
+
+impl WebSocketStream {
+    fn new(socket: TcpStream) -> WebSocketStream {
+        WebSocketStream {
+            socket,
+            write_lock: Arc::new(Mutex::new(())),
+            buffer: vec![],
+            position: 0,
+            fragments: None,
+        }
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        write_frame(&mut self.socket, opcode, payload)
+    }
+
+    fn close_with(&mut self, code: u16, msg: &str) -> io::Error {
+        let _ = self.send_frame(OPCODE_CLOSE, &code.to_be_bytes());
+        let _ = self.socket.shutdown(Shutdown::Both);
+        io::Error::new(ErrorKind::InvalidData, msg.to_string())
+    }
+
+    // Los ping y los mensajes fragmentados hacen que un read lea varios frames: el plazo se
+    // controla en cada lectura del socket
+    fn read_until(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            let frame = match read_frame(&mut DeadlineSocket::new(&self.socket, deadline)) {
+                Ok(frame) => frame,
+                Err(FrameError::Protocol(code, msg)) => return Err(self.close_with(code, msg)),
+                Err(FrameError::Io(e)) => return Err(e),
+            };
+            match frame.opcode {
+                OPCODE_BINARY | OPCODE_TEXT if self.fragments.is_some() => {
+                    return Err(self.close_with(
+                        CLOSE_PROTOCOL_ERROR,
+                        "new WebSocket message before the previous one ended",
+                    ));
+                }
+                OPCODE_CONTINUATION if self.fragments.is_none() => {
+                    return Err(self.close_with(
+                        CLOSE_PROTOCOL_ERROR,
+                        "WebSocket continuation frame without a message",
+                    ));
+                }
+                OPCODE_BINARY if frame.fin => {
+                    self.buffer = frame.payload;
+                    self.position = 0;
+                }
+                OPCODE_BINARY => self.fragments = Some(frame.payload),
+                OPCODE_CONTINUATION => {
+                    let mut message = self.fragments.take().unwrap_or_default();
+                    if (message.len() + frame.payload.len()) as u64 > MAX_FRAME_SIZE {
+                        return Err(
+                            self.close_with(CLOSE_MESSAGE_TOO_BIG, "WebSocket message too large")
+                        );
+                    }
+                    message.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        self.buffer = message;
+                        self.position = 0;
+                    } else {
+                        self.fragments = Some(message);
+                    }
+                }
+                OPCODE_TEXT => {
+                    return Err(self.close_with(
+                        CLOSE_UNSUPPORTED_DATA,
+                        "MQTT over WebSocket only uses binary frames",
+                    ));
+                }
+                OPCODE_CLOSE => {
+                    // Se contesta el close y se avisa como fin de stream
+                    let _ = self.send_frame(OPCODE_CLOSE, &frame.payload);
+                    return Ok(0);
+                }
+                OPCODE_PING => self.send_frame(OPCODE_PONG, &frame.payload)?,
+                OPCODE_PONG => {}
+                _ => return Err(self.close_with(CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+
+        let available = &self.buffer[self.position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_until(buf, None)
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_frame(OPCODE_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl ClientStream for WebSocketStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(WebSocketStream {
+            socket: self.socket.try_clone()?,
+            write_lock: self.write_lock.clone(),
+            buffer: vec![],
+            position: 0,
+            fragments: None,
+        }))
+    }
+
+    fn read_before(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        self.read_until(buf, Some(deadline))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HANDSHAKE: &str = "GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n";
+
+    fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn valid_handshake_selects_mqtt_subprotocol() {
+        let response = handshake_response(HANDSHAKE, "/mqtt").unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn handshake_without_mqtt_subprotocol_is_rejected() {
+        let request = HANDSHAKE.replace(
+            "Sec-WebSocket-Protocol: mqtt",
+            "Sec-WebSocket-Protocol: chat",
+        );
+        let (response, _) = handshake_response(&request, "/mqtt").unwrap_err();
+        assert_eq!(response, BAD_REQUEST_RESPONSE);
+    }
+
+    #[test]
+    fn handshake_on_other_path_is_not_found() {
+        let (response, _) = handshake_response(HANDSHAKE, "/otro").unwrap_err();
+        assert_eq!(response, NOT_FOUND_RESPONSE);
+    }
+
+    #[test]
+    fn masked_frame_is_unmasked() {
+        let mut bytes = Cursor::new(masked_frame(OPCODE_BINARY, &[0x10, 0x02, 0xAB]));
+        let frame = read_frame(&mut bytes).unwrap();
+        assert_eq!(frame.opcode, OPCODE_BINARY);
+        assert_eq!(frame.payload, vec![0x10, 0x02, 0xAB]);
+    }
+
+    #[test]
+    fn unmasked_client_frame_is_rejected() {
+        let mut bytes = Cursor::new(vec![0x82, 0x01, 0x10]);
+        assert!(matches!(
+            read_frame(&mut bytes),
+            Err(FrameError::Protocol(CLOSE_PROTOCOL_ERROR, _))
+        ));
+    }
+
+    fn websocket_stream(frames: Vec<Vec<u8>>) -> (WebSocketStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        client.write_all(&frames.concat()).unwrap();
+        (WebSocketStream::new(socket), client)
+    }
+
+    fn fragment(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = masked_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame
+    }
+
+    #[test]
+    fn fragmented_message_is_reassembled_around_a_ping() {
+        let (mut stream, mut client) = websocket_stream(vec![
+            fragment(false, OPCODE_BINARY, &[0xC0]),
+            masked_frame(OPCODE_PING, b"hola"),
+            fragment(true, OPCODE_CONTINUATION, &[0x00]),
+        ]);
+        let mut packet = [0u8; 2];
+        stream.read_exact(&mut packet).unwrap();
+        assert_eq!(packet, [0xC0, 0x00]);
+        // El ping se contestó en el medio del mensaje
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x80 | OPCODE_PONG, 4, b'h', b'o', b'l', b'a']);
+    }
+
+    #[test]
+    fn continuation_without_a_message_closes_with_protocol_error() {
+        let (mut stream, mut client) =
+            websocket_stream(vec![fragment(true, OPCODE_CONTINUATION, &[0xC0, 0x00])]);
+        assert!(stream.read(&mut [0u8; 2]).is_err());
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).unwrap();
+        assert_eq!(close, [0x80 | OPCODE_CLOSE, 2, 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn invalid_control_frames_are_rejected() {
+        let mut fragmented_ping = Cursor::new(fragment(false, OPCODE_PING, b"hola"));
+        assert!(matches!(
+            read_frame(&mut fragmented_ping),
+            Err(FrameError::Protocol(CLOSE_PROTOCOL_ERROR, _))
+        ));
+        let mut long_ping = vec![0x80 | OPCODE_PING, 0x80 | 126, 0x00, 126];
+        long_ping.extend_from_slice(&[0u8; 4 + 126]);
+        assert!(matches!(
+            read_frame(&mut Cursor::new(long_ping)),
+            Err(FrameError::Protocol(CLOSE_PROTOCOL_ERROR, _))
+        ));
+    }
+
+    #[test]
+    fn server_frames_use_extended_length() {
+        let mut frame = vec![];
+        write_frame(&mut frame, OPCODE_BINARY, &[0u8; 300]).unwrap();
+        assert_eq!(&frame[..4], &[0x82, 126, 0x01, 0x2C]);
+        assert_eq!(frame.len(), 304);
+    }
+}
@@ -0,0 +1,21 @@
+use server::ServerBuilder;
+use std::env;
+use std::fs;
+use std::net::TcpStream;
+use std::time::Duration;
+
+// Va en su propio archivo porque cambia el directorio de trabajo de todo el proceso
+#[test]
+fn the_builder_starts_from_any_working_directory() {
+    let working_dir = env::temp_dir().join(format!("server_builder_test_{}", std::process::id()));
+    fs::create_dir_all(&working_dir).unwrap();
+    env::set_current_dir(&working_dir).unwrap();
+
+    let handle = ServerBuilder::new().start().unwrap();
+    assert!(handle.wait_ready(Duration::from_secs(5)));
+    assert!(TcpStream::connect(handle.local_address()).is_ok());
+    handle.shutdown();
+
+    env::set_current_dir(env::temp_dir()).unwrap();
+    fs::remove_dir_all(&working_dir).unwrap();
+}
@@ -1,11 +1,28 @@
 use common::all_packets::connack::Connack;
 use common::all_packets::connect::{Connect, ConnectPayload};
+use common::all_packets::disconnect::Disconnect;
+use common::all_packets::pingreq::Pingreq;
 use common::all_packets::publish::{Publish, PublishFlags};
 use common::all_packets::suback::{Suback, SubackReturnCode};
 use common::all_packets::subscribe::Subscribe;
-use common::logging::logger::Logger;
-use server::config::Config;
-use server::server::Server;
+use common::capture::Direction;
+use common::stream::MqttStream;
+use common::tls::TlsClientConfig;
+use server::auth_limiter::AuthLimiterConfig;
+use server::authenticator::AuthPolicy;
+use server::client_handler::ClientHandlerConfig;
+use server::hooks::{ClientIdPrefix, EventCounter, EventCounts, MaxPayloadSize, TopicRewrite};
+use server::keep_alive::ManualClock;
+use server::listener::{ConnectionLimits, ListenerConfig};
+use server::{LocalClientOptions, ServerBuilder, ServerHandle};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
@@ -14,45 +31,48 @@ use common::packet::{Packet, Qos, Subscription, WritePacket};
 use std::net::TcpStream;
 
 #[test]
-fn main() {
-    test01();
-    test02();
-}
-
 fn test01() {
-    run_server();
-
-    thread::sleep(Duration::from_millis(1000));
+    let server = run_server("test01");
 
-    let client_handle = run_client01();
+    let client_handle = run_client01(server.local_address());
 
     client_handle.join().unwrap();
+    server.shutdown();
 }
 
+#[test]
 fn test02() {
-    run_server();
-
-    thread::sleep(Duration::from_millis(1000));
+    let server = run_server("test02");
 
-    let client_handle2 = run_client02();
-    let client_handle3 = run_client03();
+    // client03 publica recién cuando client02 ya está suscripto
+    let (subscribed_tx, subscribed_rx) = mpsc::channel();
+    let client_handle2 = run_client02(server.local_address(), subscribed_tx);
+    let client_handle3 = run_client03(server.local_address(), subscribed_rx);
 
     client_handle3.join().unwrap();
     client_handle2.join().unwrap();
+    server.shutdown();
 }
 
-fn run_server() -> JoinHandle<()> {
-    let config = Config::default();
-    let logger = Logger::new(&config.log_filename);
-    let server = Server::new(config, Arc::new(logger.unwrap())).unwrap();
-    thread::spawn(move || {
-        server.server_run().unwrap();
-    })
+fn temp_file(name: &str) -> String {
+    env::temp_dir()
+        .join(format!("server_test_{}_{}", std::process::id(), name))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn run_server(name: &str) -> ServerHandle {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file(&format!("{}.log", name)))
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    server
 }
 
-fn run_client01() -> JoinHandle<()> {
+fn run_client01(address: SocketAddr) -> JoinHandle<()> {
     thread::spawn(move || {
-        let mut socket = TcpStream::connect("127.0.0.1:8080").unwrap();
+        let mut socket = TcpStream::connect(address).unwrap();
 
         let connect_packet = Connect::new(
             ConnectPayload::new("a".to_owned(), None, None, None, None),
@@ -80,9 +100,9 @@ fn run_client01() -> JoinHandle<()> {
     })
 }
 
-fn run_client02() -> JoinHandle<()> {
+fn run_client02(address: SocketAddr, subscribed_tx: Sender<()>) -> JoinHandle<()> {
     thread::spawn(move || {
-        let mut socket = TcpStream::connect("127.0.0.1:8080").unwrap();
+        let mut socket = TcpStream::connect(address).unwrap();
 
         let connect_packet = Connect::new(
             ConnectPayload::new("b".to_owned(), None, None, None, None),
@@ -123,8 +143,8 @@ fn run_client02() -> JoinHandle<()> {
         if let Packet::Suback(received_suback_packet) = received_suback_packet {
             assert_eq!(received_suback_packet, expected_suback_packet);
         }
+        subscribed_tx.send(()).unwrap();
 
-        thread::sleep(Duration::from_millis(1000));
         let received_publish_packet = Packet::read_from(&mut socket).unwrap();
         let expected_publish_packet = Publish::new(
             PublishFlags::new(0b0011_0010),
@@ -135,13 +155,15 @@ fn run_client02() -> JoinHandle<()> {
 
         if let Packet::Publish(received_publish_packet) = received_publish_packet {
             assert_eq!(received_publish_packet, expected_publish_packet);
+        } else {
+            panic!("Expected Publish, received {:?}", received_publish_packet);
         }
     })
 }
 
-fn run_client03() -> JoinHandle<()> {
+fn run_client03(address: SocketAddr, subscribed_rx: Receiver<()>) -> JoinHandle<()> {
     thread::spawn(move || {
-        let mut socket = TcpStream::connect("127.0.0.1:8080").unwrap();
+        let mut socket = TcpStream::connect(address).unwrap();
 
         let connect_packet = Connect::new(
             ConnectPayload::new("c".to_owned(), None, None, None, None),
@@ -174,8 +196,1345 @@ fn run_client03() -> JoinHandle<()> {
             "hola".to_string(),
         );
 
+        subscribed_rx.recv().unwrap();
         publish_packet_1.write_to(&mut socket).unwrap();
 
-        thread::sleep(Duration::from_millis(10000));
+        let received_puback_packet = Packet::read_from(&mut socket).unwrap();
+        assert!(matches!(received_puback_packet, Packet::Puback(_)));
     })
 }
+
+#[test]
+fn shutdown_closes_clients_and_restores_persistent_sessions() {
+    let state_file = temp_file("state.json");
+    let _ = fs::remove_file(&state_file);
+
+    let handle = start_persistent_server(&state_file);
+    let mut socket = TcpStream::connect(handle.local_address()).unwrap();
+    assert!(!connect_persistent_client(&mut socket));
+
+    let mut subscribe_packet = Subscribe::new(1);
+    subscribe_packet.add_subscription(Subscription {
+        topic_filter: "casa/#".to_string(),
+        max_qos: Qos::AtLeastOnce,
+    });
+    subscribe_packet.write_to(&mut socket).unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut socket).unwrap(),
+        Packet::Suback(_)
+    ));
+
+    // Al apagar el server, el cliente ve que se cerró la conexión
+    handle.shutdown();
+    assert!(Packet::read_from(&mut socket).is_err());
+    assert!(fs::read_to_string(&state_file).unwrap().contains("casa/#"));
+
+    // Un server nuevo retoma la sesión guardada
+    let handle = start_persistent_server(&state_file);
+    let mut socket = TcpStream::connect(handle.local_address()).unwrap();
+    assert!(connect_persistent_client(&mut socket));
+    handle.shutdown();
+    fs::remove_file(&state_file).unwrap();
+}
+
+fn start_persistent_server(state_file: &str) -> ServerHandle {
+    ServerBuilder::new()
+        .log_file(&format!("{}.log", state_file))
+        .persistence_file(state_file)
+        .start()
+        .unwrap()
+}
+
+// Devuelve el flag session_present del Connack
+fn connect_persistent_client(socket: &mut TcpStream) -> bool {
+    let connect_packet = Connect::new(
+        ConnectPayload::new("persistente".to_owned(), None, None, None, None),
+        60,
+        false,
+        false,
+        false,
+    );
+    connect_packet.write_to(socket).unwrap();
+    match Packet::read_from(socket).unwrap() {
+        Packet::Connack(connack) => {
+            assert_eq!(connack.connect_return_code, 0);
+            connack.session_present
+        }
+        packet => panic!("Expected Connack, received {:?}", packet),
+    }
+}
+
+#[test]
+fn websocket_client_connects_with_mqtt_subprotocol() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("websocket.log"))
+        .websocket_port(0)
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let mut socket = TcpStream::connect(server.websocket_address().unwrap()).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    socket
+        .write_all(
+            b"GET /mqtt HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+        )
+        .unwrap();
+    let response = read_http_response(&mut socket);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert!(response.contains("Sec-WebSocket-Protocol: mqtt"));
+
+    let mut connect_bytes = vec![];
+    Connect::new(
+        ConnectPayload::new("ws".to_owned(), None, None, None, None),
+        60,
+        true,
+        false,
+        false,
+    )
+    .write_to(&mut connect_bytes)
+    .unwrap();
+    write_masked_binary_frame(&mut socket, &connect_bytes);
+
+    // El server manda un frame binario sin máscara con el Connack adentro
+    let mut header = [0u8; 2];
+    socket.read_exact(&mut header).unwrap();
+    assert_eq!(header[0], 0x82);
+    let mut payload = vec![0u8; header[1] as usize];
+    socket.read_exact(&mut payload).unwrap();
+    match Packet::read_from(&mut payload.as_slice()).unwrap() {
+        Packet::Connack(connack) => assert_eq!(connack.connect_return_code, 0),
+        packet => panic!("Expected Connack, received {:?}", packet),
+    }
+    server.shutdown();
+}
+
+#[test]
+fn connect_deadline_also_applies_inside_websocket_frames() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("websocket_deadline.log"))
+        .websocket_port(0)
+        .connection(ClientHandlerConfig {
+            connect_timeout: Duration::from_secs(1),
+            ..ClientHandlerConfig::default()
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let mut socket = TcpStream::connect(server.websocket_address().unwrap()).unwrap();
+    socket
+        .write_all(
+            b"GET /mqtt HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+        )
+        .unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    assert!(read_http_response(&mut socket).starts_with("HTTP/1.1 101"));
+
+    // El frame con el Connect llega de a un byte: todo entra en un solo read del stream
+    // WebSocket, pero el plazo corre igual
+    let mut connect = vec![];
+    Connect::new(
+        ConnectPayload::new("lento".to_owned(), None, None, None, None),
+        60,
+        true,
+        false,
+        false,
+    )
+    .write_to(&mut connect)
+    .unwrap();
+    let frame = masked_binary_frame(&connect);
+    let mut writer = socket.try_clone().unwrap();
+    let trickle = thread::spawn(move || {
+        for byte in frame {
+            if writer.write_all(&[byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(300));
+        }
+    });
+    let start = std::time::Instant::now();
+    assert_eq!(socket.read(&mut [0u8; 1]).unwrap_or(0), 0);
+    assert!(start.elapsed() < Duration::from_secs(3));
+    trickle.join().unwrap();
+    server.shutdown();
+}
+
+fn read_http_response(socket: &mut TcpStream) -> String {
+    let mut response = vec![];
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        socket.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+fn write_masked_binary_frame(socket: &mut TcpStream, payload: &[u8]) {
+    socket.write_all(&masked_binary_frame(payload)).unwrap();
+}
+
+fn masked_binary_frame(payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x82, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+#[test]
+fn embedded_server_reloads_accounts_on_request() {
+    let accounts_file = temp_file("reload_accounts.txt");
+    fs::write(&accounts_file, "agus;1234\n").unwrap();
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("reload.log"))
+        .accounts_file(&accounts_file)
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+
+    fs::write(&accounts_file, "agus;nueva\n").unwrap();
+    assert!(server.reload());
+    let mut old_password = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(
+        connect_with_credentials(&mut old_password, "viejo", "agus", "1234"),
+        Some(4)
+    );
+    let mut new_password = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(
+        connect_with_credentials(&mut new_password, "nuevo", "agus", "nueva"),
+        Some(0)
+    );
+
+    server.shutdown();
+    let _ = fs::remove_file(accounts_file);
+}
+
+#[test]
+fn require_auth_listener_refuses_a_username_without_password() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("require_auth.log"))
+        .auth_policy(AuthPolicy {
+            users_without_password: vec!["sensor".to_string()],
+            ..AuthPolicy::default()
+        })
+        .listener(ListenerConfig {
+            require_auth: true,
+            ..ListenerConfig::tcp("public", "127.0.0.1", 0)
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let public = server.listener_address("public").unwrap();
+
+    // La política de [auth] lo aceptaría, pero el listener exige credenciales
+    let mut without_password = TcpStream::connect(public).unwrap();
+    assert_eq!(
+        connect_with_username(&mut without_password, "intruso", "cualquiera"),
+        Some(5)
+    );
+    let mut listed = TcpStream::connect(public).unwrap();
+    assert_eq!(
+        connect_with_username(&mut listed, "sensor", "sensor"),
+        Some(0)
+    );
+
+    server.shutdown();
+}
+
+fn connect_with_username(socket: &mut TcpStream, client_id: &str, username: &str) -> Option<u8> {
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let connect_packet = Connect::new(
+        ConnectPayload::new(
+            client_id.to_owned(),
+            None,
+            None,
+            Some(username.to_owned()),
+            None,
+        ),
+        60,
+        true,
+        false,
+        false,
+    );
+    connect_packet.write_to(socket).ok()?;
+    match Packet::read_from(socket) {
+        Ok(Packet::Connack(connack)) => Some(connack.connect_return_code),
+        _ => None,
+    }
+}
+
+#[test]
+fn each_listener_enforces_its_own_limits() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("listeners.log"))
+        .listener(ListenerConfig {
+            max_connections: 1,
+            ..ListenerConfig::tcp("lan", "127.0.0.1", 0)
+        })
+        .listener(ListenerConfig {
+            require_auth: true,
+            ..ListenerConfig::tcp("public", "127.0.0.1", 0)
+        })
+        .listener(ListenerConfig {
+            max_packet_size: 64,
+            ..ListenerConfig::tcp("small", "127.0.0.1", 0)
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let lan = server.listener_address("lan").unwrap();
+    let public = server.listener_address("public").unwrap();
+    let small = server.listener_address("small").unwrap();
+
+    // lan acepta una sola conexión a la vez
+    let mut first = TcpStream::connect(lan).unwrap();
+    assert_eq!(connect_anonymous(&mut first, "primero"), Some(0));
+    let mut second = TcpStream::connect(lan).unwrap();
+    assert_eq!(connect_anonymous(&mut second, "segundo"), None);
+
+    // public no acepta clientes sin usuario
+    let mut anonymous = TcpStream::connect(public).unwrap();
+    assert_eq!(connect_anonymous(&mut anonymous, "anonimo"), Some(5));
+
+    // En lan no hay límite de tamaño, en small se corta la conexión
+    let big_publish = Publish::new(
+        PublishFlags::new(0b0011_0000),
+        "grande".to_string(),
+        None,
+        "x".repeat(200),
+    );
+    big_publish.write_to(&mut first).unwrap();
+    Pingreq::new().write_to(&mut first).unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut first),
+        Ok(Packet::Pingresp(_))
+    ));
+
+    let mut limited = TcpStream::connect(small).unwrap();
+    assert_eq!(connect_anonymous(&mut limited, "limitado"), Some(0));
+    big_publish.write_to(&mut limited).unwrap();
+    let _ = Pingreq::new().write_to(&mut limited);
+    assert!(Packet::read_from(&mut limited).is_err());
+
+    server.shutdown();
+}
+
+#[test]
+fn per_ip_cap_and_connect_deadline_close_idle_and_trickling_sockets() {
+    let log_file = temp_file("limits.log");
+    let server = ServerBuilder::new()
+        .log_file(&log_file)
+        .connection(ClientHandlerConfig {
+            connect_timeout: Duration::from_secs(1),
+            ..ClientHandlerConfig::default()
+        })
+        .connection_limits(ConnectionLimits {
+            max_connections: 0,
+            max_connections_per_ip: 2,
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let address = server.local_address();
+
+    let _idle = TcpStream::connect(address).unwrap();
+    let mut trickling = TcpStream::connect(address).unwrap();
+    let mut refused = TcpStream::connect(address).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(refused.read(&mut [0u8; 1]).unwrap_or(0), 0);
+
+    // Mandar el Connect de a un byte no estira el plazo
+    let mut connect = vec![];
+    Connect::new(
+        ConnectPayload::new("lento".to_owned(), None, None, None, None),
+        60,
+        true,
+        false,
+        false,
+    )
+    .write_to(&mut connect)
+    .unwrap();
+    let mut writer = trickling.try_clone().unwrap();
+    let trickle = thread::spawn(move || {
+        for byte in connect {
+            if writer.write_all(&[byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(300));
+        }
+    });
+    let start = std::time::Instant::now();
+    trickling
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    assert_eq!(trickling.read(&mut [0u8; 1]).unwrap_or(0), 0);
+    assert!(start.elapsed() < Duration::from_secs(3));
+    trickle.join().unwrap();
+
+    // Cerradas las conexiones que no mandaron el Connect, la IP vuelve a tener lugar
+    let connected = (0..20).any(|_| {
+        let mut socket = TcpStream::connect(address).unwrap();
+        if connect_anonymous(&mut socket, "puntual") == Some(0) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+        false
+    });
+    assert!(connected);
+
+    server.shutdown();
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains("already has 2 connections"));
+    assert!(log.contains("CONNECT not received in time"));
+}
+
+#[test]
+fn keep_alive_expiry_disconnects_the_client_and_publishes_its_will() {
+    let clock = ManualClock::new();
+    let log_file = temp_file("keep_alive.log");
+    let server = ServerBuilder::new()
+        .log_file(&log_file)
+        .clock(Arc::new(clock.clone()))
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let address = server.local_address();
+
+    let mut observer = TcpStream::connect(address).unwrap();
+    assert_eq!(connect_anonymous(&mut observer, "observador"), Some(0));
+    let mut subscribe = Subscribe::new(1);
+    subscribe.add_subscription(Subscription {
+        topic_filter: "estado/sensor".to_string(),
+        max_qos: Qos::AtMostOnce,
+    });
+    subscribe.write_to(&mut observer).unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut observer),
+        Ok(Packet::Suback(_))
+    ));
+
+    let mut sensor = TcpStream::connect(address).unwrap();
+    sensor
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    Connect::new(
+        ConnectPayload::new(
+            "sensor".to_owned(),
+            Some("estado/sensor".to_owned()),
+            Some("offline".to_owned()),
+            None,
+            None,
+        ),
+        10,
+        true,
+        false,
+        false,
+    )
+    .write_to(&mut sensor)
+    .unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut sensor),
+        Ok(Packet::Connack(_))
+    ));
+
+    // Un Pingreq antes de los 15 segundos reinicia el plazo
+    clock.advance(Duration::from_secs(14));
+    Pingreq::new().write_to(&mut sensor).unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut sensor),
+        Ok(Packet::Pingresp(_))
+    ));
+    clock.advance(Duration::from_secs(14));
+    thread::sleep(Duration::from_millis(600));
+    observer
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    assert!(Packet::read_from(&mut observer).is_err());
+
+    clock.advance(Duration::from_secs(2));
+    observer
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    match Packet::read_from(&mut observer).unwrap() {
+        Packet::Publish(publish) => {
+            assert_eq!(publish.topic_name, "estado/sensor");
+            assert_eq!(publish.application_message, "offline");
+        }
+        packet => panic!("Expected the will, received {:?}", packet),
+    }
+    assert!(Packet::read_from(&mut sensor).is_err());
+
+    server.shutdown();
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains("Keep alive expired (no packets in 15.0s)"));
+}
+
+#[test]
+fn sys_topics_are_published_periodically_and_reserved_for_the_server() {
+    let clock = ManualClock::new();
+    let log_file = temp_file("sys.log");
+    let server = ServerBuilder::new()
+        .log_file(&log_file)
+        .clock(Arc::new(clock.clone()))
+        .start()
+        .unwrap();
+    let mut monitor = server
+        .connect_local(LocalClientOptions::new("monitor"))
+        .unwrap();
+    monitor
+        .subscribe(&[("$SYS/broker/#", Qos::AtMostOnce)])
+        .unwrap();
+    let mut everything = server
+        .connect_local(LocalClientOptions::new("todo"))
+        .unwrap();
+    everything.subscribe(&[("#", Qos::AtMostOnce)]).unwrap();
+    // Lo publicado al arrancar (o el retenido que llega al suscribirse)
+    while monitor.recv(Duration::from_millis(300)).unwrap().is_some() {}
+
+    let mut intruder = server
+        .connect_local(LocalClientOptions::new("intruso"))
+        .unwrap();
+    intruder
+        .publish("$SYS/broker/version", "falsa", Qos::AtLeastOnce, false)
+        .unwrap();
+    intruder
+        .publish("casa/luz", "on", Qos::AtMostOnce, false)
+        .unwrap();
+
+    // El # no incluye a $SYS, y la publicación del cliente se descartó
+    let received = everything.recv(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(received.topic_name, "casa/luz");
+    assert!(everything
+        .recv(Duration::from_millis(300))
+        .unwrap()
+        .is_none());
+    assert!(monitor.recv(Duration::from_millis(300)).unwrap().is_none());
+
+    clock.advance(Duration::from_secs(10));
+    let mut values = HashMap::new();
+    while values.len() < 11 {
+        let publish = monitor.recv(Duration::from_secs(5)).unwrap().unwrap();
+        values.insert(publish.topic_name, publish.application_message);
+    }
+    assert_eq!(values["$SYS/broker/version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(values["$SYS/broker/uptime"], "10 seconds");
+    assert_eq!(values["$SYS/broker/clients/connected"], "3");
+    assert_eq!(values["$SYS/broker/clients/disconnected"], "0");
+    assert_eq!(values["$SYS/broker/subscriptions/count"], "2");
+    assert_eq!(values["$SYS/broker/messages/received"], "2");
+    assert_eq!(values["$SYS/broker/messages/dropped"], "1");
+    let bytes_received: u64 = values["$SYS/broker/bytes/received"].parse().unwrap();
+    assert!(bytes_received > 0);
+
+    server.shutdown();
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains("Publish to $SYS/broker/version denied"));
+}
+
+#[test]
+fn audit_trail_records_publishes_deliveries_and_pubacks_of_audited_topics() {
+    let audit_file = temp_file("audit.log");
+    let _ = fs::remove_file(&audit_file);
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("audit_server.log"))
+        .sys_interval_seconds(0)
+        .audit_file(&audit_file, &["pagos/#"], true)
+        .start()
+        .unwrap();
+    let mut cashier = server
+        .connect_local(LocalClientOptions::new("caja-central"))
+        .unwrap();
+    cashier.subscribe(&[("#", Qos::AtLeastOnce)]).unwrap();
+    let mut terminal = server
+        .connect_local(LocalClientOptions::new("terminal-1"))
+        .unwrap();
+    terminal
+        .publish("casa/luz", "on", Qos::AtLeastOnce, false)
+        .unwrap();
+    terminal
+        .publish("pagos/tarjeta", "100", Qos::AtLeastOnce, true)
+        .unwrap();
+    assert_eq!(
+        cashier
+            .recv(Duration::from_secs(5))
+            .unwrap()
+            .unwrap()
+            .topic_name,
+        "casa/luz"
+    );
+    assert_eq!(
+        cashier
+            .recv(Duration::from_secs(5))
+            .unwrap()
+            .unwrap()
+            .topic_name,
+        "pagos/tarjeta"
+    );
+    server.shutdown();
+
+    // El archivo lo escribe otro thread
+    let mut records: Vec<serde_json::Value> = vec![];
+    for _ in 0..50 {
+        let contents = fs::read_to_string(&audit_file).unwrap_or_default();
+        records = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        if records.len() >= 4 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let events: Vec<(&str, &str)> = records
+        .iter()
+        .map(|r| {
+            (
+                r["event"].as_str().unwrap(),
+                r["client_id"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            ("publish", "terminal-1"),
+            ("deliver", "caja-central"),
+            ("puback", "terminal-1"),
+            ("puback", "caja-central"),
+        ]
+    );
+    assert!(records.iter().all(|r| r["topic"] == "pagos/tarjeta"));
+    assert_eq!(records[0]["retain"], true);
+    assert_eq!(records[0]["qos"], 1);
+    assert_eq!(records[0]["payload_size"], 3);
+    assert!(records[0]["payload_sha256"].is_string());
+    assert_eq!(records[1]["retain"], false);
+    assert_eq!(records[2]["direction"], "sent");
+    assert_eq!(records[3]["direction"], "received");
+    assert!(records[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+}
+
+#[test]
+fn capture_records_the_raw_traffic_of_selected_clients() {
+    let capture_file = temp_file("capture.txt");
+    let _ = fs::remove_file(&capture_file);
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("capture.log"))
+        .sys_interval_seconds(0)
+        .capture_file(&capture_file, &["sensor*"])
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+
+    for client_id in ["sensor1", "otro"].iter() {
+        let mut socket = TcpStream::connect(server.local_address()).unwrap();
+        assert_eq!(connect_anonymous(&mut socket, client_id), Some(0));
+        Pingreq::new().write_to(&mut socket).unwrap();
+        assert!(matches!(
+            Packet::read_from(&mut socket),
+            Ok(Packet::Pingresp(_))
+        ));
+        Disconnect::new().write_to(&mut socket).unwrap();
+    }
+    server.shutdown();
+
+    let mut records = vec![];
+    for _ in 0..50 {
+        let contents = fs::read_to_string(&capture_file).unwrap_or_default();
+        records = common::capture::parse(&contents).unwrap();
+        if records.len() >= 5 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let summary: Vec<(&str, Direction, u8)> = records
+        .iter()
+        .map(|r| (r.client_id.as_str(), r.direction, r.bytes[0]))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("sensor1", Direction::Inbound, 0x10),
+            ("sensor1", Direction::Outbound, 0x20),
+            ("sensor1", Direction::Inbound, 0xc0),
+            ("sensor1", Direction::Outbound, 0xd0),
+            ("sensor1", Direction::Inbound, 0xe0),
+        ]
+    );
+    // Los bytes son los del socket: el Connect se puede volver a leer
+    let connect = Packet::read_from(&mut records[0].bytes.as_slice()).unwrap();
+    assert!(matches!(connect, Packet::Connect(c) if c.connect_payload.client_id == "sensor1"));
+    assert!(records.windows(2).all(|pair| pair[0].time <= pair[1].time));
+}
+
+#[test]
+fn hooks_can_reject_connections_and_reroute_or_drop_publishes() {
+    let counter = Arc::new(EventCounter::new());
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("hooks.log"))
+        .sys_interval_seconds(0)
+        .hook(Arc::new(ClientIdPrefix::new(&["sensor", "monitor"])))
+        .hook(Arc::new(TopicRewrite::new("legacy/", "devices/")))
+        .hook(Arc::new(MaxPayloadSize::new(4)))
+        .hook(counter.clone())
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+
+    let mut socket = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(connect_anonymous(&mut socket, "otro"), Some(0x05));
+
+    let mut monitor = server
+        .connect_local(LocalClientOptions::new("monitor"))
+        .unwrap();
+    // El filtro también se reescribe: queda suscripto a devices/#
+    monitor.subscribe(&[("legacy/#", Qos::AtMostOnce)]).unwrap();
+    let mut sensor = server
+        .connect_local(LocalClientOptions::new("sensor1"))
+        .unwrap();
+    sensor
+        .publish("legacy/temp", "21", Qos::AtLeastOnce, false)
+        .unwrap();
+    sensor
+        .publish("legacy/temp", "demasiado largo", Qos::AtLeastOnce, false)
+        .unwrap();
+
+    let delivered = monitor.recv(Duration::from_secs(2)).unwrap().unwrap();
+    assert_eq!(delivered.topic_name, "devices/temp");
+    assert_eq!(delivered.application_message, "21");
+    assert!(monitor.recv(Duration::from_millis(200)).unwrap().is_none());
+
+    sensor.disconnect().unwrap();
+    let mut counts = counter.counts();
+    for _ in 0..50 {
+        counts = counter.counts();
+        if counts.disconnects == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(
+        counts,
+        EventCounts {
+            connects: 2,
+            subscriptions: 1,
+            publishes: 1,
+            deliveries: 1,
+            disconnects: 1,
+        }
+    );
+    server.shutdown();
+}
+
+#[test]
+fn client_lifecycle_events_report_each_connection_and_its_reason() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("events.log"))
+        .sys_interval_seconds(0)
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let mut monitor = server
+        .connect_local(LocalClientOptions::new("monitor"))
+        .unwrap();
+    monitor
+        .subscribe(&[("$SYS/clients/+/+", Qos::AtMostOnce)])
+        .unwrap();
+    let mut next_event = || {
+        let publish = monitor.recv(Duration::from_secs(5)).unwrap().unwrap();
+        let payload: serde_json::Value =
+            serde_json::from_str(&publish.application_message).unwrap();
+        (publish.topic_name, payload)
+    };
+
+    let mut first = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(connect_anonymous(&mut first, "sensor"), Some(0));
+    let (topic, connected) = next_event();
+    assert_eq!(topic, "$SYS/clients/sensor/connected");
+    assert_eq!(connected["reason"], "connect");
+    assert_eq!(
+        connected["address"],
+        first.local_addr().unwrap().to_string()
+    );
+    assert_eq!(connected["username"], serde_json::Value::Null);
+    assert!(connected["connected_at"].as_u64().unwrap() > 0);
+
+    // Otra conexión con el mismo client id reemplaza a la primera
+    let mut second = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(connect_anonymous(&mut second, "sensor"), Some(0));
+    let (topic, replaced) = next_event();
+    assert_eq!(topic, "$SYS/clients/sensor/disconnected");
+    assert_eq!(replaced["reason"], "takeover");
+    assert_eq!(replaced["address"], connected["address"]);
+    assert!(
+        replaced["disconnected_at"].as_u64().unwrap() >= replaced["connected_at"].as_u64().unwrap()
+    );
+    let (_, reconnected) = next_event();
+    assert_eq!(reconnected["reason"], "takeover");
+    assert_eq!(
+        reconnected["address"],
+        second.local_addr().unwrap().to_string()
+    );
+
+    Disconnect::new().write_to(&mut second).unwrap();
+    let (topic, disconnected) = next_event();
+    assert_eq!(topic, "$SYS/clients/sensor/disconnected");
+    assert_eq!(disconnected["reason"], "disconnect");
+
+    let mut third = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(connect_anonymous(&mut third, "lampara"), Some(0));
+    assert_eq!(next_event().1["reason"], "connect");
+    drop(third);
+    let (topic, dropped) = next_event();
+    assert_eq!(topic, "$SYS/clients/lampara/disconnected");
+    assert_eq!(dropped["reason"], "socket_error");
+
+    server.shutdown();
+}
+
+#[test]
+fn metrics_endpoint_reports_packets_connections_and_latency() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("metrics.log"))
+        .metrics_port(0)
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let metrics_address = server.metrics_address().unwrap();
+
+    let mut client = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(connect_anonymous(&mut client, "medidor"), Some(0));
+    Publish::new(
+        PublishFlags::new(0b0011_0010),
+        "casa/consumo".to_string(),
+        Some(1),
+        "350".to_string(),
+    )
+    .write_to(&mut client)
+    .unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut client),
+        Ok(Packet::Puback(_))
+    ));
+    // Los valores de las sesiones se actualizan una vez por segundo
+    thread::sleep(Duration::from_millis(1200));
+
+    let (status, metrics) = http_get(metrics_address, "/metrics");
+    assert!(status.starts_with("HTTP/1.1 200"));
+    for line in [
+        "mqtt_packets_received_total{type=\"connect\"} 1",
+        "mqtt_packets_received_total{type=\"publish\"} 1",
+        "mqtt_packets_sent_total{type=\"connack\"} 1",
+        "mqtt_packets_sent_total{type=\"puback\"} 1",
+        "mqtt_connections_active 1",
+        "mqtt_clients_connected 1",
+        "mqtt_client_queued_messages{client_id=\"medidor\"} 0",
+        "mqtt_publish_latency_seconds_count 1",
+    ]
+    .iter()
+    {
+        assert!(metrics.lines().any(|l| l == *line), "missing {}", line);
+    }
+    let (status, _) = http_get(metrics_address, "/otra");
+    assert!(status.starts_with("HTTP/1.1 404"));
+
+    server.shutdown();
+}
+
+#[test]
+fn admin_api_inspects_sessions_and_manages_retained_messages_and_clients() {
+    let token_file = temp_file("admin.token");
+    fs::write(&token_file, "s3cret\n").unwrap();
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("admin.log"))
+        .sys_interval_seconds(0)
+        .admin_port(0, &token_file)
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let admin = server.admin_address().unwrap();
+    let api = |method: &str, path: &str, body: &str| {
+        let (status, body) =
+            http_request(admin, method, path, &["Authorization: Bearer s3cret"], body);
+        (
+            status,
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+        )
+    };
+
+    let mut client = TcpStream::connect(server.local_address()).unwrap();
+    assert_eq!(connect_anonymous(&mut client, "sensor"), Some(0));
+    let mut subscribe_packet = Subscribe::new(1);
+    subscribe_packet.add_subscription(Subscription {
+        topic_filter: "casa/#".to_string(),
+        max_qos: Qos::AtLeastOnce,
+    });
+    subscribe_packet.write_to(&mut client).unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut client),
+        Ok(Packet::Suback(_))
+    ));
+
+    let (status, _) = http_request(admin, "GET", "/api/clients", &[], "");
+    assert!(status.starts_with("HTTP/1.1 401"));
+    let (status, clients) = api("GET", "/api/clients", "");
+    assert!(status.starts_with("HTTP/1.1 200"));
+    assert_eq!(clients[0]["client_id"], "sensor");
+    assert_eq!(clients[0]["connected"], true);
+    assert_eq!(
+        clients[0]["address"],
+        client.local_addr().unwrap().to_string()
+    );
+    assert_eq!(
+        clients[0]["subscriptions"],
+        serde_json::json!([{"topic_filter": "casa/#", "qos": 1}])
+    );
+
+    // Lo publicado como broker le llega al suscriptor, y sin Puback queda en vuelo
+    let (status, _) = api(
+        "POST",
+        "/api/publish",
+        r#"{"topic": "casa/luz", "message": "on", "qos": 1, "retain": true}"#,
+    );
+    assert!(status.starts_with("HTTP/1.1 200"));
+    match Packet::read_from(&mut client) {
+        Ok(Packet::Publish(publish)) => {
+            assert_eq!(publish.topic_name, "casa/luz");
+            assert_eq!(publish.application_message, "on");
+        }
+        other => panic!("Expected Publish, received {:?}", other),
+    }
+    assert_eq!(api("GET", "/api/clients/sensor", "").1["in_flight"], 1);
+    let (status, _) = api("POST", "/api/publish", r#"{"topic": "$SYS/x"}"#);
+    assert!(status.starts_with("HTTP/1.1 400"));
+
+    let (_, retained) = api("GET", "/api/retained", "");
+    assert_eq!(
+        retained,
+        serde_json::json!([{"topic": "casa/luz", "message": "on", "qos": 1}])
+    );
+    let (status, _) = api("DELETE", "/api/retained/casa%2Fluz", "");
+    assert!(status.starts_with("HTTP/1.1 200"));
+    assert_eq!(api("GET", "/api/retained", "").1, serde_json::json!([]));
+
+    let (_, stats) = api("GET", "/api/stats", "");
+    assert_eq!(stats["packets_received"]["connect"], 1);
+    assert_eq!(stats["packets_received"]["subscribe"], 1);
+    assert_eq!(stats["connections_active"], 1);
+    let (status, reloaded) = api("POST", "/api/reload", "");
+    assert!(status.starts_with("HTTP/1.1 200"));
+    assert_eq!(reloaded["reloaded"], true);
+
+    let (status, _) = api("POST", "/api/clients/sensor/disconnect", "");
+    assert!(status.starts_with("HTTP/1.1 200"));
+    // Antes del cierre puede llegar el reenvío del Publish que quedó sin Puback
+    loop {
+        match Packet::read_from(&mut client) {
+            Ok(Packet::Publish(_)) => continue,
+            Ok(packet) => panic!("Expected the connection to close, received {:?}", packet),
+            Err(_) => break,
+        }
+    }
+    // La sesión era clean, así que ya no existe
+    let (status, _) = api("GET", "/api/clients/sensor", "");
+    assert!(status.starts_with("HTTP/1.1 404"));
+
+    server.shutdown();
+}
+
+// Devuelve la línea de estado y el body
+fn http_get(address: SocketAddr, path: &str) -> (String, String) {
+    http_request(address, "GET", path, &[], "")
+}
+
+fn http_request(
+    address: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[&str],
+    body: &str,
+) -> (String, String) {
+    let mut socket = TcpStream::connect(address).unwrap();
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: test\r\n", method, path);
+    for header in headers {
+        request.push_str(&format!("{}\r\n", header));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    socket.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+// Devuelve el return code del Connack, o None si el server cerró la conexión
+fn connect_anonymous(socket: &mut TcpStream, client_id: &str) -> Option<u8> {
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let connect_packet = Connect::new(
+        ConnectPayload::new(client_id.to_owned(), None, None, None, None),
+        60,
+        true,
+        false,
+        false,
+    );
+    connect_packet.write_to(socket).ok()?;
+    match Packet::read_from(socket) {
+        Ok(Packet::Connack(connack)) => Some(connack.connect_return_code),
+        _ => None,
+    }
+}
+
+#[test]
+fn unix_listener_uses_the_peer_uid_as_username() {
+    let socket_path = temp_file("mqtt.sock");
+    let own_uid = unsafe { libc::getuid() }.to_string();
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("unix.log"))
+        .auth_policy(AuthPolicy {
+            allow_anonymous: false,
+            require_credentials: true,
+            users_without_password: vec![own_uid],
+        })
+        .listener(ListenerConfig {
+            permissions: Some(0o600),
+            peer_uid_as_username: true,
+            ..ListenerConfig::unix("local", &socket_path)
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // Las credenciales que manda el cliente se ignoran: vale el UID del proceso
+    let mut socket = UnixStream::connect(&socket_path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    Connect::new(
+        ConnectPayload::new(
+            "local".to_owned(),
+            None,
+            None,
+            Some("otro".to_owned()),
+            Some("incorrecto".to_owned()),
+        ),
+        60,
+        true,
+        false,
+        false,
+    )
+    .write_to(&mut socket)
+    .unwrap();
+    match Packet::read_from(&mut socket).unwrap() {
+        Packet::Connack(connack) => assert_eq!(connack.connect_return_code, 0),
+        packet => panic!("Expected Connack, received {:?}", packet),
+    }
+
+    server.shutdown();
+    assert!(fs::metadata(&socket_path).is_err());
+}
+
+#[test]
+fn proxy_protocol_listener_bans_the_real_client_address() {
+    let log_file = temp_file("proxy.log");
+    let server = ServerBuilder::new()
+        .log_file(&log_file)
+        .auth_limiter(AuthLimiterConfig {
+            max_failures: 1,
+            ban_seconds: 60,
+            max_ban_seconds: 60,
+        })
+        .listener(ListenerConfig {
+            proxy_protocol: true,
+            ..ListenerConfig::tcp("balanced", "127.0.0.1", 0)
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let address = server.local_address();
+
+    let mut attacker = TcpStream::connect(address).unwrap();
+    attacker
+        .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 1883\r\n")
+        .unwrap();
+    assert_eq!(
+        connect_with_credentials(&mut attacker, "atacante", "usuario", "incorrecto"),
+        Some(4)
+    );
+
+    // La misma IP real queda bloqueada, aunque todo llega desde 127.0.0.1
+    let mut again = TcpStream::connect(address).unwrap();
+    again
+        .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51001 1883\r\n")
+        .unwrap();
+    assert_eq!(connect_anonymous(&mut again, "atacante2"), Some(5));
+
+    let mut other = TcpStream::connect(address).unwrap();
+    other
+        .write_all(b"PROXY TCP4 198.51.100.1 10.0.0.1 40000 1883\r\n")
+        .unwrap();
+    assert_eq!(connect_anonymous(&mut other, "otro"), Some(0));
+
+    // Sin header no se acepta la conexión
+    let mut direct = TcpStream::connect(address).unwrap();
+    assert_eq!(connect_anonymous(&mut direct, "directo"), None);
+
+    server.shutdown();
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains("Connect Packet received (198.51.100.1:40000)"));
+}
+
+#[test]
+fn proxy_header_without_address_does_not_ban_the_balancer() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("proxy_unknown.log"))
+        .auth_limiter(AuthLimiterConfig {
+            max_failures: 1,
+            ban_seconds: 60,
+            max_ban_seconds: 60,
+        })
+        .listener(ListenerConfig {
+            proxy_protocol: true,
+            ..ListenerConfig::tcp("balanced", "127.0.0.1", 0)
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let address = server.local_address();
+
+    let mut unknown = TcpStream::connect(address).unwrap();
+    unknown.write_all(b"PROXY UNKNOWN\r\n").unwrap();
+    assert_eq!(
+        connect_with_credentials(&mut unknown, "desconocido", "usuario", "incorrecto"),
+        Some(4)
+    );
+
+    // El fallo no se le atribuyó a 127.0.0.1, así que el resto de los clientes sigue entrando
+    let mut other = TcpStream::connect(address).unwrap();
+    other
+        .write_all(b"PROXY TCP4 198.51.100.1 10.0.0.1 40000 1883\r\n")
+        .unwrap();
+    assert_eq!(connect_anonymous(&mut other, "otro"), Some(0));
+
+    server.shutdown();
+}
+
+#[test]
+fn proxy_protocol_listener_limits_connections_by_the_real_client_address() {
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("proxy_limits.log"))
+        .connection_limits(ConnectionLimits {
+            max_connections: 0,
+            max_connections_per_ip: 1,
+        })
+        .listener(ListenerConfig {
+            proxy_protocol: true,
+            ..ListenerConfig::tcp("balanced", "127.0.0.1", 0)
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let address = server.local_address();
+
+    // Las dos llegan desde 127.0.0.1, pero son clientes distintos
+    let mut first = TcpStream::connect(address).unwrap();
+    first
+        .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 1883\r\n")
+        .unwrap();
+    assert_eq!(connect_anonymous(&mut first, "primero"), Some(0));
+
+    let mut second = TcpStream::connect(address).unwrap();
+    second
+        .write_all(b"PROXY TCP4 198.51.100.1 10.0.0.1 40000 1883\r\n")
+        .unwrap();
+    assert_eq!(connect_anonymous(&mut second, "segundo"), Some(0));
+
+    let mut same_ip = TcpStream::connect(address).unwrap();
+    same_ip
+        .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51001 1883\r\n")
+        .unwrap();
+    assert_eq!(connect_anonymous(&mut same_ip, "repetido"), None);
+
+    server.shutdown();
+}
+
+fn connect_with_credentials(
+    socket: &mut TcpStream,
+    client_id: &str,
+    username: &str,
+    password: &str,
+) -> Option<u8> {
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let connect_packet = Connect::new(
+        ConnectPayload::new(
+            client_id.to_owned(),
+            None,
+            None,
+            Some(username.to_owned()),
+            Some(password.to_owned()),
+        ),
+        60,
+        true,
+        false,
+        false,
+    );
+    connect_packet.write_to(socket).ok()?;
+    match Packet::read_from(socket) {
+        Ok(Packet::Connack(connack)) => Some(connack.connect_return_code),
+        _ => None,
+    }
+}
+
+// CA, certificado del server para 127.0.0.1 y certificado de cliente con CN sensor-1, todos
+// generados para el test
+struct TestCertificates {
+    ca_file: String,
+    server_cert_file: String,
+    server_key_file: String,
+    client_cert_file: String,
+    client_key_file: String,
+}
+
+fn generate_certificates() -> TestCertificates {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "test ca");
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_params =
+        CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+    let server_cert = server_params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+    let client_key = KeyPair::generate().unwrap();
+    let mut client_params = CertificateParams::new(vec![]).unwrap();
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, "sensor-1");
+    let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+    let files = TestCertificates {
+        ca_file: temp_file("ca.pem"),
+        server_cert_file: temp_file("server.pem"),
+        server_key_file: temp_file("server.key"),
+        client_cert_file: temp_file("client.pem"),
+        client_key_file: temp_file("client.key"),
+    };
+    fs::write(&files.ca_file, ca.pem()).unwrap();
+    fs::write(&files.server_cert_file, server_cert.pem()).unwrap();
+    fs::write(&files.server_key_file, server_key.serialize_pem()).unwrap();
+    fs::write(&files.client_cert_file, client_cert.pem()).unwrap();
+    fs::write(&files.client_key_file, client_key.serialize_pem()).unwrap();
+    files
+}
+
+#[test]
+fn tls_listener_uses_the_client_certificate_cn_as_username() {
+    let certificates = generate_certificates();
+    let server = ServerBuilder::new()
+        .log_file(&temp_file("tls.log"))
+        .auth_policy(AuthPolicy {
+            allow_anonymous: false,
+            require_credentials: true,
+            users_without_password: vec!["sensor-1".to_owned()],
+        })
+        .listener(ListenerConfig {
+            tls_cert_file: Some(certificates.server_cert_file.clone()),
+            tls_key_file: Some(certificates.server_key_file.clone()),
+            tls_client_ca_file: Some(certificates.ca_file.clone()),
+            cert_cn_as_username: true,
+            ..ListenerConfig::tcp("secure", "127.0.0.1", 0)
+        })
+        .start()
+        .unwrap();
+    assert!(server.wait_ready(Duration::from_secs(5)));
+    let address = server.local_address().to_string();
+
+    // Las credenciales del Connect se ignoran: vale el CN del certificado
+    let tls = TlsClientConfig::new(&certificates.ca_file).with_client_cert(
+        &certificates.client_cert_file,
+        &certificates.client_key_file,
+    );
+    let mut socket = MqttStream::connect(&address, Some(&tls)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    Connect::new(
+        ConnectPayload::new(
+            "sensor".to_owned(),
+            None,
+            None,
+            Some("otro".to_owned()),
+            Some("incorrecto".to_owned()),
+        ),
+        60,
+        true,
+        false,
+        false,
+    )
+    .write_to(&mut socket)
+    .unwrap();
+    match Packet::read_from(&mut socket).unwrap() {
+        Packet::Connack(connack) => assert_eq!(connack.connect_return_code, 0),
+        packet => panic!("Expected Connack, received {:?}", packet),
+    }
+    Pingreq::new().write_to(&mut socket).unwrap();
+    assert!(matches!(
+        Packet::read_from(&mut socket),
+        Ok(Packet::Pingresp(_))
+    ));
+
+    // Sin certificado de cliente el server corta la conexión
+    let without_certificate = TlsClientConfig::new(&certificates.ca_file);
+    if let Ok(mut socket) = MqttStream::connect(&address, Some(&without_certificate)) {
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let connect = Connect::new(
+            ConnectPayload::new("intruso".to_owned(), None, None, None, None),
+            60,
+            true,
+            false,
+            false,
+        );
+        let _ = connect.write_to(&mut socket);
+        assert!(Packet::read_from(&mut socket).is_err());
+    }
+
+    // Un cliente sin TLS tampoco pasa
+    let mut plain = TcpStream::connect(&address).unwrap();
+    assert_eq!(connect_anonymous(&mut plain, "plano"), None);
+
+    server.shutdown();
+}